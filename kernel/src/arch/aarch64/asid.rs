@@ -0,0 +1,234 @@
+//! Address space identifiers, tagging the TLB entries of user page tables so that
+//! switching page tables does not flush the TLB.
+//!
+//! A page table gets its ASID when first activated. ASIDs are handed out in
+//! generations: once all of them are used, the generation is rolled over, every
+//! page table takes a new ASID on its next activation and every CPU flushes its
+//! whole TLB before switching to a page table of the new generation. The ASID
+//! running on each CPU during a rollover stays reserved for its page table, that
+//! CPU keeps using it without a flush.
+//!
+//! ASID 0 is never handed out, it tags page tables activated directly by
+//! `PageTableExt::set_token`.
+//!
+//! Ref: Linux `arch/arm64/mm/context.c`
+
+use crate::{
+    consts::MAX_CPU_NUM,
+    sync::{MutexNoIrq, PerCpu},
+};
+use aarch64::translation::local_invalidate_tlb_all;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+/// ASIDs are 16 bits in TTBR0 with `TCR_EL1.AS` set, 8 bits on some cores.
+const ASID_BITS: usize = 16;
+const ASID_MASK: u64 = (1 << ASID_BITS) - 1;
+/// Generations count above the ASID, `generation | asid` identifies an ASID.
+const GENERATION_STEP: u64 = 1 << ASID_BITS;
+/// ASID field of TTBR0_EL1
+const TTBR_ASID_SHIFT: usize = 48;
+
+static GENERATION: AtomicU64 = AtomicU64::new(GENERATION_STEP);
+
+/// `generation | asid` running on each CPU, 0 after a rollover until the CPU
+/// switches page table again.
+static ACTIVE: PerCpu<AtomicU64> = {
+    const NONE: AtomicU64 = AtomicU64::new(0);
+    PerCpu::new([NONE; MAX_CPU_NUM])
+};
+
+/// CPUs which flush their TLB on the next switch, one bit per CPU.
+static FLUSH_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+static ALLOCATOR: MutexNoIrq<Allocator> = MutexNoIrq::new(Allocator {
+    used: [0; USED_WORDS],
+    next: 1,
+    reserved: [0; MAX_CPU_NUM],
+});
+
+/// Page table switches, and the ones which flushed the whole TLB.
+static SWITCHES: AtomicUsize = AtomicUsize::new(0);
+static SWITCH_FLUSHES: AtomicUsize = AtomicUsize::new(0);
+
+const USED_WORDS: usize = (1 << ASID_BITS) / 64;
+
+struct Allocator {
+    /// ASIDs taken in the current generation, one bit per ASID.
+    used: [u64; USED_WORDS],
+    /// Where the search for a free ASID starts.
+    next: usize,
+    /// `generation | asid` reserved for each CPU by the last rollover.
+    reserved: [u64; MAX_CPU_NUM],
+}
+
+impl Allocator {
+    fn take(&mut self, asid: usize) -> bool {
+        let taken = self.used[asid / 64] & 1 << (asid % 64) != 0;
+        self.used[asid / 64] |= 1 << (asid % 64);
+        taken
+    }
+
+    /// A new `generation | asid` for a page table which had `old`.
+    fn new_id(&mut self, old: u64) -> u64 {
+        let generation = GENERATION.load(Ordering::Relaxed);
+        if old != 0 {
+            let id = generation | old & ASID_MASK;
+            // still running somewhere since the rollover
+            if self.update_reserved(old, id) {
+                return id;
+            }
+            // keep the ASID if nobody took it in this generation
+            if !self.take((old & ASID_MASK) as usize) {
+                return id;
+            }
+        }
+        let asid = match self.find_free() {
+            Some(asid) => asid,
+            None => {
+                self.rollover();
+                self.find_free().expect("no ASID left after rollover")
+            }
+        };
+        self.take(asid);
+        self.next = asid + 1;
+        GENERATION.load(Ordering::Relaxed) | asid as u64
+    }
+
+    fn update_reserved(&mut self, old: u64, new: u64) -> bool {
+        let mut hit = false;
+        for reserved in self.reserved.iter_mut() {
+            if *reserved == old {
+                *reserved = new;
+                hit = true;
+            }
+        }
+        hit
+    }
+
+    fn find_free(&self) -> Option<usize> {
+        (self.next..asid_count()).find(|&asid| self.used[asid / 64] & 1 << (asid % 64) == 0)
+    }
+
+    /// Start a new generation, keeping the ASIDs running on the CPUs.
+    fn rollover(&mut self) {
+        GENERATION.fetch_add(GENERATION_STEP, Ordering::Relaxed);
+        self.used = [0; USED_WORDS];
+        for (cpu_id, active) in ACTIVE.iter().enumerate() {
+            let mut id = active.swap(0, Ordering::Relaxed);
+            // not switched since the previous rollover, still running its reserved ASID
+            if id == 0 {
+                id = self.reserved[cpu_id];
+            }
+            self.take((id & ASID_MASK) as usize);
+            self.reserved[cpu_id] = id;
+        }
+        FLUSH_PENDING.store(usize::MAX, Ordering::Relaxed);
+        self.next = 1;
+        info!(
+            "ASID rollover, generation {}",
+            GENERATION.load(Ordering::Relaxed) >> ASID_BITS
+        );
+    }
+}
+
+/// Number of ASIDs the cores implement.
+fn asid_count() -> usize {
+    let mmfr0: u64;
+    unsafe { asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0) };
+    // ID_AA64MMFR0_EL1.ASIDBits
+    match (mmfr0 >> 4) & 0xf {
+        0b0010 => 1 << 16,
+        _ => 1 << 8,
+    }
+}
+
+/// The ASID of a page table, assigned on activation.
+#[derive(Debug, Default)]
+pub struct Asid(AtomicU64);
+
+impl Asid {
+    /// No ASID until the page table is activated.
+    pub const fn new() -> Self {
+        Asid(AtomicU64::new(0))
+    }
+
+    /// The ASID in TTBR0_EL1 of this CPU.
+    pub fn active() -> Self {
+        let asid = read_ttbr0() >> TTBR_ASID_SHIFT;
+        Asid(AtomicU64::new(GENERATION.load(Ordering::Relaxed) | asid))
+    }
+
+    /// The ASID tagging the TLB entries of the page table, 0 if it has none.
+    #[inline]
+    pub fn get(&self) -> u16 {
+        (self.0.load(Ordering::Relaxed) & ASID_MASK) as u16
+    }
+}
+
+fn is_current(id: u64) -> bool {
+    id != 0 && (id ^ GENERATION.load(Ordering::Relaxed)) >> ASID_BITS == 0
+}
+
+/// Switch to the page table `token` tagged with `asid`, assigning it one first if
+/// it has none of the current generation.
+pub fn switch_to(token: u64, asid: &Asid) {
+    let cpu_id = crate::cpu::id();
+    let mut id = asid.0.load(Ordering::Relaxed);
+    let active = ACTIVE.get(cpu_id).load(Ordering::Relaxed);
+    // a rollover clears the active ASID, losing the race sends us to the slow path
+    let fast = active != 0
+        && is_current(id)
+        && ACTIVE
+            .get(cpu_id)
+            .compare_exchange(active, id, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok();
+    if !fast {
+        let mut allocator = ALLOCATOR.lock();
+        id = asid.0.load(Ordering::Relaxed);
+        if !is_current(id) {
+            id = allocator.new_id(id);
+            asid.0.store(id, Ordering::Relaxed);
+        }
+        let bit = 1 << cpu_id;
+        if FLUSH_PENDING.fetch_and(!bit, Ordering::Relaxed) & bit != 0 {
+            local_invalidate_tlb_all();
+            SWITCH_FLUSHES.fetch_add(1, Ordering::Relaxed);
+        }
+        ACTIVE.get(cpu_id).store(id, Ordering::Relaxed);
+    }
+    let ttbr = token | (id & ASID_MASK) << TTBR_ASID_SHIFT;
+    if read_ttbr0() != ttbr {
+        write_ttbr0(ttbr);
+        SWITCHES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Count a switch to a page table without an ASID, which flushes the TLB.
+pub fn count_untagged_switch() {
+    SWITCHES.fetch_add(1, Ordering::Relaxed);
+    SWITCH_FLUSHES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Page table switches since boot, and how many of them flushed the whole TLB.
+pub fn switch_stats() -> (usize, usize) {
+    (
+        SWITCHES.load(Ordering::Relaxed),
+        SWITCH_FLUSHES.load(Ordering::Relaxed),
+    )
+}
+
+/// TTBR0_EL1, root of the page table and its ASID.
+#[inline]
+pub fn read_ttbr0() -> u64 {
+    let ttbr: u64;
+    unsafe { asm!("mrs {}, ttbr0_el1", out(reg) ttbr) };
+    ttbr
+}
+
+#[inline]
+pub fn write_ttbr0(ttbr: u64) {
+    unsafe { asm!("msr ttbr0_el1, {}", "isb", in(reg) ttbr) };
+}
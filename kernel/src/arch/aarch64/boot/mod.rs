@@ -1,5 +1,5 @@
 use super::bsp::{MEMORY_END, MEMORY_START, PERIPHERALS_END, PERIPHERALS_START};
-use crate::memory::as_upper_range;
+use crate::{consts::BOOT_STACK_SIZE, memory::as_upper_range};
 use aarch64::{
     addr::{align_down, align_up, ALIGN_2MIB},
     asm::cpuid,
@@ -85,7 +85,7 @@ pub unsafe extern "C" fn enable_mmu() {
         let lr: usize;
         asm!("mov {}, x30", out(reg) lr);
         let new_lr = as_upper_range(lr) as u64;
-        let new_sp = as_upper_range(symbol_addr!(bootstacktop) - (cpuid() << 18)) as u64;
+        let new_sp = as_upper_range(symbol_addr!(bootstacktop) - cpuid() * BOOT_STACK_SIZE) as u64;
         asm!("mov x17, {}", in(reg) new_sp, options(nostack));
         asm!("mov x18, {}", in(reg) new_lr, options(nostack));
     }
@@ -129,7 +129,7 @@ pub unsafe extern "C" fn enable_mmu() {
     #[cfg(not(debug_assertions))]
     {
         // Set new stack pointer and link register.
-        SP.set(as_upper_range(symbol_addr!(bootstacktop) - (cpuid() << 18)) as u64);
+        SP.set(as_upper_range(symbol_addr!(bootstacktop) - cpuid() * BOOT_STACK_SIZE) as u64);
         LR.set(as_upper_range(LR.get() as usize) as u64);
 
         barrier::isb(barrier::SY);
@@ -1,41 +1,17 @@
-use spin::RwLock;
+use crate::{drivers::serial::PanicUart, memory::as_upper_range};
+use aarch64::registers::{Readable, SCTLR_EL1};
 
-#[derive(Debug, Clone, Copy)]
-pub struct MiniUART {
-    addr: usize,
-}
-
-impl MiniUART {
-    pub const fn new(addr: usize) -> Self {
-        Self { addr }
-    }
-}
-
-impl Default for MiniUART {
-    fn default() -> Self {
-        MiniUART::new(0x0900_0000)
-    }
-}
-
-impl core::fmt::Write for MiniUART {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for c in s.chars() {
-            unsafe {
-                core::ptr::write_volatile(self.addr as *mut u8, c as u8);
-            }
-        }
-        Ok(())
-    }
-}
-
-pub static UART: RwLock<MiniUART> = RwLock::new(MiniUART::new(0x0900_0000));
-
-#[inline]
-pub fn uart() -> MiniUART {
-    *UART.read()
-}
+/// Physical address of the PL011 of the board, known before the device tree is parsed.
+pub const UART_BASE: usize = 0x0900_0000;
 
-#[inline]
-pub fn set_new_uart(addr: usize) {
-    *UART.write() = MiniUART::new(addr);
+/// The board's UART, written without taking any lock. Usable from the first
+/// instruction: the UART is left enabled by the firmware.
+pub fn panic_uart() -> PanicUart {
+    // the MMU is off until `enable_mmu`, the peripherals are then in the upper range
+    let addr = if SCTLR_EL1.is_set(SCTLR_EL1::M) {
+        as_upper_range(UART_BASE)
+    } else {
+        UART_BASE
+    };
+    unsafe { PanicUart::new(addr) }
 }
@@ -1,10 +1,25 @@
 pub const MEMORY_OFFSET: usize = 0x4000_0000;
 pub const KERNEL_OFFSET: usize = 0xffff_0000_0000_0000;
+#[cfg(not(test))]
 pub const PHYSICAL_MEMORY_OFFSET: usize = 0xffff_8000_0000_0000;
+/// Host tests take frames from the heap and use their addresses directly.
+#[cfg(test)]
+pub const PHYSICAL_MEMORY_OFFSET: usize = 0;
 pub const KERNEL_HEAP_SIZE: usize = 64 * 1024 * 1024;
+/// Stack of each cpu carved from the top of `bootstack`, the lowest page is a guard page.
+/// `bootstack` is aligned to it, both it and the alignment are repeated in `vectors.S`.
+pub const BOOT_STACK_SIZE: usize = 1 << 18;
 
 pub const USER_STACK_OFFSET: usize = 0x0000_8000_0000_0000 - USER_STACK_SIZE;
 pub const USER_STACK_SIZE: usize = 1 * 1024 * 1024;
+/// The user stack grows on page faults up to this size, as the default RLIMIT_STACK.
+pub const USER_STACK_MAX_SIZE: usize = 8 * 1024 * 1024;
+/// The time page, below the area the user stack may grow into.
+pub const USER_TIME_PAGE_OFFSET: usize =
+    USER_STACK_OFFSET + USER_STACK_SIZE - USER_STACK_MAX_SIZE - 0x1000;
+/// Where position independent executables are loaded, 2/3 of the user space as
+/// ELF_ET_DYN_BASE on Linux.
+pub const USER_PIE_OFFSET: usize = 0x0000_5555_5555_5000;
 pub const KSEG2_START: usize = 0xffff_fe80_0000_0000;
 
 pub const ARCH: &str = "aarch64";
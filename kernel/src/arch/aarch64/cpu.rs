@@ -1,5 +1,6 @@
-use aarch64::asm;
-use core::arch::asm;
+use super::psci::{self, PsciError};
+use crate::{drivers::DeviceTree, memory::as_lower_range};
+use aarch64::{asm, cache::*};
 
 pub use aarch64::asm::{halt, nop};
 
@@ -9,23 +10,164 @@ pub fn wait_forever() -> ! {
     }
 }
 
-/// start other cpu
-/// # Safety
-#[no_mangle]
-pub unsafe extern "C" fn start_other_cpu() {
-    for cpu in 1..super::bsp::CPU_NUM {
-        asm!("ldr x0, =0xc4000003");
-        asm!("mov x1, {}", in(reg) cpu); // target CPU's MPIDR affinity
-        asm!("adr x2, other_cpu_startup");
-        asm!("ldr x3, =0"); // context ID: put into target CPU's x0
-        asm!("hvc 0");
+extern "C" {
+    fn other_cpu_startup();
+    fn spin_table_startup();
+}
+
+/// Power on the other cpus with the enable-method of their device tree nodes.
+pub fn start_others(device_tree: &DeviceTree) {
+    let psci = psci::get();
+    for cpu in 1..count() {
+        let node = match device_tree.find_node(|node| {
+            Ok(
+                device_tree.node_prop_str(node, "device_type") == Some("cpu")
+                    && device_tree.node_prop_u32(node, "reg", 0) == Some(cpu),
+            )
+        }) {
+            Some(node) => node,
+            None => {
+                warn!("CPU{} is not in the device tree.", cpu);
+                continue;
+            }
+        };
+        match device_tree.node_prop_str(&node, "enable-method") {
+            Some("psci") => {
+                let psci = match psci {
+                    Some(psci) => psci,
+                    None => {
+                        warn!("CPU{}: no PSCI firmware.", cpu);
+                        continue;
+                    }
+                };
+                // the stack below `_start` used before paging is enabled, as on the boot cpu
+                let stack_top = as_lower_range(symbol_addr!(_start)) - (cpu << 16);
+                let entry = as_lower_range(other_cpu_startup as usize);
+                match psci.cpu_on(cpu, entry, stack_top) {
+                    Ok(()) | Err(PsciError::AlreadyOn) | Err(PsciError::OnPending) => {}
+                    Err(PsciError::InvalidParameters) => warn!("CPU{}: invalid cpu.", cpu),
+                    Err(err) => warn!("CPU{}: CPU_ON failed: {:?}.", cpu, err),
+                }
+            }
+            Some("spin-table") => match device_tree.node_prop_u64(&node, "cpu-release-addr", 0) {
+                Some(release_addr) => unsafe {
+                    let entry = as_lower_range(spin_table_startup as usize);
+                    let release = crate::memory::phys_to_virt(release_addr) as *mut u64;
+                    release.write_volatile(entry as u64);
+                    // the cpu may read the release address with its caches off
+                    DCache::<Clean, PoC>::flush_range(release as usize, release as usize + 8, ISH);
+                    asm::sev();
+                },
+                None => warn!("CPU{}: no cpu-release-addr.", cpu),
+            },
+            method => warn!("CPU{}: unsupported enable-method {:?}.", cpu, method),
+        }
     }
 }
 
+#[cfg(not(test))]
 pub fn id() -> usize {
     asm::cpuid()
 }
 
+/// Host tests run in EL0, where MPIDR_EL1 cannot be read.
+#[cfg(test)]
+pub fn id() -> usize {
+    0
+}
+
+/// Number of CPUs brought up, limited by `maxcpus` of the command line.
+pub fn count() -> usize {
+    let maxcpus = crate::cmdline::cmdline().maxcpus.unwrap_or(usize::MAX);
+    maxcpus.min(super::bsp::CPU_NUM)
+}
+
+/// Power the board off, or halt if the firmware cannot.
+pub fn power_off() -> ! {
+    super::interrupt::halt_others();
+    if let Some(psci) = psci::get() {
+        warn!("PSCI: SYSTEM_OFF failed: {:?}.", psci.system_off());
+    }
+    #[cfg(feature = "semihosting")]
+    semihosting_exit();
+    warn!("Cannot power off, halting.");
+    halt_this()
+}
+
+/// Reset the board, or halt if the firmware cannot.
+pub fn reboot() -> ! {
+    super::interrupt::halt_others();
+    if let Some(psci) = psci::get() {
+        warn!("PSCI: SYSTEM_RESET failed: {:?}.", psci.system_reset());
+    }
+    warn!("Cannot reboot, halting.");
+    halt_this()
+}
+
+/// Halt all the CPUs.
+pub fn halt_all() -> ! {
+    super::interrupt::halt_others();
+    halt_this()
+}
+
+fn halt_this() -> ! {
+    unsafe { super::interrupt::disable() };
+    wait_forever()
+}
+
+/// Exit QEMU, run with `-semihosting`.
+#[cfg(feature = "semihosting")]
+fn semihosting_exit() {
+    const SYS_EXIT: usize = 0x18;
+    const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+    let block = [ADP_STOPPED_APPLICATION_EXIT, 0];
+    unsafe {
+        core::arch::asm!("hlt #0xf000", in("x0") SYS_EXIT, in("x1") block.as_ptr(), options(nostack));
+    }
+}
+
+/// Features of the cpu as the AT_HWCAP bits of Linux, from the ID registers.
+///
+/// Ref: Linux `arch/arm64/include/uapi/asm/hwcap.h`
+pub fn hwcap() -> usize {
+    let (pfr0, isar0): (u64, u64);
+    unsafe {
+        core::arch::asm!("mrs {}, id_aa64pfr0_el1", out(reg) pfr0);
+        core::arch::asm!("mrs {}, id_aa64isar0_el1", out(reg) isar0);
+    }
+    let field = |reg: u64, shift: u32| ((reg >> shift) & 0xf) as usize;
+    // FP and AdvSIMD: 0 if implemented, 1 with half precision too, 0xf if absent
+    let mut hwcap = 0;
+    for &(shift, bit, half_bit) in &[(16, 0, 9), (20, 1, 10)] {
+        match field(pfr0, shift) {
+            0 => hwcap |= 1 << bit,
+            1 => hwcap |= (1 << bit) | (1 << half_bit),
+            _ => {}
+        }
+    }
+    // (shift in ID_AA64ISAR0_EL1, minimum value, hwcap bit)
+    const ISAR0: &[(u32, usize, usize)] = &[
+        (4, 1, 3),   // AES
+        (4, 2, 4),   // PMULL
+        (8, 1, 5),   // SHA1
+        (12, 1, 6),  // SHA2
+        (16, 1, 7),  // CRC32
+        (20, 2, 8),  // ATOMICS
+        (28, 1, 12), // ASIMDRDM
+        (32, 1, 17), // SHA3
+        (36, 1, 18), // SM3
+        (40, 1, 19), // SM4
+        (44, 1, 20), // ASIMDDP
+        (12, 2, 21), // SHA512
+    ];
+    for &(shift, min, bit) in ISAR0 {
+        if field(isar0, shift) >= min {
+            hwcap |= 1 << bit;
+        }
+    }
+    hwcap
+}
+
 /// Generates an ISB (instruction synchronization barrier) instruction or equivalent CP15 instruction.
 /// # Safety
 #[inline]
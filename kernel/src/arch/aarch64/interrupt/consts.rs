@@ -1,5 +1,8 @@
 #![allow(non_upper_case_globals)]
 use super::syndrome::{Fault, Syndrome};
+use crate::signal::{
+    Signal, BUS_ADRALN, FPE_FLTUNK, ILL_ILLOPC, SEGV_MAPERR, TRAP_BRKPT, TRAP_HWBKPT, TRAP_TRACE,
+};
 use aarch64::registers::*;
 
 pub fn is_page_fault(trap: usize) -> bool {
@@ -12,7 +15,7 @@ pub fn is_page_fault(trap: usize) -> bool {
     let esr = ESR_EL1.get() as u32;
     let syndrome = Syndrome::from(esr);
     match syndrome {
-        Syndrome::DataAbort { kind, level: _ } | Syndrome::InstructionAbort { kind, level: _ } => {
+        Syndrome::DataAbort { kind, .. } | Syndrome::InstructionAbort { kind, .. } => {
             matches!(
                 kind,
                 Fault::Translation | Fault::AccessFlag | Fault::Permission
@@ -29,12 +32,115 @@ pub const IrqMin: usize = 0x10002;
 // from el0, sync
 pub const Syscall: usize = 0x00002;
 
+// from el0, fiq
+pub const Fiq: usize = 0x20002;
+
+// from el0, serror
+pub const SError: usize = 0x30002;
+
 #[inline]
 pub fn is_syscall(trap: usize) -> bool {
-    trap == Syscall
+    trap == Syscall && matches!(Syndrome::from(ESR_EL1.get() as u32), Syndrome::Svc(_))
+}
+
+#[inline]
+pub fn is_fiq(trap: usize) -> bool {
+    trap == Fiq
+}
+
+#[inline]
+pub fn is_serror(trap: usize) -> bool {
+    trap == SError
+}
+
+/// ESR of the exception being handled.
+#[inline]
+pub fn exception_syndrome() -> u32 {
+    ESR_EL1.get() as u32
+}
+
+/// The signal and its `si_code` for a synchronous exception from el0 which is
+/// neither a syscall nor a page fault, from its ESR, as Linux delivers it.
+///
+/// An undefined instruction is reported with an unknown reason.
+pub fn exception_signal(esr: u32) -> (Signal, i32) {
+    match Syndrome::from(esr) {
+        Syndrome::DataAbort {
+            kind: Fault::Alignment,
+            ..
+        }
+        | Syndrome::InstructionAbort {
+            kind: Fault::Alignment,
+            ..
+        }
+        | Syndrome::PCAlignmentFault
+        | Syndrome::SpAlignmentFault => (Signal::SIGBUS, BUS_ADRALN),
+        Syndrome::DataAbort { .. } | Syndrome::InstructionAbort { .. } => {
+            (Signal::SIGSEGV, SEGV_MAPERR)
+        }
+        Syndrome::TrappedFpu => (Signal::SIGFPE, FPE_FLTUNK),
+        Syndrome::Brk(_) | Syndrome::Breakpoint => (Signal::SIGTRAP, TRAP_BRKPT),
+        Syndrome::Step => (Signal::SIGTRAP, TRAP_TRACE),
+        Syndrome::Watchpoint => (Signal::SIGTRAP, TRAP_HWBKPT),
+        _ => (Signal::SIGILL, ILL_ILLOPC),
+    }
+}
+
+/// Whether the SError being handled may have corrupted state, from its ESR.
+///
+/// Only a corrected error, or one not consumed yet, leaves everything intact.
+/// Ref: Linux `arm64_is_fatal_ras_serror`
+pub fn is_fatal_serror(esr: u32) -> bool {
+    const ISS_IDS: u32 = 1 << 24;
+    const DFSC_MASK: u32 = 0b111111;
+    const DFSC_ASYNC: u32 = 0b010001;
+    const AET_SHIFT: u32 = 10;
+    const AET_UEO: u32 = 0b010;
+    const AET_CE: u32 = 0b110;
+    // implementation defined syndrome, nothing to tell
+    if esr & ISS_IDS != 0 || esr & DFSC_MASK != DFSC_ASYNC {
+        return true;
+    }
+    !matches!(esr >> AET_SHIFT & 0b111, AET_CE | AET_UEO)
 }
 
 #[inline]
 pub fn is_irq(trap: usize) -> bool {
     IrqMin <= trap && trap <= IrqMax
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signal_of_exception() {
+        use Signal::*;
+        // undefined instruction
+        assert_eq!(exception_signal(0x0200_0000), (SIGILL, ILL_ILLOPC));
+        // alignment faults
+        assert_eq!(exception_signal(0x9200_0061), (SIGBUS, BUS_ADRALN));
+        assert_eq!(exception_signal(0x8a00_0000), (SIGBUS, BUS_ADRALN));
+        assert_eq!(exception_signal(0x9a00_0000), (SIGBUS, BUS_ADRALN));
+        // an abort which is not a page fault
+        assert_eq!(exception_signal(0x9200_0030), (SIGSEGV, SEGV_MAPERR));
+        assert_eq!(exception_signal(0xf200_0000), (SIGTRAP, TRAP_BRKPT));
+        assert_eq!(exception_signal(0xce00_0000), (SIGTRAP, TRAP_TRACE));
+        assert_eq!(exception_signal(0xd200_0000), (SIGTRAP, TRAP_HWBKPT));
+        assert_eq!(exception_signal(0xb200_0000), (SIGFPE, FPE_FLTUNK));
+    }
+
+    #[test]
+    fn fatal_serror() {
+        const ASYNC: u32 = 0xbe00_0011;
+        // corrected and restartable errors
+        assert!(!is_fatal_serror(ASYNC | 0b110 << 10));
+        assert!(!is_fatal_serror(ASYNC | 0b010 << 10));
+        // uncontainable and unrecoverable errors
+        assert!(is_fatal_serror(ASYNC));
+        assert!(is_fatal_serror(ASYNC | 0b001 << 10));
+        // implementation defined or not asynchronous
+        assert!(is_fatal_serror(ASYNC | 1 << 24 | 0b110 << 10));
+        assert!(is_fatal_serror(0xbe00_0000 | 0b110 << 10));
+    }
+}
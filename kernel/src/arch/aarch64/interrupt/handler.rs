@@ -3,6 +3,7 @@
 use core::convert::TryFrom;
 
 use super::{
+    consts::is_fatal_serror,
     syndrome::{Fault, Syndrome},
     IRQ_MANAGER,
 };
@@ -48,6 +49,40 @@ pub struct Info {
     kind: Kind,
 }
 
+impl Info {
+    /// Whether the exception was taken from user mode.
+    #[inline]
+    fn is_user(&self) -> bool {
+        matches!(self.source, Source::LowerAArch64 | Source::LowerAArch32)
+    }
+}
+
+/// A page fault taken from user mode, on behalf of the thread polled on this CPU.
+/// SIGSEGV to it if the address is not mapped, the kernel goes on.
+fn handle_user_page_fault(addr: usize) {
+    let access = crate::arch::memory::fault_access();
+    match crate::process::thread::current_thread() {
+        Some(thread) => thread.handle_page_fault(addr, access),
+        None => panic!(
+            "\nEXCEPTION: user Page Fault @ {:#x} without a thread",
+            addr
+        ),
+    }
+}
+
+/// Called by `__kernel_vectors` on the emergency stack of the cpu, as the stack
+/// pointer `sp` reached the guard page.
+#[no_mangle]
+extern "C" fn kernel_stack_overflow(sp: usize, addr: usize, elr: usize) -> ! {
+    panic!(
+        "\nEXCEPTION: kernel stack overflow on CPU{}, SP: {:#x}, FAR: {:#x}, ELR: {:#x}",
+        crate::arch::cpu::id(),
+        sp,
+        addr,
+        elr
+    );
+}
+
 /// This function is called when an exception occurs. The `info` parameter
 /// specifies the source and kind of exception that has occurred. The `esr` is
 /// the value of the exception syndrome register. Finally, `tf` is a pointer to
@@ -72,23 +107,54 @@ pub extern "C" fn trap_handler(tf: &mut TrapFrame) {
             trace!("ESR: {:#x?}, Syndrome: {:?}", esr, syndrome);
             // syndrome is only valid with sync
             match syndrome {
-                Syndrome::DataAbort { kind, level: _ }
+                Syndrome::DataAbort { kind, .. }
                 | Syndrome::InstructionAbort { kind, level: _ } => match kind {
+                    Fault::Translation | Fault::AccessFlag | Fault::Permission
+                        if info.is_user() =>
+                    {
+                        handle_user_page_fault(FAR_EL1.get() as usize);
+                    }
                     Fault::Translation | Fault::AccessFlag | Fault::Permission => {
                         let addr = FAR_EL1.get() as usize;
+                        if let Some(cpu) = crate::arch::memory::boot_stack_guard(addr) {
+                            panic!(
+                                "\nEXCEPTION: kernel stack overflow on CPU{} @ {:#x}",
+                                cpu, addr
+                            );
+                        }
                         if !crate::memory::handle_page_fault(addr) {
                             panic!("\nEXCEPTION: Page Fault @ {:#x}", addr);
                         }
                     }
-                    _ => panic!(),
+                    _ => panic!(
+                        "\nEXCEPTION: {:?} @ {:#x}, ELR: {:#x}",
+                        syndrome,
+                        FAR_EL1.get(),
+                        tf.elr
+                    ),
                 },
-                _ => panic!(),
+                _ => panic!(
+                    "\nEXCEPTION: {:?}, ESR: {:#x}, ELR: {:#x}",
+                    syndrome, esr, tf.elr
+                ),
             }
         }
-        Kind::Irq => {
+        // FIQs are not used, in case one is routed here anyway
+        Kind::Irq | Kind::Fiq => {
             IRQ_MANAGER.wait().handle_pending_irqs();
         }
-        _ => panic!(),
+        Kind::SError => {
+            // asynchronous, the code it interrupted is not to blame
+            if is_fatal_serror(esr) {
+                panic!(
+                    "\nEXCEPTION: SError, ESR: {:#x}, FAR: {:#x}, ELR: {:#x}",
+                    esr,
+                    FAR_EL1.get(),
+                    tf.elr
+                );
+            }
+            warn!("recoverable SError, ESR: {:#x}, ELR: {:#x}", esr, tf.elr);
+        }
     }
     trace!("Exception end");
 }
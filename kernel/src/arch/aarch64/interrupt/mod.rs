@@ -2,14 +2,38 @@
 
 pub use self::handler::*;
 
-use crate::drivers::{self, irq::GicV2, DeviceTree, Driver};
+use crate::{
+    arch::timer::GenericTimer,
+    drivers::{
+        self,
+        block::VirtIOBlk,
+        gpio::Pl061Gpio,
+        irq::{ipi, IpiReason},
+        rtc::Pl031Rtc,
+        serial::Pl011Uart,
+        watchdog::Sp805Watchdog,
+        DeviceTree, Driver, DriverProbe, IrqManager,
+    },
+};
 use aarch64::registers::*;
-use core::arch::asm;
+use alloc::sync::Arc;
+use core::arch::{asm, global_asm};
 use spin::Once;
 
 pub mod consts;
 pub mod handler;
-mod syndrome;
+pub mod syndrome;
+
+global_asm!(include_str!("vectors.S"));
+
+extern "C" {
+    fn __kernel_vectors();
+}
+
+/// Install the vectors checking for kernel stack overflow, after `aarch64::trap::init`.
+unsafe fn set_vectors() {
+    VBAR_EL1.set(__kernel_vectors as usize as u64);
+}
 
 /// Enable the interrupt (only IRQ).
 /// # Safety
@@ -29,6 +53,7 @@ pub unsafe fn disable() {
 ///
 /// return: status(usize)
 /// # Safety
+#[cfg(not(test))]
 #[inline]
 pub unsafe fn disable_and_store() -> usize {
     let daif = DAIF.get() as usize;
@@ -36,40 +61,132 @@ pub unsafe fn disable_and_store() -> usize {
     daif
 }
 
+/// Host tests run in EL0, where DAIF cannot be accessed.
+#[cfg(test)]
+pub unsafe fn disable_and_store() -> usize {
+    0
+}
+
 /// Use the original status to restore the process
 ///
 /// Arguments:
 /// * flags:  original status(usize)
 /// # Safety
+#[cfg(not(test))]
 #[inline]
 pub unsafe fn restore(flags: usize) {
     DAIF.set(flags as u64);
 }
 
+#[cfg(test)]
+pub unsafe fn restore(_flags: usize) {}
+
+/// Sleep until an interrupt is pending, then take it.
+///
+/// Call with IRQs disabled after checking there is nothing to do, an IRQ arriving
+/// after the check still wakes up WFI so that no wakeup is lost.
 pub fn wait_for_interrupt() {
     let daif = DAIF.get();
     unsafe {
+        asm!("wfi");
         asm!("msr daifclr, #2");
     }
-    aarch64::asm::wfe();
     DAIF.set(daif);
 }
 
-pub static IRQ_MANAGER: Once<GicV2> = Once::new();
+pub static IRQ_MANAGER: Once<Arc<dyn IrqManager>> = Once::new();
+
+/// Handler of the inter-processor interrupts.
+struct Ipi;
+
+impl Driver for Ipi {
+    fn compatible(&self) -> &'static str {
+        "ipi"
+    }
+
+    fn handle_interrupt(&self) {
+        for reason in ipi::drain(crate::cpu::id()) {
+            match reason {
+                // the idle task is woken by the interrupt and yields to the new tasks
+                IpiReason::Reschedule => {}
+                // IRQs stay masked, the CPU is parked for good
+                IpiReason::Halt => crate::cpu::wait_forever(),
+            }
+        }
+    }
+
+    fn device_type(&self) -> drivers::DeviceType {
+        drivers::DeviceType::Intc
+    }
+}
+
+/// Send an inter-processor interrupt to core `cpu_id`, which may be the current one.
+///
+/// Ignored before the interrupt controller is initialized, the other cores are not
+/// running by then.
+pub fn send_ipi(cpu_id: usize, reason: IpiReason) {
+    if let Some(irq_manager) = IRQ_MANAGER.get() {
+        irq_manager.send_ipi(cpu_id, reason).unwrap();
+    }
+}
+
+/// Park all the other CPUs, used on panic.
+///
+/// A CPU holding a `MutexNoIrq` has IRQs masked, so it stops after releasing it.
+pub fn halt_others() {
+    if let Some(irq_manager) = IRQ_MANAGER.get() {
+        let cpu_id = crate::cpu::id();
+        for id in (0..super::bsp::CPU_NUM).filter(|&id| id != cpu_id) {
+            let _ = irq_manager.send_ipi(id, IpiReason::Halt);
+        }
+    }
+}
+
+/// Drivers of the devices in the device tree, other than the interrupt controller.
+static DRIVERS: &[DriverProbe] = &[
+    DriverProbe {
+        compatible: GenericTimer::COMPATIBLE,
+        probe: crate::arch::timer::driver_probe,
+    },
+    DriverProbe {
+        compatible: Pl011Uart::COMPATIBLE,
+        probe: drivers::serial::pl011_uart::driver_probe,
+    },
+    DriverProbe {
+        compatible: Pl031Rtc::COMPATIBLE,
+        probe: drivers::rtc::pl031::driver_probe,
+    },
+    DriverProbe {
+        compatible: Pl061Gpio::COMPATIBLE,
+        probe: drivers::gpio::pl061::driver_probe,
+    },
+    DriverProbe {
+        compatible: Sp805Watchdog::COMPATIBLE,
+        probe: drivers::watchdog::sp805::driver_probe,
+    },
+    DriverProbe {
+        compatible: VirtIOBlk::COMPATIBLE,
+        probe: drivers::block::virtio_blk::driver_probe,
+    },
+];
 
 pub fn init(device_tree: DeviceTree) {
     unsafe {
         aarch64::trap::init();
+        set_vectors();
     }
 
-    let irq_manager = drivers::irq::gicv2::driver_init(device_tree).unwrap();
-    irq_manager.init().unwrap();
-
-    crate::arch::timer::driver_init(device_tree, &irq_manager);
+    // already initialized for the boot core
+    let irq_manager = drivers::irq::driver_init(device_tree)
+        .unwrap_or_else(|err| panic!("no interrupt controller: {}", err));
 
-    drivers::serial::pl011_uart::driver_init(device_tree, &irq_manager);
+    // the interrupt controller is the root, every other device is discovered
+    drivers::probe_devices(device_tree, &*irq_manager, DRIVERS);
 
-    drivers::rtc::pl031::driver_init(device_tree, &irq_manager).unwrap();
+    // SGIs are banked, every core's redistributor or CPU interface enables them in `init()`
+    if let Err(err) = irq_manager.register_and_enable_local_irq(ipi::IPI_SGI, Arc::new(Ipi)) {
+        panic!("failed to enable IPIs: {}", err);
+    }
 
     IRQ_MANAGER.call_once(|| irq_manager);
 
@@ -81,7 +198,10 @@ pub fn init(device_tree: DeviceTree) {
 pub fn init_other() {
     unsafe {
         aarch64::trap::init();
-        IRQ_MANAGER.wait().init().unwrap();
+        set_vectors();
+        if let Err(err) = IRQ_MANAGER.wait().init() {
+            panic!("failed to initialize the interrupt controller: {}", err);
+        }
         enable();
     }
 }
@@ -43,9 +43,17 @@ pub enum Syndrome {
     Hvc(u16),
     Smc(u16),
     MsrMrsSystem,
-    InstructionAbort { kind: Fault, level: u8 },
+    InstructionAbort {
+        kind: Fault,
+        level: u8,
+    },
     PCAlignmentFault,
-    DataAbort { kind: Fault, level: u8 },
+    DataAbort {
+        kind: Fault,
+        level: u8,
+        /// Whether the access was a write, cache maintenance counts as a read.
+        write: bool,
+    },
     SpAlignmentFault,
     TrappedFpu,
     SError,
@@ -90,6 +98,8 @@ impl From<u32> for Syndrome {
             0b100100 | 0b100101 => DataAbort {
                 kind: Fault::from(iss),
                 level: (iss & 0b11) as u8,
+                // WnR, set by cache maintenance too unless CM
+                write: iss & (1 << 6) != 0 && iss & (1 << 8) == 0,
             },
             0b100110 => SpAlignmentFault,
             0b101000 => TrappedFpu,
@@ -106,3 +116,53 @@ impl From<u32> for Syndrome {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_abort() {
+        let abort = |esr| match Syndrome::from(esr) {
+            Syndrome::DataAbort { kind, level, write } => (kind, level, write),
+            other => panic!("{:#x} is {:?}", esr, other),
+        };
+        // from el0 and el1
+        assert_eq!(abort(0x9200_0047), (Fault::Translation, 3, true));
+        assert_eq!(abort(0x9600_0007), (Fault::Translation, 3, false));
+        assert_eq!(abort(0x9200_004d), (Fault::Permission, 1, true));
+        assert_eq!(abort(0x9200_000a), (Fault::AccessFlag, 2, false));
+        assert_eq!(abort(0x9200_0061), (Fault::Alignment, 1, true));
+        // cache maintenance
+        assert_eq!(abort(0x9200_0147), (Fault::Translation, 3, false));
+    }
+
+    #[test]
+    fn instruction_abort() {
+        assert_eq!(
+            Syndrome::from(0x8200_000f),
+            Syndrome::InstructionAbort {
+                kind: Fault::Permission,
+                level: 3
+            }
+        );
+        assert_eq!(
+            Syndrome::from(0x8600_0004),
+            Syndrome::InstructionAbort {
+                kind: Fault::Translation,
+                level: 0
+            }
+        );
+    }
+
+    #[test]
+    fn other_syndromes() {
+        assert_eq!(Syndrome::from(0x5600_0000), Syndrome::Svc(0));
+        assert_eq!(Syndrome::from(0x5600_1234), Syndrome::Svc(0x1234));
+        assert_eq!(Syndrome::from(0xf200_03e8), Syndrome::Brk(1000));
+        assert_eq!(Syndrome::from(0x0200_0000), Syndrome::Unknown);
+        assert_eq!(Syndrome::from(0x8a00_0000), Syndrome::PCAlignmentFault);
+        assert_eq!(Syndrome::from(0x9a00_0000), Syndrome::SpAlignmentFault);
+        assert_eq!(Syndrome::from(0xbe00_0000), Syndrome::SError);
+    }
+}
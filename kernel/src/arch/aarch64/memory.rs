@@ -1,17 +1,17 @@
-use super::bsp::{PERIPHERALS_END, PERIPHERALS_START};
+use super::{
+    asid::Asid,
+    bsp::{PERIPHERALS_END, PERIPHERALS_START},
+    interrupt::syndrome::Syndrome,
+};
 use crate::{
-    consts::KERNEL_OFFSET,
+    consts::{BOOT_STACK_SIZE, KERNEL_OFFSET},
     memory::{
-        as_lower_range, handler::Linear, init_heap, MMIOType, MemoryAttr, MemorySet,
-        FRAME_ALLOCATOR, PAGE_SIZE,
+        as_lower_range, handler::Linear, init_heap, Access, MMIOType, MemoryAttr, MemorySet,
+        FRAME_ALLOCATOR, PAGE_SIZE, TOTAL_FRAMES,
     },
     sync::spin::MutexNoIrq as Mutex,
 };
-use aarch64::{
-    paging::Frame,
-    registers::{Readable, FAR_EL1},
-    translation::{local_invalidate_tlb_all, ttbr_el1_write},
-};
+use aarch64::registers::{Readable, ESR_EL1, FAR_EL1};
 use core::ops::Range;
 
 static KERNEL_MEMORY_SET: Mutex<Option<MemorySet>> = Mutex::new(None);
@@ -43,6 +43,7 @@ fn init_frame_allocator(MemInitOpts { phys_mem_range }: &MemInitOpts) {
     let page_start = (as_lower_range(symbol_addr!(_end)) - phys_mem_range.start) / PAGE_SIZE;
     let page_end = (phys_mem_range.len() - 1) / PAGE_SIZE + 1;
     FRAME_ALLOCATOR.lock().insert(page_start..page_end);
+    TOTAL_FRAMES.fetch_add(page_end - page_start, core::sync::atomic::Ordering::Relaxed);
     info!("Initialized frame allocator.");
 }
 
@@ -71,6 +72,13 @@ fn map_kernel(MemInitOpts { phys_mem_range }: &MemInitOpts) {
         Linear::new(offset),
         "rodata",
     );
+    ms.push(
+        symbol_addr!(sksymtab),
+        symbol_addr!(eksymtab),
+        MemoryAttr::default().readonly(),
+        Linear::new(offset),
+        "ksymtab",
+    );
     ms.push(
         symbol_addr!(sgot),
         symbol_addr!(egot),
@@ -85,13 +93,16 @@ fn map_kernel(MemInitOpts { phys_mem_range }: &MemInitOpts) {
         Linear::new(offset),
         "bss",
     );
-    ms.push(
-        symbol_addr!(bootstack),
-        symbol_addr!(bootstacktop),
-        MemoryAttr::default(),
-        Linear::new(offset),
-        "kstack",
-    );
+    // leave the guard page at the bottom of each cpu's stack unmapped
+    for stack in (symbol_addr!(bootstack)..symbol_addr!(bootstacktop)).step_by(BOOT_STACK_SIZE) {
+        ms.push(
+            stack + PAGE_SIZE,
+            stack + BOOT_STACK_SIZE,
+            MemoryAttr::default(),
+            Linear::new(offset),
+            "kstack",
+        );
+    }
     ms.push(
         PERIPHERALS_START,
         PERIPHERALS_END,
@@ -129,11 +140,54 @@ pub fn ioremap(paddr: usize, len: usize, name: &'static str) -> usize {
     0
 }
 
-pub fn set_page_table(vmtoken: usize) {
-    ttbr_el1_write(0, Frame::of_addr(vmtoken as u64));
-    local_invalidate_tlb_all();
+pub fn set_page_table(vmtoken: usize, asid: &Asid) {
+    super::asid::switch_to(vmtoken as u64, asid);
+}
+
+/// Returns the cpu whose boot stack guard page contains `addr`.
+pub fn boot_stack_guard(addr: usize) -> Option<usize> {
+    let top = symbol_addr!(bootstacktop);
+    if !(symbol_addr!(bootstack)..top).contains(&addr) {
+        return None;
+    }
+    let cpu = (top - 1 - addr) / BOOT_STACK_SIZE;
+    let guard = top - (cpu + 1) * BOOT_STACK_SIZE;
+    if addr < guard + PAGE_SIZE {
+        Some(cpu)
+    } else {
+        None
+    }
 }
 
 pub fn get_page_fault_addr() -> usize {
     FAR_EL1.get() as usize
 }
+
+/// The access which caused the abort being handled, from ESR_EL1.
+pub fn fault_access() -> Access {
+    access_of(ESR_EL1.get() as u32)
+}
+
+fn access_of(esr: u32) -> Access {
+    match Syndrome::from(esr) {
+        Syndrome::InstructionAbort { .. } => Access::Execute,
+        Syndrome::DataAbort { write: true, .. } => Access::Write,
+        _ => Access::Read,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fault_access_of_esr() {
+        // data abort from el0, permission fault level 3, WnR
+        assert_eq!(access_of(0x9200_004f), Access::Write);
+        assert_eq!(access_of(0x9200_000f), Access::Read);
+        // cache maintenance sets WnR but reads
+        assert_eq!(access_of(0x9200_014f), Access::Read);
+        // instruction abort from el0, translation fault level 3
+        assert_eq!(access_of(0x8200_0007), Access::Execute);
+    }
+}
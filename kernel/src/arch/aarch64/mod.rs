@@ -6,6 +6,8 @@ use core::{
 };
 use crate::{drivers, memory::phys_to_virt, consts::QUEEN_OS};
 
+pub mod asid;
+#[cfg(not(test))]
 mod boot;
 #[cfg_attr(feature = "bsp_virt", path = "bsp/virt/mod.rs")]
 pub mod bsp;
@@ -14,9 +16,12 @@ pub mod cpu;
 pub mod interrupt;
 pub mod memory;
 pub mod paging;
+mod psci;
 pub mod signal;
 pub mod syscall;
+pub mod time_page;
 pub mod timer;
+pub mod tlb;
 
 static AP_CAN_INIT: AtomicBool = AtomicBool::new(false);
 
@@ -37,11 +42,18 @@ unsafe extern "C" fn main_start() -> ! {
     let mut buf = Vec::<u8>::with_capacity(device_tree.totalsize());
     buf.extend_from_slice(device_tree.device_tree().buf());
     let device_tree = drivers::DeviceTree::new(buf.as_slice()).unwrap();
+    crate::cmdline::init(&device_tree);
 
-    crate::task::init(bsp::CPU_NUM);
+    crate::task::init(cpu::count());
     interrupt::init(device_tree);
+    crate::memory::aging::init();
+    crate::memory::swap::init();
+    time_page::init();
+    time_page::init_cpu();
 
     async_test();
+    psci::init(&device_tree);
+    cpu::start_others(&device_tree);
     AP_CAN_INIT.store(true, Ordering::Release);
     crate::kmain();
 }
@@ -77,6 +89,8 @@ unsafe extern "C" fn others_start() -> ! {
     }
     memory::init_other();
     interrupt::init_other();
+    time_page::init_cpu();
+    info!("CPU{} started.", cpu::id());
     crate::kmain();
 }
 
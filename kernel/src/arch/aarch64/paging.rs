@@ -1,8 +1,15 @@
 //! Page table implementations for aarch64.
 
+use super::{
+    asid::{self, Asid},
+    tlb,
+};
 use crate::{
     consts::PHYSICAL_MEMORY_OFFSET,
-    memory::{alloc_frames, dealloc_frames, phys_to_virt, Entry, PageTable, PageTableExt},
+    memory::{
+        alloc_frames, dealloc_frames, phys_to_virt, Entry, PageTable, PageTableExt,
+        HUGE_PAGE_SIZE, PAGE_SIZE,
+    },
 };
 use aarch64::{
     addr::{align_down, align_up, PhysAddr, ALIGN_2MIB},
@@ -13,20 +20,28 @@ use aarch64::{
         table::{PageTable as RawPageTable, PageTableEntry, PageTableFlags as EF},
         Frame, FrameAllocator, FrameDeallocator, Page as PageAllSizes, Size2MiB, Size4KiB,
     },
-    translation::{invalidate_tlb_vaddr, local_invalidate_tlb_all, ttbr_el1_read, ttbr_el1_write},
+    translation::{local_invalidate_tlb_all, ttbr_el1_read, ttbr_el1_write},
+};
+use alloc::sync::Arc;
+use core::{
+    arch::asm,
+    mem::ManuallyDrop,
+    sync::atomic::{AtomicU64, Ordering},
 };
-use core::mem::ManuallyDrop;
 use log::*;
 
 type Page = PageAllSizes<Size4KiB>;
+type HugePage = PageAllSizes<Size2MiB>;
 
 pub struct PageTableImpl {
     page_table: OffsetPageTable<'static>,
     root_frame: Frame,
+    asid: Arc<Asid>,
     entry: Option<PageEntry>,
 }
 
-pub struct PageEntry(&'static mut PageTableEntry, Page);
+/// An entry, the start and size of the memory it maps, and the ASID of its page table.
+pub struct PageEntry(&'static mut PageTableEntry, usize, usize, Arc<Asid>);
 
 impl PageTable for PageTableImpl {
     fn map(&mut self, addr: usize, target: usize) -> &mut dyn Entry {
@@ -42,30 +57,101 @@ impl PageTable for PageTableImpl {
                     &mut FrameAllocatorForAarch64,
                 )
                 .unwrap()
-                .flush();
+                .ignore();
         }
+        tlb::flush_range(self.asid.get(), addr, addr + PAGE_SIZE);
         self.get_entry(addr).expect("fail to get entry")
     }
 
     fn unmap(&mut self, addr: usize) {
-        self.page_table
-            .unmap(Page::of_addr(addr as u64))
-            .unwrap()
-            .1
-            .flush();
+        let page = Page::of_addr(addr as u64);
+        self.page_table.unmap(page).unwrap().1.ignore();
+        let start = page.start_address().as_u64() as usize;
+        tlb::flush_range(self.asid.get(), start, start + PAGE_SIZE);
     }
 
     fn get_entry(&mut self, vaddr: usize) -> Option<&mut dyn Entry> {
         let page = Page::of_addr(vaddr as u64);
         if let Ok(e) = self.page_table.get_entry_mut(page) {
             let e = unsafe { &mut *(e as *mut PageTableEntry) };
-            self.entry = Some(PageEntry(e, page));
+            let start = page.start_address().as_u64() as usize;
+            self.entry = Some(PageEntry(e, start, PAGE_SIZE, self.asid.clone()));
             Some(self.entry.as_mut().unwrap())
         } else {
             None
         }
     }
 
+    fn map_2mib(&mut self, addr: usize, target: usize) -> Option<&mut dyn Entry> {
+        let flags = EF::default_block() | EF::PXN | EF::UXN;
+        let attr = MairNormal::attr_value();
+        unsafe {
+            // fails if the level 2 entry already points to a table of pages
+            self.page_table
+                .map_to(
+                    HugePage::of_addr(addr as u64),
+                    Frame::<Size2MiB>::of_addr(target as u64),
+                    flags,
+                    attr,
+                    &mut FrameAllocatorForAarch64,
+                )
+                .ok()?
+                .ignore();
+        }
+        tlb::flush_range(self.asid.get(), addr, addr + HUGE_PAGE_SIZE);
+        self.get_entry_2mib(addr)
+    }
+
+    fn unmap_2mib(&mut self, addr: usize) {
+        let page = HugePage::of_addr(addr as u64);
+        self.page_table.unmap(page).unwrap().1.ignore();
+        let start = page.start_address().as_u64() as usize;
+        tlb::flush_range(self.asid.get(), start, start + HUGE_PAGE_SIZE);
+    }
+
+    fn get_entry_2mib(&mut self, addr: usize) -> Option<&mut dyn Entry> {
+        let page = HugePage::of_addr(addr as u64);
+        let e = self.page_table.get_entry_mut(page).ok()?;
+        // a table descriptor has the same bit set as a page descriptor
+        let flags = e.flags();
+        if !flags.contains(EF::VALID) || flags.contains(EF::TABLE_OR_PAGE) {
+            return None;
+        }
+        let e = unsafe { &mut *(e as *mut PageTableEntry) };
+        let start = page.start_address().as_u64() as usize;
+        self.entry = Some(PageEntry(e, start, HUGE_PAGE_SIZE, self.asid.clone()));
+        Some(self.entry.as_mut().unwrap())
+    }
+
+    fn split_2mib(&mut self, addr: usize) {
+        let page = HugePage::of_addr(addr as u64);
+        let (target, flags, attr) = {
+            let e = self
+                .page_table
+                .get_entry_mut(page)
+                .expect("no 2MiB block to split");
+            (e.addr().as_usize(), e.flags(), e.attr())
+        };
+        // break-before-make, the block has to be invalidated before the pages
+        // take its place
+        self.unmap_2mib(addr);
+        let start = page.start_address().as_u64() as usize;
+        for offset in (0..HUGE_PAGE_SIZE).step_by(PAGE_SIZE) {
+            unsafe {
+                self.page_table
+                    .map_to(
+                        Page::of_addr((start + offset) as u64),
+                        Frame::of_addr((target + offset) as u64),
+                        flags | EF::TABLE_OR_PAGE,
+                        attr,
+                        &mut FrameAllocatorForAarch64,
+                    )
+                    .unwrap()
+                    .ignore();
+            }
+        }
+    }
+
     fn get_page_slice_mut<'a>(&mut self, addr: usize) -> &'a mut [u8] {
         let frame = self
             .page_table
@@ -111,7 +197,7 @@ pub enum MMIOType {
 // TODO: software dirty bit needs to be reconsidered
 impl Entry for PageEntry {
     fn update(&mut self) {
-        invalidate_tlb_vaddr(self.1.start_address());
+        tlb::flush_range(self.3.get(), self.1, self.1 + self.2);
     }
 
     fn present(&self) -> bool {
@@ -130,6 +216,9 @@ impl Entry for PageEntry {
     fn clear_accessed(&mut self) {
         self.as_flags().remove(EF::AF);
     }
+    fn set_accessed(&mut self) {
+        self.as_flags().insert(EF::AF);
+    }
     fn clear_dirty(&mut self) {
         self.as_flags().remove(EF::DIRTY);
         self.as_flags().insert(EF::AP_RO);
@@ -236,11 +325,12 @@ impl PageTableImpl {
     /// Using ManuallyDrop to wrap the page table: this is how `core::mem::forget` is implemented now.
     /// # Safety
     pub unsafe fn active() -> ManuallyDrop<Self> {
-        let frame = Frame::of_addr(PageTableImpl::active_token() as u64);
+        let frame = Frame::of_addr(ttbr_el1_read(0).start_address().as_u64());
         let table = &mut *frame_to_page_table(frame);
         ManuallyDrop::new(PageTableImpl {
             page_table: OffsetPageTable::new(table, PHYSICAL_MEMORY_OFFSET.into()),
             root_frame: frame,
+            asid: Arc::new(Asid::active()),
             entry: None,
         })
     }
@@ -254,6 +344,7 @@ impl PageTableImpl {
         ManuallyDrop::new(PageTableImpl {
             page_table: OffsetPageTable::new(table, PHYSICAL_MEMORY_OFFSET.into()),
             root_frame: frame,
+            asid: Arc::new(Asid::new()),
             entry: None,
         })
     }
@@ -266,6 +357,40 @@ impl PageTableImpl {
         local_invalidate_tlb_all();
     }
 
+    /// The ASID of this page table, to switch to it with `set_page_table`.
+    pub fn asid(&self) -> Arc<Asid> {
+        self.asid.clone()
+    }
+
+    /// Set the accessed flag of the entry mapping `addr` without the lock of the
+    /// memory set, for the kernel touching a page aged by the sweep. A concurrent
+    /// change of the entry wins.
+    /// Return `false` if `addr` is not mapped.
+    pub fn set_accessed(&mut self, addr: usize) -> bool {
+        let block = self
+            .page_table
+            .get_entry_mut(HugePage::of_addr(addr as u64))
+            .ok()
+            .filter(|e| e.flags().contains(EF::VALID) && !e.flags().contains(EF::TABLE_OR_PAGE))
+            .map(|e| e as *mut PageTableEntry);
+        let entry = match block {
+            Some(entry) => entry,
+            None => match self.page_table.get_entry_mut(Page::of_addr(addr as u64)) {
+                Ok(entry) => entry as *mut PageTableEntry,
+                Err(_) => return false,
+            },
+        };
+        let entry = unsafe { &*(entry as *const AtomicU64) };
+        let old = entry.load(Ordering::Relaxed);
+        if old & EF::VALID.bits() == 0 {
+            return false;
+        }
+        let new = old | EF::AF.bits();
+        let _ = entry.compare_exchange(old, new, Ordering::Relaxed, Ordering::Relaxed);
+        unsafe { asm!("dsb ishst", "isb") };
+        true
+    }
+
     /// Map physical memory [start, end)
     /// to virtual space [phys_to_virt(start), phys_to_virt(end))
     pub fn map_physical_memory(&mut self, start: usize, end: usize) {
@@ -304,6 +429,7 @@ impl PageTableExt for PageTableImpl {
             PageTableImpl {
                 page_table: OffsetPageTable::new(table, PHYSICAL_MEMORY_OFFSET.into()),
                 root_frame: frame,
+                asid: Arc::new(Asid::new()),
                 entry: None,
             }
         }
@@ -317,26 +443,52 @@ impl PageTableExt for PageTableImpl {
         self.root_frame.start_address().as_u64() // as TTBR0_EL1
     }
 
+    /// Activate `token` without ASID, with `flush_tlb` following.
     unsafe fn set_token(token: u64) {
-        ttbr_el1_write(0, Frame::of_addr(token));
+        asid::write_ttbr0(token);
+        asid::count_untagged_switch();
     }
 
+    /// TTBR0_EL1 with the ASID, restoring it keeps the ASID of the page table.
     fn active_token() -> u64 {
-        ttbr_el1_read(0).start_address().as_u64()
+        asid::read_ttbr0()
     }
 
     fn flush_tlb() {
         local_invalidate_tlb_all();
     }
+
+    /// Switch with the ASID of this page table, keeping the TLB.
+    unsafe fn activate(&self) {
+        asid::switch_to(self.token(), &self.asid);
+    }
 }
 
 impl Drop for PageTableImpl {
     fn drop(&mut self) {
         info!("PageTable dropping: {:?}", self.root_frame);
+        // the memory set has unmapped every page, only the tables are left
+        let root = unsafe { &*frame_to_page_table(self.root_frame) };
+        dealloc_tables(root, 0);
         dealloc_frames(self.root_frame.start_address().as_usize(), 1);
     }
 }
 
+/// Free the frames of every table below `table`, which is at `level`, 0 being the root.
+fn dealloc_tables(table: &RawPageTable, level: usize) {
+    // at level 3 the bit marks a page, at level 1 and 2 it tells a table from a block
+    if level == 3 {
+        return;
+    }
+    for entry in table.iter() {
+        if entry.flags().contains(EF::VALID | EF::TABLE_OR_PAGE) {
+            let frame = Frame::of_addr(entry.addr().as_u64());
+            dealloc_tables(unsafe { &*frame_to_page_table(frame) }, level + 1);
+            dealloc_frames(frame.start_address().as_usize(), 1);
+        }
+    }
+}
+
 struct FrameAllocatorForAarch64;
 
 unsafe impl FrameAllocator<Size4KiB> for FrameAllocatorForAarch64 {
@@ -350,3 +502,28 @@ impl FrameDeallocator<Size4KiB> for FrameAllocatorForAarch64 {
         dealloc_frames(frame.start_address().as_usize(), 1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{frame_stats, handler::ByFrame, GlobalFrameAlloc, MemoryAttr, MemorySet};
+
+    #[test]
+    fn tables_freed() {
+        let free = || frame_stats().1;
+        let baseline = free();
+        for _ in 0..8 {
+            let mut ms = MemorySet::new();
+            let attr = MemoryAttr::default().user();
+            // below different level 0, 1 and 2 entries
+            for &start in [0x40_0000, 0x4000_0000, 0x80_0000_0000].iter() {
+                let handler = ByFrame::new(GlobalFrameAlloc);
+                ms.push(start, start + 4 * PAGE_SIZE, attr, handler, "data");
+            }
+            // the pages, a root and at least a table per level
+            assert!(free() <= baseline - 12 - 4);
+            drop(ms);
+            assert_eq!(free(), baseline);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Power State Coordination Interface, used to power on the secondary cores and
+//! to power off or reset the system.
+//!
+//! [Reference](https://developer.arm.com/documentation/den0022/latest)
+
+use crate::drivers::DeviceTree;
+use core::arch::asm;
+use spin::Once;
+
+const PSCI_0_2_FN_SYSTEM_OFF: usize = 0x8400_0008;
+const PSCI_0_2_FN_SYSTEM_RESET: usize = 0x8400_0009;
+const PSCI_0_2_FN64_CPU_ON: usize = 0xc400_0003;
+
+static PSCI: Once<Psci> = Once::new();
+
+/// Find the PSCI firmware interface in the device tree, for `get`.
+pub fn init(device_tree: &DeviceTree) {
+    if let Some(psci) = Psci::probe(device_tree) {
+        PSCI.call_once(|| psci);
+    }
+}
+
+/// The PSCI firmware interface, if `init` found one.
+pub fn get() -> Option<&'static Psci> {
+    PSCI.get()
+}
+
+/// How PSCI calls reach the firmware, the `method` of the `psci` node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conduit {
+    Smc,
+    Hvc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciError {
+    NotSupported,
+    /// No core with the given MPIDR.
+    InvalidParameters,
+    Denied,
+    AlreadyOn,
+    OnPending,
+    InternalFailure,
+    NotPresent,
+    Disabled,
+    InvalidAddress,
+    Unknown(isize),
+}
+
+impl From<isize> for PsciError {
+    fn from(code: isize) -> Self {
+        match code {
+            -1 => PsciError::NotSupported,
+            -2 => PsciError::InvalidParameters,
+            -3 => PsciError::Denied,
+            -4 => PsciError::AlreadyOn,
+            -5 => PsciError::OnPending,
+            -6 => PsciError::InternalFailure,
+            -7 => PsciError::NotPresent,
+            -8 => PsciError::Disabled,
+            -9 => PsciError::InvalidAddress,
+            code => PsciError::Unknown(code),
+        }
+    }
+}
+
+pub struct Psci {
+    conduit: Conduit,
+}
+
+impl Psci {
+    pub const COMPATIBLE: &'static str = "arm,psci-0.2";
+
+    /// Find the PSCI firmware interface in the device tree.
+    pub fn probe(device_tree: &DeviceTree) -> Option<Self> {
+        let node = device_tree.find_node_with_prop(|prop| {
+            Ok(prop.name()? == "compatible" && prop.str()?.starts_with("arm,psci"))
+        })?;
+        let conduit = match device_tree.node_prop_str(&node, "method")? {
+            "smc" => Conduit::Smc,
+            "hvc" => Conduit::Hvc,
+            method => {
+                warn!("PSCI: unknown method {:?}.", method);
+                return None;
+            }
+        };
+        Some(Psci { conduit })
+    }
+
+    /// Power on the core `mpidr`, which starts at physical address `entry` with
+    /// `context_id` in x0 and the MMU off.
+    pub fn cpu_on(&self, mpidr: usize, entry: usize, context_id: usize) -> Result<(), PsciError> {
+        match self.call(PSCI_0_2_FN64_CPU_ON, mpidr, entry, context_id) {
+            0 => Ok(()),
+            code => Err(code.into()),
+        }
+    }
+
+    /// Power the system off, returns only on failure.
+    pub fn system_off(&self) -> PsciError {
+        self.call(PSCI_0_2_FN_SYSTEM_OFF, 0, 0, 0).into()
+    }
+
+    /// Reset the system, returns only on failure.
+    pub fn system_reset(&self) -> PsciError {
+        self.call(PSCI_0_2_FN_SYSTEM_RESET, 0, 0, 0).into()
+    }
+
+    fn call(&self, function: usize, arg0: usize, arg1: usize, arg2: usize) -> isize {
+        let ret: usize;
+        // SMCCC 1.0 allows x4-x17 to be clobbered
+        unsafe {
+            match self.conduit {
+                Conduit::Smc => asm!(
+                    "smc #0",
+                    inout("x0") function => ret,
+                    inout("x1") arg0 => _,
+                    inout("x2") arg1 => _,
+                    inout("x3") arg2 => _,
+                    out("x4") _, out("x5") _, out("x6") _, out("x7") _,
+                    out("x8") _, out("x9") _, out("x10") _, out("x11") _,
+                    out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+                    out("x16") _, out("x17") _,
+                ),
+                Conduit::Hvc => asm!(
+                    "hvc #0",
+                    inout("x0") function => ret,
+                    inout("x1") arg0 => _,
+                    inout("x2") arg1 => _,
+                    inout("x3") arg2 => _,
+                    out("x4") _, out("x5") _, out("x6") _, out("x7") _,
+                    out("x8") _, out("x9") _, out("x10") _, out("x11") _,
+                    out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+                    out("x16") _, out("x17") _,
+                ),
+            }
+        }
+        ret as isize
+    }
+}
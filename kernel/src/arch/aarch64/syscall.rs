@@ -278,3 +278,4 @@ pub const SYS_PKEY_ALLOC: usize = 289;
 pub const SYS_PKEY_FREE: usize = 290;
 pub const SYS_STATX: usize = 291;
 pub const SYS_IO_PGETEVENTS: usize = 292;
+pub const SYS_OPENAT2: usize = 437;
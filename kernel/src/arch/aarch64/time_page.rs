@@ -0,0 +1,104 @@
+//! The time page, a page mapped read-only at `USER_TIME_PAGE_OFFSET` in every user
+//! address space so that the clocks are read without a syscall.
+//!
+//! The page holds a `TimePage`, a `SeqLock` rewritten on every timer interrupt,
+//! starting with its sequence number. EL0 may read
+//! the physical counter `CNTPCT_EL0`, the clocks are the times at `counter` plus
+//! the time elapsed since:
+//!
+//! ```text
+//! loop {
+//!     seq = page.seq;              // retry while odd, an update is in progress
+//!     copy page.data;
+//!     now = CNTPCT_EL0;
+//!     if page.seq == seq { break } // otherwise the copy may be torn
+//! }
+//! elapsed_ns = (now - counter) * 1_000_000_000 / freq
+//! CLOCK_MONOTONIC = monotonic_ns + elapsed_ns
+//! CLOCK_REALTIME = realtime_ns + elapsed_ns
+//! ```
+
+use super::timer;
+use crate::{
+    consts::USER_TIME_PAGE_OFFSET,
+    memory::{
+        alloc_frames, handler::Linear, phys_to_virt, MemoryAttr, MemorySet, PhysAddr, PAGE_SIZE,
+    },
+    sync::SeqLock,
+};
+use core::arch::asm;
+use spin::Once;
+
+/// Layout of the time page, the sequence number then every field a little endian `u64`.
+type TimePage = SeqLock<TimeData>;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct TimeData {
+    /// Frequency of the counter in Hz.
+    freq: u64,
+    /// Counter at the last update.
+    counter: u64,
+    /// `CLOCK_MONOTONIC` at `counter`, in nanoseconds.
+    monotonic_ns: u64,
+    /// `CLOCK_REALTIME` at `counter`, in nanoseconds since the epoch.
+    realtime_ns: u64,
+}
+
+static TIME_PAGE: Once<PhysAddr> = Once::new();
+
+/// Allocate the time page.
+pub fn init() {
+    let frame = alloc_frames(1).expect("failed to allocate the time page");
+    unsafe { core::ptr::write_bytes(phys_to_virt(frame) as *mut u8, 0, PAGE_SIZE) };
+    TIME_PAGE.call_once(|| frame);
+    update();
+}
+
+/// Let EL0 read the physical counter, on this CPU.
+pub fn init_cpu() {
+    let mut cntkctl: u64;
+    unsafe {
+        asm!("mrs {}, cntkctl_el1", out(reg) cntkctl);
+        // EL0PCTEN
+        cntkctl |= 1;
+        asm!("msr cntkctl_el1, {}", "isb", in(reg) cntkctl);
+    }
+}
+
+fn page() -> Option<&'static TimePage> {
+    TIME_PAGE
+        .get()
+        .map(|&frame| unsafe { &*(phys_to_virt(frame) as *const TimePage) })
+}
+
+/// Write the current time into the page, unless another CPU is writing it.
+pub fn update() {
+    let page = match page() {
+        Some(page) => page,
+        None => return,
+    };
+    page.try_write(|data| {
+        let counter = timer::read_count();
+        let freq = timer::GenericTimer::freq();
+        *data = TimeData {
+            freq,
+            counter,
+            monotonic_ns: timer::GenericTimer::count_to_ns(counter),
+            realtime_ns: crate::syscall::realtime().as_nanos() as u64,
+        };
+    });
+}
+
+/// Map the time page read-only into the user address space `ms`.
+pub fn map(ms: &mut MemorySet) {
+    if let Some(&frame) = TIME_PAGE.get() {
+        ms.push_fixed(
+            USER_TIME_PAGE_OFFSET,
+            USER_TIME_PAGE_OFFSET + PAGE_SIZE,
+            MemoryAttr::default().user().readonly(),
+            Linear::new(frame as isize - USER_TIME_PAGE_OFFSET as isize),
+            "time_page",
+        );
+    }
+}
@@ -1,18 +1,49 @@
-use crate::drivers::{self, Driver};
+use crate::{
+    drivers::{self, Driver},
+    task::executor::{self, SCHED_TICK_MAX_INTERVAL},
+};
 use aarch64::registers::*;
 use alloc::sync::Arc;
-use core::time::Duration;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// `CNTFRQ_EL0`, set by the firmware and constant once the kernel runs.
+static FREQ: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct GenericTimer {}
 
 impl GenericTimer {
+    pub const COMPATIBLE: &'static str = "arm,armv8-timer";
     pub const IRQ_NUMBER: usize = 30;
 
     #[inline]
     pub fn freq() -> u64 {
+        match FREQ.load(Ordering::Relaxed) {
+            // read before `init`
+            0 => Self::read_freq(),
+            freq => freq,
+        }
+    }
+
+    #[cfg(not(test))]
+    fn read_freq() -> u64 {
         // 62500000 on qemu, 19200000 on real machine
-        CNTFRQ_EL0.get() as u64
+        let freq = CNTFRQ_EL0.get() as u64;
+        FREQ.store(freq, Ordering::Relaxed);
+        freq
+    }
+
+    /// Host tests run in EL0 with the frequency of qemu.
+    #[cfg(test)]
+    fn read_freq() -> u64 {
+        FREQ.store(62_500_000, Ordering::Relaxed);
+        62_500_000
     }
 
     #[inline]
@@ -31,32 +62,103 @@ impl GenericTimer {
     }
 
     #[inline]
-    pub fn read_ns(&self)-> u64 {
-        CNTPCT_EL0.get() * 1_000_000_000 / Self::freq()
+    pub fn read_ns(&self) -> u64 {
+        read_ns()
     }
 
+    /// Nanoseconds in `count` counter ticks.
+    #[inline]
+    pub fn count_to_ns(count: u64) -> u64 {
+        mul_div(count, NSEC_PER_SEC, Self::freq())
+    }
+
+    /// Counter ticks in `ns` nanoseconds.
+    #[inline]
+    fn ns_to_count(ns: u64) -> u64 {
+        mul_div(ns, Self::freq(), NSEC_PER_SEC)
+    }
+
+    /// Tick in `ns` nanoseconds, at most `SCHED_TICK_MAX_INTERVAL`.
+    ///
+    /// The compare value is absolute, unlike `CNTP_TVAL_EL0` which only holds
+    /// a signed 32-bit interval, about 34 s at 62.5 MHz.
     #[inline]
     pub fn tick_in(&self, ns: usize) {
-        let count = Self::freq() * (ns as u64) / 1_000_000_000;
-        // max `68719476` us (0xffff_ffff / 38400000 * 62500000).
-        debug_assert!(count <= u32::max_value() as u64);
-        CNTP_TVAL_EL0.set(count);
+        let ns = ns.min(SCHED_TICK_MAX_INTERVAL) as u64;
+        Self::set_compare(read_count() + Self::ns_to_count(ns));
+    }
+
+    /// Tick in `ns` nanoseconds unless the timer ticks earlier already.
+    #[inline]
+    pub fn tick_within(&self, ns: usize) {
+        Self::tick_by_count(read_count() + Self::ns_to_count(ns as u64));
+    }
+
+    /// Tick by `deadline` unless the timer ticks earlier already.
+    #[inline]
+    pub fn tick_by(&self, deadline: Duration) {
+        Self::tick_by_count(Self::ns_to_count(deadline.as_nanos() as u64));
+    }
+
+    fn tick_by_count(count: u64) {
+        // not to overwrite an earlier tick set by an interrupt in between
+        let flags = unsafe { super::interrupt::disable_and_store() };
+        if count < Self::compare() {
+            Self::set_compare(count);
+        }
+        unsafe { super::interrupt::restore(flags) };
+    }
+
+    #[cfg(not(test))]
+    #[inline]
+    fn compare() -> u64 {
+        let count;
+        unsafe { asm!("mrs {}, cntp_cval_el0", out(reg) count) };
+        count
+    }
+
+    #[cfg(not(test))]
+    #[inline]
+    fn set_compare(count: u64) {
+        unsafe { asm!("msr cntp_cval_el0, {}", in(reg) count) };
+    }
+
+    /// Host tests run in EL0, where the timer cannot be programmed.
+    #[cfg(test)]
+    fn compare() -> u64 {
+        0
     }
+
+    #[cfg(test)]
+    fn set_compare(_count: u64) {}
 }
 
 impl Driver for GenericTimer {
     fn compatible(&self) -> &'static str {
-        "arm,armv8-timer"
+        Self::COMPATIBLE
     }
 
     fn init(&self) -> drivers::Result<()> {
+        Self::read_freq();
         CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET);
         Ok(())
     }
 
     fn handle_interrupt(&self) {
-        crate::task::timer::TIMER.lock().expire(self.read());
-        self.tick_in(crate::task::executor::SCHED_MIN_GRANULARITY);
+        let now = self.read();
+        let next_timer = {
+            let mut timer = crate::task::timer::TIMER.lock();
+            timer.expire(now);
+            timer.next_deadline()
+        };
+        super::time_page::update();
+        // tick when the slice of the current task runs out or the next timer expires
+        let mut interval = executor::local_executor().next_tick();
+        if let Some(deadline) = next_timer {
+            let until = deadline.checked_sub(now).unwrap_or_default();
+            interval = interval.min(until.as_nanos() as usize);
+        }
+        self.tick_in(interval);
     }
 
     fn device_type(&self) -> drivers::DeviceType {
@@ -64,25 +166,89 @@ impl Driver for GenericTimer {
     }
 }
 
-pub fn driver_init(
-    _device_tree: drivers::DeviceTree,
-    irq_manager: &impl drivers::IrqManager,
-) -> Option<Arc<GenericTimer>> {
+pub fn driver_probe<'dt>(
+    _device_tree: drivers::DeviceTree<'dt>,
+    _node: &drivers::DevTreeNode<'_, 'dt>,
+    irq_manager: &dyn drivers::IrqManager,
+) -> drivers::Result<()> {
     let timer = Arc::new(GenericTimer::new());
-    timer.init().unwrap();
-    irq_manager
-        .register_and_enable_local_irq(GenericTimer::IRQ_NUMBER, timer.clone())
-        .unwrap();
+    timer.init()?;
+    // the non-secure physical timer, the second of the interrupts of the node
+    irq_manager.register_and_enable_local_irq(GenericTimer::IRQ_NUMBER, timer)?;
 
-    Some(timer)
+    Ok(())
 }
 
 #[inline]
 pub fn read() -> Duration {
-    GenericTimer::new().read()
+    Duration::from_nanos(read_ns())
 }
 
+/// Time since boot in nanoseconds.
 #[inline]
 pub fn read_ns() -> u64 {
-    GenericTimer::new().read_ns()
-}
\ No newline at end of file
+    GenericTimer::count_to_ns(read_count())
+}
+
+/// Make the timer of this CPU tick in `ns` nanoseconds unless it ticks earlier already.
+#[inline]
+pub fn tick_within(ns: usize) {
+    GenericTimer::new().tick_within(ns)
+}
+
+/// Make the timer of this CPU tick by `deadline` unless it ticks earlier already.
+#[inline]
+pub fn tick_by(deadline: Duration) {
+    GenericTimer::new().tick_by(deadline)
+}
+
+/// Raw value of the physical counter.
+#[cfg(not(test))]
+#[inline]
+pub fn read_count() -> u64 {
+    CNTPCT_EL0.get()
+}
+
+/// The counter of host tests, which run in EL0 and move it themselves.
+#[cfg(test)]
+pub static MOCK_COUNT: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+pub fn read_count() -> u64 {
+    MOCK_COUNT.load(Ordering::Relaxed)
+}
+
+/// `a * b / c` without overflowing the product, the result must fit in 64 bits.
+#[inline]
+fn mul_div(a: u64, b: u64, c: u64) -> u64 {
+    (a as u128 * b as u128 / c as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ns_conversion() {
+        assert_eq!(GenericTimer::freq(), 62_500_000);
+        assert_eq!(GenericTimer::count_to_ns(62_500_000), NSEC_PER_SEC);
+        assert_eq!(GenericTimer::count_to_ns(1), 16);
+        assert_eq!(GenericTimer::ns_to_count(NSEC_PER_SEC), 62_500_000);
+        // truncated, never rounded up
+        assert_eq!(GenericTimer::ns_to_count(31), 1);
+        // the product does not fit in 64 bits
+        assert_eq!(GenericTimer::count_to_ns(1 << 50), 1 << 54);
+        assert_eq!(GenericTimer::ns_to_count(1 << 54), 1 << 50);
+        assert_eq!(mul_div(u64::MAX, 3, 4), (u64::MAX / 4) * 3 + 2);
+    }
+
+    #[test]
+    fn read_after_years() {
+        let secs = 100 * 365 * 24 * 3600;
+        MOCK_COUNT.store(secs * 62_500_000 + 1, Ordering::Relaxed);
+        assert_eq!(read_ns(), secs * NSEC_PER_SEC + 16);
+        assert_eq!(read(), Duration::new(secs, 16));
+        assert_eq!(FREQ.load(Ordering::Relaxed), 62_500_000);
+        MOCK_COUNT.store(0, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,44 @@
+//! TLB maintenance across CPUs.
+//!
+//! Switching page tables keeps the TLB entries tagged with the ASID of the old one
+//! (see `asid`), so a page table may be cached by every core which ever ran it.
+//! Its mappings are invalidated by broadcast operations, reaching all the cores of
+//! the inner shareable domain.
+
+use crate::memory::{VirtAddr, PAGE_SIZE};
+use core::arch::asm;
+
+/// Above this many pages the whole ASID is flushed instead.
+const MAX_FLUSH_PAGES: usize = 32;
+
+/// Flush the mappings of `start..end` of the page table tagged `asid` on every
+/// CPU, return after all of them have flushed.
+///
+/// Global mappings, of the kernel, are flushed whatever `asid` is.
+#[cfg(not(test))]
+pub fn flush_range(asid: u16, start: VirtAddr, end: VirtAddr) {
+    let asid = (asid as u64) << 48;
+    unsafe {
+        // the table walkers see the updated entries before the invalidation
+        asm!("dsb ishst");
+        if (end - start) / PAGE_SIZE > MAX_FLUSH_PAGES {
+            if asid == 0 {
+                asm!("tlbi vmalle1is");
+            } else {
+                asm!("tlbi aside1is, {}", in(reg) asid);
+            }
+        } else {
+            for vaddr in (start..end).step_by(PAGE_SIZE) {
+                // VA[55:12]
+                let page = (vaddr >> 12) as u64 & ((1 << 44) - 1);
+                asm!("tlbi vale1is, {}", in(reg) asid | page);
+            }
+        }
+        asm!("dsb ish", "isb");
+    }
+}
+
+/// Host tests run in EL0, where the TLB cannot be maintained, and never switch
+/// to the page tables they build.
+#[cfg(test)]
+pub fn flush_range(_asid: u16, _start: VirtAddr, _end: VirtAddr) {}
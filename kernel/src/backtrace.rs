@@ -1,9 +1,70 @@
 //! Provide backtrace upon panic
-use core::{mem::size_of, arch::asm};
+use crate::logging::_print_unlocked;
+use core::{arch::asm, mem::size_of, ptr, slice, str};
 
 extern "C" {
     fn stext();
     fn etext();
+    /// Symbol table written by `tools/ksymtab.py` after linking.
+    fn sksymtab();
+    fn eksymtab();
+}
+
+const KSYMTAB_MAGIC: u32 = 0x4d59_534b;
+const KSYMTAB_HEADER_SIZE: usize = 8;
+/// `(address: u64, size: u64, name_offset: u32, name_len: u32)`
+const KSYMTAB_ENTRY_SIZE: usize = 24;
+
+/// Read a `T` at byte `offset` of the symbol table.
+unsafe fn ksymtab_read<T: Copy>(offset: usize) -> T {
+    ptr::read_unaligned((sksymtab as usize + offset) as *const T)
+}
+
+/// Find the symbol containing `pc`, return its name and the offset of `pc` in it.
+///
+/// Returns `None` if the symbol table was not filled in.
+fn lookup_symbol(pc: usize) -> Option<(&'static str, usize)> {
+    unsafe {
+        let capacity = eksymtab as usize - sksymtab as usize;
+        if ksymtab_read::<u32>(0) != KSYMTAB_MAGIC {
+            return None;
+        }
+        let count = ksymtab_read::<u32>(4) as usize;
+        let names = KSYMTAB_HEADER_SIZE + count * KSYMTAB_ENTRY_SIZE;
+        if names > capacity {
+            return None;
+        }
+        let address = |i: usize| ksymtab_read::<u64>(KSYMTAB_HEADER_SIZE + i * KSYMTAB_ENTRY_SIZE);
+
+        // the last symbol at or below `pc`, entries are sorted by address
+        let (mut low, mut high) = (0, count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if address(mid) as usize <= pc {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let entry = KSYMTAB_HEADER_SIZE + low.checked_sub(1)? * KSYMTAB_ENTRY_SIZE;
+        let start = ksymtab_read::<u64>(entry) as usize;
+        let size = ksymtab_read::<u64>(entry + 8) as usize;
+        if size != 0 && pc >= start + size {
+            return None;
+        }
+        let name_offset = names + ksymtab_read::<u32>(entry + 16) as usize;
+        let name_len = ksymtab_read::<u32>(entry + 20) as usize;
+        if name_offset + name_len > capacity {
+            return None;
+        }
+        let name = slice::from_raw_parts((sksymtab as usize + name_offset) as *const u8, name_len);
+        // a name truncated by the generator may end in the middle of a character
+        let name = match str::from_utf8(name) {
+            Ok(name) => name,
+            Err(err) => str::from_utf8_unchecked(&name[..err.valid_up_to()]),
+        };
+        Some((name, pc - start))
+    }
 }
 
 /// Returns the current frame pointer or stack base pointer
@@ -34,19 +95,25 @@ pub fn backtrace() {
         let mut current_fp = fp();
         let mut stack_num = 0;
 
-        println!("=== QueenOS stack trace BEGIN ===");
+        // only called on panic, see `_print_unlocked`
+        _print_unlocked(format_args!("=== QueenOS stack trace BEGIN ===\n"));
 
         while current_pc >= stext as usize
             && current_pc <= etext as usize
             && current_fp as usize != 0
         {
             // print current backtrace
-            println!(
-                "#{:02} PC: {:#018X} FP: {:#018X}",
-                stack_num,
-                current_pc - size_of::<usize>(),
-                current_fp
-            );
+            let pc = current_pc - size_of::<usize>();
+            match lookup_symbol(pc) {
+                Some((name, offset)) => _print_unlocked(format_args!(
+                    "#{:02} PC: {:#018X} FP: {:#018X} {}+{:#x}\n",
+                    stack_num, pc, current_fp, name, offset
+                )),
+                None => _print_unlocked(format_args!(
+                    "#{:02} PC: {:#018X} FP: {:#018X}\n",
+                    stack_num, pc, current_fp
+                )),
+            }
 
             stack_num += 1;
 
@@ -60,6 +127,6 @@ pub fn backtrace() {
                 }
             }
         }
-        println!("=== QueenOS stack trace END   ===");
+        _print_unlocked(format_args!("=== QueenOS stack trace END   ===\n"));
     }
 }
@@ -0,0 +1,148 @@
+//! Kernel command line, the `bootargs` of the device tree `/chosen` node.
+//!
+//! Options are separated by spaces, as `name=value`. Unknown options and invalid
+//! values are ignored with a warning, the defaults then apply:
+//!
+//! - `loglevel=`: `off`, `error`, `warn`, `info`, `debug` or `trace`, or a Linux
+//!   console level from 0 to 8. Overrides the `LOG` level of the build.
+//! - `maxcpus=`: number of CPUs brought up, at least 1.
+//! - `root=`: the root filesystem, only `tmpfs` is supported.
+//! - `color=`: `on` or `off`, whether log records are colored, on by default.
+
+use crate::drivers::DeviceTree;
+use alloc::string::{String, ToString};
+use log::LevelFilter;
+use spin::Once;
+
+#[derive(Debug, Default)]
+pub struct CmdLine {
+    pub loglevel: Option<LevelFilter>,
+    pub maxcpus: Option<usize>,
+    pub root: Option<String>,
+    pub color: Option<bool>,
+}
+
+static CMDLINE: Once<CmdLine> = Once::new();
+
+/// Parse the command line of `device_tree` and apply `loglevel` and `color`.
+/// Without a `/chosen` node or `bootargs`, every option keeps its default.
+pub fn init(device_tree: &DeviceTree) {
+    let bootargs = device_tree
+        .find_node(|node| Ok(node.name()? == "chosen"))
+        .and_then(|chosen| {
+            device_tree
+                .node_prop_str(&chosen, "bootargs")
+                .map(|bootargs| bootargs.to_string())
+        });
+    let cmdline = bootargs.as_deref().map(parse).unwrap_or_default();
+    if let Some(level) = cmdline.loglevel {
+        log::set_max_level(level);
+    }
+    if let Some(color) = cmdline.color {
+        crate::logging::set_color(color);
+    }
+    info!("Command line: {:?}.", bootargs.as_deref().unwrap_or(""));
+    CMDLINE.call_once(|| cmdline);
+}
+
+/// The options of the command line, all default before `init`.
+pub fn cmdline() -> &'static CmdLine {
+    static DEFAULT: CmdLine = CmdLine {
+        loglevel: None,
+        maxcpus: None,
+        root: None,
+        color: None,
+    };
+    CMDLINE.get().unwrap_or(&DEFAULT)
+}
+
+fn parse(bootargs: &str) -> CmdLine {
+    let mut cmdline = CmdLine::default();
+    for option in bootargs.split_whitespace() {
+        let (name, value) = match option.find('=') {
+            Some(i) => (&option[..i], &option[i + 1..]),
+            None => (option, ""),
+        };
+        let valid = match name {
+            "loglevel" => {
+                cmdline.loglevel = parse_level(value);
+                cmdline.loglevel.is_some()
+            }
+            "maxcpus" => {
+                cmdline.maxcpus = value.parse().ok().filter(|&count| count > 0);
+                cmdline.maxcpus.is_some()
+            }
+            "color" => {
+                cmdline.color = match value {
+                    "on" => Some(true),
+                    "off" => Some(false),
+                    _ => None,
+                };
+                cmdline.color.is_some()
+            }
+            "root" => {
+                if value != "tmpfs" {
+                    warn!("root={} is not supported, the root is a tmpfs.", value);
+                }
+                cmdline.root = Some(value.to_string());
+                true
+            }
+            _ => {
+                warn!("Unknown command line option {:?}.", option);
+                continue;
+            }
+        };
+        if !valid {
+            warn!("Invalid command line option {:?}.", option);
+        }
+    }
+    cmdline
+}
+
+fn parse_level(value: &str) -> Option<LevelFilter> {
+    Some(match value {
+        "off" => LevelFilter::Off,
+        "error" | "0" | "1" | "2" | "3" => LevelFilter::Error,
+        "warn" | "4" => LevelFilter::Warn,
+        "info" | "5" | "6" => LevelFilter::Info,
+        "debug" | "7" => LevelFilter::Debug,
+        "trace" | "8" => LevelFilter::Trace,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options() {
+        let cmdline = parse("loglevel=debug  maxcpus=2 root=tmpfs color=off");
+        assert_eq!(cmdline.loglevel, Some(LevelFilter::Debug));
+        assert_eq!(cmdline.maxcpus, Some(2));
+        assert_eq!(cmdline.root.as_deref(), Some("tmpfs"));
+        assert_eq!(cmdline.color, Some(false));
+
+        assert_eq!(parse("loglevel=4").loglevel, Some(LevelFilter::Warn));
+        assert_eq!(parse("loglevel=off").loglevel, Some(LevelFilter::Off));
+        // the last one wins
+        assert_eq!(parse("maxcpus=1 maxcpus=3").maxcpus, Some(3));
+    }
+
+    #[test]
+    fn defaults() {
+        let cmdline = parse("");
+        assert!(cmdline.loglevel.is_none());
+        assert!(cmdline.maxcpus.is_none());
+        assert!(cmdline.root.is_none());
+        assert!(cmdline.color.is_none());
+
+        // unknown options and invalid values are ignored
+        let cmdline = parse("quiet console=ttyAMA0 loglevel=9 maxcpus=0 color=yes");
+        assert!(cmdline.loglevel.is_none());
+        assert!(cmdline.maxcpus.is_none());
+        assert!(cmdline.color.is_none());
+        assert!(parse("maxcpus=-1 maxcpus").maxcpus.is_none());
+        assert!(parse("loglevel").loglevel.is_none());
+    }
+}
@@ -0,0 +1,286 @@
+use super::{BlockDevice, BLOCK_SIZE};
+use crate::{
+    drivers::{DriverError, Result},
+    memory::{alloc_frames, dealloc_frames, phys_to_virt, PhysAddr, PAGE_SIZE},
+    sync::MutexNoIrq,
+};
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::slice;
+
+const BLOCKS_PER_FRAME: usize = PAGE_SIZE / BLOCK_SIZE;
+
+/// Write-back cache of the blocks of a device, the least recently used block
+/// is evicted when the cache is full.
+///
+/// Cached blocks live in frames allocated once when the cache is created.
+///
+/// The device is read and written with the cache locked, so with IRQs off:
+/// block devices poll for the completion of a request, like `VirtIOBlk`.
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    inner: MutexNoIrq<CacheInner>,
+}
+
+struct CacheInner {
+    /// Frames holding the slots, `BLOCKS_PER_FRAME` slots each
+    frames: Vec<PhysAddr>,
+    slots: Vec<Slot>,
+    /// Slot of each cached block
+    index: BTreeMap<usize, usize>,
+    /// Incremented on every access, orders the slots by recency
+    clock: u64,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Slot {
+    block_id: Option<usize>,
+    dirty: bool,
+    last_use: u64,
+}
+
+impl BlockCache {
+    /// Create a cache of `capacity` blocks in front of `device`.
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Result<Self> {
+        let capacity = capacity.max(1);
+        let frame_count = (capacity + BLOCKS_PER_FRAME - 1) / BLOCKS_PER_FRAME;
+        let mut frames = Vec::with_capacity(frame_count);
+        for _ in 0..frame_count {
+            match alloc_frames(1) {
+                Some(frame) => frames.push(frame),
+                None => {
+                    for frame in frames {
+                        dealloc_frames(frame, 1);
+                    }
+                    return Err(DriverError::ProbeFailed("no frames for the block cache"));
+                }
+            }
+        }
+        Ok(BlockCache {
+            device,
+            inner: MutexNoIrq::new(CacheInner {
+                frames,
+                slots: vec![Slot::default(); capacity],
+                index: BTreeMap::new(),
+                clock: 0,
+            }),
+        })
+    }
+
+    /// Read block `block_id` into `buf`, which is `BLOCK_SIZE` long.
+    pub fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<()> {
+        self.check(block_id, buf.len())?;
+        let mut inner = self.inner.lock();
+        let slot = match inner.index.get(&block_id) {
+            Some(&slot) => slot,
+            None => {
+                let slot = self.evict(&mut inner)?;
+                self.device.read_block(block_id, inner.data(slot))?;
+                inner.index.insert(block_id, slot);
+                inner.slots[slot].block_id = Some(block_id);
+                slot
+            }
+        };
+        inner.touch(slot);
+        buf.copy_from_slice(inner.data(slot));
+        Ok(())
+    }
+
+    /// Write `buf`, which is `BLOCK_SIZE` long, to block `block_id`.
+    ///
+    /// The device is only written on eviction or `sync`.
+    pub fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<()> {
+        self.check(block_id, buf.len())?;
+        let mut inner = self.inner.lock();
+        let slot = match inner.index.get(&block_id) {
+            Some(&slot) => slot,
+            // the whole block is overwritten, no need to read it first
+            None => {
+                let slot = self.evict(&mut inner)?;
+                inner.index.insert(block_id, slot);
+                inner.slots[slot].block_id = Some(block_id);
+                slot
+            }
+        };
+        inner.touch(slot);
+        inner.data(slot).copy_from_slice(buf);
+        inner.slots[slot].dirty = true;
+        Ok(())
+    }
+
+    /// Write every dirty block back to the device.
+    pub fn sync(&self) -> Result<()> {
+        let mut inner = self.inner.lock();
+        for slot in 0..inner.slots.len() {
+            self.write_back(&mut inner, slot)?;
+        }
+        Ok(())
+    }
+
+    fn check(&self, block_id: usize, len: usize) -> Result<()> {
+        if block_id >= self.device.num_blocks() || len != BLOCK_SIZE {
+            return Err(DriverError::Io("bad block id or length"));
+        }
+        Ok(())
+    }
+
+    /// Return a free slot, evicting the least recently used block if there is none.
+    fn evict(&self, inner: &mut CacheInner) -> Result<usize> {
+        if let Some(slot) = inner.slots.iter().position(|slot| slot.block_id.is_none()) {
+            return Ok(slot);
+        }
+        let (slot, _) = inner
+            .slots
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, slot)| slot.last_use)
+            .unwrap();
+        // keep the block cached if it can not be written back
+        self.write_back(inner, slot)?;
+        let block_id = inner.slots[slot].block_id.take().unwrap();
+        inner.index.remove(&block_id);
+        Ok(slot)
+    }
+
+    fn write_back(&self, inner: &mut CacheInner, slot: usize) -> Result<()> {
+        if let Slot {
+            block_id: Some(block_id),
+            dirty: true,
+            ..
+        } = inner.slots[slot]
+        {
+            self.device.write_block(block_id, inner.data(slot))?;
+            inner.slots[slot].dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl CacheInner {
+    fn touch(&mut self, slot: usize) {
+        self.clock += 1;
+        self.slots[slot].last_use = self.clock;
+    }
+
+    fn data(&mut self, slot: usize) -> &mut [u8] {
+        let frame = self.frames[slot / BLOCKS_PER_FRAME];
+        let addr = phys_to_virt(frame) + slot % BLOCKS_PER_FRAME * BLOCK_SIZE;
+        unsafe { slice::from_raw_parts_mut(addr as *mut u8, BLOCK_SIZE) }
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        if self.sync().is_err() {
+            warn!("block cache: failed to write back dirty blocks");
+        }
+        for &frame in self.inner.lock().frames.iter() {
+            dealloc_frames(frame, 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::{DeviceType, Driver};
+
+    /// A disk in host memory, logging the blocks read and written.
+    struct LogDisk {
+        data: MutexNoIrq<Vec<u8>>,
+        reads: MutexNoIrq<Vec<usize>>,
+        writes: MutexNoIrq<Vec<usize>>,
+    }
+
+    impl Driver for LogDisk {
+        fn compatible(&self) -> &'static str {
+            "log-disk"
+        }
+
+        fn device_type(&self) -> DeviceType {
+            DeviceType::Block
+        }
+    }
+
+    impl BlockDevice for LogDisk {
+        fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<()> {
+            self.reads.lock().push(block_id);
+            let start = block_id * BLOCK_SIZE;
+            buf.copy_from_slice(&self.data.lock()[start..start + BLOCK_SIZE]);
+            Ok(())
+        }
+
+        fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<()> {
+            self.writes.lock().push(block_id);
+            let start = block_id * BLOCK_SIZE;
+            self.data.lock()[start..start + BLOCK_SIZE].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn num_blocks(&self) -> usize {
+            self.data.lock().len() / BLOCK_SIZE
+        }
+    }
+
+    /// A cache of 2 blocks in front of a disk of 8, block `i` filled with `i`.
+    fn cache() -> (Arc<LogDisk>, BlockCache) {
+        let mut data = vec![0; 8 * BLOCK_SIZE];
+        for (i, block) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+            block.fill(i as u8);
+        }
+        let disk = Arc::new(LogDisk {
+            data: MutexNoIrq::new(data),
+            reads: MutexNoIrq::new(Vec::new()),
+            writes: MutexNoIrq::new(Vec::new()),
+        });
+        let cache = BlockCache::new(disk.clone(), 2).unwrap();
+        (disk, cache)
+    }
+
+    #[test]
+    fn eviction_order() {
+        let (disk, cache) = cache();
+        let mut buf = [0; BLOCK_SIZE];
+        for &block_id in &[0, 1, 0, 2, 1, 0] {
+            cache.read_block(block_id, &mut buf).unwrap();
+            assert_eq!(buf, [block_id as u8; BLOCK_SIZE]);
+        }
+        // 0 is hit, then 2 evicts 1, 1 evicts 0 and 0 evicts 2
+        assert_eq!(*disk.reads.lock(), [0, 1, 2, 1, 0]);
+        assert!(disk.writes.lock().is_empty());
+
+        let result = cache.read_block(8, &mut buf);
+        assert!(matches!(result, Err(DriverError::Io(_))));
+        let result = cache.read_block(0, &mut buf[1..]);
+        assert!(matches!(result, Err(DriverError::Io(_))));
+    }
+
+    #[test]
+    fn dirty_flush() {
+        let (disk, cache) = cache();
+        let mut buf = [0; BLOCK_SIZE];
+        cache.write_block(3, &[0x33; BLOCK_SIZE]).unwrap();
+        cache.write_block(4, &[0x44; BLOCK_SIZE]).unwrap();
+        cache.write_block(3, &[0x3f; BLOCK_SIZE]).unwrap();
+        // written blocks are not read first, nor written before eviction
+        assert!(disk.reads.lock().is_empty());
+        assert!(disk.writes.lock().is_empty());
+
+        // 4 is the least recently used, written back when evicted
+        cache.read_block(5, &mut buf).unwrap();
+        assert_eq!(*disk.writes.lock(), [4]);
+        assert_eq!(disk.data.lock()[4 * BLOCK_SIZE], 0x44);
+        assert_eq!(disk.data.lock()[3 * BLOCK_SIZE], 3);
+
+        cache.sync().unwrap();
+        assert_eq!(*disk.writes.lock(), [4, 3]);
+        assert_eq!(disk.data.lock()[3 * BLOCK_SIZE], 0x3f);
+        // clean after the sync
+        cache.sync().unwrap();
+        assert_eq!(*disk.writes.lock(), [4, 3]);
+
+        // the latest write is read back from the cache
+        cache.read_block(3, &mut buf).unwrap();
+        assert_eq!(buf, [0x3f; BLOCK_SIZE]);
+        assert_eq!(*disk.reads.lock(), [5]);
+    }
+}
@@ -1 +1,21 @@
+use super::{Driver, Result};
 
+pub mod cache;
+pub mod virtio_blk;
+
+pub use cache::BlockCache;
+pub use virtio_blk::VirtIOBlk;
+
+/// Size of a block in bytes.
+pub const BLOCK_SIZE: usize = 512;
+
+pub trait BlockDevice: Driver {
+    /// Read block `block_id` into `buf`, which is `BLOCK_SIZE` long.
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<()>;
+
+    /// Write `buf`, which is `BLOCK_SIZE` long, to block `block_id`.
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<()>;
+
+    /// Number of blocks on the device.
+    fn num_blocks(&self) -> usize;
+}
@@ -0,0 +1,437 @@
+use crate::{
+    arch::timer,
+    drivers::{self, common::MMIODerefWrapper, Driver, DriverError},
+    memory::{alloc_frames, dealloc_frames, phys_to_virt, PhysAddr, PAGE_SIZE},
+};
+use alloc::sync::Arc;
+use core::{
+    hint::spin_loop,
+    mem::size_of,
+    ptr,
+    sync::atomic::{fence, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+use tock_registers::{
+    interfaces::*,
+    register_bitfields, register_structs,
+    registers::{ReadOnly, ReadWrite, WriteOnly},
+};
+
+use super::{BlockDevice, BLOCK_SIZE};
+
+// Ref: [Virtual I/O Device (VIRTIO) Version 1.1, 4.2 Virtio Over MMIO]
+
+register_bitfields! {
+    u32,
+
+    /// Device Status.
+    STATUS [
+        /// The guest OS has found the device and recognized it as a valid virtio device.
+        ACKNOWLEDGE OFFSET(0) NUMBITS(1) [],
+        /// The guest OS knows how to drive the device.
+        DRIVER OFFSET(1) NUMBITS(1) [],
+        /// The driver is set up and ready to drive the device.
+        DRIVER_OK OFFSET(2) NUMBITS(1) [],
+        /// The driver has acknowledged all the features it understands, and feature negotiation is complete.
+        FEATURES_OK OFFSET(3) NUMBITS(1) [],
+        /// Something went wrong in the guest, and it has given up on the device.
+        FAILED OFFSET(7) NUMBITS(1) []
+    ]
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub RegisterBlock {
+        (0x000 => MAGIC_VALUE: ReadOnly<u32>),
+        (0x004 => VERSION: ReadOnly<u32>),
+        (0x008 => DEVICE_ID: ReadOnly<u32>),
+        (0x00c => VENDOR_ID: ReadOnly<u32>),
+        (0x010 => DEVICE_FEATURES: ReadOnly<u32>),
+        (0x014 => DEVICE_FEATURES_SEL: WriteOnly<u32>),
+        (0x018 => _reserved0),
+        (0x020 => DRIVER_FEATURES: WriteOnly<u32>),
+        (0x024 => DRIVER_FEATURES_SEL: WriteOnly<u32>),
+        // legacy interface only
+        (0x028 => GUEST_PAGE_SIZE: WriteOnly<u32>),
+        (0x02c => _reserved1),
+        (0x030 => QUEUE_SEL: WriteOnly<u32>),
+        (0x034 => QUEUE_NUM_MAX: ReadOnly<u32>),
+        (0x038 => QUEUE_NUM: WriteOnly<u32>),
+        // legacy interface only
+        (0x03c => QUEUE_ALIGN: WriteOnly<u32>),
+        // legacy interface only
+        (0x040 => QUEUE_PFN: ReadWrite<u32>),
+        (0x044 => QUEUE_READY: ReadWrite<u32>),
+        (0x048 => _reserved2),
+        (0x050 => QUEUE_NOTIFY: WriteOnly<u32>),
+        (0x054 => _reserved3),
+        (0x060 => INTERRUPT_STATUS: ReadOnly<u32>),
+        (0x064 => INTERRUPT_ACK: WriteOnly<u32>),
+        (0x068 => _reserved4),
+        (0x070 => STATUS: ReadWrite<u32, STATUS::Register>),
+        (0x074 => _reserved5),
+        (0x080 => QUEUE_DESC_LOW: WriteOnly<u32>),
+        (0x084 => QUEUE_DESC_HIGH: WriteOnly<u32>),
+        (0x088 => _reserved6),
+        (0x090 => QUEUE_DRIVER_LOW: WriteOnly<u32>),
+        (0x094 => QUEUE_DRIVER_HIGH: WriteOnly<u32>),
+        (0x098 => _reserved7),
+        (0x0a0 => QUEUE_DEVICE_LOW: WriteOnly<u32>),
+        (0x0a4 => QUEUE_DEVICE_HIGH: WriteOnly<u32>),
+        (0x0a8 => _reserved8),
+        // `capacity` of `struct virtio_blk_config`, in 512-byte sectors
+        (0x100 => CAPACITY_LOW: ReadOnly<u32>),
+        (0x104 => CAPACITY_HIGH: ReadOnly<u32>),
+        (0x108 => @END),
+    }
+}
+
+/// Abstraction for the associated MMIO registers.
+type Registers = MMIODerefWrapper<RegisterBlock>;
+
+/// "virt" in little endian
+const VIRTIO_MAGIC: u32 = 0x7472_6976;
+const VIRTIO_DEVICE_BLOCK: u32 = 2;
+/// Feature bit 32, i.e. bit 0 of the high half, offered by non-legacy devices
+const VIRTIO_F_VERSION_1: u32 = 1 << 0;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+/// Requests are issued one at a time, a chain of 3 descriptors each
+const QUEUE_SIZE: usize = 4;
+
+/// How long a request may take before the device is given up on
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Layout of the DMA frames: the virtqueue in the legacy layout, followed by
+/// the buffers of the request in flight.
+const AVAIL_OFFSET: usize = QUEUE_SIZE * size_of::<Descriptor>();
+const USED_OFFSET: usize = PAGE_SIZE;
+const HEADER_OFFSET: usize = 2 * PAGE_SIZE;
+const STATUS_OFFSET: usize = HEADER_OFFSET + size_of::<BlkReqHeader>();
+const DATA_OFFSET: usize = HEADER_OFFSET + BLOCK_SIZE;
+const DMA_FRAMES: usize = 3;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct BlkReqHeader {
+    r#type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Offsets of the fields of the available and used rings.
+const RING_IDX: usize = 2;
+const RING_ENTRIES: usize = 4;
+
+pub struct VirtIOBlk {
+    registers: Registers,
+    /// Never held by the interrupt handler
+    queue: Mutex<VirtQueue>,
+}
+
+struct VirtQueue {
+    /// Physically contiguous `DMA_FRAMES` frames
+    dma: PhysAddr,
+    /// Next index of the available ring
+    avail_idx: u16,
+    /// Index of the used ring the last completed request was put at
+    used_idx: u16,
+    /// A request timed out, the device may still use the buffers
+    broken: bool,
+}
+
+impl VirtIOBlk {
+    pub const COMPATIBLE: &'static str = "virtio,mmio";
+
+    /// Whether a block device sits behind the transport at `mmio_start_addr`.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide a correct MMIO start address.
+    pub unsafe fn probe(mmio_start_addr: usize) -> bool {
+        let registers = Registers::new(mmio_start_addr);
+        registers.MAGIC_VALUE.get() == VIRTIO_MAGIC
+            && registers.DEVICE_ID.get() == VIRTIO_DEVICE_BLOCK
+    }
+
+    /// Create an instance, allocating the frames of the virtqueue.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide a correct MMIO start address.
+    pub unsafe fn new(mmio_start_addr: usize) -> drivers::Result<Self> {
+        let dma = alloc_frames(DMA_FRAMES)
+            .ok_or(DriverError::ProbeFailed("no frames for the virtqueue"))?;
+        ptr::write_bytes(phys_to_virt(dma) as *mut u8, 0, DMA_FRAMES * PAGE_SIZE);
+        Ok(Self {
+            registers: Registers::new(mmio_start_addr),
+            queue: Mutex::new(VirtQueue {
+                dma,
+                avail_idx: 0,
+                used_idx: 0,
+                broken: false,
+            }),
+        })
+    }
+
+    /// Give up on the device, because of `why`.
+    fn fail(&self, why: &'static str) -> drivers::Result<()> {
+        self.registers.STATUS.modify(STATUS::FAILED::SET);
+        Err(DriverError::ProbeFailed(why))
+    }
+
+    fn check(&self, block_id: usize, len: usize) -> drivers::Result<()> {
+        if block_id >= self.num_blocks() || len != BLOCK_SIZE {
+            return Err(DriverError::Io("bad block id or length"));
+        }
+        Ok(())
+    }
+
+    /// Issue a request on the data buffer of `queue` and wait for its completion.
+    fn request(&self, queue: &mut VirtQueue, r#type: u32, block_id: usize) -> drivers::Result<()> {
+        if queue.broken {
+            return Err(DriverError::Io("virtio-blk timed out before"));
+        }
+        let data_flags = match r#type {
+            VIRTIO_BLK_T_IN => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            _ => VIRTQ_DESC_F_NEXT,
+        };
+        unsafe {
+            queue.write(
+                HEADER_OFFSET,
+                BlkReqHeader {
+                    r#type,
+                    reserved: 0,
+                    sector: block_id as u64,
+                },
+            );
+            queue.write(STATUS_OFFSET, u8::MAX);
+            let header_len = size_of::<BlkReqHeader>() as u32;
+            queue.set_desc(0, HEADER_OFFSET, header_len, VIRTQ_DESC_F_NEXT, 1);
+            queue.set_desc(1, DATA_OFFSET, BLOCK_SIZE as u32, data_flags, 2);
+            queue.set_desc(2, STATUS_OFFSET, 1, VIRTQ_DESC_F_WRITE, 0);
+
+            // make the chain headed by descriptor 0 available
+            let slot = queue.avail_idx as usize % QUEUE_SIZE;
+            queue.write(AVAIL_OFFSET + RING_ENTRIES + slot * 2, 0u16);
+            queue.avail_idx = queue.avail_idx.wrapping_add(1);
+            fence(Ordering::SeqCst);
+            queue.write(AVAIL_OFFSET + RING_IDX, queue.avail_idx);
+            fence(Ordering::SeqCst);
+        }
+        self.registers.QUEUE_NOTIFY.set(0);
+
+        // `BlockDevice` is synchronous, so poll the used ring for the completion,
+        // the interrupt handler only acknowledges it
+        let used_idx = queue.used_idx.wrapping_add(1);
+        let deadline = timer::read() + REQUEST_TIMEOUT;
+        while unsafe { queue.read::<u16>(USED_OFFSET + RING_IDX) } != used_idx {
+            if timer::read() > deadline {
+                warn!("virtio-blk: request on block {} timed out", block_id);
+                queue.broken = true;
+                self.registers.STATUS.modify(STATUS::FAILED::SET);
+                return Err(DriverError::Io("virtio-blk request timed out"));
+            }
+            spin_loop();
+        }
+        fence(Ordering::SeqCst);
+        queue.used_idx = used_idx;
+
+        match unsafe { queue.read::<u8>(STATUS_OFFSET) } {
+            VIRTIO_BLK_S_OK => Ok(()),
+            status => {
+                warn!(
+                    "virtio-blk: request on block {} failed: {}",
+                    block_id, status
+                );
+                Err(DriverError::Io("virtio-blk request failed"))
+            }
+        }
+    }
+}
+
+impl VirtQueue {
+    unsafe fn write<T>(&self, offset: usize, value: T) {
+        ptr::write_volatile((phys_to_virt(self.dma) + offset) as *mut T, value);
+    }
+
+    unsafe fn read<T>(&self, offset: usize) -> T {
+        ptr::read_volatile((phys_to_virt(self.dma) + offset) as *const T)
+    }
+
+    /// Point descriptor `id` at the DMA buffer at `offset`.
+    unsafe fn set_desc(&self, id: usize, offset: usize, len: u32, flags: u16, next: u16) {
+        let desc = Descriptor {
+            addr: (self.dma + offset) as u64,
+            len,
+            flags,
+            next,
+        };
+        self.write(id * size_of::<Descriptor>(), desc);
+    }
+
+    fn data(&mut self) -> &mut [u8] {
+        let addr = phys_to_virt(self.dma) + DATA_OFFSET;
+        unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, BLOCK_SIZE) }
+    }
+}
+
+impl Driver for VirtIOBlk {
+    fn compatible(&self) -> &'static str {
+        Self::COMPATIBLE
+    }
+
+    fn init(&self) -> drivers::Result<()> {
+        let registers = &self.registers;
+        if registers.MAGIC_VALUE.get() != VIRTIO_MAGIC
+            || registers.DEVICE_ID.get() != VIRTIO_DEVICE_BLOCK
+        {
+            return Err(DriverError::ProbeFailed("not a virtio block device"));
+        }
+        let legacy = match registers.VERSION.get() {
+            1 => true,
+            2 => false,
+            _ => return Err(DriverError::Unsupported("unknown virtio version")),
+        };
+
+        // Reset the device.
+        registers.STATUS.set(0);
+        registers.STATUS.modify(STATUS::ACKNOWLEDGE::SET);
+        registers.STATUS.modify(STATUS::DRIVER::SET);
+
+        // None of the optional features is used.
+        registers.DEVICE_FEATURES_SEL.set(1);
+        let features_high = registers.DEVICE_FEATURES.get();
+        registers.DRIVER_FEATURES_SEL.set(0);
+        registers.DRIVER_FEATURES.set(0);
+        registers.DRIVER_FEATURES_SEL.set(1);
+        if legacy {
+            registers.DRIVER_FEATURES.set(0);
+        } else {
+            if features_high & VIRTIO_F_VERSION_1 == 0 {
+                return self.fail("VIRTIO_F_VERSION_1 not offered");
+            }
+            registers.DRIVER_FEATURES.set(VIRTIO_F_VERSION_1);
+            registers.STATUS.modify(STATUS::FEATURES_OK::SET);
+            // the device clears it if it does not accept the features
+            if !registers.STATUS.is_set(STATUS::FEATURES_OK) {
+                return self.fail("features not accepted");
+            }
+        }
+
+        // Set up the request queue.
+        registers.QUEUE_SEL.set(0);
+        if (registers.QUEUE_NUM_MAX.get() as usize) < QUEUE_SIZE {
+            return self.fail("request queue too small");
+        }
+        registers.QUEUE_NUM.set(QUEUE_SIZE as u32);
+        let dma = self.queue.lock().dma;
+        if legacy {
+            registers.GUEST_PAGE_SIZE.set(PAGE_SIZE as u32);
+            registers.QUEUE_ALIGN.set(PAGE_SIZE as u32);
+            registers.QUEUE_PFN.set((dma / PAGE_SIZE) as u32);
+        } else {
+            let avail = dma + AVAIL_OFFSET;
+            let used = dma + USED_OFFSET;
+            registers.QUEUE_DESC_LOW.set(dma as u32);
+            registers.QUEUE_DESC_HIGH.set((dma >> 32) as u32);
+            registers.QUEUE_DRIVER_LOW.set(avail as u32);
+            registers.QUEUE_DRIVER_HIGH.set((avail >> 32) as u32);
+            registers.QUEUE_DEVICE_LOW.set(used as u32);
+            registers.QUEUE_DEVICE_HIGH.set((used >> 32) as u32);
+            registers.QUEUE_READY.set(1);
+        }
+
+        registers.STATUS.modify(STATUS::DRIVER_OK::SET);
+
+        Ok(())
+    }
+
+    fn device_type(&self) -> drivers::DeviceType {
+        drivers::DeviceType::Block
+    }
+
+    fn handle_interrupt(&self) {
+        let status = self.registers.INTERRUPT_STATUS.get();
+        if status != 0 {
+            self.registers.INTERRUPT_ACK.set(status);
+        }
+    }
+}
+
+impl BlockDevice for VirtIOBlk {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> drivers::Result<()> {
+        self.check(block_id, buf.len())?;
+        let mut queue = self.queue.lock();
+        self.request(&mut queue, VIRTIO_BLK_T_IN, block_id)?;
+        buf.copy_from_slice(queue.data());
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> drivers::Result<()> {
+        self.check(block_id, buf.len())?;
+        let mut queue = self.queue.lock();
+        queue.data().copy_from_slice(buf);
+        self.request(&mut queue, VIRTIO_BLK_T_OUT, block_id)
+    }
+
+    fn num_blocks(&self) -> usize {
+        let high = self.registers.CAPACITY_HIGH.get() as usize;
+        let low = self.registers.CAPACITY_LOW.get() as usize;
+        high << 32 | low
+    }
+}
+
+impl Drop for VirtIOBlk {
+    fn drop(&mut self) {
+        // stop the device from using the queue before freeing it
+        self.registers.STATUS.set(0);
+        dealloc_frames(self.queue.lock().dma, DMA_FRAMES);
+    }
+}
+
+/// Initialize the block device behind a virtio-mmio transport, if any.
+pub fn driver_probe<'dt>(
+    device_tree: drivers::DeviceTree<'dt>,
+    node: &drivers::DevTreeNode<'_, 'dt>,
+    irq_manager: &dyn drivers::IrqManager,
+) -> drivers::Result<()> {
+    use crate::memory::as_upper_range;
+
+    let reg = device_tree
+        .node_reg_range_iter(node)
+        .and_then(|mut reg| reg.next())
+        .ok_or(DriverError::BadDeviceTree("no reg"))?;
+    let vaddr = as_upper_range(reg.start);
+    // there is a node for every transport, most of them without a device behind
+    if !unsafe { VirtIOBlk::probe(vaddr) } {
+        return Ok(());
+    }
+    let irq_num = device_tree
+        .node_interrupt_cell(node)
+        .ok_or(DriverError::BadDeviceTree("no interrupts"))?
+        .irq_number();
+
+    let blk = unsafe { Arc::new(VirtIOBlk::new(vaddr)?) };
+    blk.init()?;
+    irq_manager.register_and_enable_local_irq(irq_num, blk.clone())?;
+
+    info!("virtio-blk @ {:#x}: {} blocks", vaddr, blk.num_blocks());
+    crate::drivers::register_block_device(blk);
+
+    Ok(())
+}
@@ -1,7 +1,7 @@
 use core::ops::Range;
 use fdt_rs::{base::*, prelude::*};
 
-pub use fdt_rs::error::DevTreeError;
+pub use fdt_rs::{base::DevTreeNode, error::DevTreeError};
 pub type FdtResult<T> = fdt_rs::error::Result<T>;
 
 #[derive(Copy, Clone, Debug)]
@@ -89,6 +89,45 @@ impl<'dt> DeviceTree<'dt> {
         utils::read_node_prop_u32(node, "#address-cells", 0).or_else(|| self.root_address_cells())
     }
 
+    /// Returns the `index`th u32 cell of node's `prop_name` prop.
+    #[inline]
+    pub fn node_prop_u32(
+        &self,
+        node: &DevTreeNode,
+        prop_name: &str,
+        index: usize,
+    ) -> Option<usize> {
+        utils::read_node_prop_u32(node, prop_name, index)
+    }
+
+    /// Returns the `index`th u64 of node's `prop_name` prop.
+    #[inline]
+    pub fn node_prop_u64(
+        &self,
+        node: &DevTreeNode,
+        prop_name: &str,
+        index: usize,
+    ) -> Option<usize> {
+        utils::read_node_prop_u64(node, prop_name, index)
+    }
+
+    /// Returns node's `prop_name` prop as a string.
+    pub fn node_prop_str<'a>(&self, node: &'a DevTreeNode, prop_name: &str) -> Option<&'a str> {
+        utils::find_prop_by_name(node, prop_name)?.str().ok()
+    }
+
+    /// Returns the frequency in Hz of node's first clock, from its own
+    /// `clock-frequency` prop or from the clock node its `clocks` prop points to.
+    pub fn node_clock_frequency(&self, node: &DevTreeNode) -> Option<usize> {
+        if let Some(freq) = utils::read_node_prop_u32(node, "clock-frequency", 0) {
+            return Some(freq);
+        }
+        let phandle = utils::read_node_prop_u32(node, "clocks", 0)?;
+        let clock = self
+            .find_node(|node| Ok(utils::read_node_prop_u32(node, "phandle", 0) == Some(phandle)))?;
+        utils::read_node_prop_u32(&clock, "clock-frequency", 0)
+    }
+
     /// Returns node's `reg` prop's `address..address+len` ranges.
     pub fn node_reg_range_iter<'a>(
         &self,
@@ -211,7 +250,6 @@ mod utils {
             .map(|x| x as usize)
     }
 
-    #[allow(unused)]
     pub fn read_node_prop_u64(node: &DevTreeNode, prop_name: &str, index: usize) -> Option<usize> {
         find_prop_by_name(node, prop_name)?
             .u64(index)
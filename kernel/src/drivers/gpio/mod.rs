@@ -1,3 +1,52 @@
-mod pl061;
+use super::{Driver, DriverError, Result};
+use alloc::sync::Arc;
+use core::task::Waker;
+
+pub mod pl061;
 
 pub use pl061::Pl061Gpio;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+/// Edges of an input pin which raise its interrupt.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
+}
+
+/// A bank of GPIO pins, numbered from 0 to `width`.
+pub trait GpioDriver: Driver {
+    /// Number of pins of the bank.
+    fn width(&self) -> usize;
+
+    /// Make `pin` an input or an output.
+    fn set_direction(&self, pin: usize, direction: Direction) -> Result<()>;
+
+    /// Drive the output `pin` high if `value` is set, low otherwise.
+    fn write(&self, pin: usize, value: bool) -> Result<()>;
+
+    /// Level of `pin`, high if set.
+    fn read(&self, pin: usize) -> Result<bool>;
+
+    /// Wake `waker` up on the next `edge` of the input `pin`.
+    fn register_edge_waker(&self, pin: usize, edge: Edge, waker: Waker) -> Result<()>;
+}
+
+/// The bank of the global pin number `pin` and the pin in it, the pins of the
+/// banks being numbered one bank after the other in probe order.
+pub fn find_pin(pin: usize) -> Result<(Arc<dyn GpioDriver>, usize)> {
+    let mut pin = pin;
+    for bank in super::GPIO_DRIVERS.read().iter() {
+        if pin < bank.width() {
+            return Ok((bank.clone(), pin));
+        }
+        pin -= bank.width();
+    }
+    Err(DriverError::NotFound("no such GPIO pin"))
+}
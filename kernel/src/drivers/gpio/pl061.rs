@@ -1,4 +1,10 @@
-use crate::drivers::common::MMIODerefWrapper;
+use super::{Direction, Edge, GpioDriver};
+use crate::{
+    drivers::{self, common::MMIODerefWrapper, Driver},
+    sync::MutexNoIrq,
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::{ptr, task::Waker};
 use tock_registers::{
     interfaces::*,
     register_structs,
@@ -8,52 +14,193 @@ use tock_registers::{
 register_structs! {
     #[allow(non_snake_case)]
     pub RegisterBlock {
-        (0x000 => DATA: ReadWrite<u8>),
+        /// DATA, masked by the address bits [9:2], see `Pl061Gpio::data`.
+        (0x000 => _data),
         (0x400 => DIR: ReadWrite<u8>),
+        (0x401 => _reserved1),
         (0x404 => IS: ReadWrite<u8>),
+        (0x405 => _reserved2),
         (0x408 => IBE: ReadWrite<u8>),
+        (0x409 => _reserved3),
         (0x40c => IEV: ReadWrite<u8>),
+        (0x40d => _reserved4),
         (0x410 => IE: ReadWrite<u8>),
+        (0x411 => _reserved5),
         (0x414 => RIS: ReadOnly<u8>),
+        (0x415 => _reserved6),
         (0x418 => MIS: ReadOnly<u8>),
+        (0x419 => _reserved7),
         (0x41c => IC: WriteOnly<u8>),
+        (0x41d => _reserved8),
         (0x420 => AFSEL: ReadWrite<u8>),
-        (0x424 => @END),
+        (0x421 => @END),
     }
 }
 
 /// Abstraction for the associated MMIO registers.
 type Registers = MMIODerefWrapper<RegisterBlock>;
 
+/// Number of pins of a PL061.
+const WIDTH: usize = 8;
+
 pub struct Pl061Gpio {
+    base: usize,
     registers: Registers,
+    /// Woken up on the next interrupt of each pin. Also held to update the
+    /// registers shared by the pins.
+    edge_waiters: MutexNoIrq<[Vec<Waker>; WIDTH]>,
 }
 
 impl Pl061Gpio {
+    pub const COMPATIBLE: &'static str = "arm,pl061";
+
     /// Create an instance.
     ///
     /// # Safety
     ///
     /// - The user must ensure to provide a correct MMIO start address.
     pub const unsafe fn new(mmio_start_addr: usize) -> Self {
+        const NO_WAITERS: Vec<Waker> = Vec::new();
         Self {
+            base: mmio_start_addr,
             registers: Registers::new(mmio_start_addr),
+            edge_waiters: MutexNoIrq::new([NO_WAITERS; WIDTH]),
+        }
+    }
+
+    /// The bit of `pin` in the registers.
+    fn mask(pin: usize) -> drivers::Result<u8> {
+        if pin >= WIDTH {
+            return Err(drivers::DriverError::NotFound("no such PL061 pin"));
         }
+        Ok(1 << pin)
+    }
+
+    /// DATA seen through `mask`: the other bits read as 0 and ignore writes,
+    /// so that a pin is written without a read-modify-write.
+    fn data(&self, mask: u8) -> *mut u8 {
+        (self.base + ((mask as usize) << 2)) as *mut u8
+    }
+}
+
+impl Driver for Pl061Gpio {
+    fn compatible(&self) -> &'static str {
+        Self::COMPATIBLE
+    }
+
+    fn init(&self) -> drivers::Result<()> {
+        let _waiters = self.edge_waiters.lock();
+        // all pins are software controlled inputs, interrupts masked
+        self.registers.IE.set(0);
+        self.registers.IC.set(0xff);
+        self.registers.AFSEL.set(0);
+        self.registers.DIR.set(0);
+        // edge triggered
+        self.registers.IS.set(0);
+        Ok(())
+    }
+
+    fn device_type(&self) -> drivers::DeviceType {
+        drivers::DeviceType::Gpio
     }
 
-    pub fn init(&self) {
-        self.registers.IC.set(1);
-        // high level
-        self.registers.IEV.set(0b1111_1111);
-        // unmask
-        self.registers.IE.set(0b1111_1111);
-        // high level
-        self.registers.IS.set(0b1111_1111);
-        // enable hardware control
-        self.registers.AFSEL.set(0b1111_1111);
+    fn handle_interrupt(&self) {
+        let pending = self.registers.MIS.get();
+        if pending == 0 {
+            return;
+        }
+        self.registers.IC.set(pending);
+
+        let mut wakers = Vec::new();
+        {
+            let mut waiters = self.edge_waiters.lock();
+            // masked until a waker is registered again
+            self.registers.IE.set(self.registers.IE.get() & !pending);
+            for (pin, pin_waiters) in waiters.iter_mut().enumerate() {
+                if pending & (1 << pin) != 0 {
+                    wakers.append(pin_waiters);
+                }
+            }
+        }
+        for waker in wakers {
+            waker.wake();
+        }
     }
+}
 
-    pub fn get_raw_status(&self) -> u8 {
-        self.registers.RIS.get()
+impl GpioDriver for Pl061Gpio {
+    fn width(&self) -> usize {
+        WIDTH
     }
+
+    fn set_direction(&self, pin: usize, direction: Direction) -> drivers::Result<()> {
+        let mask = Self::mask(pin)?;
+        let _waiters = self.edge_waiters.lock();
+        set_bits(&self.registers.DIR, mask, direction == Direction::Output);
+        Ok(())
+    }
+
+    fn write(&self, pin: usize, value: bool) -> drivers::Result<()> {
+        let mask = Self::mask(pin)?;
+        unsafe { ptr::write_volatile(self.data(mask), if value { mask } else { 0 }) };
+        Ok(())
+    }
+
+    fn read(&self, pin: usize) -> drivers::Result<bool> {
+        let mask = Self::mask(pin)?;
+        Ok(unsafe { ptr::read_volatile(self.data(mask)) } != 0)
+    }
+
+    fn register_edge_waker(&self, pin: usize, edge: Edge, waker: Waker) -> drivers::Result<()> {
+        let mask = Self::mask(pin)?;
+        let (both, rising) = match edge {
+            Edge::Rising => (false, true),
+            Edge::Falling => (false, false),
+            Edge::Both => (true, false),
+        };
+        let mut waiters = self.edge_waiters.lock();
+        // the last edge registered applies to all the waiters of the pin
+        set_bits(&self.registers.IBE, mask, both);
+        set_bits(&self.registers.IEV, mask, rising);
+        let ie = self.registers.IE.get();
+        if ie & mask == 0 {
+            // forget an edge seen while the pin was masked
+            self.registers.IC.set(mask);
+            self.registers.IE.set(ie | mask);
+        }
+        waiters[pin].push(waker);
+        Ok(())
+    }
+}
+
+/// Set the bits of `mask` in `register` if `value` is set, clear them otherwise.
+fn set_bits(register: &ReadWrite<u8>, mask: u8, value: bool) {
+    let bits = register.get();
+    register.set(if value { bits | mask } else { bits & !mask });
+}
+
+pub fn driver_probe<'dt>(
+    device_tree: drivers::DeviceTree<'dt>,
+    node: &drivers::DevTreeNode<'_, 'dt>,
+    irq_manager: &dyn drivers::IrqManager,
+) -> drivers::Result<()> {
+    use crate::memory::as_upper_range;
+
+    let reg = device_tree
+        .node_reg_range_iter(node)
+        .and_then(|mut reg| reg.next())
+        .ok_or(drivers::DriverError::BadDeviceTree("no reg"))?;
+    let vaddr = as_upper_range(reg.start);
+
+    let gpio = unsafe { Arc::new(Pl061Gpio::new(vaddr)) };
+    gpio.init()?;
+
+    // without an interrupt, the pins are still usable without edge wakers
+    if let Some(cell) = device_tree.node_interrupt_cell(node) {
+        irq_manager.register_and_enable_local_irq(cell.irq_number(), gpio.clone())?;
+    }
+
+    drivers::register_gpio_bank(gpio);
+
+    Ok(())
 }
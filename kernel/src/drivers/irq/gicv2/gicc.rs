@@ -26,7 +26,9 @@ register_bitfields! {
 
     /// Interrupt Acknowledge Register
     IAR [
-        InterruptID OFFSET(0) NUMBITS(10) []
+        InterruptID OFFSET(0) NUMBITS(10) [],
+        /// For SGIs, the core that requested the interrupt
+        CPUID OFFSET(10) NUMBITS(3) []
     ],
 
     /// End of Interrupt Register
@@ -100,23 +102,26 @@ impl GicC {
         self.registers.CTLR.write(CTLR::Enable::SET);
     }
 
-    /// Extract the number of the highest-priority pending IRQ.
+    /// Acknowledge the highest-priority pending IRQ, return its number and the raw
+    /// IAR value to pass to `mark_completed()`.
     /// # Safety
     ///
     /// - GICC MMIO registers are banked per CPU core. It is therefore safe to have `&self` instead
     ///   of `&mut self`.
-    pub fn pending_irq_number(&self) -> usize {
-        self.registers.IAR.read(IAR::InterruptID) as usize
+    pub fn acknowledge(&self) -> (usize, u32) {
+        let iar = self.registers.IAR.extract();
+        (iar.read(IAR::InterruptID) as usize, iar.get())
     }
 
     /// Complete handling of the currently active IRQ.
-    /// To be called after `pending_irq_number()`.
+    /// To be called with the IAR value returned by `acknowledge()`, which also carries the
+    /// source core of an SGI.
     ///
     /// # Safety
     ///
     /// - GICC MMIO registers are banked per CPU core. It is therefore safe to have `&self` instead
     ///   of `&mut self`.
-    pub fn mark_completed(&self, irq_num: u32) {
-        self.registers.EOIR.write(EOIR::EOIINTID.val(irq_num));
+    pub fn mark_completed(&self, iar: u32) {
+        self.registers.EOIR.set(iar);
     }
 }
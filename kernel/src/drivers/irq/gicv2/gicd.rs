@@ -27,6 +27,17 @@ register_bitfields! {
         ITLinesNumber OFFSET(0)  NUMBITS(5) []
     ],
 
+    /// Software Generated Interrupt Register
+    SGIR [
+        TargetListFilter OFFSET(24) NUMBITS(2) [
+            TargetList = 0b00,
+            AllOthers = 0b01,
+            Myself = 0b10
+        ],
+        CPUTargetList OFFSET(16) NUMBITS(8) [],
+        INTID OFFSET(0) NUMBITS(4) []
+    ],
+
     /// Interrupt Processor Targets Registers
     ITARGETSR [
         Offset3 OFFSET(24) NUMBITS(8) [],
@@ -59,6 +70,15 @@ register_structs! {
     }
 }
 
+register_structs! {
+    #[allow(non_snake_case)]
+    SgiRegisterBlock {
+        (0x000 => _reserved1),
+        (0xf00 => SGIR: WriteOnly<u32, SGIR::Register>),
+        (0xf04 => @END),
+    }
+}
+
 register_structs! {
     #[allow(non_snake_case)]
     BankedRegisterBlock {
@@ -76,6 +96,9 @@ type SharedRegisters = MMIODerefWrapper<SharedRegisterBlock>;
 /// Abstraction for the banked parts of the associated MMIO registers.
 type BankedRegisters = MMIODerefWrapper<BankedRegisterBlock>;
 
+/// Abstraction for the software generated interrupt register.
+type SgiRegisters = MMIODerefWrapper<SgiRegisterBlock>;
+
 /// Representation of the GIC Distributor.
 pub struct GicD {
     /// Access to shared registers is guarded with a lock.
@@ -83,6 +106,9 @@ pub struct GicD {
 
     /// Access to banked registers is unguarded.
     banked_registers: BankedRegisters,
+
+    /// Write only, a single write sends an SGI, so access is unguarded.
+    sgi_registers: SgiRegisters,
 }
 
 impl SharedRegisters {
@@ -122,6 +148,7 @@ impl GicD {
         Self {
             shared_registers: MutexNoIrq::new(SharedRegisters::new(mmio_start_addr)),
             banked_registers: BankedRegisters::new(mmio_start_addr),
+            sgi_registers: SgiRegisters::new(mmio_start_addr),
         }
     }
 
@@ -160,6 +187,22 @@ impl GicD {
         regs.CTLR.write(CTLR::Enable::SET);
     }
 
+    /// Send the software generated interrupt `sgi` to the CPU interfaces in `target_mask`.
+    pub fn send_sgi(&self, target_mask: u32, sgi: u32) {
+        self.sgi_registers.SGIR.write(
+            SGIR::TargetListFilter::TargetList
+                + SGIR::CPUTargetList.val(target_mask)
+                + SGIR::INTID.val(sgi),
+        );
+    }
+
+    /// Send the software generated interrupt `sgi` to the CPU interface of the current core.
+    pub fn send_sgi_to_self(&self, sgi: u32) {
+        self.sgi_registers
+            .SGIR
+            .write(SGIR::TargetListFilter::Myself + SGIR::INTID.val(sgi));
+    }
+
     /// Enable an interrupt.
     pub fn enable(&self, irq_num: usize) {
         // Each bit in the u32 enable register corresponds to one IRQ number. Shift right by 5
@@ -76,10 +76,23 @@ impl IrqManager for GicV2 {
         Ok(())
     }
 
+    fn send_sgi(&self, cpu_id: usize, sgi: usize) -> drivers::Result<()> {
+        if sgi >= 16 || cpu_id >= 8 {
+            return Err(drivers::DriverError::Unsupported("SGI or CPU out of range"));
+        }
+        if cpu_id == crate::cpu::id() {
+            self.gicd.send_sgi_to_self(sgi as u32);
+        } else {
+            // the CPU interface of core n is bit n of the target list
+            self.gicd.send_sgi(1 << cpu_id, sgi as u32);
+        }
+        Ok(())
+    }
+
     fn handle_pending_irqs(&self) {
         // Extract the highest priority pending IRQ number from the Interrupt Acknowledge Register
         // (IAR).
-        let irq_number = self.gicc.pending_irq_number();
+        let (irq_number, iar) = self.gicc.acknowledge();
 
         if irq_number == 1023 {
             return;
@@ -94,26 +107,36 @@ impl IrqManager for GicV2 {
         }
 
         // Signal completion of handling.
-        self.gicc.mark_completed(irq_number as u32);
+        self.gicc.mark_completed(iar);
     }
 }
 
-pub fn driver_init(device_tree: drivers::DeviceTree) -> Option<GicV2> {
+pub fn driver_init(device_tree: drivers::DeviceTree) -> drivers::Result<GicV2> {
     use crate::memory::as_upper_range;
+    use drivers::DriverError;
     use fdt_rs::prelude::PropReader;
 
-    let gic_node = device_tree.find_node_with_prop(|prop| {
-        Ok(prop.name()?.eq("compatible") && prop.str()?.eq(GicV2::COMPATIBLE))
-    })?;
-    let mut reg_range_iter = device_tree.node_reg_range_iter(&gic_node)?;
-
-    let gicd_mmio_start_addr = as_upper_range(reg_range_iter.next()?.start);
-    let gicc_mmio_start_addr = as_upper_range(reg_range_iter.next()?.start);
+    let gic_node = device_tree
+        .find_node_with_prop(|prop| {
+            Ok(prop.name()?.eq("compatible") && prop.str()?.eq(GicV2::COMPATIBLE))
+        })
+        .ok_or(DriverError::NotFound("no GICv2"))?;
+    let mut reg_range_iter = device_tree
+        .node_reg_range_iter(&gic_node)
+        .ok_or(DriverError::BadDeviceTree("no reg"))?;
+    let mut next_reg = || {
+        reg_range_iter
+            .next()
+            .ok_or(DriverError::BadDeviceTree("missing reg range"))
+    };
+
+    let gicd_mmio_start_addr = as_upper_range(next_reg()?.start);
+    let gicc_mmio_start_addr = as_upper_range(next_reg()?.start);
 
     let gic = unsafe { GicV2::new(gicd_mmio_start_addr, gicc_mmio_start_addr) };
-    gic.init().unwrap();
+    gic.init()?;
 
     info!("Initialized GICv2 interrupt controller.");
 
-    Some(gic)
+    Ok(gic)
 }
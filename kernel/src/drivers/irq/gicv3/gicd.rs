@@ -1,5 +1,9 @@
 use crate::{drivers::common::MMIODerefWrapper, sync::spin::MutexNoIrq};
-use register::{mmio::*, register_bitfields, register_structs};
+use tock_registers::{
+    interfaces::*,
+    register_bitfields, register_structs,
+    registers::{ReadOnly, ReadWrite, WriteOnly},
+};
 
 register_bitfields! {
     u32,
@@ -27,14 +31,6 @@ register_bitfields! {
         /// Reports the number of PEs that can be used when affinity routing is not enabled, minus 1.
         CPUNumber OFFSET(5) NUMBITS(3) [],
         ESPI_range OFFSET(27) NUMBITS(5) []
-    ],
-
-    /// Interrupt Processor Targets Registers
-    ITARGETSR [
-        Offset3 OFFSET(24) NUMBITS(8) [],
-        Offset2 OFFSET(16) NUMBITS(8) [],
-        Offset1 OFFSET(8)  NUMBITS(8) [],
-        Offset0 OFFSET(0)  NUMBITS(8) []
     ]
 }
 
@@ -44,19 +40,26 @@ register_structs! {
         (0x0000 => CTLR: ReadWrite<u32, CTLR::Register>),
         (0x0004 => TYPER: ReadOnly<u32, TYPER::Register>),
         (0x0008 => IIDR: ReadOnly<u32>),
+        (0x000c => _reserved0),
         (0x0040 => SETSPI_NSR: WriteOnly<u32>),
+        (0x0044 => _reserved1),
         (0x0048 => CLRSPI_NSR: WriteOnly<u32, CLRSPI_NSR::Register>),
+        (0x004c => _reserved2),
         (0x0080 => IGROUPR: [ReadWrite<u32>; 32]),
         (0x0100 => ISENABLER: [ReadWrite<u32>; 32]),
         (0x0180 => ICENABLER: [ReadWrite<u32>; 32]),
-        (0x0200 => ISPENDR: [ReadWrite<u32> ;32]),
-        (0x0280 => ICPENDR: [ReadWrite<u32> ;32]),
-        (0x0400 => IPRIRITYR: [ReadWrite<u32>; 255]),
-        (0x0820 => ITARGETSR: [ReadWrite<u32, ITARGETSR::Register>; 248]),
+        (0x0200 => ISPENDR: [ReadWrite<u32>; 32]),
+        (0x0280 => ICPENDR: [ReadWrite<u32>; 32]),
+        (0x0300 => _reserved3),
+        (0x0400 => IPRIORITYR: [ReadWrite<u32>; 255]),
+        // ITARGETSR is RES0 with affinity routing
+        (0x07fc => _reserved4),
         (0x0c00 => ICFGR: [ReadWrite<u32>; 64]),
         (0x0d00 => IGRPMODR: [WriteOnly<u32>; 32]),
+        (0x0d80 => _reserved5),
+        /// Indexed by INTID, the first 32 are reserved.
         (0x6000 => IROUTER: [ReadWrite<u64>; 1020]),
-        (0xfffc => @END),
+        (0x7fe0 => @END),
     }
 }
 
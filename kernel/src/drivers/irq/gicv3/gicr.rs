@@ -1,5 +1,9 @@
 use crate::drivers::common::MMIODerefWrapper;
-use register::{mmio::*, register_bitfields, register_structs};
+use tock_registers::{
+    interfaces::*,
+    register_bitfields, register_structs,
+    registers::{ReadOnly, ReadWrite},
+};
 
 register_bitfields! {
     u32,
@@ -18,27 +22,47 @@ register_bitfields! {
     ]
 }
 
+register_bitfields! {
+    u64,
+    /// Provides information about the configuration of this Redistributor.
+    TYPER [
+        /// Whether this Redistributor is the highest-numbered Redistributor in a series of
+        /// contiguous Redistributor pages.
+        Last OFFSET(4) NUMBITS(1) [],
+        /// The affinity value of the PE, in the format of Aff3.Aff2.Aff1.Aff0.
+        Affinity OFFSET(32) NUMBITS(32) []
+    ]
+}
+
 register_structs! {
     #[allow(non_snake_case)]
     pub RdBasedRegisterBlock {
         (0x0000 => CTLR: ReadWrite<u32, CTLR::Register>),
-        (0x0008 => TYPER: ReadOnly<u32>),
+        (0x0004 => IIDR: ReadOnly<u32>),
+        (0x0008 => TYPER: ReadOnly<u64, TYPER::Register>),
+        (0x0010 => _reserved0),
         (0x0014 => WAKER: ReadWrite<u32, WAKER::Register>),
-        (0x0100  => @END),
+        (0x0018 => @END),
     }
 }
 
 register_structs! {
     #[allow(non_snake_case)]
     pub SgiBasedRegisterBlock {
+        (0x0000 => _reserved0),
         (0x0080 => IGROUPR0: ReadWrite<u32>),
+        (0x0084 => _reserved1),
         /// Enables forwarding of the corresponding SGI or PPI to the CPU interfaces.
         (0x0100 => ISENABLER0: ReadWrite<u32>),
+        (0x0104 => _reserved2),
         (0x0180 => ICENABLER0: ReadWrite<u32>),
+        (0x0184 => _reserved3),
         (0x0280 => ICPENDR0: ReadWrite<u32>),
+        (0x0284 => _reserved4),
         (0x0400 => IPRIORITYR: [ReadWrite<u32>; 8]),
+        (0x0420 => _reserved5),
         (0x0c04 => ICFGR1: ReadWrite<u32>),
-        (0x0E00 => @END),
+        (0x0c08 => @END),
     }
 }
 
@@ -46,6 +70,9 @@ register_structs! {
 type RdBasedRegisters = MMIODerefWrapper<RdBasedRegisterBlock>;
 type SgiBasedRegisters = MMIODerefWrapper<SgiBasedRegisterBlock>;
 
+/// Size of the frames of a Redistributor, `RD_base` followed by `SGI_base`.
+const GICR_STRIDE: usize = 0x20000;
+
 pub struct GicR {
     rd_based_registers: RdBasedRegisters,
     sgi_based_registers: SgiBasedRegisters,
@@ -64,6 +91,27 @@ impl GicR {
         }
     }
 
+    /// Find the Redistributor of the PE whose affinity is `affinity`, walking the
+    /// region starting at `region_start_addr`.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide a correct MMIO start address.
+    pub unsafe fn find(region_start_addr: usize, affinity: u64) -> Option<Self> {
+        let mut addr = region_start_addr;
+        loop {
+            let gicr = Self::new(addr);
+            let typer = gicr.rd_based_registers.TYPER.extract();
+            if typer.read(TYPER::Affinity) == affinity {
+                return Some(gicr);
+            }
+            if typer.is_set(TYPER::Last) {
+                return None;
+            }
+            addr += GICR_STRIDE;
+        }
+    }
+
     #[inline]
     pub fn enable(&self, irq_num: usize) {
         self.sgi_based_registers.ISENABLER0.set(1 << irq_num);
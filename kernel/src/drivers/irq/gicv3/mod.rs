@@ -5,6 +5,7 @@ use crate::{
 };
 use aarch64::registers::*;
 use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::arch::asm;
 
 mod gicd;
 mod gicr;
@@ -14,12 +15,15 @@ pub struct GicV3 {
     /// The Distributor.
     gicd: gicd::GicD,
 
-    gicr: gicr::GicR,
+    /// Start of the Redistributors, one for each PE.
+    gicr_region_start_addr: usize,
 
     irq_map: MutexNoIrq<BTreeMap<usize, Vec<Arc<dyn Driver>>>>,
 }
 
 impl GicV3 {
+    pub const COMPATIBLE: &'static str = "arm,gic-v3";
+
     /// Create an instance.
     ///
     /// # Safety
@@ -28,11 +32,19 @@ impl GicV3 {
     pub unsafe fn new(gicd_mmio_start_addr: usize, gicr_mmio_start_addr: usize) -> Self {
         Self {
             gicd: gicd::GicD::new(gicd_mmio_start_addr),
-            gicr: gicr::GicR::new(gicr_mmio_start_addr),
+            gicr_region_start_addr: gicr_mmio_start_addr,
             irq_map: MutexNoIrq::new(BTreeMap::new()),
         }
     }
 
+    /// The Redistributor of the current PE.
+    fn gicr(&self) -> gicr::GicR {
+        // Aff0 is the cpu id, the upper affinity levels are all 0
+        let affinity = crate::cpu::id() as u64;
+        unsafe { gicr::GicR::find(self.gicr_region_start_addr, affinity) }
+            .expect("arm_gicv3: no redistributor for the current cpu")
+    }
+
     fn gicc_init(&self) {
         // enable system register interface
         let sre = ICC_SRE_EL1.get_sre();
@@ -53,7 +65,7 @@ impl GicV3 {
 
 impl Driver for GicV3 {
     fn compatible(&self) -> &'static str {
-        "GICv3 (ARM Generic Interrupt Controller v3)"
+        Self::COMPATIBLE
     }
 
     fn init(&self) -> drivers::Result<()> {
@@ -61,7 +73,7 @@ impl Driver for GicV3 {
             self.gicd.boot_core_init();
         }
 
-        self.gicr.init();
+        self.gicr().init();
         self.gicc_init();
 
         Ok(())
@@ -86,18 +98,31 @@ impl IrqManager for GicV3 {
         map.entry(irq_num).or_insert_with(Vec::new).push(driver);
 
         match irq_num {
-            0..=31 => self.gicr.enable(irq_num),
+            0..=31 => self.gicr().enable(irq_num),
             _ => self.gicd.enable(irq_num),
         }
 
         Ok(())
     }
 
+    fn send_sgi(&self, cpu_id: usize, sgi: usize) -> drivers::Result<()> {
+        if sgi >= 16 || cpu_id >= 16 {
+            return Err(drivers::DriverError::Unsupported("SGI or CPU out of range"));
+        }
+        // ICC_SGI1R_EL1: INTID in bits 27:24, Aff3.Aff2.Aff1 are 0 and the target list
+        // selects Aff0.
+        let value = (sgi as u64) << 24 | 1 << cpu_id;
+        unsafe {
+            asm!("msr S3_0_C12_C11_5, {}", in(reg) value, options(nostack));
+            crate::cpu::isb();
+        }
+        Ok(())
+    }
+
     fn handle_pending_irqs(&self) {
         // Extract the highest priority pending IRQ number from the Interrupt Acknowledge Register
         // (IAR).
         let irq_num = ICC_IAR1_EL1.get_pending_interrupt() as usize;
-        dbg!(irq_num);
 
         if irq_num == 1023 {
             return;
@@ -115,3 +140,33 @@ impl IrqManager for GicV3 {
         ICC_EOIR1_EL1.mark_completed(irq_num as u32);
     }
 }
+
+pub fn driver_init(device_tree: drivers::DeviceTree) -> drivers::Result<GicV3> {
+    use crate::memory::as_upper_range;
+    use drivers::DriverError;
+    use fdt_rs::prelude::PropReader;
+
+    let gic_node = device_tree
+        .find_node_with_prop(|prop| {
+            Ok(prop.name()?.eq("compatible") && prop.str()?.eq(GicV3::COMPATIBLE))
+        })
+        .ok_or(DriverError::NotFound("no GICv3"))?;
+    let mut reg_range_iter = device_tree
+        .node_reg_range_iter(&gic_node)
+        .ok_or(DriverError::BadDeviceTree("no reg"))?;
+    let mut next_reg = || {
+        reg_range_iter
+            .next()
+            .ok_or(DriverError::BadDeviceTree("missing reg range"))
+    };
+
+    let gicd_mmio_start_addr = as_upper_range(next_reg()?.start);
+    let gicr_mmio_start_addr = as_upper_range(next_reg()?.start);
+
+    let gic = unsafe { GicV3::new(gicd_mmio_start_addr, gicr_mmio_start_addr) };
+    gic.init()?;
+
+    info!("Initialized GICv3 interrupt controller.");
+
+    Ok(gic)
+}
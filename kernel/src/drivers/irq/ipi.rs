@@ -0,0 +1,52 @@
+//! Inter-processor interrupts.
+//!
+//! Every IPI is delivered through the same SGI, the reasons are queued per core
+//! and drained by the receiver's handler.
+
+use crate::{consts::MAX_CPU_NUM, sync::PerCpu};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// SGI carrying all inter-processor interrupts.
+pub const IPI_SGI: usize = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum IpiReason {
+    /// New tasks were queued, pick the next task to run.
+    Reschedule = 0,
+    /// Another CPU panicked, stop here.
+    Halt = 1,
+}
+
+impl IpiReason {
+    const ALL: [IpiReason; 2] = [IpiReason::Reschedule, IpiReason::Halt];
+
+    #[inline]
+    const fn bit(self) -> usize {
+        1 << self as usize
+    }
+}
+
+/// Pending reasons of each core, one bit per reason. A reason queued again
+/// before the receiver drained it is only handled once.
+static PENDING: PerCpu<AtomicUsize> = {
+    const EMPTY: AtomicUsize = AtomicUsize::new(0);
+    PerCpu::new([EMPTY; MAX_CPU_NUM])
+};
+
+/// Queue `reason` for core `cpu_id`, to be followed by sending `IPI_SGI` to it.
+#[inline]
+pub fn push(cpu_id: usize, reason: IpiReason) {
+    PENDING
+        .get(cpu_id)
+        .fetch_or(reason.bit(), Ordering::Release);
+}
+
+/// Take all the reasons queued for core `cpu_id`.
+pub fn drain(cpu_id: usize) -> impl Iterator<Item = IpiReason> {
+    let pending = PENDING.get(cpu_id).swap(0, Ordering::Acquire);
+    IpiReason::ALL
+        .iter()
+        .copied()
+        .filter(move |reason| pending & reason.bit() != 0)
+}
@@ -1,11 +1,14 @@
 use alloc::sync::Arc;
 
-use super::{Driver, Result};
+use super::{DeviceTree, Driver, DriverError, Result};
 
 pub mod gicv2;
-// mod gicv3;
+pub mod gicv3;
+pub mod ipi;
 
 pub use gicv2::GicV2;
+pub use gicv3::GicV3;
+pub use ipi::IpiReason;
 
 /// IRQ management functions.
 ///
@@ -15,5 +18,27 @@ pub trait IrqManager: Driver {
     /// Register and enable interrupt controller local irq
     fn register_and_enable_local_irq(&self, irq_num: usize, driver: Arc<dyn Driver>) -> Result<()>;
 
+    /// Send software generated interrupt `sgi` (0-15) to core `cpu_id`.
+    fn send_sgi(&self, cpu_id: usize, sgi: usize) -> Result<()>;
+
+    /// Queue `reason` for core `cpu_id` and interrupt it. `cpu_id` may be the current
+    /// core, the IPI is then taken once IRQs are enabled again.
+    fn send_ipi(&self, cpu_id: usize, reason: IpiReason) -> Result<()> {
+        ipi::push(cpu_id, reason);
+        self.send_sgi(cpu_id, ipi::IPI_SGI)
+    }
+
     fn handle_pending_irqs(&self);
 }
+
+/// Initialize the interrupt controller found in the device tree, GICv3 if
+/// present, GICv2 otherwise.
+pub fn driver_init(device_tree: DeviceTree) -> Result<Arc<dyn IrqManager>> {
+    match gicv3::driver_init(device_tree) {
+        Ok(gic) => return Ok(Arc::new(gic)),
+        Err(DriverError::NotFound(_)) => {}
+        Err(err) => return Err(err),
+    }
+    let gic = gicv2::driver_init(device_tree)?;
+    Ok(Arc::new(gic))
+}
@@ -1,5 +1,5 @@
-use spin::Once;
-use alloc::sync::Arc;
+use spin::{Once, RwLock};
+use alloc::{sync::Arc, vec::Vec};
 
 pub mod block;
 pub mod bus;
@@ -9,26 +9,129 @@ pub mod gpio;
 pub mod irq;
 pub mod rtc;
 pub mod serial;
+pub mod watchdog;
 
 use core::fmt::Display;
 
-pub use device_tree::DeviceTree;
+pub use block::BlockDevice;
+pub use device_tree::{DevTreeNode, DeviceTree};
+pub use gpio::GpioDriver;
 pub use irq::IrqManager;
 pub use rtc::RtcDriver;
 pub use serial::SerialDriver;
+pub use watchdog::WatchdogDriver;
 
 
 pub type Result<T> = core::result::Result<T, DriverError>;
 
 pub static RTC_DRIVER: Once<Arc<dyn RtcDriver>> = Once::new();
 
+pub static WATCHDOG_DRIVER: Once<Arc<dyn WatchdogDriver>> = Once::new();
+
+/// Registered serial consoles, in probe order.
+pub static SERIAL_DRIVERS: RwLock<Vec<Arc<dyn SerialDriver>>> = RwLock::new(Vec::new());
+
+/// The first console registered, which receives kernel output. Set once, so that
+/// `print!` finds it without a lock, even in an interrupt handler.
+static CONSOLE: Once<Arc<dyn SerialDriver>> = Once::new();
+
+pub fn register_console(serial: Arc<dyn SerialDriver>) {
+    CONSOLE.call_once(|| serial.clone());
+    SERIAL_DRIVERS.write().push(serial);
+}
+
+/// Registered block devices, in probe order.
+pub static BLOCK_DRIVERS: RwLock<Vec<Arc<dyn BlockDevice>>> = RwLock::new(Vec::new());
+
+pub fn register_block_device(device: Arc<dyn BlockDevice>) {
+    BLOCK_DRIVERS.write().push(device);
+}
+
+/// Registered GPIO banks, in probe order.
+pub static GPIO_DRIVERS: RwLock<Vec<Arc<dyn GpioDriver>>> = RwLock::new(Vec::new());
+
+pub fn register_gpio_bank(bank: Arc<dyn GpioDriver>) {
+    GPIO_DRIVERS.write().push(bank);
+}
+
+/// The console used by `print!`, if any has been registered.
+#[inline]
+pub fn console() -> Option<&'static dyn SerialDriver> {
+    CONSOLE.get().map(|console| &**console)
+}
+
 #[inline]
 pub fn read_epoch() -> crate::TimeSpec {
     RTC_DRIVER.get().map(|rtc| rtc.read_epoch()).unwrap_or(crate::TimeSpec::zero())
 }
 
-#[derive(Debug)]
-pub struct DriverError {}
+/// Why a driver failed, with a message telling what failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverError {
+    /// No such device, pin or other resource.
+    NotFound(&'static str),
+    /// The device did not come up.
+    ProbeFailed(&'static str),
+    /// A device tree node lacks a prop or has a malformed one.
+    BadDeviceTree(&'static str),
+    /// The device or the request is not supported.
+    Unsupported(&'static str),
+    /// A transfer with the device failed.
+    Io(&'static str),
+}
+
+impl Display for DriverError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let (kind, message) = match self {
+            DriverError::NotFound(message) => ("not found", message),
+            DriverError::ProbeFailed(message) => ("probe failed", message),
+            DriverError::BadDeviceTree(message) => ("bad device tree", message),
+            DriverError::Unsupported(message) => ("unsupported", message),
+            DriverError::Io(message) => ("I/O error", message),
+        };
+        write!(f, "{}: {}", kind, message)
+    }
+}
+
+/// Initialize the device described by a device tree node, and register its irq.
+pub type ProbeFn =
+    for<'dt> fn(DeviceTree<'dt>, &DevTreeNode<'_, 'dt>, &dyn IrqManager) -> Result<()>;
+
+/// A driver found by the `compatible` string of device tree nodes.
+pub struct DriverProbe {
+    /// Matched against the first string of the `compatible` prop
+    pub compatible: &'static str,
+    pub probe: ProbeFn,
+}
+
+/// Walk the device tree once, probing every node `drivers` has a driver for.
+pub fn probe_devices(device_tree: DeviceTree, irq_manager: &dyn IrqManager, drivers: &[DriverProbe]) {
+    use fdt_rs::prelude::{FallibleIterator, PropReader};
+
+    let mut nodes = device_tree.nodes();
+    while let Ok(Some(node)) = nodes.next() {
+        let compatible = match node
+            .props()
+            .find(|prop| Ok(prop.name()?.eq("compatible")))
+        {
+            Ok(Some(prop)) => match prop.str() {
+                Ok(compatible) => compatible,
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        for driver in drivers.iter().filter(|driver| driver.compatible == compatible) {
+            if let Err(err) = (driver.probe)(device_tree, &node, irq_manager) {
+                warn!(
+                    "failed to probe device {} ({}): {}",
+                    node.name().unwrap_or("?"),
+                    compatible,
+                    err
+                );
+            }
+        }
+    }
+}
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum DeviceType {
@@ -41,6 +144,8 @@ pub enum DeviceType {
     /// Interrupt controller
     Intc,
     Timer,
+    Gpio,
+    Watchdog,
 }
 
 impl DeviceType {
@@ -55,6 +160,8 @@ impl DeviceType {
             DeviceType::Serial => "Serial",
             DeviceType::Intc => "Interrupt Controller",
             DeviceType::Timer => "Timer",
+            DeviceType::Gpio => "GPIO",
+            DeviceType::Watchdog => "Watchdog",
         }
     }
 }
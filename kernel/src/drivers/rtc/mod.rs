@@ -1,4 +1,5 @@
 use super::Driver;
+use core::task::Waker;
 
 pub mod pl031;
 
@@ -7,4 +8,10 @@ pub use pl031::Pl031Rtc;
 pub trait RtcDriver: Driver {
     /// Read seconds since 1970-01-01
     fn read_epoch(&self) -> crate::TimeSpec;
+
+    /// Set seconds since 1970-01-01
+    fn set_epoch(&self, secs: u64);
+
+    /// Wake `waker` up on the next alarm interrupt.
+    fn register_alarm_waker(&self, waker: Waker);
 }
@@ -1,8 +1,10 @@
 use crate::{
     drivers::{self, common::MMIODerefWrapper, Driver},
+    sync::MutexNoIrq,
     TimeSpec,
 };
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
+use core::task::Waker;
 use tock_registers::{
     interfaces::*,
     register_bitfields, register_structs,
@@ -68,6 +70,8 @@ type Registers = MMIODerefWrapper<RegisterBlock>;
 
 pub struct Pl031Rtc {
     registers: Registers,
+    /// Woken up on every alarm interrupt.
+    alarm_waiters: MutexNoIrq<Vec<Waker>>,
 }
 
 impl Pl031Rtc {
@@ -81,12 +85,14 @@ impl Pl031Rtc {
     pub const unsafe fn new(mmio_start_addr: usize) -> Self {
         Self {
             registers: Registers::new(mmio_start_addr),
+            alarm_waiters: MutexNoIrq::new(Vec::new()),
         }
     }
 
     pub fn set_next(&self) {
         let x = self.read_epoch().secs as u32;
         self.registers.MR.set(x + 2);
+        self.registers.IMSC.write(IMSC::RTCIMSC::SET);
     }
 }
 
@@ -113,7 +119,16 @@ impl Driver for Pl031Rtc {
     }
 
     fn handle_interrupt(&self) {
-        todo!()
+        if !self.registers.MIS.is_set(MIS::RTCMIS) {
+            return;
+        }
+        self.registers.ICR.write(ICR::RTCICR::SET);
+        self.set_next();
+
+        let waiters = core::mem::take(&mut *self.alarm_waiters.lock());
+        for waker in waiters {
+            waker.wake();
+        }
     }
 }
 
@@ -121,30 +136,40 @@ impl RtcDriver for Pl031Rtc {
     fn read_epoch(&self) -> TimeSpec {
         TimeSpec::new(self.registers.DR.get() as i64, 0)
     }
+
+    fn set_epoch(&self, secs: u64) {
+        // The new value shows up in `DR` on the next RTC clock edge.
+        self.registers.LR.set(secs as u32);
+    }
+
+    fn register_alarm_waker(&self, waker: Waker) {
+        self.alarm_waiters.lock().push(waker);
+    }
 }
 
-pub fn driver_init(
-    device_tree: drivers::DeviceTree,
-    irq_manager: &impl drivers::IrqManager,
-) -> Option<Arc<Pl031Rtc>> {
+pub fn driver_probe<'dt>(
+    device_tree: drivers::DeviceTree<'dt>,
+    node: &drivers::DevTreeNode<'_, 'dt>,
+    irq_manager: &dyn drivers::IrqManager,
+) -> drivers::Result<()> {
     use crate::memory::as_upper_range;
-    use fdt_rs::prelude::PropReader;
-
-    let rtc_node = device_tree.find_node_with_prop(|prop| {
-        Ok(prop.name()?.eq("compatible") && prop.str()?.eq(Pl031Rtc::COMPATIBLE))
-    })?;
 
-    let vaddr = as_upper_range(device_tree.node_reg_range_iter(&rtc_node)?.next()?.start);
-    let irq_num = device_tree.node_interrupt_cell(&rtc_node)?.irq_number();
+    let reg = device_tree
+        .node_reg_range_iter(node)
+        .and_then(|mut reg| reg.next())
+        .ok_or(drivers::DriverError::BadDeviceTree("no reg"))?;
+    let vaddr = as_upper_range(reg.start);
+    let irq_num = device_tree
+        .node_interrupt_cell(node)
+        .ok_or(drivers::DriverError::BadDeviceTree("no interrupts"))?
+        .irq_number();
 
     let rtc = unsafe { Arc::new(Pl031Rtc::new(vaddr)) };
-    rtc.init().unwrap();
+    rtc.init()?;
 
-    irq_manager
-        .register_and_enable_local_irq(irq_num, rtc.clone())
-        .unwrap();
+    irq_manager.register_and_enable_local_irq(irq_num, rtc.clone())?;
 
-    crate::drivers::RTC_DRIVER.call_once(|| rtc.clone());
+    crate::drivers::RTC_DRIVER.call_once(|| rtc);
 
-    Some(rtc)
+    Ok(())
 }
@@ -3,7 +3,7 @@ use crate::{
     drivers::{self, common::MMIODerefWrapper, Driver},
     sync::spin::MutexNoIrq,
 };
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 use core::fmt;
 use tock_registers::{
     interfaces::*,
@@ -17,6 +17,28 @@ use tock_registers::{
 register_bitfields! {
     u32,
 
+    /// Data Register.
+    DR [
+        /// Overrun error. This bit is set to 1 if data is received and the receive FIFO is already
+        /// full. The FIFO contents remain valid because no more data is written when the FIFO is
+        /// full, only the contents of the shift register are overwritten.
+        OE OFFSET(11) NUMBITS(1) [],
+
+        /// Break error. This bit is set to 1 if a break condition was detected.
+        BE OFFSET(10) NUMBITS(1) [],
+
+        /// Parity error. When set to 1, it indicates that the parity of the received data
+        /// character does not match the parity that the EPS and SPS bits in LCR_H select.
+        PE OFFSET(9) NUMBITS(1) [],
+
+        /// Framing error. When set to 1, it indicates that the received character did not have a
+        /// valid stop bit.
+        FE OFFSET(8) NUMBITS(1) [],
+
+        /// Data character.
+        DATA OFFSET(0) NUMBITS(8) []
+    ],
+
     /// Flag Register.
     FR [
         /// Transmit FIFO empty. The meaning of this bit depends on the state of the FEN bit in the
@@ -141,6 +163,16 @@ register_bitfields! {
 
     /// Interrupt Mask Set/Clear Register.
     IMSC [
+        /// Overrun error interrupt mask. A read returns the current mask for the UARTOEINTR
+        /// interrupt.
+        ///
+        /// - On a write of 1, the mask of the UARTOEINTR interrupt is set.
+        /// - A write of 0 clears the mask.
+        OEIM OFFSET(10) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
         /// Receive timeout interrupt mask. A read returns the current mask for the UARTRTINTR
         /// interrupt.
         ///
@@ -163,6 +195,10 @@ register_bitfields! {
 
     /// Masked Interrupt Status Register.
     MIS [
+        /// Overrun error masked interrupt status. Returns the masked interrupt state of the
+        /// UARTOEINTR interrupt.
+        OEMIS OFFSET(10) NUMBITS(1) [],
+
         /// Receive timeout masked interrupt status. Returns the masked interrupt state of the
         /// UARTRTINTR interrupt.
         RTMIS OFFSET(6) NUMBITS(1) [],
@@ -182,8 +218,9 @@ register_bitfields! {
 register_structs! {
     #[allow(non_snake_case)]
     pub RegisterBlock {
-        (0x00 => DR: ReadWrite<u32>),
-        (0x04 => _reserved1),
+        (0x00 => DR: ReadWrite<u32, DR::Register>),
+        (0x04 => RSR_ECR: ReadWrite<u32>),
+        (0x08 => _reserved1),
         (0x18 => FR: ReadOnly<u32, FR::Register>),
         (0x1c => _reserved2),
         (0x24 => IBRD: WriteOnly<u32, IBRD::Register>),
@@ -288,10 +325,10 @@ impl Pl011UartInner {
         // Set RX FIFO fill level at 1/8.
         self.registers.IFLS.write(IFLS::RXIFLSEL::OneEigth);
 
-        // Enable RX IRQ + RX timeout IRQ.
+        // Enable RX IRQ + RX timeout IRQ + overrun IRQ.
         self.registers
             .IMSC
-            .write(IMSC::RXIM::Enabled + IMSC::RTIM::Enabled);
+            .write(IMSC::RXIM::Enabled + IMSC::RTIM::Enabled + IMSC::OEIM::Enabled);
 
         // Turn the UART on.
         self.registers
@@ -335,8 +372,14 @@ impl Pl011UartInner {
             }
         }
 
-        // Read one character.
-        let mut ret = self.registers.DR.get() as u8 as char;
+        // Read one character, dropping it if it was received with an error.
+        // An overrun does not invalidate the character itself, it is reported by the interrupt.
+        let data = self.registers.DR.extract();
+        if data.matches_any(DR::BE::SET + DR::PE::SET + DR::FE::SET) {
+            self.registers.RSR_ECR.set(0);
+            return None;
+        }
+        let mut ret = data.read(DR::DATA) as u8 as char;
 
         // Convert carrige return to newline.
         if ret == '\r' {
@@ -405,13 +448,31 @@ impl Driver for Pl011Uart {
         // Clear all pending IRQs.
         inner.registers.ICR.write(ICR::ALL::CLEAR);
 
+        let overrun = pending.matches_any(MIS::OEMIS::SET);
+        if overrun {
+            inner.registers.RSR_ECR.set(0);
+        }
+
         // Check for any kind of RX interrupt.
+        let mut received = Vec::new();
         if pending.matches_any(MIS::RXMIS::SET + MIS::RTMIS::SET) {
-            // Echo any received characters.
-            while let Some(c) = inner.read_char_converting(BlockingMode::NonBlocking) {
-                inner.write_char(c)
+            // Drain the RX FIFO until it is indicating empty.
+            while !inner.registers.FR.matches_all(FR::RXFE::SET) {
+                if let Some(c) = inner.read_char_converting(BlockingMode::NonBlocking) {
+                    received.push(c as u8);
+                }
             }
         }
+        // Printing goes through this UART, so the lock must be released first.
+        drop(inner);
+
+        if overrun {
+            warn!("PL011 UART: receive FIFO overrun");
+        }
+        // Hand the input over to the console tty without holding the UART lock.
+        for c in received {
+            crate::fs::TTY.push(c);
+        }
     }
 
     fn device_type(&self) -> drivers::DeviceType {
@@ -429,43 +490,50 @@ impl SerialDriver for Pl011Uart {
     }
 
     fn read_char(&self) -> char {
-        self.inner
-            .lock()
-            .read_char_converting(BlockingMode::Blocking)
-            .unwrap()
+        // Skip characters received with an error.
+        loop {
+            if let Some(c) = self
+                .inner
+                .lock()
+                .read_char_converting(BlockingMode::Blocking)
+            {
+                return c;
+            }
+        }
     }
 
     fn clear_rx(&self) {
         // Read from the RX FIFO until it is indicating empty.
-        while self
-            .inner
-            .lock()
-            .read_char_converting(BlockingMode::NonBlocking)
-            .is_some()
-        {}
+        let inner = self.inner.lock();
+        while !inner.registers.FR.matches_all(FR::RXFE::SET) {
+            inner.registers.DR.get();
+        }
+        inner.registers.RSR_ECR.set(0);
     }
 }
 
-pub fn driver_init(
-    device_tree: drivers::DeviceTree,
-    irq_manager: &impl drivers::IrqManager,
-) -> Option<Arc<Pl011Uart>> {
+pub fn driver_probe<'dt>(
+    device_tree: drivers::DeviceTree<'dt>,
+    node: &drivers::DevTreeNode<'_, 'dt>,
+    irq_manager: &dyn drivers::IrqManager,
+) -> drivers::Result<()> {
     use crate::memory::as_upper_range;
-    use fdt_rs::prelude::PropReader;
-
-    let uart_node = device_tree.find_node_with_prop(|prop| {
-        Ok(prop.name()?.eq("compatible") && prop.str()?.eq(Pl011Uart::COMPATIBLE))
-    })?;
 
-    let vaddr = as_upper_range(device_tree.node_reg_range_iter(&uart_node)?.next()?.start);
-    crate::arch::bsp::uart::set_new_uart(vaddr);
-    let irq_num = device_tree.node_interrupt_cell(&uart_node)?.irq_number();
+    let reg = device_tree
+        .node_reg_range_iter(node)
+        .and_then(|mut reg| reg.next())
+        .ok_or(drivers::DriverError::BadDeviceTree("no reg"))?;
+    let vaddr = as_upper_range(reg.start);
+    let irq_num = device_tree
+        .node_interrupt_cell(node)
+        .ok_or(drivers::DriverError::BadDeviceTree("no interrupts"))?
+        .irq_number();
 
     let uart = unsafe { Arc::new(Pl011Uart::new(vaddr)) };
-    uart.init().unwrap();
-    irq_manager
-        .register_and_enable_local_irq(irq_num, uart.clone())
-        .unwrap();
+    uart.init()?;
+    irq_manager.register_and_enable_local_irq(irq_num, uart.clone())?;
+
+    crate::drivers::register_console(uart);
 
-    Some(uart)
+    Ok(())
 }
@@ -0,0 +1,57 @@
+//! Watchdogs, resetting the board unless pet in time.
+//!
+//! `start` pets the watchdog from a kernel task at `MIN_NICE` on the CPU which
+//! probed it. A hang stopping that CPU from running tasks, e.g. a deadlock on a
+//! `MutexNoIrq` or any lock held with IRQs disabled for longer than the timeout,
+//! resets the board: a lock must not be held that long even when it is eventually
+//! released. A hang confined to the other CPUs goes unnoticed.
+
+use super::{Driver, Result};
+use crate::task::{delay_for, executor::MIN_NICE, local_executor};
+use alloc::sync::Arc;
+use core::time::Duration;
+
+pub mod sp805;
+
+pub use sp805::Sp805Watchdog;
+
+/// Time without petting after which the board is reset, unless the watchdog
+/// supports less.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The watchdog is pet this many times per timeout, a late pet does not reset.
+const PETS_PER_TIMEOUT: u32 = 3;
+
+pub trait WatchdogDriver: Driver {
+    /// Longest timeout supported.
+    fn max_timeout(&self) -> Duration;
+
+    /// Reset the board unless pet within `timeout`, from now on.
+    /// `timeout` must be nonzero and at most `max_timeout`.
+    fn enable(&self, timeout: Duration) -> Result<()>;
+
+    /// Start the timeout again.
+    fn pet(&self);
+
+    fn disable(&self);
+}
+
+/// Enable `watchdog` and spawn the task petting it.
+pub fn start(watchdog: Arc<dyn WatchdogDriver>) -> Result<()> {
+    let timeout = WATCHDOG_TIMEOUT.min(watchdog.max_timeout());
+    watchdog.enable(timeout)?;
+    let interval = timeout / PETS_PER_TIMEOUT;
+    info!("watchdog enabled, timeout {:?}", timeout);
+
+    let pet = async move {
+        loop {
+            delay_for(interval).await;
+            watchdog.pet();
+        }
+    };
+    local_executor()
+        .spawn(pet, MIN_NICE, Default::default())
+        .0
+        .detach();
+    Ok(())
+}
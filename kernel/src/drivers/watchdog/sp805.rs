@@ -0,0 +1,162 @@
+use super::WatchdogDriver;
+use crate::{
+    drivers::{self, common::MMIODerefWrapper, Driver},
+    sync::MutexNoIrq,
+};
+use alloc::sync::Arc;
+use core::time::Duration;
+use tock_registers::{
+    interfaces::*,
+    register_bitfields, register_structs,
+    registers::{ReadOnly, ReadWrite, WriteOnly},
+};
+
+register_bitfields! {
+    u32,
+
+    /// Control Register.
+    CONTROL [
+        /// Enable the counter and the interrupt.
+        INTEN OFFSET(0) NUMBITS(1) [],
+        /// Reset the board on the second timeout without the interrupt cleared.
+        RESEN OFFSET(1) NUMBITS(1) []
+    ]
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub RegisterBlock {
+        (0x000 => LOAD: ReadWrite<u32>),
+        (0x004 => VALUE: ReadOnly<u32>),
+        (0x008 => CONTROL: ReadWrite<u32, CONTROL::Register>),
+        (0x00c => INTCLR: WriteOnly<u32>),
+        (0x010 => RIS: ReadOnly<u32>),
+        (0x014 => MIS: ReadOnly<u32>),
+        (0x018 => _reserved),
+        (0xc00 => LOCK: ReadWrite<u32>),
+        (0xc04 => @END),
+    }
+}
+
+/// Abstraction for the associated MMIO registers.
+type Registers = MMIODerefWrapper<RegisterBlock>;
+
+/// Written to `LOCK` to allow writes to the other registers.
+const UNLOCK_KEY: u32 = 0x1acc_e551;
+
+/// ARM SP805 watchdog.
+///
+/// The counter counts `LOAD` down twice: the first time it reaches zero it raises
+/// its interrupt and starts again, the second time it resets the board if the
+/// interrupt is still pending. `LOAD` is thus half of the timeout. The interrupt
+/// is not registered: clearing it would pet the watchdog.
+pub struct Sp805Watchdog {
+    registers: Registers,
+    /// Frequency of the counter in Hz.
+    rate: u64,
+    /// Held while the registers are unlocked.
+    unlocked: MutexNoIrq<()>,
+}
+
+impl Sp805Watchdog {
+    pub const COMPATIBLE: &'static str = "arm,sp805";
+
+    /// Create an instance counting at `rate` Hz.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide a correct MMIO start address.
+    pub const unsafe fn new(mmio_start_addr: usize, rate: u64) -> Self {
+        Self {
+            registers: Registers::new(mmio_start_addr),
+            rate,
+            unlocked: MutexNoIrq::new(()),
+        }
+    }
+
+    /// Run `f` with the registers unlocked.
+    fn unlocked(&self, f: impl FnOnce(&RegisterBlock)) {
+        let _unlocked = self.unlocked.lock();
+        self.registers.LOCK.set(UNLOCK_KEY);
+        f(&self.registers);
+        self.registers.LOCK.set(0);
+    }
+}
+
+impl Driver for Sp805Watchdog {
+    fn compatible(&self) -> &'static str {
+        Self::COMPATIBLE
+    }
+
+    fn init(&self) -> drivers::Result<()> {
+        // left running by the firmware, maybe
+        self.disable();
+        Ok(())
+    }
+
+    fn device_type(&self) -> drivers::DeviceType {
+        drivers::DeviceType::Watchdog
+    }
+}
+
+impl WatchdogDriver for Sp805Watchdog {
+    fn max_timeout(&self) -> Duration {
+        let max_ns = 2 * (u32::MAX as u128 + 1) * 1_000_000_000 / self.rate as u128;
+        Duration::from_nanos(max_ns as u64)
+    }
+
+    fn enable(&self, timeout: Duration) -> drivers::Result<()> {
+        let ticks = timeout.as_nanos() * self.rate as u128 / 1_000_000_000 / 2;
+        if ticks == 0 || ticks > u32::MAX as u128 + 1 {
+            return Err(drivers::DriverError::Unsupported("timeout out of range"));
+        }
+        self.unlocked(|registers| {
+            registers.LOAD.set((ticks - 1) as u32);
+            // a pending interrupt left from before would reset at the first timeout
+            registers.INTCLR.set(1);
+            registers
+                .CONTROL
+                .write(CONTROL::INTEN::SET + CONTROL::RESEN::SET);
+        });
+        Ok(())
+    }
+
+    fn pet(&self) {
+        // clearing the interrupt reloads the counter
+        self.unlocked(|registers| registers.INTCLR.set(1));
+    }
+
+    fn disable(&self) {
+        self.unlocked(|registers| {
+            registers.CONTROL.set(0);
+            registers.INTCLR.set(1);
+        });
+    }
+}
+
+pub fn driver_probe<'dt>(
+    device_tree: drivers::DeviceTree<'dt>,
+    node: &drivers::DevTreeNode<'_, 'dt>,
+    _irq_manager: &dyn drivers::IrqManager,
+) -> drivers::Result<()> {
+    use crate::memory::as_upper_range;
+
+    let reg = device_tree
+        .node_reg_range_iter(node)
+        .and_then(|mut reg| reg.next())
+        .ok_or(drivers::DriverError::BadDeviceTree("no reg"))?;
+    let vaddr = as_upper_range(reg.start);
+    let rate = device_tree
+        .node_clock_frequency(node)
+        .filter(|&rate| rate != 0)
+        .ok_or(drivers::DriverError::BadDeviceTree("no clock frequency"))?;
+
+    let watchdog = unsafe { Arc::new(Sp805Watchdog::new(vaddr, rate as u64)) };
+    watchdog.init()?;
+
+    super::start(watchdog.clone())?;
+
+    crate::drivers::WATCHDOG_DRIVER.call_once(|| watchdog);
+
+    Ok(())
+}
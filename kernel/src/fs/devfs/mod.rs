@@ -1,3 +1,147 @@
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+};
+use core::any::Any;
+use queen_fs::vfs::*;
+use spin::RwLock;
+
+mod null;
+mod random;
 mod tty;
+mod zero;
+
+pub use null::{NullINode, NULL};
+pub use random::{RandomINode, RANDOM, URANDOM};
+pub use tty::*;
+pub use zero::{ZeroINode, ZERO};
+
+/// Device filesystem, a flat directory of device INodes.
+pub struct DevFs {
+    root: Arc<DevRootINode>,
+}
+
+impl DevFs {
+    pub fn new() -> Arc<Self> {
+        let root = Arc::new(DevRootINode {
+            devices: RwLock::new(BTreeMap::new()),
+            fs: RwLock::new(Weak::new()),
+        });
+        let fs = Arc::new(DevFs { root: root.clone() });
+        *root.fs.write() = Arc::downgrade(&fs);
+        root.add("tty", TTY.clone()).unwrap();
+        root.add("null", NULL.clone()).unwrap();
+        root.add("zero", ZERO.clone()).unwrap();
+        root.add("random", RANDOM.clone()).unwrap();
+        root.add("urandom", URANDOM.clone()).unwrap();
+        fs
+    }
+}
+
+impl FileSystem for DevFs {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.root.clone()
+    }
+
+    fn info(&self) -> FsInfo {
+        FsInfo {
+            bsize: 0,
+            frsize: 0,
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            namemax: 255,
+        }
+    }
+}
+
+pub struct DevRootINode {
+    devices: RwLock<BTreeMap<String, Arc<dyn INode>>>,
+    fs: RwLock<Weak<DevFs>>,
+}
+
+impl DevRootINode {
+    /// Register a device.
+    pub fn add(&self, name: &str, device: Arc<dyn INode>) -> Result<()> {
+        let mut devices = self.devices.write();
+        if devices.contains_key(name) {
+            return Err(FsError::EntryExist);
+        }
+        devices.insert(name.to_string(), device);
+        Ok(())
+    }
+}
+
+impl INode for DevRootINode {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Err(FsError::IsDir)
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 0,
+            inode: 1,
+            size: self.devices.read().len() + 2,
+            blk_size: 0,
+            blocks: 0,
+            atime: TimeSpec::zero(),
+            mtime: TimeSpec::zero(),
+            ctime: TimeSpec::zero(),
+            r#type: FileType::Dir,
+            mode: 0o755,
+            nlinks: 2,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        match name {
+            "." | ".." | "" => Ok(self.fs().root_inode()),
+            name => self
+                .devices
+                .read()
+                .get(name)
+                .cloned()
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            id => self
+                .devices
+                .read()
+                .keys()
+                .nth(id - 2)
+                .cloned()
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs.read().upgrade().unwrap()
+    }
 
-pub use tty::*;
\ No newline at end of file
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,56 @@
+use alloc::sync::Arc;
+use core::any::Any;
+use queen_fs::vfs::*;
+use spin::Lazy;
+
+/// `/dev/null`: reads hit EOF, writes are discarded.
+#[derive(Default)]
+pub struct NullINode;
+
+pub static NULL: Lazy<Arc<NullINode>> = Lazy::new(|| Arc::new(NullINode));
+
+impl INode for NullINode {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(char_device_metadata(3, make_rdev(1, 3)))
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Metadata shared by the memory character devices.
+pub(super) fn char_device_metadata(inode: usize, rdev: usize) -> Metadata {
+    Metadata {
+        dev: 1,
+        inode,
+        size: 0,
+        blk_size: 0,
+        blocks: 0,
+        atime: TimeSpec::zero(),
+        mtime: TimeSpec::zero(),
+        ctime: TimeSpec::zero(),
+        r#type: FileType::CharDevice,
+        mode: 0o666,
+        nlinks: 1,
+        uid: 0,
+        gid: 0,
+        rdev,
+    }
+}
@@ -0,0 +1,43 @@
+use super::null::char_device_metadata;
+use crate::utils::{add_entropy, fill_random};
+use alloc::sync::Arc;
+use core::any::Any;
+use queen_fs::vfs::*;
+use spin::Lazy;
+
+/// `/dev/random` and `/dev/urandom`, both backed by the kernel generator.
+pub struct RandomINode {
+    minor: usize,
+}
+
+pub static RANDOM: Lazy<Arc<RandomINode>> = Lazy::new(|| Arc::new(RandomINode { minor: 8 }));
+pub static URANDOM: Lazy<Arc<RandomINode>> = Lazy::new(|| Arc::new(RandomINode { minor: 9 }));
+
+impl INode for RandomINode {
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        fill_random(buf);
+        Ok(buf.len())
+    }
+
+    /// Writing mixes the data into the generator.
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        add_entropy(buf);
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(char_device_metadata(self.minor, make_rdev(1, self.minor)))
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
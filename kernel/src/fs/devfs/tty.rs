@@ -1,17 +1,123 @@
 use crate::{
-    process::{process_group, Pgid},
-    signal::{send_signal, Siginfo, Signal, SI_KERNEL},
-    sync::{Event, EventBus},
+    process::{process_group, thread::Thread, Pgid},
+    signal::{send_signal, Siginfo, Signal, SIG_IGN, SI_KERNEL},
+    sync::{Event, EventBus, MutexNoIrq, Subscription},
+    syscall::SysError,
 };
-use alloc::{boxed::Box, collections::VecDeque, sync::Arc};
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
 use core::{
     any::Any,
     future::Future,
     pin::Pin,
-    task::{Context, Poll},
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
 };
+use bitflags::bitflags;
 use queen_fs::vfs::*;
-use spin::{Lazy, Mutex, RwLock};
+use spin::{Lazy, RwLock};
+
+pub const TCGETS: u32 = 0x5401;
+pub const TCSETS: u32 = 0x5402;
+pub const TCSETSW: u32 = 0x5403;
+pub const TCSETSF: u32 = 0x5404;
+pub const TIOCGPGRP: u32 = 0x540F;
+pub const TIOCSPGRP: u32 = 0x5410;
+pub const TIOCGWINSZ: u32 = 0x5413;
+pub const FIONREAD: u32 = 0x541B;
+
+/// Number of control characters in the kernel `struct termios`.
+const NCCS: usize = 19;
+
+// c_cc indices
+const VINTR: usize = 0;
+const VQUIT: usize = 1;
+const VERASE: usize = 2;
+const VKILL: usize = 3;
+const VEOF: usize = 4;
+const VMIN: usize = 6;
+const VSUSP: usize = 10;
+
+bitflags! {
+    #[derive(Default)]
+    pub struct LocalFlags: u32 {
+        const ISIG      = 0o000001;
+        const ICANON    = 0o000002;
+        const ECHO      = 0o000010;
+        const ECHOE     = 0o000020;
+        const ECHOK     = 0o000040;
+        const ECHONL    = 0o000100;
+        const IEXTEN    = 0o100000;
+    }
+}
+
+/// Ref: [https://man7.org/linux/man-pages/man3/termios.3.html]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; NCCS],
+}
+
+impl Termios {
+    #[inline]
+    pub fn local_flags(&self) -> LocalFlags {
+        LocalFlags::from_bits_truncate(self.lflag)
+    }
+}
+
+impl Default for Termios {
+    fn default() -> Self {
+        let mut cc = [0; NCCS];
+        cc[VINTR] = 0o3;
+        cc[VQUIT] = 0o34;
+        cc[VERASE] = 0o177;
+        cc[VKILL] = 0o25;
+        cc[VEOF] = 0o4;
+        cc[VMIN] = 1;
+        cc[VSUSP] = 0o32;
+        Termios {
+            // ICRNL | IXON
+            iflag: 0o2400,
+            // OPOST | ONLCR
+            oflag: 0o5,
+            // B38400 | CS8 | CREAD
+            cflag: 0o277,
+            lflag: (LocalFlags::ISIG
+                | LocalFlags::ICANON
+                | LocalFlags::ECHO
+                | LocalFlags::ECHOE
+                | LocalFlags::ECHOK
+                | LocalFlags::IEXTEN)
+                .bits(),
+            line: 0,
+            cc,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct WinSize {
+    row: u16,
+    col: u16,
+    xpixel: u16,
+    ypixel: u16,
+}
+
+impl Default for WinSize {
+    fn default() -> Self {
+        WinSize {
+            row: 24,
+            col: 80,
+            xpixel: 0,
+            ypixel: 0,
+        }
+    }
+}
 
 /// console tty
 // Ref: [https://linux.die.net/man/4/tty]
@@ -19,8 +125,16 @@ use spin::{Lazy, Mutex, RwLock};
 pub struct TtyINode {
     /// foreground process group
     foreground_pgid: RwLock<Pgid>,
-    buf: Mutex<VecDeque<u8>>,
-    event_bus: Mutex<EventBus>,
+    termios: MutexNoIrq<Termios>,
+    /// Filled from the serial RX interrupt
+    buf: MutexNoIrq<VecDeque<u8>>,
+    /// The line being edited in canonical mode
+    line: MutexNoIrq<Vec<u8>>,
+    /// EOF was typed on an empty line, the next read returns 0
+    eof: AtomicBool,
+    event_bus: Arc<MutexNoIrq<EventBus>>,
+    /// Background readers waiting to be moved to the foreground
+    foreground_waiters: MutexNoIrq<Vec<Waker>>,
 }
 
 pub static TTY: Lazy<Arc<TtyINode>> = Lazy::new(|| Arc::new(TtyINode::default()));
@@ -30,12 +144,60 @@ pub fn foreground_pgid() -> Pgid {
 }
 
 impl TtyINode {
+    pub fn set_foreground_pgid(&self, pgid: Pgid) {
+        *self.foreground_pgid.write() = pgid;
+        for waker in self.foreground_waiters.lock().drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Called before a read by `thread`.
+    ///
+    /// A background process group gets SIGTTIN and blocks until it
+    /// is moved to the foreground, or a signal interrupts the read.
+    /// The read fails at once if the reader blocks or ignores SIGTTIN.
+    pub async fn wait_for_foreground(&self, thread: &Thread) -> core::result::Result<(), SysError> {
+        let (pgid, ignored, event_bus) = {
+            let process = thread.process.lock();
+            let ignored = process.dispositions[Signal::SIGTTIN as usize].handler == SIG_IGN;
+            (process.pgid, ignored, process.event_bus.clone())
+        };
+        if *self.foreground_pgid.read() == pgid {
+            return Ok(());
+        }
+        if ignored || thread.inner.lock().sig_mask.contains(Signal::SIGTTIN) {
+            return Err(SysError::EIO);
+        }
+        for proc in process_group(pgid) {
+            send_signal(
+                proc,
+                -1,
+                Siginfo {
+                    signo: Signal::SIGTTIN as i32,
+                    errno: 0,
+                    code: SI_KERNEL,
+                    field: Default::default(),
+                },
+            );
+        }
+        ForegroundFuture {
+            tty: self,
+            pgid,
+            thread,
+            event_bus,
+            subscription: None,
+        }
+        .await
+    }
+
     pub fn push(&self, c: u8) {
-        if [0o3, 0o34, 0o32, 0o31].contains(&(c as i32)) {
+        let termios = *self.termios.lock();
+        let lflag = termios.local_flags();
+        let cc = termios.cc;
+        if lflag.contains(LocalFlags::ISIG) && [cc[VINTR], cc[VQUIT], cc[VSUSP]].contains(&c) {
             let foreground_processes = process_group(foreground_pgid());
-            match c as i32 {
-                // INTR
-                0o3 => {
+            match c {
+                c if c == cc[VINTR] => {
                     for proc in foreground_processes {
                         send_signal(
                             proc,
@@ -51,12 +213,64 @@ impl TtyINode {
                 }
                 _ => warn!("special char {} is unimplented", c),
             }
+        } else if lflag.contains(LocalFlags::ICANON) {
+            self.push_canonical(c, &termios);
         } else {
+            if lflag.contains(LocalFlags::ECHO) {
+                echo(c);
+            }
             self.buf.lock().push_back(c);
             self.event_bus.lock().set(Event::READABLE);
         }
     }
 
+    /// Line editing: input is only readable once a whole line is typed.
+    fn push_canonical(&self, c: u8, termios: &Termios) {
+        let lflag = termios.local_flags();
+        let mut line = self.line.lock();
+        match c {
+            // erase, also accept ^H
+            c if c == termios.cc[VERASE] || c == 0o10 => {
+                if let Some(c) = line.pop() {
+                    if lflag.contains(LocalFlags::ECHOE) {
+                        erase_echo(c);
+                    }
+                }
+            }
+            c if c == termios.cc[VKILL] => {
+                if lflag.contains(LocalFlags::ECHOK) {
+                    line.iter().rev().for_each(|&c| erase_echo(c));
+                }
+                line.clear();
+            }
+            c if c == termios.cc[VEOF] => {
+                if line.is_empty() {
+                    self.eof.store(true, Ordering::Release);
+                }
+                self.flush_line(&mut line);
+            }
+            b'\n' => {
+                if lflag.intersects(LocalFlags::ECHO | LocalFlags::ECHONL) {
+                    echo(c);
+                }
+                line.push(c);
+                self.flush_line(&mut line);
+            }
+            _ => {
+                if lflag.contains(LocalFlags::ECHO) {
+                    echo(c);
+                }
+                line.push(c);
+            }
+        }
+    }
+
+    /// Move the edited line to the read buffer.
+    fn flush_line(&self, line: &mut Vec<u8>) {
+        self.buf.lock().extend(line.drain(..));
+        self.event_bus.lock().set(Event::READABLE);
+    }
+
     pub fn pop(&self) -> u8 {
         let mut buf_lock = self.buf.lock();
         let c = buf_lock.pop_front().unwrap();
@@ -67,19 +281,94 @@ impl TtyINode {
     }
 
     pub fn can_read(&self) -> bool {
-        return self.buf.lock().len() > 0;
+        return self.buf.lock().len() > 0 || self.eof.load(Ordering::Acquire);
+    }
+}
+
+#[must_use = "future does nothing unless polled/`await`-ed"]
+struct ForegroundFuture<'a> {
+    tty: &'a TtyINode,
+    pgid: Pgid,
+    thread: &'a Thread,
+    /// Of the process, where signals to the whole process are announced
+    event_bus: Arc<MutexNoIrq<EventBus>>,
+    subscription: Option<Subscription>,
+}
+
+impl Future for ForegroundFuture<'_> {
+    type Output = core::result::Result<(), SysError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.thread.has_signal_to_handle() {
+            return Poll::Ready(Err(SysError::EINTR));
+        }
+        let waker = cx.waker().clone();
+        let id = self
+            .event_bus
+            .lock()
+            .subscribe_once(Event::RECEIVE_SIGNAL, Box::new(move |_| waker.wake()));
+        // replaces the subscription of the previous poll
+        self.subscription = Some(Subscription::new(&self.event_bus, id));
+
+        let mut waiters = self.tty.foreground_waiters.lock();
+        if *self.tty.foreground_pgid.read() == self.pgid {
+            return Poll::Ready(Ok(()));
+        }
+        waiters.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+fn echo(c: u8) {
+    match c {
+        b'\n' => print!("\n"),
+        // control characters are echoed as `^X`
+        0..=0o37 => print!("^{}", (c + 0o100) as char),
+        _ => print!("{}", c as char),
+    }
+}
+
+/// Columns taken by the echo of `c`.
+fn echo_width(c: u8) -> usize {
+    match c {
+        0..=0o37 => 2,
+        _ => 1,
+    }
+}
+
+/// Erase the echo of `c`, both columns of a `^X`.
+fn erase_echo(c: u8) {
+    for _ in 0..echo_width(c) {
+        print!("\x08 \x08");
     }
 }
 
 impl INode for TtyINode {
     /// Read bytes at `offset` into `buf`, return the number of bytes read.
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
-        if self.can_read() {
-            buf[0] = self.pop() as u8;
-            Ok(1)
-        } else {
-            Err(FsError::Again)
+        if !self.can_read() {
+            return Err(FsError::Again);
+        }
+        if self.eof.swap(false, Ordering::AcqRel) && self.buf.lock().is_empty() {
+            self.event_bus.lock().clear(Event::READABLE);
+            return Ok(0);
         }
+        // in canonical mode, a read returns at most one line
+        let canonical = self
+            .termios
+            .lock()
+            .local_flags()
+            .contains(LocalFlags::ICANON);
+        let mut len = 0;
+        while len < buf.len() && self.buf.lock().len() > 0 {
+            let c = self.pop();
+            buf[len] = c;
+            len += 1;
+            if canonical && c == b'\n' {
+                break;
+            }
+        }
+        Ok(len)
     }
 
     /// Write bytes at `offset` from `buf`, return the number of bytes written.
@@ -91,6 +380,65 @@ impl INode for TtyINode {
         Ok(buf.len())
     }
 
+    /// `data` points to user memory checked by `sys_ioctl`.
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        match cmd {
+            TCGETS => {
+                let termios = data as *mut Termios;
+                unsafe { termios.write(*self.termios.lock()) };
+                Ok(0)
+            }
+            TCSETS | TCSETSW | TCSETSF => {
+                let termios = unsafe { (data as *const Termios).read() };
+                if cmd == TCSETSF {
+                    // discard the pending input, the line being edited too
+                    self.buf.lock().clear();
+                    self.line.lock().clear();
+                    self.eof.store(false, Ordering::Release);
+                    self.event_bus.lock().clear(Event::READABLE);
+                }
+                let was_canonical = self
+                    .termios
+                    .lock()
+                    .local_flags()
+                    .contains(LocalFlags::ICANON);
+                *self.termios.lock() = termios;
+                // pending input becomes readable right away when leaving canonical mode
+                if was_canonical && !termios.local_flags().contains(LocalFlags::ICANON) {
+                    let mut line = self.line.lock();
+                    if !line.is_empty() {
+                        self.flush_line(&mut line);
+                    }
+                }
+                Ok(0)
+            }
+            TIOCGPGRP => {
+                let pgid = data as *mut Pgid;
+                unsafe { pgid.write(foreground_pgid()) };
+                Ok(0)
+            }
+            TIOCSPGRP => {
+                let pgid = unsafe { (data as *const Pgid).read() };
+                if pgid < 0 {
+                    return Err(FsError::InvalidParam);
+                }
+                self.set_foreground_pgid(pgid);
+                Ok(0)
+            }
+            TIOCGWINSZ => {
+                let winsize = data as *mut WinSize;
+                unsafe { winsize.write(WinSize::default()) };
+                Ok(0)
+            }
+            FIONREAD => {
+                let len = data as *mut i32;
+                unsafe { len.write(self.buf.lock().len() as i32) };
+                Ok(0)
+            }
+            _ => Err(FsError::IOCTLError),
+        }
+    }
+
     /// Poll the events, return a bitmap of events.
     fn poll(&self) -> Result<PollStatus> {
         Ok(PollStatus {
@@ -106,27 +454,32 @@ impl INode for TtyINode {
         #[must_use = "future does nothing unless polled/`await`-ed"]
         struct SerialFuture<'a> {
             tty: &'a TtyINode,
+            subscription: Option<Subscription>,
         }
 
         impl<'a> Future for SerialFuture<'a> {
             type Output = Result<PollStatus>;
 
-            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
                 if self.tty.can_read() {
                     return Poll::Ready(self.tty.poll());
                 }
                 let waker = cx.waker().clone();
-                self.tty.event_bus.lock().subscribe(Box::new({
-                    move |_| {
-                        waker.wake_by_ref();
-                        true
-                    }
-                }));
+                let id = self
+                    .tty
+                    .event_bus
+                    .lock()
+                    .subscribe_once(Event::all(), Box::new(move |_| waker.wake()));
+                // replaces the subscription of the previous poll
+                self.subscription = Some(Subscription::new(&self.tty.event_bus, id));
                 Poll::Pending
             }
         }
 
-        Box::pin(SerialFuture { tty: self })
+        Box::pin(SerialFuture {
+            tty: self,
+            subscription: None,
+        })
     }
 
     /// Get metadata of the INode
@@ -153,3 +506,106 @@ impl INode for TtyINode {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        process::mock::MockProcess,
+        signal::SignalAction,
+        sync::mock::{poll_once, MockWaker},
+    };
+
+    #[test]
+    fn background_read() {
+        let tty = TtyINode::default();
+        let mock = MockProcess::new(0);
+        let (_, waker) = MockWaker::new();
+        let read = || poll_once(&mut Box::pin(tty.wait_for_foreground(&mock.thread)), &waker);
+        let sigttin = Signal::SIGTTIN as usize;
+
+        mock.process().dispositions[sigttin].handler = SIG_IGN;
+        assert!(matches!(read(), Poll::Ready(Err(SysError::EIO))));
+        mock.process().dispositions[sigttin] = SignalAction::default();
+        mock.thread.inner.lock().sig_mask.add(Signal::SIGTTIN);
+        assert!(matches!(read(), Poll::Ready(Err(SysError::EIO))));
+        mock.thread.inner.lock().sig_mask.remove(Signal::SIGTTIN);
+
+        // interrupted by its own SIGTTIN
+        assert!(matches!(read(), Poll::Ready(Err(SysError::EINTR))));
+        assert!(mock.process().pending_sigset.contains(Signal::SIGTTIN));
+
+        tty.set_foreground_pgid(mock.process().pgid);
+        assert!(matches!(read(), Poll::Ready(Ok(()))));
+    }
+
+    #[test]
+    fn wait_for_foreground() {
+        let tty = TtyINode::default();
+        let mock = MockProcess::new(0);
+        let (waker_mock, waker) = MockWaker::new();
+        let (pgid, event_bus) = {
+            let process = mock.process();
+            (process.pgid, process.event_bus.clone())
+        };
+        let future = || ForegroundFuture {
+            tty: &tty,
+            pgid,
+            thread: &mock.thread,
+            event_bus: event_bus.clone(),
+            subscription: None,
+        };
+
+        let mut waiting = future();
+        assert!(poll_once(&mut waiting, &waker).is_pending());
+        tty.set_foreground_pgid(pgid);
+        assert_eq!(waker_mock.wakes(), 1);
+        let result = poll_once(&mut waiting, &waker);
+        assert!(matches!(result, Poll::Ready(Ok(()))));
+
+        // a signal to the process wakes it up
+        tty.set_foreground_pgid(0);
+        waiting = future();
+        assert!(poll_once(&mut waiting, &waker).is_pending());
+        let info = Siginfo {
+            signo: Signal::SIGUSR1 as i32,
+            errno: 0,
+            code: SI_KERNEL,
+            field: Default::default(),
+        };
+        send_signal(mock.thread.process.clone(), -1, info);
+        assert_eq!(waker_mock.wakes(), 2);
+        let result = poll_once(&mut waiting, &waker);
+        assert!(matches!(result, Poll::Ready(Err(SysError::EINTR))));
+    }
+
+    #[test]
+    fn flush_discards_line() {
+        let tty = TtyINode::default();
+        // `print!` has no console in host tests
+        let mut termios = Termios::default();
+        termios.lflag = LocalFlags::ICANON.bits();
+        let data = &termios as *const Termios as usize;
+        tty.io_control(TCSETS, data).unwrap();
+        for &c in b"ab\ncd" {
+            tty.push(c);
+        }
+        assert!(tty.can_read());
+
+        tty.io_control(TCSETSF, data).unwrap();
+        assert!(!tty.can_read());
+        tty.push(b'e');
+        tty.push(b'\n');
+        let mut buf = [0; 8];
+        assert_eq!(tty.read_at(0, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"e\n");
+    }
+
+    #[test]
+    fn control_echo_width() {
+        assert_eq!(echo_width(b'a'), 1);
+        // echoed as `^C` and `^I`
+        assert_eq!(echo_width(0o3), 2);
+        assert_eq!(echo_width(b'\t'), 2);
+    }
+}
@@ -0,0 +1,38 @@
+use super::null::char_device_metadata;
+use alloc::sync::Arc;
+use core::any::Any;
+use queen_fs::vfs::*;
+use spin::Lazy;
+
+/// `/dev/zero`: reads return zeros, writes are discarded.
+#[derive(Default)]
+pub struct ZeroINode;
+
+pub static ZERO: Lazy<Arc<ZeroINode>> = Lazy::new(|| Arc::new(ZeroINode));
+
+impl INode for ZeroINode {
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(char_device_metadata(5, make_rdev(1, 5)))
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
@@ -1,5 +1,10 @@
+use crate::memory::{SlabBox, SlabCache};
 use alloc::{string::String, sync::Arc};
-use core::fmt;
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use queen_fs::vfs::{ FsError, INode, Metadata, PollStatus, Result};
 use spin::RwLock;
 
@@ -9,24 +14,76 @@ enum Flock {
     Exclusive = 2,
 }
 
+/// System-wide limit on open file descriptions, past it `open` fails with `ENFILE`.
+pub const MAX_OPEN_FILES: usize = 8192;
+
+/// Number of open file descriptions, shared ones are counted once.
+static OPEN_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// One of the `MAX_OPEN_FILES` open file descriptions, given back when dropped.
+///
+/// Reserve it before touching the filesystem, so that `ENFILE` has no side effects.
+pub struct OpenFileSlot(());
+
+impl OpenFileSlot {
+    /// Take a slot, `None` if the system-wide limit is reached.
+    pub fn reserve() -> Option<Self> {
+        OPEN_FILES
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                if count < MAX_OPEN_FILES {
+                    Some(count + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| OpenFileSlot(()))
+    }
+
+    /// Take a slot regardless of the limit, for files opened by the kernel itself.
+    fn force() -> Self {
+        OPEN_FILES.fetch_add(1, Ordering::Relaxed);
+        OpenFileSlot(())
+    }
+}
+
+impl Drop for OpenFileSlot {
+    fn drop(&mut self) {
+        OPEN_FILES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 struct OpenFileDescription {
     offset: u64,
     options: OpenOptions,
     flock: Flock,
+    _slot: OpenFileSlot,
 }
 
 impl OpenFileDescription {
-    fn create(options: OpenOptions) -> Arc<RwLock<Self>> {
+    fn create(options: OpenOptions, slot: OpenFileSlot) -> Arc<RwLock<Self>> {
         Arc::new(RwLock::new(OpenFileDescription {
             offset: 0,
             options,
             flock: Flock::None,
+            _slot: slot,
         }))
     }
 }
 
+/// A file descriptor of a process.
+///
+/// Dropping the last handle of an inode drops the inode, which may write it back
+/// and take the locks of its filesystem and block device. Drop handles once the
+/// process is unlocked, so that this runs with IRQs enabled and cannot re-enter
+/// the process lock.
+pub struct FileHandle(SlabBox<FileHandleInner>);
+
+/// Handles are created by every `open`, `dup` and `fork`, they live in a cache.
+static FILE_HANDLES: SlabCache<FileHandleInner> = SlabCache::new("file_handle");
+
 #[derive(Clone)]
-pub struct FileHandle {
+pub struct FileHandleInner {
     inode: Arc<dyn INode>,
     description: Arc<RwLock<OpenFileDescription>>,
     pub path: String,
@@ -65,22 +122,41 @@ impl FileHandle {
         path: String,
         fd_cloexec: bool,
     ) -> Self {
-        return FileHandle {
+        Self::new_in_slot(OpenFileSlot::force(), inode, options, path, fd_cloexec)
+    }
+
+    /// Like `new`, taking up the `slot` reserved for it.
+    pub fn new_in_slot(
+        slot: OpenFileSlot,
+        inode: Arc<dyn INode>,
+        options: OpenOptions,
+        path: String,
+        fd_cloexec: bool,
+    ) -> Self {
+        Self::from_inner(FileHandleInner {
             inode,
-            description: OpenFileDescription::create(options),
+            description: OpenFileDescription::create(options, slot),
             path,
             fd_cloexec,
-        };
+        })
+    }
+
+    fn from_inner(inner: FileHandleInner) -> Self {
+        // out of memory, as `Box::new` would be
+        let inner = FILE_HANDLES
+            .alloc(inner)
+            .expect("no frame for file handles");
+        FileHandle(inner)
     }
 
     // do almost as default clone does, but with fd_cloexec specified
     pub fn dup(&self, fd_cloexec: bool) -> Self {
-        FileHandle {
+        Self::from_inner(FileHandleInner {
             inode: self.inode.clone(),
             description: self.description.clone(),
             path: self.path.clone(),
             fd_cloexec, // this field do not share
-        }
+        })
     }
 
     pub fn set_options(&self, arg: usize) {}
@@ -169,10 +245,6 @@ impl FileHandle {
         self.inode.metadata()
     }
 
-    pub fn lookup_follow(&self, path: &str, max_follow: usize) -> Result<Arc<dyn INode>> {
-        self.inode.lookup_follow(path, max_follow)
-    }
-
     pub fn read_entry(&mut self) -> Result<String> {
         let mut description = self.description.write();
         if !description.options.read {
@@ -212,6 +284,26 @@ impl FileHandle {
     }
 }
 
+impl Clone for FileHandle {
+    fn clone(&self) -> Self {
+        Self::from_inner((*self.0).clone())
+    }
+}
+
+impl Deref for FileHandle {
+    type Target = FileHandleInner;
+
+    fn deref(&self) -> &FileHandleInner {
+        &self.0
+    }
+}
+
+impl DerefMut for FileHandle {
+    fn deref_mut(&mut self) -> &mut FileHandleInner {
+        &mut self.0
+    }
+}
+
 impl fmt::Debug for FileHandle {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let description = self.description.read();
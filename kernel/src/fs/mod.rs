@@ -3,9 +3,22 @@ use spin::Lazy;
 
 mod devfs;
 mod file;
+mod mount;
+mod procfs;
+mod timerfd;
+mod tmpfs;
 
-pub use self::{devfs::*, file::*};
+pub use self::{devfs::*, file::*, mount::*, procfs::ProcFs, timerfd::TimerFdINode, tmpfs::*};
 pub use queen_fs::{INode, FileSystem, FileType, FsInfo, FsError};
 
 pub const FOLLOW_MAX_DEPTH: usize = 3;
-pub static ROOT_INODE: Lazy<Arc<dyn INode>> = Lazy::new(|| todo!());
+/// The root filesystem, inodes only hold weak references to it.
+static ROOT_FS: Lazy<Arc<TmpFs>> = Lazy::new(TmpFs::new);
+pub static ROOT_INODE: Lazy<Arc<dyn INode>> = Lazy::new(|| {
+    let root = ROOT_FS.root_inode();
+    root.create("tmp", FileType::Dir, 0o777).unwrap();
+    // mount point of devfs
+    root.create("dev", FileType::Dir, 0o755).unwrap();
+    root.create("proc", FileType::Dir, 0o555).unwrap();
+    root
+});
@@ -0,0 +1,214 @@
+use super::{DevFs, ProcFs, TmpFs, FOLLOW_MAX_DEPTH, ROOT_INODE};
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use queen_fs::vfs::*;
+use spin::{Lazy, RwLock};
+
+/// Filesystems grafted onto the root filesystem, keyed by normalized absolute path.
+pub static MOUNTS: Lazy<RwLock<BTreeMap<String, Arc<dyn FileSystem>>>> = Lazy::new(|| {
+    let mut mounts = BTreeMap::<String, Arc<dyn FileSystem>>::new();
+    mounts.insert(String::from("/dev"), DevFs::new());
+    mounts.insert(String::from("/proc"), ProcFs::new());
+    RwLock::new(mounts)
+});
+
+/// Create a filesystem by its type name.
+pub fn new_fs(fs_type: &str) -> Result<Arc<dyn FileSystem>> {
+    match fs_type {
+        "tmpfs" => Ok(TmpFs::new()),
+        "devfs" => Ok(DevFs::new()),
+        "proc" => Ok(ProcFs::new()),
+        _ => Err(FsError::WrongFs),
+    }
+}
+
+/// Mount `fs` at the absolute path `target`, which must be an existing directory.
+pub fn mount(target: &str, fs: Arc<dyn FileSystem>) -> Result<()> {
+    let target = normalize_path("/", target);
+    if lookup(&target, 0)?.metadata()?.r#type != FileType::Dir {
+        return Err(FsError::NotDir);
+    }
+    let mut mounts = MOUNTS.write();
+    if target == "/" || mounts.contains_key(&target) {
+        return Err(FsError::Busy);
+    }
+    mounts.insert(target, fs);
+    Ok(())
+}
+
+/// Unmount the filesystem at `target`.
+///
+/// `is_busy` tells whether a path below the mount point is still in use.
+pub fn umount(target: &str, is_busy: impl Fn(&str) -> bool) -> Result<()> {
+    let target = normalize_path("/", target);
+    {
+        // `is_busy` locks processes, which may be looking up paths: never call it with the
+        // write lock held
+        let mounts = MOUNTS.read();
+        if !mounts.contains_key(&target) {
+            return Err(FsError::InvalidParam);
+        }
+        let nested = mounts.keys().any(|path| path != &target && is_below(path, &target));
+        if nested || is_busy(&target) {
+            return Err(FsError::Busy);
+        }
+    }
+    MOUNTS.write().remove(&target);
+    Ok(())
+}
+
+/// Whether `path` is `dir` or inside it. Both must be normalized.
+pub fn is_below(path: &str, dir: &str) -> bool {
+    dir == "/"
+        || path == dir
+        || (path.starts_with(dir) && path.as_bytes().get(dir.len()) == Some(&b'/'))
+}
+
+/// Resolve an absolute path, crossing mount points and following symbolic links.
+///
+/// Links in the middle of the path are always followed, the last component only
+/// if `follow` is set. Following more than `FOLLOW_MAX_DEPTH` links in total fails
+/// with `SymLoop`.
+///
+/// The path is normalized lexically first, so `..` never escapes into the
+/// filesystem a mount point is grafted on.
+pub fn lookup(path: &str, follow: bool) -> Result<Arc<dyn INode>> {
+    let mut path = normalize_path("/", path);
+    let mut links = 0;
+    loop {
+        let (link, parent, rest) = match walk(&path, follow)? {
+            Walk::Found(inode) => return Ok(inode),
+            Walk::Link(link, parent, rest) => (link, parent, rest),
+        };
+        if links == FOLLOW_MAX_DEPTH {
+            return Err(FsError::SymLoop);
+        }
+        links += 1;
+        let target = read_link(&*link)?;
+        // a relative target is resolved against the directory holding the link
+        path = normalize_path(parent, &[target.as_str(), rest].concat());
+    }
+}
+
+enum Walk<'a> {
+    /// The inode at the end of the path
+    Found(Arc<dyn INode>),
+    /// A link to follow, with the path of its directory and the rest of the path after it
+    Link(Arc<dyn INode>, &'a str, &'a str),
+}
+
+/// Walk the normalized absolute `path` up to its end or the first link to follow.
+fn walk(path: &str, follow: bool) -> Result<Walk> {
+    let mounts = MOUNTS.read();
+    let mut inode = ROOT_INODE.clone();
+    if path == "/" {
+        return Ok(Walk::Found(inode));
+    }
+    let mut end = 0;
+    while end < path.len() {
+        let start = end + 1;
+        end = path[start..].find('/').map_or(path.len(), |len| start + len);
+        inode = match mounts.get(&path[..end]) {
+            Some(fs) => fs.root_inode(),
+            None => inode.find(&path[start..end])?,
+        };
+        let last = end == path.len();
+        if (follow || !last) && inode.metadata()?.r#type == FileType::SymLink {
+            return Ok(Walk::Link(inode, &path[..start], &path[end..]));
+        }
+    }
+    Ok(Walk::Found(inode))
+}
+
+/// Read the target of the symbolic link `inode`.
+pub fn read_link(inode: &dyn INode) -> Result<String> {
+    let mut buf = vec![0; inode.metadata()?.size];
+    let len = inode.read_at(0, &mut buf)?;
+    buf.truncate(len);
+    match String::from_utf8(buf) {
+        Ok(target) if !target.is_empty() => Ok(target),
+        Ok(_) => Err(FsError::EntryNotFound),
+        Err(_) => Err(FsError::InvalidParam),
+    }
+}
+
+/// Join `path` to the absolute directory `base` and resolve `.` and `..`.
+pub fn normalize_path(base: &str, path: &str) -> String {
+    let mut segments = Vec::new();
+    let full = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        [base, "/", path].concat()
+    };
+    for segment in full.split('/') {
+        match segment {
+            "" | "." => {}
+            // ".." of "/" is itself
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    if segments.is_empty() {
+        return String::from("/");
+    }
+    let mut normalized = String::new();
+    for segment in segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_path("/", "/a/b/../../.."), "/");
+        assert_eq!(normalize_path("/a/b", "../../../c"), "/c");
+        assert_eq!(normalize_path("/a/b", "."), "/a/b");
+        assert_eq!(normalize_path("/a/b", "./c/."), "/a/b/c");
+        assert_eq!(normalize_path("/a", "//"), "/");
+        assert_eq!(normalize_path("/", "//a//b/"), "/a/b");
+        assert_eq!(normalize_path("/a/", "b//"), "/a/b");
+    }
+
+    /// Create the symbolic link `path` to `target`.
+    fn symlink(path: &str, target: &str) {
+        let (dir, name) = path.split_at(path.rfind('/').unwrap());
+        let dir = lookup(dir, true).unwrap();
+        let link = dir.create(&name[1..], FileType::SymLink, 0o777).unwrap();
+        link.write_at(0, target.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn symlink_loops() {
+        let tmp = ROOT_INODE.find("tmp").unwrap();
+        let dir = tmp.create("links", FileType::Dir, 0o777).unwrap();
+        dir.create("file", FileType::File, 0o666).unwrap();
+
+        let looped = |path| matches!(lookup(path, true), Err(FsError::SymLoop));
+
+        symlink("/tmp/links/self", "self");
+        assert!(looped("/tmp/links/self"));
+        assert!(lookup("/tmp/links/self", false).is_ok());
+
+        // a chain of 4 links, one more than can be followed
+        assert_eq!(FOLLOW_MAX_DEPTH, 3);
+        symlink("/tmp/links/a", "b");
+        symlink("/tmp/links/b", "/tmp/links/c");
+        symlink("/tmp/links/c", "../links/d");
+        symlink("/tmp/links/d", "file");
+        assert!(looped("/tmp/links/a"));
+        let file = lookup("/tmp/links/b", true).unwrap();
+        assert_eq!(file.metadata().unwrap().r#type, FileType::File);
+    }
+}
@@ -0,0 +1,434 @@
+use crate::{
+    arch::asid::switch_stats,
+    memory::{frame_stats, slab_stats, swap::swap_stats, PAGE_SIZE},
+    process::{process, thread::current_pid, Pid, PROCESSES},
+    task::executor::sched_debug,
+};
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+};
+use core::{any::Any, fmt::Write};
+use queen_fs::vfs::*;
+use spin::Lazy;
+
+static PROC_FS: Lazy<Arc<ProcFs>> = Lazy::new(|| {
+    Arc::new(ProcFs {
+        root: Arc::new(ProcRootINode),
+    })
+});
+
+/// Synthetic filesystem exposing process information, every read regenerates the content.
+pub struct ProcFs {
+    root: Arc<ProcRootINode>,
+}
+
+impl ProcFs {
+    pub fn new() -> Arc<Self> {
+        PROC_FS.clone()
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.root.clone()
+    }
+
+    fn info(&self) -> FsInfo {
+        FsInfo {
+            bsize: 0,
+            frsize: 0,
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            namemax: 255,
+        }
+    }
+}
+
+fn proc_metadata(inode: usize, r#type: FileType) -> Metadata {
+    let (mode, nlinks) = match r#type {
+        FileType::Dir => (0o555, 2),
+        _ => (0o444, 1),
+    };
+    Metadata {
+        dev: 0,
+        inode,
+        size: 0,
+        blk_size: 0,
+        blocks: 0,
+        atime: TimeSpec::zero(),
+        mtime: TimeSpec::zero(),
+        ctime: TimeSpec::zero(),
+        r#type,
+        mode,
+        nlinks,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+    }
+}
+
+/// `/proc`
+struct ProcRootINode;
+
+impl INode for ProcRootINode {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Err(FsError::IsDir)
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(proc_metadata(1, FileType::Dir))
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        match name {
+            "." | ".." | "" => Ok(PROC_FS.root_inode()),
+            "meminfo" => Ok(Arc::new(ProcFileINode::MemInfo)),
+            "sched_debug" => Ok(Arc::new(ProcFileINode::SchedDebug)),
+            "slabinfo" => Ok(Arc::new(ProcFileINode::SlabInfo)),
+            "vmstat" => Ok(Arc::new(ProcFileINode::VmStat)),
+            // resolves to the caller
+            "self" => {
+                let pid = current_pid().ok_or(FsError::EntryNotFound)?;
+                Ok(Arc::new(ProcPidINode { pid }))
+            }
+            name => {
+                let pid = name.parse::<Pid>().map_err(|_| FsError::EntryNotFound)?;
+                process(pid).ok_or(FsError::EntryNotFound)?;
+                Ok(Arc::new(ProcPidINode { pid }))
+            }
+        }
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            2 => Ok(String::from("self")),
+            3 => Ok(String::from("meminfo")),
+            4 => Ok(String::from("sched_debug")),
+            5 => Ok(String::from("slabinfo")),
+            6 => Ok(String::from("vmstat")),
+            id => PROCESSES
+                .read()
+                .keys()
+                .nth(id - 7)
+                .map(|pid| pid.to_string())
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        PROC_FS.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// `/proc/<pid>`
+struct ProcPidINode {
+    pid: Pid,
+}
+
+const PID_ENTRIES: [&str; 3] = ["stat", "status", "maps"];
+
+impl INode for ProcPidINode {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Err(FsError::IsDir)
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(proc_metadata(self.pid << 8, FileType::Dir))
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        match name {
+            "." | "" => Ok(Arc::new(ProcPidINode { pid: self.pid })),
+            ".." => Ok(PROC_FS.root_inode()),
+            "stat" => Ok(Arc::new(ProcFileINode::Stat(self.pid))),
+            "status" => Ok(Arc::new(ProcFileINode::Status(self.pid))),
+            "maps" => Ok(Arc::new(ProcFileINode::Maps(self.pid))),
+            _ => Err(FsError::EntryNotFound),
+        }
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            id => PID_ENTRIES
+                .get(id - 2)
+                .map(|name| name.to_string())
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        PROC_FS.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Read-only text files.
+enum ProcFileINode {
+    /// `/proc/meminfo`
+    MemInfo,
+    /// `/proc/sched_debug`
+    SchedDebug,
+    /// `/proc/slabinfo`
+    SlabInfo,
+    /// `/proc/vmstat`
+    VmStat,
+    /// `/proc/<pid>/stat`
+    Stat(Pid),
+    /// `/proc/<pid>/status`
+    Status(Pid),
+    /// `/proc/<pid>/maps`
+    Maps(Pid),
+}
+
+impl ProcFileINode {
+    fn generate(&self) -> Result<String> {
+        match *self {
+            ProcFileINode::MemInfo => {
+                let (total, free) = frame_stats();
+                let (swap_total, swap_used) = swap_stats();
+                let kb = PAGE_SIZE / 1024;
+                Ok(format!(
+                    "MemTotal:{:>16} kB\nMemFree:{:>17} kB\nMemAvailable:{:>12} kB\n\
+                     SwapTotal:{:>15} kB\nSwapFree:{:>16} kB\n",
+                    total * kb,
+                    free * kb,
+                    free * kb,
+                    swap_total * kb,
+                    (swap_total - swap_used) * kb
+                ))
+            }
+            ProcFileINode::SchedDebug => {
+                let mut text = String::new();
+                for rq in sched_debug() {
+                    writeln!(text, "cpu#{}", rq.cpu).unwrap();
+                    writeln!(text, "  .nr_running{:>20}", rq.nr_running).unwrap();
+                    writeln!(text, "  .load{:>25}", rq.load).unwrap();
+                    writeln!(text, "  .min_vruntime{:>18}", rq.min_vruntime).unwrap();
+                    match rq.current {
+                        Some((tid, Some(vruntime))) => {
+                            writeln!(text, "  current: {:>5} {:>20}", tid, vruntime).unwrap()
+                        }
+                        Some((tid, None)) => writeln!(text, "  current: {:>5}", tid).unwrap(),
+                        None => {}
+                    }
+                    writeln!(text, "  runnable tasks:").unwrap();
+                    for (tid, vruntime) in rq.ready_tasks {
+                        writeln!(text, "           {:>5} {:>20}", tid, vruntime).unwrap();
+                    }
+                }
+                Ok(text)
+            }
+            ProcFileINode::SlabInfo => {
+                let mut text = String::from(
+                    "# name            <active_objs> <num_objs> <objsize> <num_slabs>\n",
+                );
+                for stats in slab_stats() {
+                    writeln!(
+                        text,
+                        "{:<17} {:>13} {:>10} {:>9} {:>11}",
+                        stats.name,
+                        stats.allocated,
+                        stats.allocated + stats.free,
+                        stats.object_size,
+                        stats.slabs
+                    )
+                    .unwrap();
+                }
+                Ok(text)
+            }
+            ProcFileINode::VmStat => {
+                let (switches, flushes) = switch_stats();
+                Ok(format!(
+                    "nr_tlb_switch {}\nnr_tlb_switch_flush_all {}\n",
+                    switches, flushes
+                ))
+            }
+            ProcFileINode::Stat(pid) => {
+                let info = ProcessInfo::of(pid)?;
+                // Ref: [https://man7.org/linux/man-pages/man5/proc.5.html]
+                Ok(format!(
+                    "{} ({}) {} {} {} 0 0 0 0 0 0 0 0 0 0 0 0 0 0 {} 0 0 {} {}\n",
+                    info.pid,
+                    info.name,
+                    info.state,
+                    info.ppid,
+                    info.pgid,
+                    info.threads,
+                    info.vm_size,
+                    info.vm_rss / PAGE_SIZE
+                ))
+            }
+            ProcFileINode::Status(pid) => {
+                let info = ProcessInfo::of(pid)?;
+                let state = match info.state {
+                    'Z' => "Z (zombie)",
+                    _ => "R (running)",
+                };
+                Ok(format!(
+                    "Name:\t{}\nState:\t{}\nTgid:\t{}\nPid:\t{}\nPPid:\t{}\nVmSize:\t{} kB\n\
+                     VmRSS:\t{} kB\nVmSwap:\t{} kB\nThreads:\t{}\n",
+                    info.name,
+                    state,
+                    info.pid,
+                    info.pid,
+                    info.ppid,
+                    info.vm_size / 1024,
+                    info.vm_rss / 1024,
+                    info.vm_swap / 1024,
+                    info.threads
+                ))
+            }
+            ProcFileINode::Maps(pid) => {
+                let proc = process(pid).ok_or(FsError::EntryNotFound)?;
+                let vm = proc.lock().vm.clone();
+                let perm = |allowed: bool, c: char| if allowed { c } else { '-' };
+                let mut text = String::new();
+                for (start, end, name, attr) in vm.lock().areas() {
+                    // Ref: [https://man7.org/linux/man-pages/man5/proc.5.html]
+                    let line = format!(
+                        "{:08x}-{:08x} {}{}{}p 00000000 00:00 0",
+                        start,
+                        end,
+                        perm(attr.user, 'r'),
+                        perm(attr.user && !attr.readonly, 'w'),
+                        perm(attr.user && attr.execute, 'x'),
+                    );
+                    if name.is_empty() {
+                        writeln!(text, "{}", line).unwrap();
+                    } else {
+                        writeln!(text, "{:<73}{}", line, name).unwrap();
+                    }
+                }
+                Ok(text)
+            }
+        }
+    }
+
+    fn id(&self) -> usize {
+        match *self {
+            ProcFileINode::MemInfo => 2,
+            ProcFileINode::SchedDebug => 3,
+            ProcFileINode::SlabInfo => 4,
+            ProcFileINode::VmStat => 5,
+            ProcFileINode::Stat(pid) => pid << 8 | 1,
+            ProcFileINode::Status(pid) => pid << 8 | 2,
+            ProcFileINode::Maps(pid) => pid << 8 | 3,
+        }
+    }
+}
+
+impl INode for ProcFileINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let content = self.generate()?;
+        let content = content.as_bytes();
+        if offset >= content.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: false,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(proc_metadata(self.id(), FileType::File))
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        PROC_FS.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A snapshot of the fields shown in `/proc/<pid>`.
+struct ProcessInfo {
+    pid: Pid,
+    name: String,
+    state: char,
+    ppid: Pid,
+    pgid: i32,
+    threads: usize,
+    vm_size: usize,
+    /// Bytes backed by frames
+    vm_rss: usize,
+    /// Bytes written out to the swap
+    vm_swap: usize,
+}
+
+impl ProcessInfo {
+    fn of(pid: Pid) -> Result<Self> {
+        let proc = process(pid).ok_or(FsError::EntryNotFound)?;
+        let proc = proc.lock();
+        let name = proc
+            .exec_path
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let (vm_size, summary) = {
+            let mut vm = proc.vm.lock();
+            (vm.size(), vm.summary())
+        };
+        Ok(ProcessInfo {
+            pid: proc.pid,
+            name,
+            state: if proc.exited() { 'Z' } else { 'R' },
+            ppid: proc.parent.0,
+            pgid: proc.pgid,
+            threads: proc.threads.len(),
+            vm_size,
+            vm_rss: summary.resident * PAGE_SIZE,
+            vm_swap: summary.swapped * PAGE_SIZE,
+        })
+    }
+}
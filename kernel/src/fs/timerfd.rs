@@ -0,0 +1,301 @@
+//! `timerfd`, a timer read as a file.
+//!
+//! A read returns the number of expirations since the last read as a native
+//! endian `u64`, blocking until there is at least one. Expirations are counted
+//! when the timer is looked at, the timer only wakes the readers.
+
+use crate::{
+    arch::timer,
+    sync::MutexNoIrq,
+    task::timer::{TimerHandle, TIMER},
+};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    any::Any,
+    future::Future,
+    mem::size_of,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use queen_fs::vfs::*;
+
+pub struct TimerFdINode {
+    /// Whether absolute times are on `CLOCK_REALTIME` rather than the monotonic clock.
+    realtime: bool,
+    state: MutexNoIrq<TimerFdState>,
+}
+
+#[derive(Default)]
+struct TimerFdState {
+    /// Next expiration on the monotonic clock, `None` while disarmed.
+    deadline: Option<Duration>,
+    /// Period, zero for a one-shot timer.
+    interval: Duration,
+    /// Expirations not read yet.
+    expirations: u64,
+    /// Readers waiting while the timer is disarmed or rearmed.
+    waiters: Vec<Waker>,
+}
+
+impl TimerFdState {
+    /// Count the expirations up to `now`.
+    fn update(&mut self, now: Duration) {
+        let deadline = match self.deadline {
+            Some(deadline) if deadline <= now => deadline,
+            _ => return,
+        };
+        let interval = self.interval.as_nanos();
+        if interval == 0 {
+            self.expirations += 1;
+            self.deadline = None;
+            return;
+        }
+        let count = (now - deadline).as_nanos() / interval + 1;
+        let saturate = |ns: u128| ns.min(u64::MAX.into()) as u64;
+        self.expirations = self.expirations.saturating_add(saturate(count));
+        let next = deadline
+            .as_nanos()
+            .saturating_add(count.saturating_mul(interval));
+        self.deadline = Some(Duration::from_nanos(saturate(next)));
+    }
+
+    fn can_read(&self) -> bool {
+        self.expirations > 0
+    }
+
+    /// Wait for a rearm with `waker` instead of `old`, the waker of the reader's
+    /// previous poll, unless a rearm took it already.
+    fn replace_waiter(&mut self, old: Option<&Waker>, waker: &Waker) {
+        if let Some(old) = old {
+            self.remove_waiter(old);
+        }
+        self.waiters.push(waker.clone());
+    }
+
+    /// Remove a waker which will wake the same task as `waker`.
+    fn remove_waiter(&mut self, waker: &Waker) {
+        if let Some(i) = self.waiters.iter().position(|w| w.will_wake(waker)) {
+            self.waiters.swap_remove(i);
+        }
+    }
+}
+
+impl TimerFdINode {
+    pub fn new(realtime: bool) -> Arc<Self> {
+        Arc::new(TimerFdINode {
+            realtime,
+            state: MutexNoIrq::new(TimerFdState::default()),
+        })
+    }
+
+    pub fn is_realtime(&self) -> bool {
+        self.realtime
+    }
+
+    /// Time until the next expiration, zero while disarmed, and the interval.
+    pub fn get(&self) -> (Duration, Duration) {
+        let now = timer::read();
+        let mut state = self.state.lock();
+        state.update(now);
+        let remaining = state
+            .deadline
+            .map_or(Duration::default(), |deadline| deadline - now);
+        (remaining, state.interval)
+    }
+
+    /// Arm the timer to expire at `deadline` on the monotonic clock then every
+    /// `interval`, or disarm it if `deadline` is `None`.
+    /// Return the previous setting like `get`.
+    pub fn set(&self, deadline: Option<Duration>, interval: Duration) -> (Duration, Duration) {
+        let old = self.get();
+        let waiters = {
+            let mut state = self.state.lock();
+            state.deadline = deadline;
+            state.interval = interval;
+            state.expirations = 0;
+            core::mem::take(&mut state.waiters)
+        };
+        // let them wait for the new deadline
+        for waker in waiters {
+            waker.wake();
+        }
+        old
+    }
+}
+
+impl INode for TimerFdINode {
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if buf.len() < size_of::<u64>() {
+            return Err(FsError::InvalidParam);
+        }
+        let mut state = self.state.lock();
+        state.update(timer::read());
+        if !state.can_read() {
+            return Err(FsError::Again);
+        }
+        buf[..size_of::<u64>()].copy_from_slice(&state.expirations.to_ne_bytes());
+        state.expirations = 0;
+        Ok(size_of::<u64>())
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        let mut state = self.state.lock();
+        state.update(timer::read());
+        Ok(PollStatus {
+            read: state.can_read(),
+            write: false,
+            error: false,
+        })
+    }
+
+    fn async_poll<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>> {
+        #[must_use = "future does nothing unless polled/`await`-ed"]
+        struct TimerFdFuture<'a> {
+            timerfd: &'a TimerFdINode,
+            timer: Option<TimerHandle>,
+            /// The waker in `waiters` since the previous poll
+            waiter: Option<Waker>,
+        }
+
+        impl<'a> Future for TimerFdFuture<'a> {
+            type Output = Result<PollStatus>;
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                let this = &mut *self;
+                let deadline = {
+                    let mut state = this.timerfd.state.lock();
+                    state.update(timer::read());
+                    if state.can_read() {
+                        if let Some(waiter) = this.waiter.take() {
+                            state.remove_waiter(&waiter);
+                        }
+                        drop(state);
+                        return Poll::Ready(this.timerfd.poll());
+                    }
+                    state.replace_waiter(this.waiter.as_ref(), cx.waker());
+                    state.deadline
+                };
+                this.waiter = Some(cx.waker().clone());
+                let timer = deadline.map(|deadline| TIMER.lock().add(deadline, cx.waker().clone()));
+                // replaces the timer of the previous poll, with `TIMER` unlocked
+                this.timer = timer;
+                Poll::Pending
+            }
+        }
+
+        impl Drop for TimerFdFuture<'_> {
+            fn drop(&mut self) {
+                if let Some(waiter) = self.waiter.take() {
+                    self.timerfd.state.lock().remove_waiter(&waiter);
+                }
+            }
+        }
+
+        Box::pin(TimerFdFuture {
+            timerfd: self,
+            timer: None,
+            waiter: None,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 0,
+            inode: 0,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: TimeSpec::zero(),
+            mtime: TimeSpec::zero(),
+            ctime: TimeSpec::zero(),
+            r#type: FileType::File,
+            mode: 0o600,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        arch::timer::MOCK_COUNT,
+        sync::mock::{poll_once, MockWaker},
+    };
+    use core::sync::atomic::Ordering;
+
+    /// Move the counter to `ns` since boot, at 62.5 MHz.
+    fn set_now(ns: u64) {
+        MOCK_COUNT.store(ns / 16, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn periodic_expirations() {
+        let mut state = TimerFdState {
+            deadline: Some(Duration::from_nanos(100)),
+            interval: Duration::from_nanos(10),
+            ..TimerFdState::default()
+        };
+        state.update(Duration::from_nanos(99));
+        assert!(!state.can_read());
+        state.update(Duration::from_nanos(125));
+        assert_eq!(state.expirations, 3);
+        assert_eq!(state.deadline, Some(Duration::from_nanos(130)));
+
+        // the next deadline does not fit in 64 bits of nanoseconds
+        state.interval = Duration::from_secs(1 << 40);
+        state.update(Duration::from_nanos(130));
+        assert_eq!(state.expirations, 4);
+        assert_eq!(state.deadline, Some(Duration::from_nanos(u64::MAX)));
+    }
+
+    #[test]
+    fn one_waiter_per_reader() {
+        set_now(0);
+        let timerfd = TimerFdINode::new(false);
+        let (mock, waker) = MockWaker::new();
+        let (_, other) = MockWaker::new();
+        let mut future = timerfd.async_poll();
+        for _ in 0..10 {
+            assert!(poll_once(&mut future, &other).is_pending());
+        }
+        // moved to another task
+        assert!(poll_once(&mut future, &waker).is_pending());
+        assert_eq!(timerfd.state.lock().waiters.len(), 1);
+
+        // rearming wakes the reader, which waits for the new deadline
+        timerfd.set(Some(Duration::from_micros(1)), Duration::default());
+        assert_eq!(mock.wakes(), 1);
+        assert!(timerfd.state.lock().waiters.is_empty());
+        assert!(poll_once(&mut future, &waker).is_pending());
+        assert_eq!(timerfd.state.lock().waiters.len(), 1);
+        assert_eq!(TIMER.lock().next_deadline(), Some(Duration::from_micros(1)));
+
+        set_now(1_000);
+        assert!(poll_once(&mut future, &waker).is_ready());
+        assert!(timerfd.state.lock().waiters.is_empty());
+        drop(future);
+        assert_eq!(TIMER.lock().next_deadline(), None);
+
+        // dropped while waiting
+        let mut future = timerfd.async_poll();
+        timerfd.set(None, Duration::default());
+        assert!(poll_once(&mut future, &waker).is_pending());
+        drop(future);
+        assert!(timerfd.state.lock().waiters.is_empty());
+    }
+}
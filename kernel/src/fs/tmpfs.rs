@@ -0,0 +1,427 @@
+use crate::TimeSpec;
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use queen_fs::vfs::*;
+use spin::RwLock;
+
+/// Bytes of file content a tmpfs can hold.
+const MAX_SIZE: usize = 64 * 1024 * 1024;
+
+/// In-memory filesystem, all contents are lost on reboot.
+///
+/// Inodes only point back to it weakly, whoever mounts it must keep it alive.
+pub struct TmpFs {
+    root: Arc<TmpINode>,
+    next_inode_id: AtomicUsize,
+    /// Bytes of file content in use
+    used: AtomicUsize,
+}
+
+impl TmpFs {
+    pub fn new() -> Arc<Self> {
+        let root = TmpINode::new(Weak::new(), 1, FileType::Dir, 0o777, Weak::new());
+        let fs = Arc::new(TmpFs {
+            root: root.clone(),
+            next_inode_id: AtomicUsize::new(2),
+            used: AtomicUsize::new(0),
+        });
+        let mut inner = root.inner.write();
+        inner.fs = Arc::downgrade(&fs);
+        // root is its own parent
+        inner.parent = Arc::downgrade(&root);
+        drop(inner);
+        fs
+    }
+
+    fn alloc_inode_id(&self) -> usize {
+        self.next_inode_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Account for a file growing or shrinking from `old` to `new` bytes.
+    fn charge(&self, old: usize, new: usize) -> Result<()> {
+        if new <= old {
+            self.used.fetch_sub(old - new, Ordering::Relaxed);
+            return Ok(());
+        }
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(new - old).filter(|&used| used <= MAX_SIZE)
+            })
+            .map(|_| ())
+            .map_err(|_| FsError::NoDeviceSpace)
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.root.clone()
+    }
+
+    fn info(&self) -> FsInfo {
+        FsInfo {
+            bsize: 0,
+            frsize: 0,
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            namemax: 255,
+        }
+    }
+}
+
+struct TmpINodeInner {
+    /// `..`, the root points to itself
+    parent: Weak<TmpINode>,
+    /// Directory entries, excluding `.` and `..`
+    children: BTreeMap<String, Arc<TmpINode>>,
+    /// File content or symlink target
+    content: Vec<u8>,
+    metadata: Metadata,
+    this: Weak<TmpINode>,
+    fs: Weak<TmpFs>,
+}
+
+pub struct TmpINode {
+    inner: RwLock<TmpINodeInner>,
+}
+
+impl TmpINode {
+    fn new(
+        fs: Weak<TmpFs>,
+        id: usize,
+        r#type: FileType,
+        mode: u32,
+        parent: Weak<TmpINode>,
+    ) -> Arc<Self> {
+        let metadata = Metadata {
+            dev: 0,
+            inode: id,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: TimeSpec::zero(),
+            mtime: TimeSpec::zero(),
+            ctime: TimeSpec::zero(),
+            r#type,
+            mode: mode as _,
+            nlinks: if r#type == FileType::Dir { 2 } else { 1 },
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        };
+        let inode = Arc::new(TmpINode {
+            inner: RwLock::new(TmpINodeInner {
+                parent,
+                children: BTreeMap::new(),
+                content: Vec::new(),
+                metadata,
+                this: Weak::new(),
+                fs,
+            }),
+        });
+        inode.inner.write().this = Arc::downgrade(&inode);
+        inode
+    }
+
+    fn is_dir(&self) -> bool {
+        self.inner.read().metadata.r#type == FileType::Dir
+    }
+
+    fn this(&self) -> Arc<TmpINode> {
+        self.inner.read().this.upgrade().unwrap()
+    }
+
+    fn fs_weak(&self) -> Weak<TmpFs> {
+        self.inner.read().fs.clone()
+    }
+
+    /// Resize the content of a file, within the space left in the filesystem.
+    fn set_len(inner: &mut TmpINodeInner, len: usize) -> Result<()> {
+        if let Some(fs) = inner.fs.upgrade() {
+            fs.charge(inner.content.len(), len)?;
+        }
+        inner.content.resize(len, 0);
+        Ok(())
+    }
+
+    /// Downcast an INode of the same filesystem.
+    fn downcast(&self, other: &Arc<dyn INode>) -> Result<Arc<TmpINode>> {
+        let other = other
+            .as_any_ref()
+            .downcast_ref::<TmpINode>()
+            .ok_or(FsError::NotSameFs)?
+            .this();
+        if !Weak::ptr_eq(&self.fs_weak(), &other.fs_weak()) {
+            return Err(FsError::NotSameFs);
+        }
+        Ok(other)
+    }
+}
+
+impl Drop for TmpINode {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut();
+        if let Some(fs) = inner.fs.upgrade() {
+            fs.used.fetch_sub(inner.content.len(), Ordering::Relaxed);
+        }
+    }
+}
+
+impl INode for TmpINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let inner = self.inner.read();
+        if inner.metadata.r#type == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
+        let content = &inner.content;
+        if offset >= content.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut inner = self.inner.write();
+        if inner.metadata.r#type == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
+        let end = offset
+            .checked_add(buf.len())
+            .ok_or(FsError::NoDeviceSpace)?;
+        if inner.content.len() < end {
+            Self::set_len(&mut inner, end)?;
+        }
+        inner.content[offset..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        if self.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        let inner = self.inner.read();
+        let mut metadata = inner.metadata.clone();
+        metadata.size = match metadata.r#type {
+            FileType::Dir => inner.children.len() + 2,
+            _ => inner.content.len(),
+        };
+        Ok(metadata)
+    }
+
+    fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        let mut inner = self.inner.write();
+        inner.metadata.atime = metadata.atime;
+        inner.metadata.mtime = metadata.mtime;
+        inner.metadata.ctime = metadata.ctime;
+        inner.metadata.mode = metadata.mode;
+        inner.metadata.uid = metadata.uid;
+        inner.metadata.gid = metadata.gid;
+        Ok(())
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn resize(&self, len: usize) -> Result<()> {
+        let mut inner = self.inner.write();
+        if inner.metadata.r#type == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
+        Self::set_len(&mut inner, len)
+    }
+
+    fn create(&self, name: &str, r#type: FileType, mode: u32) -> Result<Arc<dyn INode>> {
+        let mut inner = self.inner.write();
+        if inner.metadata.r#type != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if name == "." || name == ".." || inner.children.contains_key(name) {
+            return Err(FsError::EntryExist);
+        }
+        let fs = inner.fs.upgrade().unwrap();
+        let inode = TmpINode::new(
+            inner.fs.clone(),
+            fs.alloc_inode_id(),
+            r#type,
+            mode,
+            inner.this.clone(),
+        );
+        if r#type == FileType::Dir {
+            inner.metadata.nlinks += 1;
+        }
+        inner.children.insert(name.to_string(), inode.clone());
+        Ok(inode)
+    }
+
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
+        let other = self.downcast(other)?;
+        if other.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        let mut inner = self.inner.write();
+        if inner.metadata.r#type != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if name == "." || name == ".." || inner.children.contains_key(name) {
+            return Err(FsError::EntryExist);
+        }
+        other.inner.write().metadata.nlinks += 1;
+        inner.children.insert(name.to_string(), other);
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        if name == "." || name == ".." {
+            return Err(FsError::DirNotEmpty);
+        }
+        let mut inner = self.inner.write();
+        if inner.metadata.r#type != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let child = inner.children.get(name).ok_or(FsError::EntryNotFound)?;
+        let mut child_inner = child.inner.write();
+        let child_is_dir = child_inner.metadata.r#type == FileType::Dir;
+        if child_is_dir && !child_inner.children.is_empty() {
+            return Err(FsError::DirNotEmpty);
+        }
+        child_inner.metadata.nlinks -= 1;
+        drop(child_inner);
+        inner.children.remove(name);
+        if child_is_dir {
+            inner.metadata.nlinks -= 1;
+        }
+        Ok(())
+    }
+
+    fn r#move(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        if [old_name, new_name].iter().any(|&n| n == "." || n == "..") {
+            return Err(FsError::IsDir);
+        }
+        let target = self.downcast(target)?;
+        if !target.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        let child = self.find(old_name)?;
+        let child = self.downcast(&child)?;
+        let child_is_dir = child.is_dir();
+
+        // a directory can not be moved into itself
+        let mut ancestor = target.clone();
+        loop {
+            if Arc::ptr_eq(&ancestor, &child) {
+                return Err(FsError::InvalidParam);
+            }
+            let parent = ancestor.inner.read().parent.upgrade().unwrap();
+            if Arc::ptr_eq(&parent, &ancestor) {
+                break;
+            }
+            ancestor = parent;
+        }
+
+        // replace the destination, as rename(2) does
+        if let Ok(existing) = target.find(new_name) {
+            let existing = self.downcast(&existing)?;
+            if Arc::ptr_eq(&existing, &child) {
+                return Ok(());
+            }
+            if existing.is_dir() != child_is_dir {
+                return Err(if child_is_dir {
+                    FsError::NotDir
+                } else {
+                    FsError::IsDir
+                });
+            }
+            target.unlink(new_name)?;
+        }
+
+        self.inner.write().children.remove(old_name);
+        target
+            .inner
+            .write()
+            .children
+            .insert(new_name.to_string(), child.clone());
+        if child_is_dir {
+            child.inner.write().parent = Arc::downgrade(&target);
+            self.inner.write().metadata.nlinks -= 1;
+            target.inner.write().metadata.nlinks += 1;
+        }
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        let inner = self.inner.read();
+        if inner.metadata.r#type != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        match name {
+            "." | "" => Ok(inner.this.upgrade().unwrap()),
+            ".." => Ok(inner.parent.upgrade().unwrap()),
+            name => inner
+                .children
+                .get(name)
+                .map(|inode| inode.clone() as Arc<dyn INode>)
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        let inner = self.inner.read();
+        if inner.metadata.r#type != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            id => inner
+                .children
+                .keys()
+                .nth(id - 2)
+                .cloned()
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs_weak().upgrade().unwrap()
+    }
+
+    fn update_time(&self, now: TimeSpec) {
+        let mut inner = self.inner.write();
+        inner.metadata.atime = now;
+        inner.metadata.mtime = now;
+        inner.metadata.ctime = now;
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,136 @@
+//! Kernel log buffer, the records of the logger kept in memory for `syslog`.
+//!
+//! The buffer is a ring of bytes overwriting its oldest records when full, a
+//! reader which fell behind loses them. It is locked with IRQs disabled, so that
+//! IRQ handlers log safely, and only for the time of a copy.
+
+use crate::sync::MutexNoIrq;
+use core::fmt::{self, Write};
+
+/// Size of the buffer in bytes.
+pub const LOG_BUF_LEN: usize = 1 << 16;
+
+static LOG_BUF: MutexNoIrq<LogBuf> = MutexNoIrq::new(LogBuf {
+    buf: [0; LOG_BUF_LEN],
+    end: 0,
+    read: 0,
+    clear: 0,
+});
+
+struct LogBuf {
+    buf: [u8; LOG_BUF_LEN],
+    /// Bytes written since boot, the buffer holds the last `LOG_BUF_LEN` of them.
+    end: usize,
+    /// Position of the next byte read by `read`.
+    read: usize,
+    /// Position before which `read_all` does not read, moved by `clear`.
+    clear: usize,
+}
+
+impl LogBuf {
+    /// Position of the oldest byte still in the buffer.
+    fn start(&self) -> usize {
+        self.end.saturating_sub(LOG_BUF_LEN)
+    }
+
+    /// Copy the bytes from `from` to the end into `buf`, as many as fit.
+    /// Return the number of bytes copied.
+    fn copy_from(&self, from: usize, buf: &mut [u8]) -> usize {
+        let from = from.max(self.start());
+        let to = self.end.min(from + buf.len());
+        for (i, byte) in (from..to).zip(buf.iter_mut()) {
+            *byte = self.buf[i % LOG_BUF_LEN];
+        }
+        to - from
+    }
+}
+
+impl Write for LogBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buf[self.end % LOG_BUF_LEN] = byte;
+            self.end += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Append a record.
+pub fn record(args: fmt::Arguments) {
+    let _ = LOG_BUF.lock().write_fmt(args);
+}
+
+/// Take the bytes not read yet into `buf`, as many as fit.
+/// Return the number of bytes taken.
+pub fn read(buf: &mut [u8]) -> usize {
+    let mut log = LOG_BUF.lock();
+    let len = log.copy_from(log.read, buf);
+    log.read = log.read.max(log.start()) + len;
+    len
+}
+
+/// Copy the last bytes in the buffer into `buf`, as many as fit, read or not,
+/// since the last `clear`. Then `clear` if `clear` is set.
+/// Return the number of bytes copied.
+pub fn read_all(buf: &mut [u8], clear: bool) -> usize {
+    let mut log = LOG_BUF.lock();
+    let len = log.copy_from(log.end.saturating_sub(buf.len()).max(log.clear), buf);
+    if clear {
+        log.clear = log.end;
+    }
+    len
+}
+
+/// Hide the bytes in the buffer from `read_all`.
+pub fn clear() {
+    let mut log = LOG_BUF.lock();
+    log.clear = log.end;
+}
+
+/// Number of bytes not read yet.
+pub fn unread() -> usize {
+    let log = LOG_BUF.lock();
+    log.end - log.read.max(log.start())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_into_buffer() {
+        let mut all = [0; LOG_BUF_LEN];
+        read(&mut all);
+        clear();
+        record(format_args!("hello {}\n", "world"));
+
+        let mut buf = [0; 5];
+        assert_eq!(read(&mut buf), 5);
+        assert_eq!(&buf, b"hello");
+        assert_eq!(unread(), 7);
+
+        let mut buf = [0; 32];
+        assert_eq!(read_all(&mut buf, true), 12);
+        assert_eq!(&buf[..12], b"hello world\n");
+        assert_eq!(read_all(&mut buf, false), 0);
+        assert_eq!(read(&mut buf), 7);
+        assert_eq!(&buf[..7], b" world\n");
+        assert_eq!(unread(), 0);
+    }
+
+    #[test]
+    fn overwrite_oldest() {
+        let mut all = [0; LOG_BUF_LEN];
+        read(&mut all);
+        clear();
+        record(format_args!("{:1$}end", "", LOG_BUF_LEN));
+
+        let mut buf = [0; 3];
+        assert_eq!(read_all(&mut buf, false), 3);
+        assert_eq!(&buf, b"end");
+        // the reader fell behind and lost the first 3 bytes
+        assert_eq!(unread(), LOG_BUF_LEN);
+        assert_eq!(read(&mut all), LOG_BUF_LEN);
+        assert_eq!(&all[LOG_BUF_LEN - 3..], b"end");
+    }
+}
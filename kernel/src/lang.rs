@@ -1,14 +1,52 @@
+use crate::{consts::MAX_CPU_NUM, sync::PerCpu};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Set on a CPU once it panicked.
+static IN_PANIC: PerCpu<AtomicBool> = {
+    const NO: AtomicBool = AtomicBool::new(false);
+    PerCpu::new([NO; MAX_CPU_NUM])
+};
+
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    unsafe { crate::arch::interrupt::disable() };
+    let cpu_id = crate::cpu::id();
+    if IN_PANIC.get(cpu_id).swap(true, Ordering::SeqCst) {
+        // panicked while reporting a panic, the console or the stack may be broken
+        crate::logging::_print_unlocked(format_args!(
+            "\nKernel panic on CPU{} while panicking: {}\n",
+            cpu_id, info
+        ));
+        crate::cpu::wait_forever();
+    }
+
+    crate::arch::interrupt::halt_others();
+    if !crate::logging::has_console() {
+        // early boot, `println!` could wait for a lock the panic left held
+        crate::logging::_print_unlocked(format_args!(
+            "\nKernel panic on CPU{} during boot: {}\n",
+            cpu_id, info
+        ));
+        crate::cpu::wait_forever();
+    }
+    // the panic may have come with the console lock held, never wait for it
     if let Some(args) = info.message() {
-        println!("\nKernel panic: {}", args);
+        crate::logging::_print_unlocked(format_args!(
+            "\nKernel panic on CPU{}: {}\n",
+            cpu_id, args
+        ));
     } else {
-        println!("\nKernel panic!");
+        crate::logging::_print_unlocked(format_args!("\nKernel panic on CPU{}!\n", cpu_id));
+    }
+    if let Some(location) = info.location() {
+        crate::logging::_print_unlocked(format_args!("at {}\n", location));
     }
-    // crate::backtrace::backtrace();
+    crate::backtrace::backtrace();
     crate::cpu::wait_forever();
 }
 
+#[cfg(not(test))]
 #[lang = "oom"]
 fn oom(_: core::alloc::Layout) -> ! {
     panic!("out of memory");
@@ -1,4 +1,5 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
 #![feature(lang_items)]
 #![feature(panic_info_message)]
 #![feature(format_args_nl)]
@@ -22,6 +23,8 @@ mod lang;
 #[path = "arch/aarch64/mod.rs"]
 pub mod arch;
 mod backtrace;
+pub mod cmdline;
+pub mod klog;
 pub mod consts;
 pub mod drivers;
 pub mod fs;
@@ -1,9 +1,15 @@
-use crate::sync::spin::MutexNoIrq;
-use core::fmt::{self, Write};
+use crate::{arch::bsp::uart::panic_uart, drivers::SerialDriver, sync::spin::MutexNoIrq};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
 use log::{Level, LevelFilter, Log};
 
 static LOG_LOCK: MutexNoIrq<()> = MutexNoIrq::new(());
 
+/// Whether log records are colored by level with ANSI escape sequences.
+static COLOR: AtomicBool = AtomicBool::new(true);
+
 pub fn init() {
     static LOGGER: SimpleLogger = SimpleLogger;
     log::set_logger(&LOGGER).unwrap();
@@ -17,10 +23,42 @@ pub fn init() {
     });
 }
 
+/// Color the log records by level, or print them plain for consoles without
+/// ANSI escape sequences. A serial line cannot tell whether a terminal is on the
+/// other end, so the colors stay on unless disabled.
+pub fn set_color(enabled: bool) {
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     let _guard = LOG_LOCK.lock();
-    crate::arch::bsp::uart::uart().write_fmt(args).unwrap();
+    match crate::drivers::console() {
+        Some(console) => ConsoleWriter(console).write_fmt(args).unwrap(),
+        // fall back to the boot UART before any console is registered
+        None => panic_uart().write_fmt(args).unwrap(),
+    }
+}
+
+/// Print straight to the boot UART without taking any lock, for a CPU that may
+/// have panicked while holding one, or before the console is registered.
+#[doc(hidden)]
+pub fn _print_unlocked(args: fmt::Arguments) {
+    let _ = panic_uart().write_fmt(args);
+}
+
+/// Whether a console is registered.
+pub fn has_console() -> bool {
+    crate::drivers::console().is_some()
+}
+
+struct ConsoleWriter<'a>(&'a dyn SerialDriver);
+
+impl Write for ConsoleWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
 }
 
 /// Prints without a newline.
@@ -67,7 +105,11 @@ macro_rules! with_color {
 }
 
 fn print_with_color(args: fmt::Arguments, color_code: u8) {
-    _print(with_color!(args, color_code));
+    if COLOR.load(Ordering::Relaxed) {
+        _print(with_color!(args, color_code));
+    } else {
+        _print(args);
+    }
 }
 
 struct SimpleLogger;
@@ -81,11 +123,18 @@ impl Log for SimpleLogger {
         if !self.enabled(record.metadata()) {
             return;
         }
+        let cpu_id = crate::arch::cpu::id();
+        crate::klog::record(format_args!(
+            "[{:<5}][CPU-{}]: {}\n",
+            record.level(),
+            cpu_id,
+            record.args()
+        ));
         print_with_color(
             format_args!(
                 "[{:<5}][CPU-{}]: {}\n",
                 record.level(),
-                crate::arch::cpu::id(),
+                cpu_id,
                 record.args()
             ),
             level_to_color_code(record.level()),
@@ -0,0 +1,107 @@
+//! Page aging, the basis of a page replacement policy.
+//!
+//! A kernel task woken by the timer sweeps the user pages of every process, a
+//! bounded batch at a time since the page table is locked with IRQs off. A page
+//! accessed since the previous sweep gets young again and has its accessed bit
+//! cleared, so that the next access faults to set it. A page not accessed grows
+//! older, and after `INACTIVE_AGE` sweeps it is inactive: the pages a swap
+//! implementation would evict first.
+
+use super::VirtAddr;
+use crate::{process::PROCESSES, task::delay_for};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::time::Duration;
+
+/// Sweeps without an access after which a page is inactive.
+pub const INACTIVE_AGE: u8 = 4;
+/// Pages looked at per step of the sweep.
+const SWEEP_BATCH: usize = 256;
+/// Time between two steps of the sweep.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Ages of the pages of an address space, in sweeps since their last access.
+#[derive(Debug, Default)]
+pub struct PageAges {
+    ages: BTreeMap<VirtAddr, u8>,
+    /// Where the next step of the sweep starts.
+    pub(super) cursor: VirtAddr,
+}
+
+impl PageAges {
+    /// Age of the page at `addr`, `None` if it was not swept since it was mapped.
+    pub fn age(&self, addr: VirtAddr) -> Option<u8> {
+        self.ages.get(&addr).copied()
+    }
+
+    /// Pages accessed during the last `INACTIVE_AGE` sweeps, with their age.
+    pub fn active(&self) -> impl Iterator<Item = (VirtAddr, u8)> + '_ {
+        self.iter().filter(|&(_, age)| age < INACTIVE_AGE)
+    }
+
+    /// Pages not accessed for `INACTIVE_AGE` sweeps, with their age.
+    pub fn inactive(&self) -> impl Iterator<Item = (VirtAddr, u8)> + '_ {
+        self.iter().filter(|&(_, age)| age >= INACTIVE_AGE)
+    }
+
+    /// Up to `count` inactive pages, the oldest first.
+    pub fn victims(&self, count: usize) -> Vec<VirtAddr> {
+        let mut pages: Vec<_> = self.inactive().collect();
+        pages.sort_by(|a, b| b.1.cmp(&a.1));
+        pages
+            .into_iter()
+            .take(count)
+            .map(|(addr, _)| addr)
+            .collect()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (VirtAddr, u8)> + '_ {
+        self.ages.iter().map(|(&addr, &age)| (addr, age))
+    }
+
+    /// Record a sweep of the page at `addr`.
+    pub(super) fn sweep(&mut self, addr: VirtAddr, accessed: bool) {
+        let age = self.ages.entry(addr).or_insert(0);
+        *age = if accessed { 0 } else { age.saturating_add(1) };
+    }
+
+    /// Drop the pages of `start..end`, no longer mapped.
+    pub(super) fn forget(&mut self, start: VirtAddr, end: VirtAddr) {
+        let stale: Vec<_> = self.ages.range(start..end).map(|(&addr, _)| addr).collect();
+        for addr in stale {
+            self.ages.remove(&addr);
+        }
+    }
+}
+
+/// Start the sweep.
+pub fn init() {
+    crate::task::spawn(sweep()).detach();
+}
+
+async fn sweep() {
+    // the process being swept
+    let mut pid = 0;
+    loop {
+        delay_for(SWEEP_INTERVAL).await;
+        let next = {
+            let processes = PROCESSES.read();
+            processes
+                .range(pid..)
+                .next()
+                .or_else(|| processes.iter().next())
+                .map(|(&pid, process)| (pid, process.clone()))
+        };
+        let (next_pid, process) = match next {
+            Some(next) => next,
+            None => continue,
+        };
+        let vm = process.lock().vm.clone();
+        let swept = vm.lock().age_pages(SWEEP_BATCH);
+        // move on once the whole address space was swept
+        pid = if swept < SWEEP_BATCH {
+            next_pid + 1
+        } else {
+            next_pid
+        };
+    }
+}
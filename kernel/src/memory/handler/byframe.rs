@@ -10,16 +10,22 @@ impl<T: FrameAllocator> MemoryHandler for ByFrame<T> {
         Box::new(self.clone())
     }
 
+    fn is_anonymous(&self) -> bool {
+        true
+    }
+
     fn map(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) {
         let target = self.allocator.alloc(1).expect("failed to allocate frame");
+        unsafe { core::ptr::write_bytes(phys_to_virt(target) as *mut u8, 0, PAGE_SIZE) };
         let entry = pt.map(addr, target);
         attr.apply(entry);
     }
 
     fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
         let target = pt.get_entry(addr).expect("fail to get entry").target();
-        self.allocator.dealloc(target, 1);
+        // the other CPUs have dropped the mapping once `unmap` returns
         pt.unmap(addr);
+        self.allocator.dealloc(target, 1);
     }
 
     fn clone_map(
@@ -34,7 +40,7 @@ impl<T: FrameAllocator> MemoryHandler for ByFrame<T> {
         pt.get_page_slice_mut(addr).copy_from_slice(data);
     }
 
-    fn handle_page_fault(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> bool {
+    fn handle_page_fault(&self, _pt: &mut dyn PageTable, _addr: VirtAddr, _access: Access) -> bool {
         false
     }
 }
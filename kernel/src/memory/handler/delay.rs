@@ -1,4 +1,5 @@
 use super::*;
+use crate::memory::swap::{slot_to_target, swap_device, target_to_slot};
 
 #[derive(Debug, Clone)]
 pub struct Delay<T: FrameAllocator> {
@@ -10,6 +11,10 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
         Box::new(self.clone())
     }
 
+    fn is_anonymous(&self) -> bool {
+        true
+    }
+
     fn map(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) {
         let entry = pt.map(addr, 0);
         entry.set_present(false);
@@ -18,13 +23,46 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
 
     fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
         let entry = pt.get_entry(addr).expect("failed to get entry");
-        if entry.present() {
-            self.allocator.dealloc(entry.target(), 1);
+        let target = (entry.present() && entry.target() != zero_frame()).then(|| entry.target());
+        if entry.swapped() {
+            swap_device()
+                .expect("swapped page without swap")
+                .free_slot(target_to_slot(entry.target()));
+            entry.set_swapped(false);
         }
 
         // PageTable::unmap requires page to be present
         entry.set_present(true);
+        // the other CPUs have dropped the mapping once `unmap` returns
         pt.unmap(addr);
+        if let Some(target) = target {
+            self.allocator.dealloc(target, 1);
+        }
+    }
+
+    fn protect_range(
+        &self,
+        pt: &mut dyn PageTable,
+        start: VirtAddr,
+        end: VirtAddr,
+        attr: &MemoryAttr,
+    ) {
+        for page in Page::range_of(start, end) {
+            let entry = pt
+                .get_entry(page.start_address())
+                .expect("failed to get entry");
+            entry.set_user(attr.user);
+            entry.set_execute(attr.execute);
+            entry.set_mmio(attr.mmio);
+            if entry.present() && entry.target() == zero_frame() {
+                // the zero frame stays read-only until the first write
+                entry.set_shared(!attr.readonly);
+                entry.set_writable(false);
+            } else {
+                entry.set_writable(!attr.readonly);
+            }
+            entry.update();
+        }
     }
 
     fn clone_map(
@@ -35,7 +73,17 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
         attr: &MemoryAttr,
     ) {
         let entry = src_pt.get_entry(addr).expect("failed to get entry");
-        if entry.present() {
+        if entry.swapped() {
+            // both share the slot until either reads it back
+            let target = entry.target();
+            swap_device()
+                .expect("swapped page without swap")
+                .dup_slot(target_to_slot(target));
+            let entry = pt.map(addr, target);
+            entry.set_present(false);
+            entry.set_swapped(true);
+            attr.apply(entry);
+        } else if entry.present() && entry.target() != zero_frame() {
             // eager map and copy data
             let data = src_pt.get_page_slice_mut(addr);
             let target = self.allocator.alloc(1).expect("failed to alloc frame");
@@ -49,23 +97,125 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
         }
     }
 
-    fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> bool {
+    fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: VirtAddr, access: Access) -> bool {
         let entry = pt.get_entry(addr).expect("failed to get entry");
-        if entry.present() {
+        if entry.swapped() {
+            // read back by `swap_in`, without the page table locked
+            return false;
+        }
+        if !entry.present() && !access.is_write() {
+            // read the zero frame until the first write, remembering whether
+            // the page may be written
+            let writable = entry.writable();
+            entry.set_target(zero_frame());
+            entry.set_shared(writable);
+            entry.set_writable(false);
+            entry.set_present(true);
+            entry.update();
+            return true;
+        }
+        let copy_on_write =
+            entry.present() && entry.writable_shared() && entry.target() == zero_frame();
+        if entry.present() && !copy_on_write {
             // not a delay case
             return false;
         }
+        // init with zero for delay mmap mode, before the frame is visible
         let frame = self.allocator.alloc(1).expect("failed to alloc frame");
+        unsafe { core::ptr::write_bytes(phys_to_virt(frame) as *mut u8, 0, PAGE_SIZE) };
+        if copy_on_write {
+            // break before make
+            entry.set_present(false);
+            entry.update();
+            entry.clear_shared();
+            entry.set_writable(true);
+        }
         entry.set_target(frame);
         entry.set_present(true);
         entry.update();
-        //init with zero for delay mmap mode
-        let data = pt.get_page_slice_mut(addr);
-        let len = data.len();
-        for x in data {
-            *x = 0;
+        pt.flush_cache_copy_user(addr, addr + PAGE_SIZE, false);
+        true
+    }
+
+    fn swap_out(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> Option<PhysAddr> {
+        let entry = pt.get_entry(addr)?;
+        if !entry.present() || entry.target() == zero_frame() {
+            return None;
+        }
+        // nobody writes the page while it is written out
+        entry.set_present(false);
+        entry.update();
+        Some(entry.target())
+    }
+
+    fn swap_out_done(
+        &self,
+        pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        frame: PhysAddr,
+        slot: Option<usize>,
+    ) {
+        let swap = swap_device().expect("swapped page without swap");
+        let entry = pt
+            .get_entry(addr)
+            .filter(|entry| !entry.present() && !entry.swapped() && entry.target() == frame);
+        match (entry, slot) {
+            (Some(entry), Some(slot)) => {
+                entry.set_swapped(true);
+                entry.set_target(slot_to_target(slot));
+                entry.update();
+                self.allocator.dealloc(frame, 1);
+            }
+            (Some(entry), None) => {
+                entry.set_present(true);
+                entry.update();
+            }
+            (None, slot) => {
+                // unmapped while written out
+                if let Some(slot) = slot {
+                    swap.free_slot(slot);
+                }
+                self.allocator.dealloc(frame, 1);
+            }
+        }
+    }
+
+    fn swap_in(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> Option<(usize, PhysAddr)> {
+        let entry = pt.get_entry(addr)?;
+        if !entry.swapped() {
+            return None;
         }
-        pt.flush_cache_copy_user(addr, addr + len, false);
+        let frame = self.allocator.alloc(1)?;
+        Some((target_to_slot(entry.target()), frame))
+    }
+
+    fn swap_in_done(
+        &self,
+        pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        slot: usize,
+        frame: PhysAddr,
+        read: bool,
+    ) -> bool {
+        let entry = match pt.get_entry(addr) {
+            Some(entry) if read && entry.swapped() && entry.target() == slot_to_target(slot) => {
+                entry
+            }
+            // the page stays swapped out, or was unmapped and its slot freed meanwhile
+            _ => {
+                self.allocator.dealloc(frame, 1);
+                return false;
+            }
+        };
+        swap_device()
+            .expect("swapped page without swap")
+            .free_slot(slot);
+        let execute = entry.execute();
+        entry.set_swapped(false);
+        entry.set_target(frame);
+        entry.set_present(true);
+        entry.update();
+        pt.flush_cache_copy_user(addr, addr + PAGE_SIZE, execute);
         true
     }
 }
@@ -75,3 +225,113 @@ impl<T: FrameAllocator> Delay<T> {
         Delay { allocator }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory::mock::{MockFrameAlloc, MockPageTable},
+        sync::MutexNoIrq,
+    };
+    use alloc::vec::Vec;
+
+    const ADDR: VirtAddr = 0x1000;
+
+    static FREED: MutexNoIrq<Vec<PhysAddr>> = MutexNoIrq::new(Vec::new());
+
+    /// Hands out the frames freed before new ones, like the buddy allocator.
+    #[derive(Debug, Clone)]
+    struct Recycle;
+
+    impl FrameAllocator for Recycle {
+        fn alloc(&self, count: usize) -> Option<usize> {
+            assert_eq!(count, 1);
+            FREED.lock().pop().or_else(|| MockFrameAlloc.alloc(1))
+        }
+
+        fn alloc_aligned(&self, _count: usize, _align_log2: usize) -> Option<usize> {
+            None
+        }
+
+        fn dealloc(&self, frame: usize, count: usize) {
+            assert_eq!(count, 1);
+            FREED.lock().push(frame);
+        }
+    }
+
+    #[test]
+    fn protect_zero_frame() {
+        let delay = Delay::new(MockFrameAlloc);
+        let mut pt = MockPageTable::new();
+        let attr = MemoryAttr::default().user();
+        delay.map(&mut pt, ADDR, &attr);
+        assert!(delay.handle_page_fault(&mut pt, ADDR, Access::Read));
+        assert_eq!(pt.entry(ADDR).target, zero_frame());
+
+        let updates = pt.entry(ADDR).updates;
+        delay.protect_range(&mut pt, ADDR, ADDR + PAGE_SIZE, &attr.execute());
+        let entry = pt.entry(ADDR);
+        // never writable in between
+        assert_eq!(entry.updates, updates + 1);
+        assert!(!entry.writable && entry.writable_shared && entry.execute);
+
+        delay.protect_range(&mut pt, ADDR, ADDR + PAGE_SIZE, &attr.readonly());
+        let entry = pt.entry(ADDR);
+        assert!(!entry.writable && entry.readonly_shared);
+        assert!(!delay.handle_page_fault(&mut pt, ADDR, Access::Write));
+
+        delay.protect_range(&mut pt, ADDR, ADDR + PAGE_SIZE, &attr);
+        assert!(delay.handle_page_fault(&mut pt, ADDR, Access::Write));
+        let entry = pt.entry(ADDR);
+        assert!(entry.writable && entry.target != zero_frame());
+        assert_eq!(pt.read(ADDR), 0);
+        delay.unmap(&mut pt, ADDR);
+    }
+
+    #[test]
+    fn protect_written_page() {
+        let delay = Delay::new(MockFrameAlloc);
+        let mut pt = MockPageTable::new();
+        let attr = MemoryAttr::default().user();
+        delay.map(&mut pt, ADDR, &attr);
+        assert!(delay.handle_page_fault(&mut pt, ADDR, Access::Write));
+        pt.write(ADDR, 42);
+
+        delay.protect_range(&mut pt, ADDR, ADDR + PAGE_SIZE, &attr.readonly());
+        assert!(!pt.entry(ADDR).writable);
+        delay.protect_range(&mut pt, ADDR, ADDR + PAGE_SIZE, &attr);
+        assert!(pt.entry(ADDR).writable);
+        assert_eq!(pt.read(ADDR), 42);
+        delay.unmap(&mut pt, ADDR);
+    }
+
+    #[test]
+    fn fresh_page_zeroed() {
+        let delay = Delay::new(Recycle);
+        let mut pt = MockPageTable::new();
+        let attr = MemoryAttr::default().user();
+        delay.map(&mut pt, ADDR, &attr);
+        assert!(delay.handle_page_fault(&mut pt, ADDR, Access::Write));
+        let frame = pt.entry(ADDR).target;
+        pt.get_page_slice_mut(ADDR).fill(0x5a);
+        delay.unmap(&mut pt, ADDR);
+
+        // the dirty frame is handed out again, by a write fault or on the write
+        // after a read
+        for &first in &[Access::Write, Access::Read] {
+            let page = phys_to_virt(frame) as *const u8;
+            let data = unsafe { core::slice::from_raw_parts(page, PAGE_SIZE) };
+            assert!(data.iter().all(|&byte| byte == 0x5a));
+            delay.map(&mut pt, ADDR, &attr);
+            assert!(delay.handle_page_fault(&mut pt, ADDR, first));
+            assert!(pt.get_page_slice_mut(ADDR).iter().all(|&byte| byte == 0));
+            if pt.entry(ADDR).target == zero_frame() {
+                assert!(delay.handle_page_fault(&mut pt, ADDR, Access::Write));
+            }
+            assert_eq!(pt.entry(ADDR).target, frame);
+            assert!(pt.get_page_slice_mut(ADDR).iter().all(|&byte| byte == 0));
+            pt.get_page_slice_mut(ADDR).fill(0x5a);
+            delay.unmap(&mut pt, ADDR);
+        }
+    }
+}
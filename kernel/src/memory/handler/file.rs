@@ -28,13 +28,15 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
 
     fn unmap(&self, pt: &mut dyn PageTable, addr: usize) {
         let entry = pt.get_entry(addr).expect("failed to get entry");
-        if entry.present() {
-            self.allocator.dealloc(entry.target(), 1);
-        }
+        let target = entry.present().then(|| entry.target());
 
         // PageTable::unmap requires page to be present
         entry.set_present(true);
+        // the other CPUs have dropped the mapping once `unmap` returns
         pt.unmap(addr);
+        if let Some(target) = target {
+            self.allocator.dealloc(target, 1);
+        }
     }
 
     fn clone_map(
@@ -59,7 +61,7 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
         }
     }
 
-    fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: usize) -> bool {
+    fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: usize, _access: Access) -> bool {
         let addr = addr & !(PAGE_SIZE - 1);
         let entry = pt.get_entry(addr).expect("failed to get entry");
         if entry.present() {
@@ -0,0 +1,210 @@
+use super::*;
+
+/// Frames per 2MiB block
+const HUGE_PAGE_FRAMES: usize = HUGE_PAGE_SIZE / PAGE_SIZE;
+
+/// Zero-filled memory allocated on map, like `ByFrame`.
+///
+/// Every aligned 2MiB block inside the mapped range is mapped by a single entry
+/// when the allocator has such a block free, the rest by pages. A block partly
+/// unmapped is split into pages first. Pages no frame is left for are allocated
+/// on the first access instead, the page fault fails if there is still none.
+#[derive(Debug, Clone)]
+pub struct ByHugeFrame<T: FrameAllocator> {
+    allocator: T,
+}
+
+impl<T: FrameAllocator> MemoryHandler for ByHugeFrame<T> {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+
+    fn is_anonymous(&self) -> bool {
+        true
+    }
+
+    fn map(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) {
+        match self.allocator.alloc(1) {
+            Some(target) => {
+                zero_frames(target, PAGE_SIZE);
+                let entry = pt.map(addr, target);
+                attr.apply(entry);
+            }
+            None => {
+                let entry = pt.map(addr, 0);
+                entry.set_present(false);
+                attr.apply(entry);
+            }
+        }
+    }
+
+    fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        if pt.get_entry_2mib(addr).is_some() {
+            pt.split_2mib(addr);
+        }
+        let entry = pt.get_entry(addr).expect("fail to get entry");
+        let target = entry.present().then(|| entry.target());
+        // PageTable::unmap requires page to be present
+        entry.set_present(true);
+        // the other CPUs have dropped the mapping once `unmap` returns
+        pt.unmap(addr);
+        if let Some(target) = target {
+            self.allocator.dealloc(target, 1);
+        }
+    }
+
+    fn map_range(&self, pt: &mut dyn PageTable, start: VirtAddr, end: VirtAddr, attr: &MemoryAttr) {
+        let mut addr = start;
+        while addr < end {
+            if addr % HUGE_PAGE_SIZE == 0
+                && addr + HUGE_PAGE_SIZE <= end
+                && self.map_huge(pt, addr, attr)
+            {
+                addr += HUGE_PAGE_SIZE;
+            } else {
+                self.map(pt, addr, attr);
+                addr += PAGE_SIZE;
+            }
+        }
+    }
+
+    fn unmap_range(&self, pt: &mut dyn PageTable, start: VirtAddr, end: VirtAddr) {
+        let mut addr = start;
+        while addr < end {
+            if addr % HUGE_PAGE_SIZE == 0 && addr + HUGE_PAGE_SIZE <= end {
+                if let Some(target) = pt.get_entry_2mib(addr).map(|entry| entry.target()) {
+                    pt.unmap_2mib(addr);
+                    self.allocator.dealloc(target, HUGE_PAGE_FRAMES);
+                    addr += HUGE_PAGE_SIZE;
+                    continue;
+                }
+            }
+            self.unmap(pt, addr);
+            addr += PAGE_SIZE;
+        }
+    }
+
+    fn protect_range(
+        &self,
+        pt: &mut dyn PageTable,
+        start: VirtAddr,
+        end: VirtAddr,
+        attr: &MemoryAttr,
+    ) {
+        let mut addr = start;
+        while addr < end {
+            if let Some(entry) = pt.get_entry_2mib(addr) {
+                if addr % HUGE_PAGE_SIZE == 0 && addr + HUGE_PAGE_SIZE <= end {
+                    attr.apply(entry);
+                    addr += HUGE_PAGE_SIZE;
+                    continue;
+                }
+                pt.split_2mib(addr);
+            }
+            if let Some(entry) = pt.get_entry(addr) {
+                attr.apply(entry);
+            }
+            addr += PAGE_SIZE;
+        }
+    }
+
+    fn clone_map(
+        &self,
+        pt: &mut dyn PageTable,
+        src_pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        attr: &MemoryAttr,
+    ) {
+        let src_block = src_pt.get_entry_2mib(addr).map(|entry| entry.target());
+        match src_block {
+            // the whole block is copied along with its first page
+            Some(_) if addr % HUGE_PAGE_SIZE != 0 => {}
+            Some(src) => {
+                if self.map_huge(pt, addr, attr) {
+                    let target = pt.get_entry_2mib(addr).unwrap().target();
+                    copy_frames(target, src, HUGE_PAGE_SIZE);
+                } else {
+                    for offset in (0..HUGE_PAGE_SIZE).step_by(PAGE_SIZE) {
+                        let target = self.allocator.alloc(1).expect("failed to allocate frame");
+                        copy_frames(target, src + offset, PAGE_SIZE);
+                        let entry = pt.map(addr + offset, target);
+                        attr.apply(entry);
+                    }
+                }
+                pt.flush_cache_copy_user(addr, addr + HUGE_PAGE_SIZE, attr.execute);
+            }
+            None if src_pt
+                .get_entry(addr)
+                .map_or(false, |entry| entry.present()) =>
+            {
+                let target = self.allocator.alloc(1).expect("failed to allocate frame");
+                let entry = pt.map(addr, target);
+                attr.apply(entry);
+                let data = src_pt.get_page_slice_mut(addr);
+                pt.get_page_slice_mut(addr).copy_from_slice(data);
+            }
+            // not allocated yet, neither is the copy
+            None => self.map(pt, addr, attr),
+        }
+    }
+
+    /// Allocate a page `map` had no frame for.
+    fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: VirtAddr, _access: Access) -> bool {
+        let entry = match pt.get_entry(addr) {
+            Some(entry) if !entry.present() => entry,
+            _ => return false,
+        };
+        let target = match self.allocator.alloc(1) {
+            Some(target) => target,
+            None => return false,
+        };
+        zero_frames(target, PAGE_SIZE);
+        let execute = entry.execute();
+        entry.set_target(target);
+        entry.set_present(true);
+        entry.update();
+        pt.flush_cache_copy_user(addr, addr + PAGE_SIZE, execute);
+        true
+    }
+}
+
+impl<T: FrameAllocator> ByHugeFrame<T> {
+    pub fn new(allocator: T) -> Self {
+        ByHugeFrame { allocator }
+    }
+
+    /// Map the 2MiB block at `addr` by a single entry.
+    /// Return false if no aligned block is free or `addr` already has pages mapped.
+    fn map_huge(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) -> bool {
+        let align_log2 = HUGE_PAGE_SIZE.trailing_zeros() as usize;
+        let target = match self.allocator.alloc_aligned(HUGE_PAGE_FRAMES, align_log2) {
+            Some(target) => target,
+            None => return false,
+        };
+        zero_frames(target, HUGE_PAGE_SIZE);
+        match pt.map_2mib(addr, target) {
+            Some(entry) => {
+                attr.apply(entry);
+                true
+            }
+            None => {
+                self.allocator.dealloc(target, HUGE_PAGE_FRAMES);
+                false
+            }
+        }
+    }
+}
+
+fn zero_frames(target: PhysAddr, len: usize) {
+    unsafe { core::ptr::write_bytes(phys_to_virt(target) as *mut u8, 0, len) };
+}
+
+fn copy_frames(target: PhysAddr, src: PhysAddr, len: usize) {
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            phys_to_virt(src) as *const u8,
+            phys_to_virt(target) as *mut u8,
+            len,
+        )
+    };
+}
@@ -30,7 +30,7 @@ impl MemoryHandler for Linear {
         self.map(pt, addr, attr);
     }
 
-    fn handle_page_fault(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> bool {
+    fn handle_page_fault(&self, _pt: &mut dyn PageTable, _addr: VirtAddr, _access: Access) -> bool {
         false
     }
 }
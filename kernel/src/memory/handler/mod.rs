@@ -5,11 +5,13 @@ use core::fmt::Debug;
 mod byframe;
 mod delay;
 pub mod file;
+mod huge;
 mod linear;
 
 pub use byframe::ByFrame;
 pub use delay::Delay;
 pub use file::File;
+pub use huge::ByHugeFrame;
 pub use linear::Linear;
 
 pub trait MemoryHandler: Debug + Send + Sync + 'static {
@@ -22,6 +24,35 @@ pub trait MemoryHandler: Debug + Send + Sync + 'static {
     /// Unmap `addr` in the page table
     fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr);
 
+    /// Map the pages of `[start, end)` in the page table
+    fn map_range(&self, pt: &mut dyn PageTable, start: VirtAddr, end: VirtAddr, attr: &MemoryAttr) {
+        for page in Page::range_of(start, end) {
+            self.map(pt, page.start_address(), attr);
+        }
+    }
+
+    /// Unmap the pages of `[start, end)` in the page table
+    fn unmap_range(&self, pt: &mut dyn PageTable, start: VirtAddr, end: VirtAddr) {
+        for page in Page::range_of(start, end) {
+            self.unmap(pt, page.start_address());
+        }
+    }
+
+    /// Apply the new `attr` to the pages of `[start, end)` in the page table
+    fn protect_range(
+        &self,
+        pt: &mut dyn PageTable,
+        start: VirtAddr,
+        end: VirtAddr,
+        attr: &MemoryAttr,
+    ) {
+        for page in Page::range_of(start, end) {
+            if let Some(entry) = pt.get_entry(page.start_address()) {
+                attr.apply(entry);
+            }
+        }
+    }
+
     /// Clone map `addr` from page table `src_pt` to `pt`.
     fn clone_map(
         &self,
@@ -31,9 +62,54 @@ pub trait MemoryHandler: Debug + Send + Sync + 'static {
         attr: &MemoryAttr,
     );
 
-    /// Handle page fault on `addr`
+    /// Handle page fault on `addr`, caused by `access`
     /// Return true if success, false if error
-    fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> bool;
+    fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: VirtAddr, access: Access) -> bool;
+
+    /// Whether the pages are private memory not backed by a file or the kernel,
+    /// which read as zeros once dropped.
+    fn is_anonymous(&self) -> bool {
+        false
+    }
+
+    /// Take the frame of the page at `addr` to be written out to the swap, the
+    /// page is not present until `swap_out_done`.
+    /// Return `None` if the page cannot be swapped out.
+    fn swap_out(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> Option<PhysAddr> {
+        None
+    }
+
+    /// Finish swapping out the page at `addr`: free `frame` if it was written
+    /// to `slot`, put it back otherwise. The page may have been unmapped
+    /// meanwhile, then both are freed.
+    fn swap_out_done(
+        &self,
+        _pt: &mut dyn PageTable,
+        _addr: VirtAddr,
+        _frame: PhysAddr,
+        _slot: Option<usize>,
+    ) {
+        unreachable!("no page to swap out")
+    }
+
+    /// Take a frame to read the swapped out page at `addr` into.
+    /// Return `(slot, frame)`, `None` if the page is not swapped out.
+    fn swap_in(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> Option<(usize, PhysAddr)> {
+        None
+    }
+
+    /// Finish reading the page at `addr` from `slot` into `frame`, `read` tells
+    /// whether it succeeded. Return `true` if the page is present again.
+    fn swap_in_done(
+        &self,
+        _pt: &mut dyn PageTable,
+        _addr: VirtAddr,
+        _slot: usize,
+        _frame: PhysAddr,
+        _read: bool,
+    ) -> bool {
+        unreachable!("no page to swap in")
+    }
 }
 
 impl Clone for Box<dyn MemoryHandler> {
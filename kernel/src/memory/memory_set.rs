@@ -1,8 +1,14 @@
-use super::*;
-use alloc::{boxed::Box, vec::Vec};
+use super::{
+    aging::PageAges,
+    swap::{low_on_frames, swap_device, SWAP_BATCH},
+    *,
+};
+use crate::sync::MutexGuardNoIrq;
+use alloc::{boxed::Box, collections::BTreeSet, string::String, vec::Vec};
 use core::{
     fmt::{Debug, Error, Formatter},
-    mem::size_of,
+    hint::spin_loop,
+    mem::{align_of, size_of},
 };
 
 /// A continuous memory space with the same attribute
@@ -13,6 +19,8 @@ pub struct MemoryArea {
     attr: MemoryAttr,
     handler: Box<dyn MemoryHandler>,
     name: &'static str,
+    /// Owned by the kernel, like the time page: user space can not change it
+    fixed: bool,
 }
 
 impl MemoryArea {
@@ -21,30 +29,6 @@ impl MemoryArea {
         addr >= self.start_addr && addr < self.end_addr
     }
 
-    /// Check the array is within the readable memory.
-    /// Return the size of space covered in the area.
-    fn check_read_array<S>(&self, ptr: *const S, count: usize) -> usize {
-        // page align
-        let min_bound = (ptr as usize).max(Page::of_addr(self.start_addr).start_address());
-        let max_bound = unsafe { ptr.add(count) as usize }
-            .min(Page::of_addr(self.end_addr + Page::SIZE - 1).start_address());
-        if max_bound >= min_bound {
-            max_bound - min_bound
-        } else {
-            0
-        }
-    }
-
-    /// Check the array is within the writable memory.
-    /// Return the size of space covered in the area.
-    fn check_write_array<S>(&self, ptr: *mut S, count: usize) -> usize {
-        if self.attr.readonly {
-            0
-        } else {
-            self.check_read_array(ptr, count)
-        }
-    }
-
     /// Test whether this area is (page) overlap with area [`start_addr`, `end_addr`)
     pub fn is_overlap_with(&self, start_addr: VirtAddr, end_addr: VirtAddr) -> bool {
         let p0 = Page::of_addr(self.start_addr);
@@ -56,16 +40,13 @@ impl MemoryArea {
 
     /// Map all pages in the area to page table `pt`
     fn map(&self, pt: &mut dyn PageTable) {
-        for page in Page::range_of(self.start_addr, self.end_addr) {
-            self.handler.map(pt, page.start_address(), &self.attr);
-        }
+        self.handler
+            .map_range(pt, self.start_addr, self.end_addr, &self.attr);
     }
 
     /// Unmap all pages in the area from page table `pt`
     fn unmap(&self, pt: &mut dyn PageTable) {
-        for page in Page::range_of(self.start_addr, self.end_addr) {
-            self.handler.unmap(pt, page.start_address());
-        }
+        self.handler.unmap_range(pt, self.start_addr, self.end_addr);
     }
 }
 
@@ -114,10 +95,44 @@ impl MemoryAttr {
     }
 }
 
+/// Totals of a `MemorySet`, in pages.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MemorySummary {
+    pub areas: usize,
+    /// Pages covered by the areas
+    pub mapped: usize,
+    /// Pages backed by a frame
+    pub resident: usize,
+    /// Pages written out to the swap
+    pub swapped: usize,
+}
+
 /// A set of memory space with multiple memory areas with associated page table
 pub struct MemorySet<T: PageTableExt> {
     areas: Vec<MemoryArea>,
     page_table: T,
+    ages: PageAges,
+    /// `(tid, start, end)` of the user memory checked by the syscalls in flight,
+    /// which the kernel accesses without the lock: never swapped out
+    pins: Vec<(usize, VirtAddr, VirtAddr)>,
+    /// Pages read from or written to the swap with the lock released
+    transit: BTreeSet<VirtAddr>,
+}
+
+/// A page being written out to the swap.
+struct SwapOut {
+    addr: VirtAddr,
+    frame: PhysAddr,
+    slot: usize,
+    handler: Box<dyn MemoryHandler>,
+}
+
+/// A swapped out page being read back.
+struct SwapIn {
+    addr: VirtAddr,
+    slot: usize,
+    frame: PhysAddr,
+    handler: Box<dyn MemoryHandler>,
 }
 
 impl<T: PageTableExt> Default for MemorySet<T> {
@@ -125,11 +140,27 @@ impl<T: PageTableExt> Default for MemorySet<T> {
         MemorySet {
             areas: Vec::new(),
             page_table: T::new(),
+            ages: PageAges::default(),
+            pins: Vec::new(),
+            transit: BTreeSet::new(),
         }
     }
 }
 
 impl<T: PageTableExt> MemorySet<T> {
+    /// Total size of all areas in bytes.
+    pub fn size(&self) -> usize {
+        self.areas
+            .iter()
+            .map(|area| area.end_addr - area.start_addr)
+            .sum()
+    }
+
+    /// End address of the highest area, `0` if there is none.
+    pub fn end_addr(&self) -> VirtAddr {
+        self.areas.last().map_or(0, |area| area.end_addr)
+    }
+
     /// Create a new `MemorySet`
     #[inline]
     pub fn new() -> Self {
@@ -141,53 +172,50 @@ impl<T: PageTableExt> MemorySet<T> {
         MemorySet {
             areas: Vec::new(),
             page_table: T::new_bare(),
+            ages: PageAges::default(),
+            pins: Vec::new(),
+            transit: BTreeSet::new(),
         }
     }
 
-    /// Check the pointer is within the readable memory
-    /// # Safety
-    pub unsafe fn check_read_ptr<S>(&self, ptr: *const S) -> VmResult<&'static S> {
-        self.check_read_array(ptr, 1).map(|s| &s[0])
+    /// Whether every page of `[addr, end)` is covered by user areas, and writable
+    /// if `write` is set.
+    fn is_user(&self, addr: VirtAddr, end: VirtAddr, write: bool) -> bool {
+        // areas are sorted and never overlap, walk them from `addr` to `end`
+        let mut cur = addr;
+        while cur < end {
+            match self.areas.iter().find(|area| area.contains(cur)) {
+                Some(area) if area.attr.user && !(write && area.attr.readonly) => {
+                    cur = area.end_addr
+                }
+                _ => return false,
+            }
+        }
+        true
     }
 
-    /// Check the pointer is within the writable memory
-    /// # Safety
-    pub unsafe fn check_write_ptr<S>(&self, ptr: *mut S) -> VmResult<&'static mut S> {
-        self.check_write_array(ptr, 1).map(|s| &mut s[0])
+    /// Whether the page at `addr` can be accessed by the kernel without a fault.
+    fn is_present(&mut self, addr: VirtAddr, write: bool) -> bool {
+        match self.page_table.get_entry(addr) {
+            Some(entry) => entry.present() && (!write || entry.writable()),
+            None => self.page_table.get_entry_2mib(addr).is_some(),
+        }
     }
 
-    /// Check the array is within the readable memory
-    /// # Safety
-    pub unsafe fn check_read_array<S>(
-        &self,
-        ptr: *const S,
-        count: usize,
-    ) -> VmResult<&'static [S]> {
-        let mut valid_size = 0;
-        for area in self.areas.iter() {
-            valid_size += area.check_read_array(ptr, count);
-            if valid_size == size_of::<S>() * count {
-                return Ok(core::slice::from_raw_parts(ptr, count));
-            }
-        }
-        Err(VmError::InvalidPtr)
+    /// Keep the pages of `[start, end)` from being swapped out until `unpin(tid)`.
+    pub fn pin(&mut self, tid: usize, start: VirtAddr, end: VirtAddr) {
+        self.pins.push((tid, start, end));
     }
 
-    /// Check the array is within the writable memory
-    /// # Safety
-    pub unsafe fn check_write_array<S>(
-        &self,
-        ptr: *mut S,
-        count: usize,
-    ) -> VmResult<&'static mut [S]> {
-        let mut valid_size = 0;
-        for area in self.areas.iter() {
-            valid_size += area.check_write_array(ptr, count);
-            if valid_size == size_of::<S>() * count {
-                return Ok(core::slice::from_raw_parts_mut(ptr, count));
-            }
-        }
-        Err(VmError::InvalidPtr)
+    /// Drop the pins of thread `tid`, once its syscall returned.
+    pub fn unpin(&mut self, tid: usize) {
+        self.pins.retain(|&(owner, _, _)| owner != tid);
+    }
+
+    fn is_pinned(&self, addr: VirtAddr) -> bool {
+        self.pins
+            .iter()
+            .any(|&(_, start, end)| start < addr + PAGE_SIZE && addr < end)
     }
 
     /// Find a free area with hint address `addr_hint` and length `len`.
@@ -204,7 +232,7 @@ impl<T: PageTableExt> MemorySet<T> {
     }
 
     /// Test if [`start_addr`, `end_addr`) is a free area
-    fn test_free_area(&self, start_addr: usize, end_addr: usize) -> bool {
+    pub fn test_free_area(&self, start_addr: usize, end_addr: usize) -> bool {
         self.areas
             .iter()
             .find(|area| area.is_overlap_with(start_addr, end_addr))
@@ -236,6 +264,7 @@ impl<T: PageTableExt> MemorySet<T> {
             attr,
             handler: Box::new(handler),
             name,
+            fixed: false,
         };
         area.map(&mut self.page_table);
         // keep order by start address
@@ -249,6 +278,22 @@ impl<T: PageTableExt> MemorySet<T> {
         self.areas.insert(idx, area);
     }
 
+    /// Like `push`, for an area owned by the kernel which user space can neither
+    /// protect nor discard.
+    pub fn push_fixed(
+        &mut self,
+        start_addr: VirtAddr,
+        end_addr: VirtAddr,
+        attr: MemoryAttr,
+        handler: impl MemoryHandler,
+        name: &'static str,
+    ) {
+        self.push(start_addr, end_addr, attr, handler, name);
+        if let Some(area) = self.areas.iter_mut().find(|area| area.contains(start_addr)) {
+            area.fixed = true;
+        }
+    }
+
     /// Remove the area `[start_addr, end_addr)` from `MemorySet`
     pub fn pop(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) {
         assert!(start_addr <= end_addr, "invalid memory area");
@@ -285,6 +330,7 @@ impl<T: PageTableExt> MemorySet<T> {
                         attr: area.attr,
                         handler: area.handler.box_clone(),
                         name: area.name,
+                        fixed: area.fixed,
                     };
                     dead_area.unmap(&mut self.page_table);
                     let new_area = MemoryArea {
@@ -293,6 +339,7 @@ impl<T: PageTableExt> MemorySet<T> {
                         attr: area.attr,
                         handler: area.handler,
                         name: area.name,
+                        fixed: area.fixed,
                     };
                     self.areas.insert(i, new_area);
                 } else if self.areas[i].end_addr <= end_addr && self.areas[i].end_addr > start_addr
@@ -305,6 +352,7 @@ impl<T: PageTableExt> MemorySet<T> {
                         attr: area.attr,
                         handler: area.handler.box_clone(),
                         name: area.name,
+                        fixed: area.fixed,
                     };
                     dead_area.unmap(&mut self.page_table);
                     let new_area = MemoryArea {
@@ -313,6 +361,7 @@ impl<T: PageTableExt> MemorySet<T> {
                         attr: area.attr,
                         handler: area.handler,
                         name: area.name,
+                        fixed: area.fixed,
                     };
                     self.areas.insert(i, new_area);
                 } else {
@@ -324,6 +373,7 @@ impl<T: PageTableExt> MemorySet<T> {
                         attr: area.attr,
                         handler: area.handler.box_clone(),
                         name: area.name,
+                        fixed: area.fixed,
                     };
                     dead_area.unmap(&mut self.page_table);
                     let new_area_left = MemoryArea {
@@ -332,6 +382,7 @@ impl<T: PageTableExt> MemorySet<T> {
                         attr: area.attr,
                         handler: area.handler.box_clone(),
                         name: area.name,
+                        fixed: area.fixed,
                     };
                     self.areas.insert(i, new_area_left);
                     let new_area_right = MemoryArea {
@@ -340,6 +391,7 @@ impl<T: PageTableExt> MemorySet<T> {
                         attr: area.attr,
                         handler: area.handler,
                         name: area.name,
+                        fixed: area.fixed,
                     };
                     self.areas.insert(i + 1, new_area_right);
                     i += 1;
@@ -349,11 +401,172 @@ impl<T: PageTableExt> MemorySet<T> {
         }
     }
 
+    /// Change the attribute of `[start_addr, end_addr)` by `update`, splitting the
+    /// areas partly covered.
+    /// Return `false` without changing anything if part of the range is not mapped,
+    /// or is owned by the kernel.
+    pub fn protect(
+        &mut self,
+        start_addr: VirtAddr,
+        end_addr: VirtAddr,
+        update: impl Fn(&mut MemoryAttr),
+    ) -> bool {
+        if !self.is_mapped(start_addr, end_addr) || self.is_fixed(start_addr, end_addr) {
+            return false;
+        }
+        self.split_at(start_addr);
+        self.split_at(end_addr);
+
+        let Self {
+            ref mut page_table,
+            ref mut areas,
+            ..
+        } = self;
+        let covered = areas
+            .iter_mut()
+            .filter(|area| area.start_addr >= start_addr && area.end_addr <= end_addr);
+        for area in covered {
+            let old_attr = area.attr;
+            update(&mut area.attr);
+            if area.attr == old_attr {
+                continue;
+            }
+            area.handler
+                .protect_range(page_table, area.start_addr, area.end_addr, &area.attr);
+            if area.attr.execute && !old_attr.execute {
+                // the instructions may have been written through the D-cache
+                for page in Page::range_of(area.start_addr, area.end_addr) {
+                    let addr = page.start_address();
+                    let present = match page_table.get_entry(addr) {
+                        Some(entry) => entry.present(),
+                        None => page_table.get_entry_2mib(addr).is_some(),
+                    };
+                    if present {
+                        page_table.flush_cache_copy_user(addr, addr + PAGE_SIZE, true);
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Drop the content of the anonymous pages in `[start_addr, end_addr)`, keeping
+    /// the areas. The pages are unmapped and mapped again by their handlers, so they
+    /// read as zeros on the next access.
+    /// Return `false` without changing anything if part of the range is not mapped,
+    /// or is mapped by an area which is not anonymous or is owned by the kernel.
+    pub fn discard(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) -> bool {
+        let foreign = self.areas.iter().any(|area| {
+            area.is_overlap_with(start_addr, end_addr)
+                && (!area.handler.is_anonymous() || area.fixed)
+        });
+        if !self.is_mapped(start_addr, end_addr) || foreign {
+            return false;
+        }
+        let Self {
+            ref mut page_table,
+            ref areas,
+            ..
+        } = self;
+        for area in areas.iter() {
+            let start = area.start_addr.max(start_addr);
+            let end = area.end_addr.min(end_addr);
+            if start < end {
+                area.handler.unmap_range(page_table, start, end);
+                area.handler.map_range(page_table, start, end, &area.attr);
+            }
+        }
+        true
+    }
+
+    /// Whether every page of `[start_addr, end_addr)` is in an area.
+    pub fn is_mapped(&self, start_addr: VirtAddr, end_addr: VirtAddr) -> bool {
+        // areas are sorted and never overlap, walk them from `start_addr` to `end_addr`
+        let mut cur = start_addr;
+        while cur < end_addr {
+            match self.areas.iter().find(|area| area.contains(cur)) {
+                Some(area) => cur = area.end_addr,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Whether part of `[start_addr, end_addr)` is in an area owned by the kernel.
+    pub fn is_fixed(&self, start_addr: VirtAddr, end_addr: VirtAddr) -> bool {
+        start_addr < end_addr
+            && self
+                .areas
+                .iter()
+                .any(|area| area.fixed && area.is_overlap_with(start_addr, end_addr))
+    }
+
+    /// Split the area containing `addr` in two at `addr`, the pages stay mapped.
+    fn split_at(&mut self, addr: VirtAddr) {
+        let i = match self
+            .areas
+            .iter()
+            .position(|area| area.start_addr < addr && addr < area.end_addr)
+        {
+            Some(i) => i,
+            None => return,
+        };
+        let area = &mut self.areas[i];
+        let right = MemoryArea {
+            start_addr: addr,
+            end_addr: area.end_addr,
+            attr: area.attr,
+            handler: area.handler.box_clone(),
+            name: area.name,
+            fixed: area.fixed,
+        };
+        area.end_addr = addr;
+        self.areas.insert(i + 1, right);
+    }
+
     /// Get iterator of areas
     pub fn iter(&self) -> impl Iterator<Item = &MemoryArea> {
         self.areas.iter()
     }
 
+    /// `(start, end, name, attr)` of every area, sorted by address.
+    pub fn areas(&self) -> Vec<(VirtAddr, VirtAddr, &'static str, MemoryAttr)> {
+        self.areas
+            .iter()
+            .map(|area| (area.start_addr, area.end_addr, area.name, area.attr))
+            .collect()
+    }
+
+    /// Count the pages of the areas, walking the page table.
+    pub fn summary(&mut self) -> MemorySummary {
+        let mut summary = MemorySummary {
+            areas: self.areas.len(),
+            ..MemorySummary::default()
+        };
+        for area in self.areas.iter() {
+            let mut addr = area.start_addr & !(PAGE_SIZE - 1);
+            while addr < area.end_addr {
+                if self.page_table.get_entry_2mib(addr).is_some() {
+                    let pages = HUGE_PAGE_SIZE / PAGE_SIZE;
+                    summary.mapped += pages;
+                    summary.resident += pages;
+                    addr += HUGE_PAGE_SIZE;
+                    continue;
+                }
+                summary.mapped += 1;
+                if let Some(entry) = self.page_table.get_entry(addr) {
+                    if entry.present() {
+                        summary.resident += 1;
+                    } else if entry.swapped() {
+                        summary.swapped += 1;
+                    }
+                }
+                addr += PAGE_SIZE;
+            }
+        }
+        summary
+    }
+
     /// Execute function `f` with the associated page table
     /// # Safety
     pub unsafe fn with(&self, f: impl FnOnce()) {
@@ -400,14 +613,192 @@ impl<T: PageTableExt> MemorySet<T> {
         &mut self.page_table
     }
 
-    pub fn handle_page_fault(&mut self, addr: VirtAddr) -> bool {
+    /// Handle a page fault at `addr`, caused by `access`.
+    ///
+    /// Swapped out pages are left to `UserMemory::fault_in`, which reads them
+    /// back without the lock.
+    pub fn handle_page_fault(&mut self, addr: VirtAddr, access: Access) -> bool {
+        if self.mark_accessed(addr) {
+            return true;
+        }
         let area = self.areas.iter().find(|area| area.contains(addr));
         match area {
-            Some(area) => area.handler.handle_page_fault(&mut self.page_table, addr),
+            Some(area) => area
+                .handler
+                .handle_page_fault(&mut self.page_table, addr, access),
             None => false,
         }
     }
 
+    /// Set the accessed bit of the page at `addr`, cleared by the aging sweep.
+    /// Return `false` if the page is not mapped or was accessed already.
+    fn mark_accessed(&mut self, addr: VirtAddr) -> bool {
+        let entry = if self.page_table.get_entry_2mib(addr).is_some() {
+            self.page_table.get_entry_2mib(addr)
+        } else {
+            self.page_table.get_entry(addr)
+        };
+        match entry {
+            Some(entry) if entry.present() && !entry.accessed() => {
+                entry.set_accessed();
+                entry.update();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Age up to `budget` pages of the user areas, resuming where the previous call
+    /// stopped. A 2MiB block ages as a whole, under the address of its first page.
+    /// Return the number of pages looked at, less than `budget` once the sweep
+    /// reached the end of the address space: the next call starts over.
+    pub fn age_pages(&mut self, budget: usize) -> usize {
+        let Self {
+            ref mut page_table,
+            ref areas,
+            ref mut ages,
+        } = self;
+        let mut swept = 0;
+        let mut cursor = ages.cursor;
+        for area in areas
+            .iter()
+            .filter(|area| area.attr.user && area.end_addr > cursor)
+        {
+            let mut addr = area.start_addr.max(cursor);
+            ages.forget(cursor, addr);
+            while addr < area.end_addr {
+                if swept == budget {
+                    ages.cursor = addr;
+                    return swept;
+                }
+                swept += 1;
+                if let Some(entry) = page_table.get_entry_2mib(addr) {
+                    let accessed = entry.accessed();
+                    if accessed {
+                        entry.clear_accessed();
+                        entry.update();
+                    }
+                    ages.sweep(addr, accessed);
+                    addr += HUGE_PAGE_SIZE;
+                    continue;
+                }
+                match page_table.get_entry(addr) {
+                    Some(entry) if entry.present() => {
+                        let accessed = entry.accessed();
+                        if accessed {
+                            entry.clear_accessed();
+                            entry.update();
+                        }
+                        ages.sweep(addr, accessed);
+                    }
+                    _ => ages.forget(addr, addr + PAGE_SIZE),
+                }
+                addr += PAGE_SIZE;
+            }
+            cursor = area.end_addr;
+        }
+        ages.forget(cursor, usize::MAX);
+        ages.cursor = 0;
+        swept
+    }
+
+    /// Take the frames of up to `count` inactive pages, the oldest first, with a
+    /// swap slot each to write them to. Pinned pages are skipped.
+    fn take_victims(&mut self, count: usize) -> Vec<SwapOut> {
+        let swap = match swap_device() {
+            Some(swap) => swap,
+            None => return Vec::new(),
+        };
+        let mut victims = Vec::new();
+        for addr in self.ages.victims(count) {
+            if self.is_pinned(addr) || self.transit.contains(&addr) {
+                continue;
+            }
+            let area = match self.areas.iter().find(|area| area.contains(addr)) {
+                Some(area) => area,
+                None => continue,
+            };
+            let slot = match swap.alloc_slot() {
+                Some(slot) => slot,
+                None => break,
+            };
+            match area.handler.swap_out(&mut self.page_table, addr) {
+                Some(frame) => {
+                    self.ages.forget(addr, addr + PAGE_SIZE);
+                    self.transit.insert(addr);
+                    victims.push(SwapOut {
+                        addr,
+                        frame,
+                        slot,
+                        handler: area.handler.box_clone(),
+                    });
+                }
+                None => swap.free_slot(slot),
+            }
+        }
+        victims
+    }
+
+    /// Start reading back the page at `addr` if it is swapped out.
+    fn take_swapped(&mut self, addr: VirtAddr) -> Option<SwapIn> {
+        let area = self.areas.iter().find(|area| area.contains(addr))?;
+        let (slot, frame) = area.handler.swap_in(&mut self.page_table, addr)?;
+        self.transit.insert(addr);
+        Some(SwapIn {
+            addr,
+            slot,
+            frame,
+            handler: area.handler.box_clone(),
+        })
+    }
+
+    /// Ages of the pages swept so far.
+    pub fn page_ages(&self) -> &PageAges {
+        &self.ages
+    }
+
+    /// Grow the areas ending at `top` downwards to cover `addr`, e.g. a stack.
+    ///
+    /// The new pages are mapped by the handler of the lowest area, the total size is
+    /// bounded by `limit` and the gap below the areas must be free.
+    /// Return `true` if the page fault at `addr` is handled.
+    pub fn grow_down(
+        &mut self,
+        top: VirtAddr,
+        limit: usize,
+        addr: VirtAddr,
+        access: Access,
+    ) -> bool {
+        let lowest = {
+            let mut lowest = None;
+            let mut bottom = top;
+            while let Some(i) = self.areas.iter().position(|area| area.end_addr == bottom) {
+                lowest = Some(i);
+                bottom = self.areas[i].start_addr;
+            }
+            match lowest {
+                Some(i) => i,
+                None => return false,
+            }
+        };
+        let new_start = addr & !(PAGE_SIZE - 1);
+        let bottom = self.areas[lowest].start_addr;
+        if addr >= bottom || top - new_start > limit || !self.test_free_area(new_start, bottom) {
+            return false;
+        }
+
+        let Self {
+            ref mut page_table,
+            ref mut areas,
+            ..
+        } = self;
+        let area = &mut areas[lowest];
+        area.handler
+            .map_range(page_table, new_start, bottom, &area.attr);
+        area.start_addr = new_start;
+        self.handle_page_fault(addr, access)
+    }
+
     pub fn clone(&mut self) -> Self {
         let mut new_page_table = T::new();
         let Self {
@@ -428,6 +819,199 @@ impl<T: PageTableExt> MemorySet<T> {
         MemorySet {
             areas: areas.clone(),
             page_table: new_page_table,
+            ages: PageAges::default(),
+            pins: Vec::new(),
+            transit: BTreeSet::new(),
+        }
+    }
+}
+
+/// Access to the user memory of a locked `MemorySet`, the lock is released while
+/// pages are read from or written to the swap.
+pub trait UserMemory {
+    /// Check the pointer is within the readable memory
+    /// # Safety
+    unsafe fn check_read_ptr<S>(&mut self, ptr: *const S) -> VmResult<&'static S> {
+        self.check_read_array(ptr, 1).map(|s| &s[0])
+    }
+
+    /// Check the pointer is within the writable memory
+    /// # Safety
+    unsafe fn check_write_ptr<S>(&mut self, ptr: *mut S) -> VmResult<&'static mut S> {
+        self.check_write_array(ptr, 1).map(|s| &mut s[0])
+    }
+
+    /// Check the array is within the readable memory
+    /// # Safety
+    unsafe fn check_read_array<S>(
+        &mut self,
+        ptr: *const S,
+        count: usize,
+    ) -> VmResult<&'static [S]> {
+        if self.check_array(ptr as usize, count, size_of::<S>(), align_of::<S>(), false)? {
+            Ok(core::slice::from_raw_parts(ptr, count))
+        } else {
+            Ok(&[])
+        }
+    }
+
+    /// Check the null-terminated string at `ptr` is within the readable memory, and
+    /// copy it. Fail if no null is found in the first `max_len` bytes.
+    fn check_read_cstr(&mut self, ptr: *const u8, max_len: usize) -> VmResult<String> {
+        let mut bytes = Vec::new();
+        let mut addr = ptr as usize;
+        while bytes.len() < max_len {
+            // a page at a time, the string may end before an unmapped page
+            let len = (PAGE_SIZE - addr % PAGE_SIZE).min(max_len - bytes.len());
+            let chunk = unsafe { self.check_read_array(addr as *const u8, len)? };
+            if let Some(end) = chunk.iter().position(|&byte| byte == 0) {
+                bytes.extend_from_slice(&chunk[..end]);
+                return Ok(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            bytes.extend_from_slice(chunk);
+            addr = addr.checked_add(len).ok_or(VmError::InvalidPtr)?;
+        }
+        Err(VmError::InvalidPtr)
+    }
+
+    /// Check the array is within the writable memory
+    /// # Safety
+    unsafe fn check_write_array<S>(
+        &mut self,
+        ptr: *mut S,
+        count: usize,
+    ) -> VmResult<&'static mut [S]> {
+        if self.check_array(ptr as usize, count, size_of::<S>(), align_of::<S>(), true)? {
+            Ok(core::slice::from_raw_parts_mut(ptr, count))
+        } else {
+            Ok(&mut [])
+        }
+    }
+
+    /// Check every page of `[addr, addr + count * size)` is covered by user areas,
+    /// and writable if `write` is set, then fault in the pages not mapped yet so that
+    /// the kernel can access them. The pages are pinned until the syscall of the
+    /// current thread returns.
+    /// Return `false` if the range is empty.
+    fn check_array(
+        &mut self,
+        addr: VirtAddr,
+        count: usize,
+        size: usize,
+        align: usize,
+        write: bool,
+    ) -> VmResult<bool>;
+
+    /// Handle a page fault at `addr`, caused by `access`. Swapped out pages are
+    /// read back, and inactive pages swapped out first if frames run short.
+    fn fault_in(&mut self, addr: VirtAddr, access: Access) -> bool;
+
+    /// Swap out up to `count` inactive pages, the oldest first.
+    /// Return the number of pages swapped out.
+    fn swap_out_inactive(&mut self, count: usize) -> usize;
+
+    /// Wait for the pages being read from or written to the swap, e.g. before
+    /// cloning the memory set.
+    fn wait_swap_io(&mut self);
+}
+
+impl<T: PageTableExt> UserMemory for MutexGuardNoIrq<'_, MemorySet<T>> {
+    fn check_array(
+        &mut self,
+        addr: VirtAddr,
+        count: usize,
+        size: usize,
+        align: usize,
+        write: bool,
+    ) -> VmResult<bool> {
+        let len = count.checked_mul(size).ok_or(VmError::InvalidPtr)?;
+        // reject ranges wrapping around the address space
+        let end = addr.checked_add(len).ok_or(VmError::InvalidPtr)?;
+        if len == 0 {
+            return Ok(false);
+        }
+        if addr == 0 || addr % align != 0 || !self.is_user(addr, end, write) {
+            return Err(VmError::InvalidPtr);
+        }
+        // the kernel accesses the pages without the lock, keep them in memory
+        if let Some(thread) = crate::process::thread::current_thread() {
+            self.pin(thread.tid, addr, end);
+        }
+        let access = if write { Access::Write } else { Access::Read };
+        for page in Page::range_of(addr, end) {
+            let addr = page.start_address();
+            if !self.is_present(addr, write) && !self.fault_in(addr, access) {
+                return Err(VmError::InvalidPtr);
+            }
+        }
+        Ok(true)
+    }
+
+    fn fault_in(&mut self, addr: VirtAddr, access: Access) -> bool {
+        if self.mark_accessed(addr) {
+            return true;
+        }
+        if low_on_frames() {
+            self.swap_out_inactive(SWAP_BATCH);
+        }
+        let page = addr & !(PAGE_SIZE - 1);
+        // another CPU is reading or writing the page
+        while self.transit.contains(&page) {
+            MutexGuardNoIrq::unlocked(self, spin_loop);
+        }
+        if let Some(swap_in) = self.take_swapped(page) {
+            let swap = swap_device().expect("swapped page without swap");
+            let read = MutexGuardNoIrq::unlocked(self, || {
+                swap.read_page(swap_in.slot, swap_in.frame).is_ok()
+            });
+            if !read {
+                warn!("failed to read swap slot {}", swap_in.slot);
+            }
+            self.transit.remove(&page);
+            return swap_in.handler.swap_in_done(
+                &mut self.page_table,
+                page,
+                swap_in.slot,
+                swap_in.frame,
+                read,
+            );
+        }
+        self.handle_page_fault(addr, access)
+    }
+
+    fn swap_out_inactive(&mut self, count: usize) -> usize {
+        let victims = self.take_victims(count);
+        let swap = match swap_device() {
+            Some(swap) if !victims.is_empty() => swap,
+            _ => return 0,
+        };
+        let written: Vec<bool> = MutexGuardNoIrq::unlocked(self, || {
+            victims
+                .iter()
+                .map(|victim| swap.write_page(victim.slot, victim.frame).is_ok())
+                .collect()
+        });
+        let mut swapped = 0;
+        for (victim, written) in victims.into_iter().zip(written) {
+            self.transit.remove(&victim.addr);
+            let slot = if written {
+                swapped += 1;
+                Some(victim.slot)
+            } else {
+                warn!("failed to write swap slot {}", victim.slot);
+                swap.free_slot(victim.slot);
+                None
+            };
+            victim
+                .handler
+                .swap_out_done(&mut self.page_table, victim.addr, victim.frame, slot);
+        }
+        swapped
+    }
+
+    fn wait_swap_io(&mut self) {
+        while !self.transit.is_empty() {
+            MutexGuardNoIrq::unlocked(self, spin_loop);
         }
     }
 }
@@ -443,3 +1027,254 @@ impl<T: PageTableExt> Debug for MemorySet<T> {
         f.debug_list().entries(self.areas.iter()).finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory::{
+            aging::INACTIVE_AGE,
+            handler::{Delay, File, Linear},
+            mock::{mock_swap, MockFile, MockFrameAlloc, MockPageTable},
+        },
+        sync::MutexNoIrq,
+    };
+
+    type MockSet = MemorySet<MockPageTable>;
+
+    #[test]
+    fn discard_anonymous() {
+        let mut ms = MockSet::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x4000, attr, Delay::new(MockFrameAlloc), "anon");
+        for addr in (0x1000..0x4000).step_by(PAGE_SIZE) {
+            assert!(ms.handle_page_fault(addr, Access::Write));
+            ms.get_page_table_mut().write(addr, 1);
+        }
+
+        // a part of the area
+        assert!(ms.discard(0x2000, 0x3000));
+        let pt = ms.get_page_table_mut();
+        assert!(!pt.entry(0x2000).present);
+        assert_eq!(pt.read(0x1000), 1);
+        assert_eq!(pt.read(0x3000), 1);
+        assert!(ms.handle_page_fault(0x2000, Access::Read));
+        assert_eq!(ms.get_page_table_mut().read(0x2000), 0);
+
+        // past the end of the area
+        assert!(!ms.is_mapped(0x3000, 0x5000));
+        assert!(!ms.discard(0x3000, 0x5000));
+        assert_eq!(ms.get_page_table_mut().read(0x3000), 1);
+    }
+
+    #[test]
+    fn discard_file_mapping() {
+        let mut ms = MockSet::new();
+        let attr = MemoryAttr::default().user();
+        let file = File {
+            file: MockFile {
+                byte: 7,
+                len: PAGE_SIZE,
+            },
+            mem_start: 0x1000,
+            file_start: 0,
+            file_end: PAGE_SIZE,
+            allocator: MockFrameAlloc,
+        };
+        ms.push(0x1000, 0x2000, attr, file, "file");
+        ms.push(0x2000, 0x3000, attr, Delay::new(MockFrameAlloc), "anon");
+        assert!(ms.handle_page_fault(0x1000, Access::Read));
+        assert!(ms.handle_page_fault(0x2000, Access::Write));
+        ms.get_page_table_mut().write(0x2000, 1);
+
+        assert!(ms.is_mapped(0x1000, 0x3000));
+        assert!(!ms.discard(0x1000, 0x3000));
+        let pt = ms.get_page_table_mut();
+        assert_eq!(pt.read(0x1000), 7);
+        assert_eq!(pt.read(0x2000), 1);
+    }
+
+    #[test]
+    fn fixed_area() {
+        let mut ms = MockSet::new();
+        let attr = MemoryAttr::default().user().readonly();
+        ms.push(0x1000, 0x2000, attr, Delay::new(MockFrameAlloc), "anon");
+        ms.push_fixed(0x2000, 0x3000, attr, Linear::new(0), "time_page");
+        assert!(!ms.is_fixed(0x1000, 0x2000));
+        assert!(ms.is_fixed(0x1000, 0x3000));
+        assert!(!ms.is_fixed(0x2000, 0x2000));
+
+        assert!(!ms.protect(0x1000, 0x3000, |attr| attr.readonly = false));
+        assert!(!ms.discard(0x2000, 0x3000));
+        assert!(ms.areas().iter().all(|&(_, _, _, attr)| attr.readonly));
+        assert!(!ms.get_page_table_mut().entry(0x2000).writable);
+        // the areas next to it can still be changed
+        assert!(ms.protect(0x1000, 0x2000, |attr| attr.readonly = false));
+        assert!(ms.discard(0x1000, 0x2000));
+    }
+
+    #[test]
+    fn swap_round_trip() {
+        let swap = mock_swap();
+        let (_, used) = swap.stats();
+        let vm = MutexNoIrq::new(MockSet::new());
+        let mut ms = vm.lock();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x4000, attr, Delay::new(MockFrameAlloc), "anon");
+        for (i, addr) in (0x1000..0x4000).step_by(PAGE_SIZE).enumerate() {
+            assert!(ms.handle_page_fault(addr, Access::Write));
+            ms.get_page_table_mut().write(addr, i as u8 + 1);
+        }
+        for _ in 0..INACTIVE_AGE {
+            ms.age_pages(usize::MAX);
+        }
+
+        // a syscall in flight accesses the last page
+        ms.pin(1, 0x3000, 0x3001);
+        assert_eq!(ms.swap_out_inactive(16), 2);
+        assert_eq!(swap.stats().1, used + 2);
+        let pt = ms.get_page_table_mut();
+        assert!(pt.entry(0x1000).swapped && pt.entry(0x2000).swapped);
+        assert!(pt.entry(0x3000).present);
+        ms.unpin(1);
+        // accessed again, no victim of the next page faults
+        assert!(ms.mark_accessed(0x3000));
+        ms.age_pages(usize::MAX);
+
+        // the child shares the slots
+        let child = MutexNoIrq::new(ms.clone());
+        assert_eq!(swap.stats().1, used + 2);
+        assert!(ms.fault_in(0x1000, Access::Read));
+        assert!(ms.fault_in(0x2000, Access::Write));
+        let pt = ms.get_page_table_mut();
+        assert_eq!(pt.read(0x1000), 1);
+        assert_eq!(pt.read(0x2000), 2);
+        pt.write(0x2000, 42);
+        assert_eq!(swap.stats().1, used + 2);
+        drop(ms);
+
+        let mut guard = child.lock();
+        assert!(guard.fault_in(0x2000, Access::Read));
+        assert_eq!(guard.get_page_table_mut().read(0x2000), 2);
+        assert_eq!(guard.get_page_table_mut().read(0x3000), 3);
+        assert_eq!(swap.stats().1, used + 1);
+        drop(guard);
+        drop(child);
+        // unmapping frees the slot of the page still swapped out
+        assert_eq!(swap.stats().1, used);
+    }
+
+    #[test]
+    fn check_array_faults_in_swapped_pages() {
+        mock_swap();
+        let vm = MutexNoIrq::new(MockSet::new());
+        let mut ms = vm.lock();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x2000, attr, Delay::new(MockFrameAlloc), "anon");
+        assert!(ms.handle_page_fault(0x1000, Access::Write));
+        ms.get_page_table_mut().write(0x1000, 7);
+        for _ in 0..INACTIVE_AGE {
+            ms.age_pages(usize::MAX);
+        }
+        assert_eq!(ms.swap_out_inactive(16), 1);
+
+        let buf = unsafe { ms.check_read_array(0x1000 as *const u8, 16) };
+        assert!(matches!(buf, Ok(buf) if buf.len() == 16));
+        assert_eq!(ms.get_page_table_mut().read(0x1000), 7);
+        assert!(unsafe { ms.check_write_ptr(0x2000 as *mut u8) }.is_err());
+    }
+
+    #[test]
+    fn check_array_bounds() {
+        let vm = MutexNoIrq::new(MockSet::new());
+        let mut ms = vm.lock();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x3000, attr, Delay::new(MockFrameAlloc), "anon");
+        let readonly = attr.readonly();
+        ms.push(0x3000, 0x4000, readonly, Delay::new(MockFrameAlloc), "ro");
+        let mut check = |addr: usize, count: usize, write: bool| {
+            let result = if write {
+                unsafe { ms.check_write_array(addr as *mut u32, count) }.map(|buf| buf.len())
+            } else {
+                unsafe { ms.check_read_array(addr as *const u32, count) }.map(|buf| buf.len())
+            };
+            result.map_err(|_| ())
+        };
+
+        assert_eq!(check(0x1000, 0x800, true), Ok(0x800));
+        assert_eq!(check(0x1000, 0xc00, false), Ok(0xc00));
+        assert_eq!(check(0x1000, 0, true), Ok(0));
+        // addr + len wraps around, or count * size overflows
+        assert_eq!(check(0x1000, usize::MAX / 4, false), Err(()));
+        assert_eq!(check(0x1000, usize::MAX / 2, false), Err(()));
+        assert_eq!(check(usize::MAX - 3, 2, false), Err(()));
+        // past the areas, or writing a read-only one
+        assert_eq!(check(0x1000, 0xc01, false), Err(()));
+        assert_eq!(check(0x2ffc, 2, true), Err(()));
+        assert_eq!(check(0x1002, 1, false), Err(()));
+    }
+
+    #[test]
+    fn check_read_cstr() {
+        use crate::memory::FrameAllocator;
+        // user pages at the address of host frames, the strings are read in place
+        let frames = MockFrameAlloc.alloc(2).unwrap();
+        let end = frames + 2 * PAGE_SIZE;
+        let vm = MutexNoIrq::new(MockSet::new());
+        let mut ms = vm.lock();
+        let attr = MemoryAttr::default().user();
+        ms.push(frames, end, attr, Linear::new(0), "strings");
+        let bytes = unsafe { core::slice::from_raw_parts_mut(frames as *mut u8, end - frames) };
+        bytes.fill(b'a');
+
+        // across the two pages
+        let ptr = (frames + PAGE_SIZE - 3) as *const u8;
+        bytes[PAGE_SIZE + 2] = 0;
+        assert!(matches!(ms.check_read_cstr(ptr, 16), Ok(s) if s == "aaaaa"));
+        assert!(ms.check_read_cstr(ptr, 5).is_err());
+        // no null before the end of the area
+        bytes[PAGE_SIZE + 2] = b'a';
+        assert!(ms.check_read_cstr(ptr, 2 * PAGE_SIZE).is_err());
+        assert!(ms.check_read_cstr(core::ptr::null(), 16).is_err());
+
+        MockFrameAlloc.dealloc(frames, 2);
+    }
+
+    #[test]
+    fn grow_down() {
+        const TOP: VirtAddr = 0x10_0000;
+        let stack = |ms: &MockSet| {
+            ms.iter()
+                .find(|area| area.end_addr == TOP)
+                .unwrap()
+                .start_addr
+        };
+        let mut ms = MockSet::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(TOP - 0x4000, TOP, attr, Delay::new(MockFrameAlloc), "stack");
+        // another area farther down
+        ms.push(0xf_0000, 0xf_1000, attr, Delay::new(MockFrameAlloc), "gap");
+
+        // within the limit, the pages skipped are mapped too
+        assert!(ms.grow_down(TOP, 0x8000, TOP - 0x5ff0, Access::Write));
+        assert_eq!(stack(&ms), TOP - 0x6000);
+        assert!(ms.get_page_table_mut().entry(TOP - 0x6000).present);
+        assert!(ms.handle_page_fault(TOP - 0x5000, Access::Read));
+        // not below the stack
+        assert!(!ms.grow_down(TOP, 0x8000, TOP - 0x10, Access::Write));
+
+        // past the limit
+        assert!(!ms.grow_down(TOP, 0x8000, TOP - 0x8010, Access::Write));
+        assert_eq!(stack(&ms), TOP - 0x6000);
+        assert!(ms.grow_down(TOP, 0x8000, TOP - 0x8000, Access::Write));
+
+        // into the gap
+        assert!(!ms.grow_down(TOP, 0x10_0000, 0xf_0ff0, Access::Write));
+        assert_eq!(stack(&ms), TOP - 0x8000);
+        assert!(ms.grow_down(TOP, 0x10_0000, 0xf_1000, Access::Write));
+        assert_eq!(stack(&ms), 0xf_1000);
+
+        // no area ends at the top
+        assert!(!ms.grow_down(TOP + PAGE_SIZE, 0x10_0000, 0xe_0000, Access::Write));
+    }
+}
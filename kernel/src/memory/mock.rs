@@ -0,0 +1,329 @@
+//! Page table and frames in host memory, for testing the memory handlers.
+
+use super::{
+    swap::{self, swap_device, SwapDevice},
+    *,
+};
+use crate::{
+    drivers::{
+        self,
+        block::{BlockDevice, BLOCK_SIZE},
+        DeviceType, Driver,
+    },
+    sync::MutexNoIrq,
+};
+use alloc::{
+    alloc::{self as heap, Layout},
+    boxed::Box,
+    collections::BTreeMap,
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Once;
+
+#[repr(C, align(4096))]
+#[derive(Clone)]
+struct Frame([u8; PAGE_SIZE]);
+
+/// Frames allocated on the host heap, `PHYSICAL_MEMORY_OFFSET` is 0 in tests.
+#[derive(Debug, Clone, Copy)]
+pub struct MockFrameAlloc;
+
+/// Layouts of the frames from `MockFrameAlloc::alloc_aligned`, to free them with.
+static ALIGNED: MutexNoIrq<BTreeMap<usize, Layout>> = MutexNoIrq::new(BTreeMap::new());
+
+impl FrameAllocator for MockFrameAlloc {
+    fn alloc(&self, count: usize) -> Option<usize> {
+        let frames = vec![Frame([0xcc; PAGE_SIZE]); count].into_boxed_slice();
+        Some(Box::leak(frames).as_mut_ptr() as usize)
+    }
+
+    fn alloc_aligned(&self, count: usize, align_log2: usize) -> Option<usize> {
+        let align = 1usize.checked_shl(align_log2 as u32)?.max(PAGE_SIZE);
+        let layout = Layout::from_size_align(count * PAGE_SIZE, align).ok()?;
+        let frame = unsafe { heap::alloc(layout) };
+        if frame.is_null() {
+            return None;
+        }
+        unsafe { frame.write_bytes(0xcc, layout.size()) };
+        ALIGNED.lock().insert(frame as usize, layout);
+        Some(frame as usize)
+    }
+
+    fn dealloc(&self, frame: usize, count: usize) {
+        if let Some(layout) = ALIGNED.lock().remove(&frame) {
+            assert_eq!(layout.size(), count * PAGE_SIZE);
+            unsafe { heap::dealloc(frame as *mut u8, layout) };
+            return;
+        }
+        let frames = core::ptr::slice_from_raw_parts_mut(frame as *mut Frame, count);
+        drop(unsafe { Box::from_raw(frames) });
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MockEntry {
+    pub target: PhysAddr,
+    pub present: bool,
+    pub accessed: bool,
+    pub dirty: bool,
+    pub writable: bool,
+    pub writable_shared: bool,
+    pub readonly_shared: bool,
+    pub swapped: bool,
+    pub user: bool,
+    pub execute: bool,
+    pub mmio: u8,
+    /// Number of `update()` calls, each one is a TLB flush on hardware
+    pub updates: usize,
+}
+
+impl Entry for MockEntry {
+    fn update(&mut self) {
+        self.updates += 1;
+    }
+    fn accessed(&self) -> bool {
+        self.accessed
+    }
+    fn dirty(&self) -> bool {
+        self.dirty
+    }
+    fn writable(&self) -> bool {
+        self.writable
+    }
+    fn present(&self) -> bool {
+        self.present
+    }
+    fn clear_accessed(&mut self) {
+        self.accessed = false;
+    }
+    fn set_accessed(&mut self) {
+        self.accessed = true;
+    }
+    fn clear_dirty(&mut self) {
+        self.dirty = false;
+    }
+    fn set_writable(&mut self, value: bool) {
+        self.writable = value;
+    }
+    fn set_present(&mut self, value: bool) {
+        self.present = value;
+    }
+    fn target(&self) -> PhysAddr {
+        self.target
+    }
+    fn set_target(&mut self, target: PhysAddr) {
+        self.target = target;
+    }
+    fn writable_shared(&self) -> bool {
+        self.writable_shared
+    }
+    fn readonly_shared(&self) -> bool {
+        self.readonly_shared
+    }
+    fn set_shared(&mut self, writable: bool) {
+        self.writable_shared = writable;
+        self.readonly_shared = !writable;
+    }
+    fn clear_shared(&mut self) {
+        self.writable_shared = false;
+        self.readonly_shared = false;
+    }
+    fn swapped(&self) -> bool {
+        self.swapped
+    }
+    fn set_swapped(&mut self, value: bool) {
+        self.swapped = value;
+    }
+    fn user(&self) -> bool {
+        self.user
+    }
+    fn set_user(&mut self, value: bool) {
+        self.user = value;
+    }
+    fn execute(&self) -> bool {
+        self.execute
+    }
+    fn set_execute(&mut self, value: bool) {
+        self.execute = value;
+    }
+    fn mmio(&self) -> u8 {
+        self.mmio
+    }
+    fn set_mmio(&mut self, value: u8) {
+        self.mmio = value;
+    }
+}
+
+/// A page table of 4KiB pages only.
+#[derive(Debug, Default)]
+pub struct MockPageTable {
+    entries: BTreeMap<VirtAddr, MockEntry>,
+}
+
+impl MockPageTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The entry of the page at `addr`, which must be mapped
+    pub fn entry(&self, addr: VirtAddr) -> &MockEntry {
+        &self.entries[&(addr & !(PAGE_SIZE - 1))]
+    }
+
+    pub fn is_mapped(&self, addr: VirtAddr) -> bool {
+        self.entries.contains_key(&(addr & !(PAGE_SIZE - 1)))
+    }
+}
+
+impl PageTable for MockPageTable {
+    fn map(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut dyn Entry {
+        let entry = MockEntry {
+            target,
+            present: true,
+            writable: true,
+            ..MockEntry::default()
+        };
+        let old = self.entries.insert(addr & !(PAGE_SIZE - 1), entry);
+        assert!(old.is_none(), "page {:#x} is already mapped", addr);
+        self.get_entry(addr).unwrap()
+    }
+
+    fn unmap(&mut self, addr: VirtAddr) {
+        let entry = self
+            .entries
+            .remove(&(addr & !(PAGE_SIZE - 1)))
+            .expect("unmap a page not mapped");
+        assert!(entry.present, "unmap a page not present");
+    }
+
+    fn get_entry(&mut self, addr: VirtAddr) -> Option<&mut dyn Entry> {
+        self.entries
+            .get_mut(&(addr & !(PAGE_SIZE - 1)))
+            .map(|entry| entry as &mut dyn Entry)
+    }
+
+    fn map_2mib(&mut self, _addr: VirtAddr, _target: PhysAddr) -> Option<&mut dyn Entry> {
+        None
+    }
+
+    fn unmap_2mib(&mut self, _addr: VirtAddr) {
+        unimplemented!()
+    }
+
+    fn get_entry_2mib(&mut self, _addr: VirtAddr) -> Option<&mut dyn Entry> {
+        None
+    }
+
+    fn split_2mib(&mut self, _addr: VirtAddr) {
+        unimplemented!()
+    }
+
+    fn get_page_slice_mut<'a>(&mut self, addr: VirtAddr) -> &'a mut [u8] {
+        let entry = self.entry(addr);
+        assert!(entry.present, "access a page not present");
+        unsafe { core::slice::from_raw_parts_mut(phys_to_virt(entry.target) as *mut u8, PAGE_SIZE) }
+    }
+
+    fn flush_cache_copy_user(&mut self, _start: VirtAddr, _end: VirtAddr, _execute: bool) {}
+
+    fn read(&mut self, addr: VirtAddr) -> u8 {
+        self.get_page_slice_mut(addr)[addr % PAGE_SIZE]
+    }
+
+    fn write(&mut self, addr: VirtAddr, data: u8) {
+        let entry = self.entry(addr);
+        assert!(entry.writable, "write a read-only page");
+        self.get_page_slice_mut(addr)[addr % PAGE_SIZE] = data;
+    }
+}
+
+impl PageTableExt for MockPageTable {
+    fn new_bare() -> Self {
+        Self::default()
+    }
+
+    fn map_kernel(&mut self) {}
+
+    fn token(&self) -> u64 {
+        0
+    }
+
+    unsafe fn set_token(_token: u64) {}
+
+    fn active_token() -> u64 {
+        0
+    }
+
+    fn flush_tlb() {}
+}
+
+/// A file of `len` bytes of `byte`, to be mapped by `handler::File`.
+#[derive(Debug, Clone, Copy)]
+pub struct MockFile {
+    pub byte: u8,
+    pub len: usize,
+}
+
+impl handler::file::Read for MockFile {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.len.saturating_sub(offset));
+        buf[..len].fill(self.byte);
+        len
+    }
+}
+
+/// A disk in host memory.
+pub struct MockDisk {
+    data: MutexNoIrq<Vec<u8>>,
+}
+
+impl MockDisk {
+    /// A disk of `pages` pages prepared as swap, like by `mkswap`.
+    pub fn swap(pages: usize) -> Self {
+        let mut data = vec![0; pages * PAGE_SIZE];
+        data[1024 + 4..1024 + 8].copy_from_slice(&(pages as u32 - 1).to_le_bytes());
+        data[PAGE_SIZE - 10..PAGE_SIZE].copy_from_slice(b"SWAPSPACE2");
+        MockDisk {
+            data: MutexNoIrq::new(data),
+        }
+    }
+}
+
+impl Driver for MockDisk {
+    fn compatible(&self) -> &'static str {
+        "mock-disk"
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+}
+
+impl BlockDevice for MockDisk {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> drivers::Result<()> {
+        let start = block_id * BLOCK_SIZE;
+        buf.copy_from_slice(&self.data.lock()[start..start + BLOCK_SIZE]);
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> drivers::Result<()> {
+        let start = block_id * BLOCK_SIZE;
+        self.data.lock()[start..start + BLOCK_SIZE].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.data.lock().len() / BLOCK_SIZE
+    }
+}
+
+/// The swap, on a `MockDisk` registered on first use.
+pub fn mock_swap() -> &'static SwapDevice {
+    static INIT: Once<()> = Once::new();
+    INIT.call_once(|| {
+        drivers::register_block_device(Arc::new(MockDisk::swap(64)));
+        swap::init();
+    });
+    swap_device().expect("no mock swap")
+}
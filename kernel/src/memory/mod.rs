@@ -1,16 +1,27 @@
-use core::{fmt::Debug, mem::size_of, ptr::NonNull};
+use core::{
+    fmt::Debug,
+    mem::size_of,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::consts::{KERNEL_HEAP_SIZE, KERNEL_OFFSET, MEMORY_OFFSET, PHYSICAL_MEMORY_OFFSET};
 use spin::Lazy;
 
+pub mod aging;
 pub mod handler;
 mod memory_set;
+#[cfg(test)]
+pub mod mock;
 mod paging;
+mod slab;
+pub mod swap;
 
 pub use crate::arch::paging::*;
 pub use handler::MemoryHandler;
-pub use memory_set::{MemoryArea, MemoryAttr};
+pub use memory_set::{MemoryArea, MemoryAttr, MemorySummary, UserMemory};
 pub use paging::{Entry, Page, PageRange, PageTable, PageTableExt};
+pub use slab::{slab_stats, SlabBox, SlabCache, SlabStats};
 
 pub enum VmError {
     InvalidPtr,
@@ -20,16 +31,39 @@ pub type VmResult<T> = Result<T, VmError>;
 pub type PhysAddr = usize;
 pub type VirtAddr = usize;
 
+/// Access which caused a page fault.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    #[inline]
+    pub fn is_write(self) -> bool {
+        self == Access::Write
+    }
+}
+
 pub type MemorySet = memory_set::MemorySet<PageTableImpl>;
 
 pub type FrameAlloc = allocators::frame::buddy_system::LockedFrameAlloc;
 pub static FRAME_ALLOCATOR: Lazy<FrameAlloc> = Lazy::new(FrameAlloc::new);
 
+/// Number of frames handed to the frame allocator, host tests take theirs from
+/// the heap and make up a total.
+pub static TOTAL_FRAMES: AtomicUsize = AtomicUsize::new(if cfg!(test) { 1 << 20 } else { 0 });
+/// Number of frames currently allocated.
+static ALLOCATED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
 pub type HeapAlloc = allocators::heap::explicit_free_list::LockedHeapAlloc;
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 pub static HEAP_ALLOCATOR: HeapAlloc = HeapAlloc::new();
 
 pub const PAGE_SIZE: usize = 1 << 12;
+/// Size of a block mapped by a single level 2 entry
+pub const HUGE_PAGE_SIZE: usize = 1 << 21;
 
 /// Convert physical address to virtual address
 #[inline]
@@ -57,6 +91,9 @@ pub const fn as_upper_range(addr: VirtAddr) -> VirtAddr {
 pub trait FrameAllocator: Debug + Clone + Send + Sync + 'static {
     /// Allocate a range of frames from the allocator, return the first frame of the allocated range.
     fn alloc(&self, count: usize) -> Option<usize>;
+    /// Allocate a range of frames whose start address is aligned to `1 << align_log2` bytes.
+    /// Return `None` if that is not possible without allocating more than `count` frames.
+    fn alloc_aligned(&self, count: usize, align_log2: usize) -> Option<usize>;
     /// Deallocate a range of frames [frame, frame+count) from the frame allocator.
     fn dealloc(&self, frame: usize, count: usize);
 }
@@ -65,20 +102,54 @@ pub trait FrameAllocator: Debug + Clone + Send + Sync + 'static {
 pub struct GlobalFrameAlloc;
 
 impl FrameAllocator for GlobalFrameAlloc {
+    #[cfg(not(test))]
     fn alloc(&self, count: usize) -> Option<usize> {
         // get the real address of the alloc frame
         FRAME_ALLOCATOR.lock().alloc(count).map(|id| {
+            ALLOCATED_FRAMES.fetch_add(count, Ordering::Relaxed);
             let frame = id * PAGE_SIZE + MEMORY_OFFSET;
             trace!("Allocate frame: {:x?}", frame);
             frame
         })
     }
 
+    fn alloc_aligned(&self, count: usize, align_log2: usize) -> Option<usize> {
+        let align = 1usize.checked_shl(align_log2 as u32)?;
+        // the buddy allocator hands out blocks of `count.next_power_of_two()` frames
+        // aligned to their size, relative to MEMORY_OFFSET
+        if align > count.next_power_of_two() * PAGE_SIZE || MEMORY_OFFSET % align != 0 {
+            return None;
+        }
+        let frame = self.alloc(count)?;
+        if frame % align != 0 {
+            warn!("Frame allocator returned unaligned block {:#x}", frame);
+            self.dealloc(frame, count);
+            return None;
+        }
+        Some(frame)
+    }
+
+    #[cfg(not(test))]
     fn dealloc(&self, target: usize, count: usize) {
         trace!("Deallocate frame: {:x?}", target);
+        ALLOCATED_FRAMES.fetch_sub(count, Ordering::Relaxed);
         FRAME_ALLOCATOR
             .lock()
-            .dealloc((target / PAGE_SIZE) as usize, count);
+            .dealloc((target - MEMORY_OFFSET) / PAGE_SIZE, count);
+    }
+
+    /// Host tests take the frames from the heap, still counted.
+    #[cfg(test)]
+    fn alloc(&self, count: usize) -> Option<usize> {
+        let frame = mock::MockFrameAlloc.alloc(count)?;
+        ALLOCATED_FRAMES.fetch_add(count, Ordering::Relaxed);
+        Some(frame)
+    }
+
+    #[cfg(test)]
+    fn dealloc(&self, target: usize, count: usize) {
+        ALLOCATED_FRAMES.fetch_sub(count, Ordering::Relaxed);
+        mock::MockFrameAlloc.dealloc(target, count);
     }
 }
 
@@ -87,11 +158,40 @@ pub fn alloc_frames(count: usize) -> Option<PhysAddr> {
     GlobalFrameAlloc.alloc(count)
 }
 
+/// Allocate `count` contiguous frames starting at a multiple of `1 << align_log2` bytes,
+/// e.g. a 2MiB block or a DMA buffer.
+#[inline]
+pub fn alloc_frames_aligned(count: usize, align_log2: usize) -> Option<PhysAddr> {
+    GlobalFrameAlloc.alloc_aligned(count, align_log2)
+}
+
 #[inline]
 pub fn dealloc_frames(target: PhysAddr, count: usize) {
     GlobalFrameAlloc.dealloc(target, count)
 }
 
+/// A frame of zeros, shared read-only by anonymous pages until they are first written.
+static ZERO_FRAME: Lazy<PhysAddr> = Lazy::new(|| {
+    #[cfg(not(test))]
+    let frame = alloc_frames(1).expect("failed to allocate the zero frame");
+    #[cfg(test)]
+    let frame = mock::MockFrameAlloc.alloc(1).unwrap();
+    unsafe { core::ptr::write_bytes(phys_to_virt(frame) as *mut u8, 0, PAGE_SIZE) };
+    frame
+});
+
+#[inline]
+pub fn zero_frame() -> PhysAddr {
+    *ZERO_FRAME
+}
+
+/// Return `(total, free)` number of frames.
+pub fn frame_stats() -> (usize, usize) {
+    let total = TOTAL_FRAMES.load(Ordering::Relaxed);
+    let allocated = ALLOCATED_FRAMES.load(Ordering::Relaxed);
+    (total, total.saturating_sub(allocated))
+}
+
 pub fn init_heap() {
     const LEN: usize = KERNEL_HEAP_SIZE / size_of::<usize>();
     static mut HEAP: [usize; LEN] = [0; LEN];
@@ -108,8 +208,33 @@ pub fn init_heap() {
 /// Return true to continue, false to halt.
 pub fn handle_page_fault(addr: usize) -> bool {
     debug!("page fault from kernel @ {:#x}", addr);
-    // let thread = current_thread().unwrap();
-    // let mut lock = thread.vm.lock();
-    // lock.handle_page_fault(addr)
-    false
+    if addr >= KERNEL_OFFSET {
+        return false;
+    }
+    // user pages aged by the sweep, the memory set may be locked by the faulting code.
+    // The user memory checked by a syscall is pinned, it is never swapped out meanwhile.
+    unsafe { PageTableImpl::active() }.set_accessed(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockFrameAlloc;
+
+    #[test]
+    fn alloc_2mib_aligned() {
+        let align_log2 = 21;
+        let count = (1 << align_log2) / PAGE_SIZE;
+        let frame = MockFrameAlloc.alloc_aligned(count, align_log2).unwrap();
+        assert_eq!(frame % (1 << align_log2), 0);
+        // all of the block is usable
+        unsafe { core::ptr::write_bytes(phys_to_virt(frame) as *mut u8, 0, count * PAGE_SIZE) };
+        MockFrameAlloc.dealloc(frame, count);
+
+        // the buddy allocator fails rather than allocating more frames to align
+        let free = frame_stats().1;
+        assert_eq!(GlobalFrameAlloc.alloc_aligned(1, align_log2), None);
+        assert_eq!(GlobalFrameAlloc.alloc_aligned(count, 64), None);
+        assert_eq!(frame_stats().1, free);
+    }
 }
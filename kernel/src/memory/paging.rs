@@ -15,6 +15,20 @@ pub trait PageTable {
     /// If its page do not exist, return `None`
     fn get_entry(&mut self, addr: VirtAddr) -> Option<&mut dyn Entry>;
 
+    /// Map the 2MiB block of virtual address `addr` to the 2MiB aligned frames at `target`
+    /// Return `None` if part of the block is already mapped by pages
+    fn map_2mib(&mut self, addr: VirtAddr, target: PhysAddr) -> Option<&mut dyn Entry>;
+
+    /// Unmap the 2MiB block of virtual address `addr`
+    fn unmap_2mib(&mut self, addr: VirtAddr);
+
+    /// Get the entry of the 2MiB block containing `addr`
+    /// If `addr` is not mapped by a 2MiB block, return `None`
+    fn get_entry_2mib(&mut self, addr: VirtAddr) -> Option<&mut dyn Entry>;
+
+    /// Replace the 2MiB block containing `addr` by pages mapping the same frames
+    fn split_2mib(&mut self, addr: VirtAddr);
+
     /// Get a mutable reference of the content of a page of virtual address `addr`
     fn get_page_slice_mut<'a>(&mut self, addr: VirtAddr) -> &'a mut [u8];
 
@@ -106,6 +120,7 @@ pub trait Entry {
     fn present(&self) -> bool;
 
     fn clear_accessed(&mut self);
+    fn set_accessed(&mut self);
     fn clear_dirty(&mut self);
     fn set_writable(&mut self, value: bool);
     fn set_present(&mut self, value: bool);
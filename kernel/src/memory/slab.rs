@@ -0,0 +1,273 @@
+//! Slab allocator, caches of fixed-size objects carved out of whole frames.
+//!
+//! A cache takes a frame from the frame allocator whenever it runs out of free
+//! objects and splits it into objects linked in a free list. Freed objects go back
+//! to the free list of their cache, the frames are never given back.
+
+use super::{phys_to_virt, PAGE_SIZE};
+use crate::sync::MutexNoIrq;
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+/// Every cache which allocated at least one slab, for `/proc/slabinfo`.
+static CACHES: MutexNoIrq<Vec<&'static dyn CacheStats>> = MutexNoIrq::new(Vec::new());
+
+/// A free object, the link lives in the object itself.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+struct CacheInner {
+    free_list: Option<NonNull<FreeObject>>,
+    slabs: usize,
+    allocated: usize,
+    free: usize,
+}
+
+// the free objects are only reached through the lock
+unsafe impl Send for CacheInner {}
+
+/// Usage of a cache.
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    /// Number of frames taken by the cache
+    pub slabs: usize,
+    /// Objects in use
+    pub allocated: usize,
+    /// Objects ready to be allocated
+    pub free: usize,
+}
+
+trait CacheStats: Sync {
+    fn stats(&self) -> SlabStats;
+}
+
+/// Cache of objects of type `T`, meant to be a `static`.
+pub struct SlabCache<T> {
+    name: &'static str,
+    inner: MutexNoIrq<CacheInner>,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+const fn align_up(size: usize, align: usize) -> usize {
+    (size + align - 1) & !(align - 1)
+}
+
+impl<T> SlabCache<T> {
+    const OBJECT_ALIGN: usize = max(align_of::<T>(), align_of::<FreeObject>());
+    /// Size of `T` rounded up so that every object of a slab is aligned.
+    const OBJECT_SIZE: usize = align_up(
+        max(size_of::<T>(), size_of::<FreeObject>()),
+        Self::OBJECT_ALIGN,
+    );
+
+    pub const fn new(name: &'static str) -> Self {
+        SlabCache {
+            name,
+            inner: MutexNoIrq::new(CacheInner {
+                free_list: None,
+                slabs: 0,
+                allocated: 0,
+                free: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Put an object allocated from this cache back to the free list.
+    ///
+    /// # Safety
+    ///
+    /// `object` must come from `alloc_object` of this cache and not be used afterwards.
+    unsafe fn dealloc_object(&self, object: NonNull<FreeObject>) {
+        let mut inner = self.inner.lock();
+        object.as_ptr().write(FreeObject {
+            next: inner.free_list,
+        });
+        inner.free_list = Some(object);
+        inner.allocated -= 1;
+        inner.free += 1;
+    }
+}
+
+impl<T: Send> SlabCache<T> {
+    /// Move `value` into an object of the cache.
+    /// Return `None` if the cache is empty and no frame is left.
+    pub fn alloc(&'static self, value: T) -> Option<SlabBox<T>> {
+        let ptr = self.alloc_object()?.cast::<T>();
+        unsafe { ptr.as_ptr().write(value) };
+        Some(SlabBox { ptr, cache: self })
+    }
+
+    fn alloc_object(&'static self) -> Option<NonNull<FreeObject>> {
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                if let Some(object) = inner.free_list {
+                    inner.free_list = unsafe { object.as_ref().next };
+                    inner.allocated += 1;
+                    inner.free -= 1;
+                    return Some(object);
+                }
+            }
+            self.grow()?;
+        }
+    }
+
+    /// Split a new frame into free objects.
+    fn grow(&'static self) -> Option<()> {
+        assert!(
+            Self::OBJECT_SIZE <= PAGE_SIZE,
+            "slab cache {}: objects larger than a page",
+            self.name
+        );
+        // no lock held, the frame allocator may allocate itself
+        #[cfg(not(test))]
+        let slab = phys_to_virt(super::alloc_frames(1)?);
+        #[cfg(test)]
+        let slab = {
+            use super::FrameAllocator;
+            phys_to_virt(super::mock::MockFrameAlloc.alloc(1)?)
+        };
+        let count = PAGE_SIZE / Self::OBJECT_SIZE;
+
+        let mut inner = self.inner.lock();
+        for i in (0..count).rev() {
+            let object = (slab + i * Self::OBJECT_SIZE) as *mut FreeObject;
+            unsafe {
+                object.write(FreeObject {
+                    next: inner.free_list,
+                });
+            }
+            inner.free_list = NonNull::new(object);
+        }
+        inner.slabs += 1;
+        inner.free += count;
+        let first_slab = inner.slabs == 1;
+        drop(inner);
+
+        if first_slab {
+            CACHES.lock().push(self);
+        }
+        Some(())
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let inner = self.inner.lock();
+        SlabStats {
+            name: self.name,
+            object_size: Self::OBJECT_SIZE,
+            slabs: inner.slabs,
+            allocated: inner.allocated,
+            free: inner.free,
+        }
+    }
+}
+
+impl<T: Send> CacheStats for SlabCache<T> {
+    fn stats(&self) -> SlabStats {
+        SlabCache::stats(self)
+    }
+}
+
+/// Usage of every cache in use.
+pub fn slab_stats() -> Vec<SlabStats> {
+    CACHES.lock().iter().map(|cache| cache.stats()).collect()
+}
+
+/// Owning pointer to an object of a `SlabCache`, like a `Box`.
+pub struct SlabBox<T> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.dealloc_object(self.ptr.cast());
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn churn() {
+        static CACHE: SlabCache<[usize; 5]> = SlabCache::new("test");
+        let per_slab = PAGE_SIZE / SlabCache::<[usize; 5]>::OBJECT_SIZE;
+
+        let mut objects: Vec<_> = (0..3 * per_slab)
+            .map(|i| CACHE.alloc([i; 5]).unwrap())
+            .collect();
+        let s = CACHE.stats();
+        assert_eq!((s.slabs, s.allocated, s.free), (3, 3 * per_slab, 0));
+
+        // free every other object, then take them again
+        for _ in 0..10 {
+            let mut i = 0;
+            objects.retain(|_| {
+                i += 1;
+                i % 2 == 0
+            });
+            let stats = CACHE.stats();
+            assert_eq!(stats.allocated + stats.free, 3 * per_slab);
+            while objects.len() < 3 * per_slab {
+                objects.push(CACHE.alloc([usize::MAX; 5]).unwrap());
+            }
+        }
+        // no new slab, and the objects kept are intact
+        assert_eq!(CACHE.stats().slabs, 3);
+        assert!(objects
+            .iter()
+            .all(|object| object.iter().all(|&x| x == object[0])));
+
+        drop(objects);
+        let s = CACHE.stats();
+        assert_eq!((s.slabs, s.allocated, s.free), (3, 0, 3 * per_slab));
+        assert!(slab_stats().iter().any(|stats| stats.name == "test"));
+    }
+}
@@ -0,0 +1,186 @@
+//! Swap, anonymous pages written out to a block device when frames run short.
+//!
+//! The swap area is a block device prepared by `mkswap`: its first page holds the
+//! header, every following page is a slot for a page swapped out. A swapped out
+//! page keeps a non-present entry with `SWAPPED` set and its slot as target.
+//!
+//! Ref: Linux `include/linux/swap.h`, `union swap_header`
+
+use super::{frame_stats, phys_to_virt, PhysAddr, PAGE_SIZE};
+use crate::{
+    drivers::{
+        block::{BlockDevice, BLOCK_SIZE},
+        DriverError, Result, BLOCK_DRIVERS,
+    },
+    sync::MutexNoIrq,
+};
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::slice;
+use spin::Once;
+
+const BLOCKS_PER_PAGE: usize = PAGE_SIZE / BLOCK_SIZE;
+/// Signature ending the header page.
+const SWAP_MAGIC: &[u8] = b"SWAPSPACE2";
+/// Offset of `last_page` in the header page.
+const LAST_PAGE_OFFSET: usize = 1024 + 4;
+
+/// Below this many free frames, page faults swap pages out first.
+pub const SWAP_LOW_FRAMES: usize = 256;
+/// Pages swapped out at a time when frames run short.
+pub const SWAP_BATCH: usize = 16;
+
+static SWAP_DEVICE: Once<SwapDevice> = Once::new();
+
+/// A swap area on a block device.
+pub struct SwapDevice {
+    device: Arc<dyn BlockDevice>,
+    slots: MutexNoIrq<Slots>,
+}
+
+struct Slots {
+    /// One bit per slot, set if in use
+    used: Vec<u64>,
+    /// Slots `1..=last` are usable, slot 0 is the header
+    last: usize,
+    in_use: usize,
+    /// Where the search for a free slot starts
+    next: usize,
+    /// References to a slot besides the first one, taken by forked page tables
+    shared: BTreeMap<usize, usize>,
+}
+
+impl SwapDevice {
+    /// Open the swap area on `device`, `None` if it has no swap header.
+    pub fn probe(device: Arc<dyn BlockDevice>) -> Option<Self> {
+        let mut header = vec![0u8; PAGE_SIZE];
+        for (i, block) in header.chunks_mut(BLOCK_SIZE).enumerate() {
+            device.read_block(i, block).ok()?;
+        }
+        if &header[PAGE_SIZE - SWAP_MAGIC.len()..] != SWAP_MAGIC {
+            return None;
+        }
+        let mut last_page = [0; 4];
+        last_page.copy_from_slice(&header[LAST_PAGE_OFFSET..LAST_PAGE_OFFSET + 4]);
+        let pages = device.num_blocks() / BLOCKS_PER_PAGE;
+        let last = (u32::from_le_bytes(last_page) as usize).min(pages - 1);
+        if last == 0 {
+            return None;
+        }
+        Some(SwapDevice {
+            device,
+            slots: MutexNoIrq::new(Slots {
+                used: vec![0; last / 64 + 1],
+                last,
+                in_use: 0,
+                next: 1,
+                shared: BTreeMap::new(),
+            }),
+        })
+    }
+
+    /// Take a free slot.
+    pub fn alloc_slot(&self) -> Option<usize> {
+        let mut slots = self.slots.lock();
+        let last = slots.last;
+        let next = slots.next;
+        let slot = (next..=last)
+            .chain(1..next)
+            .find(|&slot| slots.used[slot / 64] & 1 << (slot % 64) == 0)?;
+        slots.used[slot / 64] |= 1 << (slot % 64);
+        slots.in_use += 1;
+        slots.next = if slot == last { 1 } else { slot + 1 };
+        Some(slot)
+    }
+
+    /// Take another reference to `slot`, a page swapped out in two page tables.
+    pub fn dup_slot(&self, slot: usize) {
+        let mut slots = self.slots.lock();
+        assert!(
+            slots.used[slot / 64] & 1 << (slot % 64) != 0,
+            "slot {} not in use",
+            slot
+        );
+        *slots.shared.entry(slot).or_insert(0) += 1;
+    }
+
+    /// Drop a reference to `slot`, it is free once the last one is dropped.
+    pub fn free_slot(&self, slot: usize) {
+        let mut slots = self.slots.lock();
+        assert!(
+            slots.used[slot / 64] & 1 << (slot % 64) != 0,
+            "slot {} not in use",
+            slot
+        );
+        if let Some(refs) = slots.shared.get_mut(&slot) {
+            *refs -= 1;
+            if *refs == 0 {
+                slots.shared.remove(&slot);
+            }
+            return;
+        }
+        slots.used[slot / 64] &= !(1 << (slot % 64));
+        slots.in_use -= 1;
+    }
+
+    /// Write the frame `frame` to `slot`.
+    pub fn write_page(&self, slot: usize, frame: PhysAddr) -> Result<()> {
+        let data = unsafe { slice::from_raw_parts(phys_to_virt(frame) as *const u8, PAGE_SIZE) };
+        for (i, block) in data.chunks(BLOCK_SIZE).enumerate() {
+            self.device.write_block(slot * BLOCKS_PER_PAGE + i, block)?;
+        }
+        Ok(())
+    }
+
+    /// Read `slot` into the frame `frame`.
+    pub fn read_page(&self, slot: usize, frame: PhysAddr) -> Result<()> {
+        if slot == 0 || slot > self.slots.lock().last {
+            return Err(DriverError::Io("bad swap slot"));
+        }
+        let data = unsafe { slice::from_raw_parts_mut(phys_to_virt(frame) as *mut u8, PAGE_SIZE) };
+        for (i, block) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+            self.device.read_block(slot * BLOCKS_PER_PAGE + i, block)?;
+        }
+        Ok(())
+    }
+
+    /// Total and used slots.
+    pub fn stats(&self) -> (usize, usize) {
+        let slots = self.slots.lock();
+        (slots.last, slots.in_use)
+    }
+}
+
+/// Use the first block device with a swap header as swap.
+pub fn init() {
+    let devices = BLOCK_DRIVERS.read().clone();
+    if let Some(swap) = devices.into_iter().find_map(SwapDevice::probe) {
+        info!("swap: {} pages", swap.stats().0);
+        SWAP_DEVICE.call_once(|| swap);
+    }
+}
+
+/// The swap area, if any.
+pub fn swap_device() -> Option<&'static SwapDevice> {
+    SWAP_DEVICE.get()
+}
+
+/// Total and used swap in pages, zero without swap.
+pub fn swap_stats() -> (usize, usize) {
+    swap_device().map_or((0, 0), SwapDevice::stats)
+}
+
+/// Whether page faults should swap pages out before taking frames.
+pub fn low_on_frames() -> bool {
+    swap_device().is_some() && frame_stats().1 < SWAP_LOW_FRAMES
+}
+
+/// Page table target of a swapped out page in `slot`.
+#[inline]
+pub fn slot_to_target(slot: usize) -> PhysAddr {
+    slot * PAGE_SIZE
+}
+
+#[inline]
+pub fn target_to_slot(target: PhysAddr) -> usize {
+    target / PAGE_SIZE
+}
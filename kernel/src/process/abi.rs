@@ -1,3 +1,4 @@
+use crate::utils::fill_random;
 use alloc::{collections::btree_map::BTreeMap, string::String, vec::Vec};
 use core::ptr::null;
 
@@ -31,8 +32,20 @@ impl ProcInitInfo {
                 writer.sp
             })
             .collect();
+        // random bytes for AT_RANDOM
+        let mut random = [0u8; 16];
+        fill_random(&mut random);
+        writer.push_slice(&random);
+        let random = writer.sp;
+        // the stack pointer must be 16-byte aligned at entry, with argc at its address
+        writer.sp &= !0xf;
+        let words = 1 + (argv.len() + 1) + (envs.len() + 1) + 2 * (self.auxv.len() + 2);
+        if words % 2 != 0 {
+            writer.push_slice(&[0usize]);
+        }
         // auxiliary vector entries
         writer.push_slice(&[null::<u8>(), null::<u8>()]);
+        writer.push_slice(&[AT_RANDOM as usize, random]);
         for (&type_, &value) in self.auxv.iter() {
             writer.push_slice(&[type_ as usize, value]);
         }
@@ -44,6 +57,7 @@ impl ProcInitInfo {
         writer.push_slice(argv.as_slice());
         // argc
         writer.push_slice(&[argv.len()]);
+        debug_assert_eq!(writer.sp % 16, 0);
         writer.sp
     }
 }
@@ -74,3 +88,63 @@ pub const AT_PHNUM: u8 = 5;
 pub const AT_PAGESZ: u8 = 6;
 pub const AT_BASE: u8 = 7;
 pub const AT_ENTRY: u8 = 9;
+pub const AT_UID: u8 = 11;
+pub const AT_EUID: u8 = 12;
+pub const AT_GID: u8 = 13;
+pub const AT_EGID: u8 = 14;
+pub const AT_HWCAP: u8 = 16;
+pub const AT_CLKTCK: u8 = 17;
+pub const AT_SECURE: u8 = 23;
+pub const AT_RANDOM: u8 = 25;
+
+/// Clock ticks per second reported by AT_CLKTCK, as `sysconf(_SC_CLK_TCK)` on Linux.
+pub const USER_HZ: usize = 100;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::from_cstr;
+    use alloc::{boxed::Box, vec};
+
+    #[repr(C, align(16))]
+    struct Stack([u8; 0x1000]);
+
+    /// Push `args` and `envs` with one auxv entry, then check the layout read by crt0.
+    fn check(args: &[&str], envs: &[&str]) {
+        let mut stack = Box::new(Stack([0; 0x1000]));
+        let top = stack.0.as_mut_ptr() as usize + 0x1000;
+        let info = ProcInitInfo {
+            args: args.iter().map(|&s| String::from(s)).collect(),
+            envs: envs.iter().map(|&s| String::from(s)).collect(),
+            auxv: vec![(AT_PAGESZ, 0x1000)].into_iter().collect(),
+        };
+        let sp = unsafe { info.push_at(top) };
+        assert_eq!(sp % 16, 0);
+        let words = unsafe { core::slice::from_raw_parts(sp as *const usize, (top - sp) / 8) };
+        assert_eq!(words[0], args.len());
+        let mut i = 1;
+        for &arg in args {
+            assert_eq!(unsafe { from_cstr(words[i] as *const u8) }, arg);
+            i += 1;
+        }
+        assert_eq!(words[i], 0);
+        i += 1;
+        for &env in envs {
+            assert_eq!(unsafe { from_cstr(words[i] as *const u8) }, env);
+            i += 1;
+        }
+        assert_eq!(words[i], 0);
+        i += 1;
+        assert_eq!(&words[i..i + 2], &[AT_PAGESZ as usize, 0x1000]);
+        assert_eq!(words[i + 2], AT_RANDOM as usize);
+        assert!((sp..top).contains(&words[i + 3]));
+        assert_eq!(&words[i + 4..i + 6], &[0, 0]);
+    }
+
+    #[test]
+    fn stack_aligned() {
+        check(&["init"], &[]);
+        check(&["sh", "-c", "echo"], &["PATH=/bin"]);
+        check(&["a", "bc"], &["X=1", "Y=22", "Z=333"]);
+    }
+}
@@ -0,0 +1,99 @@
+//! Processes without a user image, to run syscalls in host tests.
+
+use super::{thread::ThreadInner, *};
+use crate::{
+    memory::{handler::Linear, mock::MockFrameAlloc, FrameAllocator, MemoryAttr, PAGE_SIZE},
+    sync::MutexGuardNoIrq,
+    task::executor,
+};
+
+/// A process of one thread, in the process and thread tables until dropped.
+pub struct MockProcess {
+    pub thread: Arc<Thread>,
+}
+
+impl MockProcess {
+    /// A process of `uid`, in `/` with no file open and an empty memory set.
+    pub fn new(uid: Uid) -> Self {
+        let vm = Arc::new(MutexNoIrq::new(MemorySet::new()));
+        let process = Process {
+            vm: vm.clone(),
+            files: BTreeMap::new(),
+            cwd: String::from("/"),
+            exec_path: String::from("/mock"),
+            futexes: BTreeMap::new(),
+            brk_start: 0,
+            brk: 0,
+            pid: 0,
+            pgid: 0,
+            uid,
+            euid: uid,
+            gid: uid,
+            egid: uid,
+            parent: (0, Weak::new()),
+            children: Vec::new(),
+            threads: Vec::new(),
+            exit_code: 0,
+            exited_cpu_time: Duration::default(),
+            children_cpu_time: Duration::default(),
+            exit_signal: None,
+            stop_signal: None,
+            pending_sigset: Sigset::empty(),
+            sig_queue: VecDeque::new(),
+            dispositions: [SignalAction::default(); Signal::RTMAX + 1],
+            rlimits: RLimit::defaults(),
+            event_bus: EventBus::new(),
+        };
+        let thread = Thread {
+            inner: MutexNoIrq::new(ThreadInner::mock()),
+            process: Arc::new(MutexNoIrq::new(process)),
+            vm,
+            tid: 0,
+        }
+        .add_to_table();
+        add_to_process_table(thread.process.clone(), thread.tid);
+        {
+            let mut process = thread.process.lock();
+            process.pgid = thread.tid as Pgid;
+            process.threads.push(thread.tid);
+        }
+        MockProcess { thread }
+    }
+
+    /// Map `pages` pages of host memory for the user at their own address,
+    /// return the address.
+    pub fn map_user(&self, pages: usize) -> usize {
+        let start = MockFrameAlloc.alloc(pages).unwrap();
+        let end = start + pages * PAGE_SIZE;
+        let attr = MemoryAttr::default().user();
+        let mut vm = self.thread.vm.lock();
+        vm.push(start, end, attr, Linear::new(0), "mock");
+        start
+    }
+
+    /// Give the thread a scheduler task, which never runs.
+    pub fn schedule(&self) {
+        executor::init(1);
+        let executor = executor::local_executor();
+        let (task, sched_task) = executor.spawn(async {}, 0, Default::default());
+        self.thread.inner.lock().task = Some((task, sched_task));
+    }
+
+    pub fn process(&self) -> MutexGuardNoIrq<Process> {
+        self.thread.process.lock()
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.thread.tid
+    }
+}
+
+impl Drop for MockProcess {
+    fn drop(&mut self) {
+        THREADS.write().remove(&self.thread.tid);
+        PROCESSES.write().remove(&self.thread.tid);
+        // with the process unlocked, see `FileHandle`
+        let files = core::mem::take(&mut self.process().files);
+        drop(files);
+    }
+}
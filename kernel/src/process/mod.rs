@@ -3,7 +3,8 @@ use crate::{
     fs::FileHandle,
     memory::MemorySet,
     signal::{Siginfo, Signal, SignalAction, Sigset},
-    sync::{Event, EventBus, MutexNoIrq},
+    sync::{Event, EventBus, Futex, MutexNoIrq, RwLockNoIrq},
+    syscall::SysError,
 };
 use alloc::{
     collections::{BTreeMap, VecDeque},
@@ -11,9 +12,11 @@ use alloc::{
     sync::{Arc, Weak},
     vec::Vec,
 };
-use spin::RwLock;
+use core::time::Duration;
 
 pub mod abi;
+#[cfg(test)]
+pub mod mock;
 pub mod structs;
 pub mod thread;
 
@@ -23,9 +26,61 @@ pub use thread::Thread;
 pub type Pid = usize;
 /// process group id type
 pub type Pgid = i32;
+/// User id type
+pub type Uid = u32;
+/// Group id type
+pub type Gid = u32;
 pub type ProcessRef = Arc<MutexNoIrq<Process>>;
 pub const PID_INIT: usize = 1;
-pub static PROCESSES: RwLock<BTreeMap<Pid, ProcessRef>> = RwLock::new(BTreeMap::new());
+pub static PROCESSES: RwLockNoIrq<BTreeMap<Pid, ProcessRef>> = RwLockNoIrq::new(BTreeMap::new());
+
+/// Maximum size of the heap grown by `brk`
+pub const RLIMIT_DATA: usize = 2;
+/// Maximum size of the stack
+pub const RLIMIT_STACK: usize = 3;
+/// One more than the maximum fd number
+pub const RLIMIT_NOFILE: usize = 7;
+pub const RLIM_NLIMITS: usize = 16;
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+/// `struct rlimit`, soft and hard limit of a resource
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RLimit {
+    pub cur: u64,
+    pub max: u64,
+}
+
+impl RLimit {
+    pub const INFINITY: RLimit = RLimit {
+        cur: RLIM_INFINITY,
+        max: RLIM_INFINITY,
+    };
+
+    /// Limits of the init process, inherited by everything else.
+    pub fn defaults() -> [RLimit; RLIM_NLIMITS] {
+        let mut rlimits = [RLimit::INFINITY; RLIM_NLIMITS];
+        rlimits[RLIMIT_STACK] = RLimit {
+            cur: crate::consts::USER_STACK_MAX_SIZE as u64,
+            max: RLIM_INFINITY,
+        };
+        rlimits[RLIMIT_NOFILE] = RLimit {
+            cur: 1024,
+            max: 4096,
+        };
+        rlimits
+    }
+}
+
+/// Remove the files marked with `FD_CLOEXEC` from `files`, and return them.
+fn take_cloexec(files: &mut BTreeMap<usize, FileHandle>) -> Vec<FileHandle> {
+    let fds = files
+        .iter()
+        .filter(|(_, file)| file.fd_cloexec)
+        .map(|(fd, _)| *fd)
+        .collect::<Vec<_>>();
+    fds.iter().map(|fd| files.remove(fd).unwrap()).collect()
+}
 
 pub struct Process {
     /// Virtual memory
@@ -35,13 +90,20 @@ pub struct Process {
     pub files: BTreeMap<usize, FileHandle>,
 
     /// Current working directory
+    /// always absolute and normalized, see `fs::normalize_path`
     pub cwd: String,
 
     /// Executable path
     pub exec_path: String,
 
-    // /// Futex
-    // pub futexes: BTreeMap<usize, Arc<Futex>>,
+    /// Start of the heap, right after the loaded ELF images
+    pub brk_start: usize,
+
+    /// Current program break
+    pub brk: usize,
+
+    /// Futex
+    pub futexes: BTreeMap<usize, Arc<Futex>>,
 
     // /// Semaphore
     // pub semaphores: SemProc,
@@ -52,6 +114,14 @@ pub struct Process {
     //// Process group id
     pub pgid: Pgid,
 
+    /// Real and effective user id, 0 is root
+    pub uid: Uid,
+    pub euid: Uid,
+
+    /// Real and effective group id
+    pub gid: Gid,
+    pub egid: Gid,
+
     /// Parent process
     /// Avoid deadlock, put pid out
     pub parent: (Pid, Weak<MutexNoIrq<Process>>),
@@ -69,22 +139,41 @@ pub struct Process {
     /// Exit code
     pub exit_code: usize,
 
+    /// CPU time of the threads that have exited
+    pub exited_cpu_time: Duration,
+
+    /// CPU time of the reaped children and their descendants
+    pub children_cpu_time: Duration,
+
+    /// Fatal signal that terminated the process, `None` if it called `exit`
+    pub exit_signal: Option<Signal>,
+
+    /// Signal that stopped the process, until reported by `wait4`
+    pub stop_signal: Option<Signal>,
+
     // delivered signals, tid specified thread, -1 stands for any thread
     pub sig_queue: VecDeque<(Siginfo, isize)>,
     pub pending_sigset: Sigset,
 
     /// signal actions
     pub dispositions: [SignalAction; Signal::RTMAX + 1],
+
+    /// Resource limits, indexed by `RLIMIT_*`
+    pub rlimits: [RLimit; RLIM_NLIMITS],
     // /// shared memory
     // pub shm_identifiers: ShmProc,
 }
 
+/// All processes, to be locked once the table is not: a process may take the
+/// table while locked.
+fn all_processes() -> Vec<ProcessRef> {
+    PROCESSES.read().values().cloned().collect()
+}
+
 /// Return the process which thread tid is in
 pub fn process_of(tid: usize) -> Option<ProcessRef> {
-    PROCESSES
-        .read()
-        .iter()
-        .map(|(_, proc)| proc.clone())
+    all_processes()
+        .into_iter()
         .find(|proc| proc.lock().threads.contains(&tid))
 }
 
@@ -95,14 +184,20 @@ pub fn process(pid: usize) -> Option<ProcessRef> {
 
 /// Get process group by pgid
 pub fn process_group(pgid: Pgid) -> Vec<ProcessRef> {
-    PROCESSES
-        .read()
-        .iter()
-        .map(|(_, proc)| proc.clone())
+    all_processes()
+        .into_iter()
         .filter(|proc| proc.lock().pgid == pgid)
         .collect::<Vec<_>>()
 }
 
+/// Return the processes whose real user is uid
+pub fn processes_of_user(uid: Uid) -> Vec<ProcessRef> {
+    all_processes()
+        .into_iter()
+        .filter(|proc| proc.lock().uid == uid)
+        .collect::<Vec<_>>()
+}
+
 /// Set pid and put itself to global process table.
 pub fn add_to_process_table(process: ProcessRef, pid: Pid) {
     let mut process_table = PROCESSES.write();
@@ -115,44 +210,105 @@ pub fn add_to_process_table(process: ProcessRef, pid: Pid) {
 }
 
 impl Process {
+    /// One more than the largest fd allowed by `RLIMIT_NOFILE`.
+    pub fn max_fd(&self) -> usize {
+        self.rlimits[RLIMIT_NOFILE].cur.min(usize::MAX as u64) as usize
+    }
+
     /// Get lowest free fd
-    fn get_free_fd(&self) -> usize {
-        (0..).find(|i| !self.files.contains_key(i)).unwrap()
+    fn get_free_fd(&self) -> Result<usize, SysError> {
+        self.get_free_fd_from(0)
     }
 
     /// get the lowest available fd great than or equal to arg
-    pub fn get_free_fd_from(&self, arg: usize) -> usize {
-        (arg..).find(|i| !self.files.contains_key(i)).unwrap()
+    pub fn get_free_fd_from(&self, arg: usize) -> Result<usize, SysError> {
+        (arg..self.max_fd())
+            .find(|i| !self.files.contains_key(i))
+            .ok_or(SysError::EMFILE)
     }
 
     /// Add a file to the process, return its fd.
-    pub fn add_file(&mut self, file: FileHandle) -> usize {
-        let fd = self.get_free_fd();
+    pub fn add_file(&mut self, file: FileHandle) -> Result<usize, SysError> {
+        let fd = self.get_free_fd()?;
         self.files.insert(fd, file);
-        fd
+        Ok(fd)
     }
 
-    // /// Get futex by addr
-    // pub fn get_futex(&mut self, uaddr: usize) -> Arc<Futex> {
-    //     if !self.futexes.contains_key(&uaddr) {
-    //         self.futexes.insert(uaddr, Arc::new(Futex::new()));
-    //     }
-    //     self.futexes.get(&uaddr).unwrap().clone()
-    // }
+    /// Heap size allowed by `RLIMIT_DATA`.
+    pub fn max_data_size(&self) -> usize {
+        self.rlimits[RLIMIT_DATA].cur.min(usize::MAX as u64) as usize
+    }
+
+    /// Stack size allowed by `RLIMIT_STACK`.
+    pub fn max_stack_size(&self) -> usize {
+        self.rlimits[RLIMIT_STACK].cur.min(usize::MAX as u64) as usize
+    }
+
+    /// Take out every file marked with `FD_CLOEXEC`, keeping the rest.
+    ///
+    /// Must be called by `execve` before the new image is loaded. The caller drops
+    /// the files taken once the process is unlocked.
+    pub fn close_on_exec(&mut self) -> Vec<FileHandle> {
+        take_cloexec(&mut self.files)
+    }
+
+    /// Get futex by addr
+    pub fn get_futex(&mut self, uaddr: usize) -> Arc<Futex> {
+        if !self.futexes.contains_key(&uaddr) {
+            self.futexes.insert(uaddr, Arc::new(Futex::new()));
+        }
+        self.futexes.get(&uaddr).unwrap().clone()
+    }
+
+    /// Forget the futex at `uaddr` once nobody else holds it, so nobody waits on it.
+    ///
+    /// Others can only get it by `get_futex` with the process locked.
+    pub fn put_futex(&mut self, uaddr: usize) {
+        if let Some(futex) = self.futexes.get(&uaddr) {
+            if Arc::strong_count(futex) == 1 {
+                self.futexes.remove(&uaddr);
+            }
+        }
+    }
+
+    /// Wake up to `count` waiters on `uaddr`, return the number woken.
+    pub fn wake_futex(&mut self, uaddr: usize, count: usize) -> usize {
+        let woken = match self.futexes.get(&uaddr) {
+            Some(futex) => futex.wake(count),
+            None => 0,
+        };
+        self.put_futex(uaddr);
+        woken
+    }
 
     /// Exit the process.
     /// Kill all threads and notify parent with the exit code.
-    pub fn exit(&mut self, exit_code: usize) {
-        // avoid some strange dead lock
-        // self.files.clear(); this does not work sometime, for unknown reason
-        // manually drop
-        let fds = self.files.iter().map(|(fd, _)| *fd).collect::<Vec<_>>();
-        for fd in fds.iter() {
-            let file = self.files.remove(fd).unwrap();
-            drop(file);
+    ///
+    /// Return the files of the process, to be dropped by the caller once the
+    /// process is unlocked (see `FileHandle`).
+    #[must_use]
+    pub fn exit(&mut self, exit_code: usize) -> BTreeMap<usize, FileHandle> {
+        let files = core::mem::take(&mut self.files);
+
+        // fill exit code
+        // this must be before clearing the threads, or the process will be treated exited before the exit code is set
+        self.exit_code = exit_code;
+
+        // quit all threads
+        // siblings running on other CPUs stop before they return to user mode
+        let threads = {
+            let mut thread_table = THREADS.write();
+            self.threads
+                .drain(..)
+                .filter_map(|tid| thread_table.remove(&tid))
+                .collect::<Vec<_>>()
+        };
+        for thread in threads {
+            self.exited_cpu_time += thread.exec_runtime();
+            thread.kill();
         }
 
-        // notify parent and fill exit code
+        // notify parent
         self.event_bus.lock().set(Event::PROCESS_QUIT);
         if let Some(parent) = self.parent.1.upgrade() {
             parent
@@ -161,21 +317,84 @@ impl Process {
                 .lock()
                 .set(Event::CHILD_PROCESS_QUIT);
         }
-        self.exit_code = exit_code;
 
-        // quit all threads
-        // this must be after setting the value of subprocess, or the threads will be treated exit before actually exits
-        // remove from thread table
-        let mut thread_table = THREADS.write();
-        for tid in self.threads.iter() {
-            thread_table.remove(tid);
+        info!("process {} exit with {}", self.pid, exit_code);
+        files
+    }
+
+    /// Stop every thread but `tid`, which is about to `execve`.
+    pub fn kill_other_threads(&mut self, tid: Tid) {
+        let threads = {
+            let mut thread_table = THREADS.write();
+            let others = self
+                .threads
+                .iter()
+                .copied()
+                .filter(|&id| id != tid)
+                .collect::<Vec<_>>();
+            self.threads.retain(|&id| id == tid);
+            others
+                .iter()
+                .filter_map(|id| thread_table.remove(id))
+                .collect::<Vec<_>>()
+        };
+        for thread in threads {
+            self.exited_cpu_time += thread.exec_runtime();
+            thread.kill();
         }
-        self.threads.clear();
+    }
 
-        info!("process {} exit with {}", self.pid, exit_code);
+    /// Exit the process because of the fatal `signal`, like `exit`.
+    #[must_use]
+    pub fn exit_by_signal(&mut self, signal: Signal) -> BTreeMap<usize, FileHandle> {
+        self.exit_signal = Some(signal);
+        self.exit(signal as usize + 128)
+    }
+
+    /// CPU time consumed by all threads of the process, including exited ones.
+    pub fn cpu_time(&self) -> Duration {
+        let thread_table = THREADS.read();
+        let live: Duration = self
+            .threads
+            .iter()
+            .filter_map(|tid| thread_table.get(tid))
+            .map(|thread| thread.exec_runtime())
+            .sum();
+        self.exited_cpu_time + live
     }
 
     pub fn exited(&self) -> bool {
         self.threads.is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{NullINode, OpenOptions};
+
+    fn open(fd_cloexec: bool) -> FileHandle {
+        let options = OpenOptions {
+            read: true,
+            write: false,
+            append: false,
+        };
+        let path = String::from("/dev/null");
+        FileHandle::new(Arc::new(NullINode), options, path, fd_cloexec)
+    }
+
+    #[test]
+    fn cloexec_files_closed() {
+        let mut files = BTreeMap::new();
+        files.insert(0, open(false));
+        files.insert(3, open(true));
+        files.insert(4, open(false));
+        files.insert(5, open(true));
+
+        let closed = take_cloexec(&mut files);
+        assert_eq!(closed.len(), 2);
+        assert!(closed.iter().all(|file| file.fd_cloexec));
+        assert_eq!(files.keys().copied().collect::<Vec<_>>(), [0, 4]);
+        assert_eq!(take_cloexec(&mut files).len(), 0);
+    }
+}
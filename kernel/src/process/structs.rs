@@ -1,21 +1,82 @@
-use crate::memory::{handler, GlobalFrameAlloc, MemoryAttr, MemorySet, Page, PAGE_SIZE};
-use alloc::sync::Arc;
-use core::str;
+use crate::consts::USER_TIME_PAGE_OFFSET;
+use crate::memory::{handler, GlobalFrameAlloc, MemoryAttr, MemorySet, Page, VirtAddr, PAGE_SIZE};
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::{mem::size_of, str};
 use log::*;
 use queen_fs::vfs::INode;
 use xmas_elf::{
-    program::{Flags, SegmentData, Type},
+    header,
+    program::{Flags, ProgramHeader64, SegmentData, Type},
     ElfFile,
 };
 
+/// Bytes of an ELF read at first, enough for the headers of most files.
+/// 0x3c0: magic number from ld-musl.so
+const ELF_HEADER_READ: usize = 0x3c0;
+/// Limit on the bytes read for the headers of an ELF.
+const ELF_HEADER_MAX: usize = 0x10000;
+/// Program header type of PT_GNU_STACK, its flags are the ones of the stack.
+const PT_GNU_STACK: u32 = 0x6474_e551;
+
+/// Read the ELF header of `inode` with its program headers and interpreter path.
+pub fn read_elf_header(inode: &Arc<dyn INode>) -> Result<Vec<u8>, &'static str> {
+    let mut data = vec![0u8; ELF_HEADER_READ];
+    let len = inode
+        .read_at(0, &mut data)
+        .map_err(|_| "failed to read from INode")?;
+    data.truncate(len);
+    // grows at most twice, for the program headers then for the interpreter path
+    loop {
+        let needed = elf_header_size(&data)?;
+        if needed <= data.len() {
+            return Ok(data);
+        }
+        if needed > ELF_HEADER_MAX {
+            return Err("ELF headers too large");
+        }
+        data.resize(needed, 0);
+        let len = inode
+            .read_at(0, &mut data)
+            .map_err(|_| "failed to read from INode")?;
+        if len < needed {
+            return Err("ELF headers past the end of file");
+        }
+    }
+}
+
+/// Bytes of the ELF starting with `data` up to the end of the headers known so far.
+fn elf_header_size(data: &[u8]) -> Result<usize, &'static str> {
+    let elf = ElfFile::new(data)?;
+    if elf.header.pt1.class() != header::Class::SixtyFour {
+        return Err("ELF is not 64-bit");
+    }
+    let pt2 = &elf.header.pt2;
+    if pt2.ph_entry_size() as usize != size_of::<ProgramHeader64>() {
+        return Err("invalid ELF program header size");
+    }
+    let ph_end = (pt2.ph_offset() as usize)
+        .checked_add(pt2.ph_count() as usize * size_of::<ProgramHeader64>())
+        .ok_or("ELF program headers out of range")?;
+    if ph_end > data.len() {
+        return Ok(ph_end);
+    }
+    let interp_end = elf
+        .program_iter()
+        .filter(|ph| ph.get_type() == Ok(Type::Interp))
+        .map(|ph| ph.offset().saturating_add(ph.file_size()) as usize)
+        .fold(ph_end, usize::max);
+    Ok(interp_end)
+}
+
 trait ToMemoryAttr {
     fn to_attr(&self) -> MemoryAttr;
 }
 
 impl ToMemoryAttr for Flags {
+    /// W^X: a writable segment is never executable.
     fn to_attr(&self) -> MemoryAttr {
         let mut flags = MemoryAttr::default().user();
-        if self.is_execute() {
+        if self.is_execute() && !self.is_write() {
             flags = flags.execute();
         }
         if !self.is_write() {
@@ -27,75 +88,80 @@ impl ToMemoryAttr for Flags {
 
 /// Helper functions to process ELF file
 pub trait ElfExt {
-    /// Setup MemorySet according to the ELF file.
-    fn make_memory_set(&self, ms: &mut MemorySet, inode: &Arc<dyn INode>) -> usize;
+    /// The loadable segments of a file of `file_size` bytes, loaded `bias` higher.
+    /// Each must lie in the file and below the time page, without sharing a page
+    /// with another or with one of `taken`. Nothing is mapped yet.
+    fn load_segments(
+        &self,
+        file_size: usize,
+        bias: usize,
+        taken: &[LoadSegment],
+    ) -> Result<Vec<LoadSegment>, &'static str>;
 
     /// Get interpreter string if it has.
     fn get_interpreter(&self) -> Result<&str, &str>;
 
-    /// Append current ELF file as interpreter into given memory set.
-    /// This will insert the interpreter it a place which is "good enough" (since ld.so should be PIC).
-    fn append_as_interpreter(
-        &self,
-        inode: &Arc<dyn INode>,
-        memory_set: &mut MemorySet,
-        bias: usize,
-    );
-
     /// Get virtual address of PHDR section if it has.
     fn get_phdr_vaddr(&self) -> Option<u64>;
+
+    /// Whether the user stack should be executable, as the PT_GNU_STACK header asks.
+    /// Without the header it is not, like on Linux for AArch64.
+    fn has_executable_stack(&self) -> bool;
 }
 
 impl ElfExt for ElfFile<'_> {
-    fn make_memory_set(&self, ms: &mut MemorySet, inode: &Arc<dyn INode>) -> usize {
-        debug!("creating MemorySet from ELF");
-        let mut farthest_memory: usize = 0;
+    fn load_segments(
+        &self,
+        file_size: usize,
+        bias: usize,
+        taken: &[LoadSegment],
+    ) -> Result<Vec<LoadSegment>, &'static str> {
+        let mut segments: Vec<LoadSegment> = Vec::new();
         for ph in self.program_iter() {
-            if ph.get_type() != Ok(Type::Load) {
+            if ph.get_type() != Ok(Type::Load) || ph.mem_size() == 0 {
                 continue;
             }
-            ms.push(
-                ph.virtual_addr() as usize,
-                ph.virtual_addr() as usize + ph.mem_size() as usize,
-                ph.flags().to_attr(),
-                handler::File {
-                    file: INodeForMap(inode.clone()),
-                    mem_start: ph.virtual_addr() as usize,
-                    file_start: ph.offset() as usize,
-                    file_end: ph.offset() as usize + ph.file_size() as usize,
-                    allocator: GlobalFrameAlloc,
-                },
-                "elf",
-            );
-            if ph.virtual_addr() as usize + ph.mem_size() as usize > farthest_memory {
-                farthest_memory = ph.virtual_addr() as usize + ph.mem_size() as usize;
+            let (vaddr, mem_size) = (ph.virtual_addr() as usize, ph.mem_size() as usize);
+            let (offset, file_len) = (ph.offset() as usize, ph.file_size() as usize);
+            if file_len > mem_size {
+                return Err("ELF segment larger in file than in memory");
             }
-        }
-
-        Page::of_addr(farthest_memory + PAGE_SIZE).start_address()
-    }
-
-    fn append_as_interpreter(&self, inode: &Arc<dyn INode>, ms: &mut MemorySet, bias: usize) {
-        debug!("inserting interpreter from ELF");
-
-        for ph in self.program_iter() {
-            if ph.get_type() != Ok(Type::Load) {
-                continue;
+            let file_end = offset
+                .checked_add(file_len)
+                .filter(|&end| end <= file_size)
+                .ok_or("ELF segment past the end of file")?;
+            if vaddr % PAGE_SIZE != offset % PAGE_SIZE {
+                return Err("ELF segment not aligned with its file offset");
+            }
+            let (start, end) = vaddr
+                .checked_add(bias)
+                .and_then(|start| Some((start, start.checked_add(mem_size)?)))
+                .filter(|&(_, end)| end <= USER_TIME_PAGE_OFFSET)
+                .ok_or("ELF segment outside of user space")?;
+            let flags = ph.flags();
+            if flags.is_write() && flags.is_execute() {
+                warn!(
+                    "elf: segment at {:#x} is writable and executable, mapped without execute",
+                    start
+                );
             }
-            ms.push(
-                ph.virtual_addr() as usize + bias,
-                ph.virtual_addr() as usize + ph.mem_size() as usize + bias,
-                ph.flags().to_attr(),
-                handler::File {
-                    file: INodeForMap(inode.clone()),
-                    mem_start: ph.virtual_addr() as usize + bias,
-                    file_start: ph.offset() as usize,
-                    file_end: ph.offset() as usize + ph.file_size() as usize,
-                    allocator: GlobalFrameAlloc,
-                },
-                "elf-interp",
-            )
+            let segment = LoadSegment {
+                start,
+                end,
+                file_start: offset,
+                file_end,
+                attr: flags.to_attr(),
+            };
+            if taken
+                .iter()
+                .chain(&segments)
+                .any(|seg| seg.overlaps(&segment))
+            {
+                return Err("ELF segments overlap");
+            }
+            segments.push(segment);
         }
+        Ok(segments)
     }
 
     fn get_interpreter(&self) -> Result<&str, &str> {
@@ -104,6 +170,9 @@ impl ElfExt for ElfFile<'_> {
             .filter(|ph| ph.get_type() == Ok(Type::Interp))
             .next()
             .ok_or("no interp header")?;
+        if header.offset().saturating_add(header.file_size()) > self.input.len() as u64 {
+            return Err("interp header out of range");
+        }
         let mut data = match header.get_data(self)? {
             SegmentData::Undefined(data) => data,
             _ => unreachable!(),
@@ -134,6 +203,52 @@ impl ElfExt for ElfFile<'_> {
             None
         }
     }
+
+    fn has_executable_stack(&self) -> bool {
+        self.program_iter()
+            .find(|ph| ph.get_type() == Ok(Type::OsSpecific(PT_GNU_STACK)))
+            .map_or(false, |ph| ph.flags().is_execute())
+    }
+}
+
+/// A loadable segment of an ELF, checked before anything is mapped.
+pub struct LoadSegment {
+    start: VirtAddr,
+    end: VirtAddr,
+    file_start: usize,
+    file_end: usize,
+    attr: MemoryAttr,
+}
+
+impl LoadSegment {
+    /// Whether the two segments share a page.
+    fn overlaps(&self, other: &LoadSegment) -> bool {
+        let page = |addr: VirtAddr| addr / PAGE_SIZE;
+        page(self.start) <= page(other.end - 1) && page(other.start) <= page(self.end - 1)
+    }
+
+    /// Map the segment from `inode`, where no area of `ms` may be.
+    pub fn push(self, ms: &mut MemorySet, inode: &Arc<dyn INode>, name: &'static str) {
+        ms.push(
+            self.start,
+            self.end,
+            self.attr,
+            handler::File {
+                file: INodeForMap(inode.clone()),
+                mem_start: self.start,
+                file_start: self.file_start,
+                file_end: self.file_end,
+                allocator: GlobalFrameAlloc,
+            },
+            name,
+        );
+    }
+}
+
+/// Where an interpreter can be loaded after `segments`, "good enough" since ld.so is PIC.
+pub fn interpreter_bias(segments: &[LoadSegment]) -> VirtAddr {
+    let farthest_memory = segments.iter().map(|seg| seg.end).max().unwrap_or(0);
+    Page::of_addr(farthest_memory + PAGE_SIZE).start_address()
 }
 
 #[derive(Clone)]
@@ -144,3 +259,133 @@ impl handler::file::Read for INodeForMap {
         self.0.read_at(offset, buf).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PT_LOAD: u32 = 1;
+    const PF_RX: u32 = 0b101;
+    const PF_RW: u32 = 0b110;
+    const PF_RWX: u32 = 0b111;
+
+    /// A 64-bit AArch64 executable with program headers of
+    /// `(type, flags, offset, vaddr, file_size, mem_size)`, padded to `len` bytes.
+    fn elf(phdrs: &[(u32, u32, u64, u64, u64, u64)], len: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"\x7fELF");
+        data.extend_from_slice(&[2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        data.extend_from_slice(&183u16.to_le_bytes()); // EM_AARCH64
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&0x40_0000u64.to_le_bytes()); // entry
+        data.extend_from_slice(&64u64.to_le_bytes()); // program headers
+        data.extend_from_slice(&0u64.to_le_bytes()); // section headers
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&64u16.to_le_bytes());
+        data.extend_from_slice(&(size_of::<ProgramHeader64>() as u16).to_le_bytes());
+        data.extend_from_slice(&(phdrs.len() as u16).to_le_bytes());
+        data.extend_from_slice(&[64, 0, 0, 0, 0, 0]);
+        for &(type_, flags, offset, vaddr, file_size, mem_size) in phdrs {
+            data.extend_from_slice(&type_.to_le_bytes());
+            data.extend_from_slice(&flags.to_le_bytes());
+            for field in &[offset, vaddr, vaddr, file_size, mem_size, PAGE_SIZE as u64] {
+                data.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        data.resize(len.max(data.len()), 0);
+        data
+    }
+
+    fn segments(data: &[u8], bias: usize) -> Result<Vec<LoadSegment>, &'static str> {
+        ElfFile::new(data)?.load_segments(data.len(), bias, &[])
+    }
+
+    #[test]
+    fn corrupt_header() {
+        let data = elf(&[(PT_LOAD, PF_RX, 0, 0x40_0000, 0x100, 0x100)], 0x1000);
+        assert!(elf_header_size(&data[..40]).is_err());
+        let mut bad_magic = data.clone();
+        bad_magic[1] = b'X';
+        assert!(elf_header_size(&bad_magic).is_err());
+        let mut bad_phentsize = data.clone();
+        bad_phentsize[54] = 32;
+        assert!(elf_header_size(&bad_phentsize).is_err());
+        // more program headers than read so far, more has to be read
+        let mut many = data[..64 + 56].to_vec();
+        many[56] = 3;
+        assert_eq!(elf_header_size(&many), Ok(64 + 56 * 3));
+        assert_eq!(elf_header_size(&data), Ok(64 + 56));
+    }
+
+    #[test]
+    fn valid_segments() {
+        let data = elf(
+            &[
+                (PT_LOAD, PF_RX, 0, 0x40_0000, 0x1800, 0x1800),
+                (PT_LOAD, PF_RW, 0x1800, 0x40_2800, 0x100, 0x3000),
+            ],
+            0x2000,
+        );
+        let segs = segments(&data, 0x1000_0000).unwrap();
+        assert_eq!(segs.len(), 2);
+        assert_eq!(segs[0].start, 0x1040_0000);
+        assert!(segs[0].attr.readonly && segs[0].attr.execute);
+        assert_eq!((segs[1].file_start, segs[1].file_end), (0x1800, 0x1900));
+        assert!(!segs[1].attr.readonly && !segs[1].attr.execute);
+        assert_eq!(interpreter_bias(&segs), 0x1040_6000);
+    }
+
+    #[test]
+    fn corrupt_segments() {
+        let load = |phdrs: &[(u32, u32, u64, u64, u64, u64)]| segments(&elf(phdrs, 0x2000), 0);
+        // larger in the file than in memory
+        assert!(load(&[(PT_LOAD, PF_RX, 0, 0x40_0000, 0x200, 0x100)]).is_err());
+        // past the end of the file, also by overflow
+        assert!(load(&[(PT_LOAD, PF_RX, 0x1000, 0x40_1000, 0x1001, 0x1001)]).is_err());
+        assert!(load(&[(PT_LOAD, PF_RX, u64::MAX, 0x40_0fff, 2, 2)]).is_err());
+        // address and offset not congruent
+        assert!(load(&[(PT_LOAD, PF_RX, 0x10, 0x40_0000, 0x100, 0x100)]).is_err());
+        // over the time page, or wrapping around
+        let top = USER_TIME_PAGE_OFFSET as u64;
+        assert!(load(&[(PT_LOAD, PF_RW, 0, top - 0x1000, 0, 0x2000)]).is_err());
+        assert!(load(&[(PT_LOAD, PF_RW, 0, u64::MAX & !0xfff, 0, 0x2000)]).is_err());
+        // sharing a page
+        assert!(load(&[
+            (PT_LOAD, PF_RX, 0, 0x40_0000, 0x100, 0x100),
+            (PT_LOAD, PF_RW, 0x800, 0x40_0800, 0x100, 0x100),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn interpreter_overlapping_executable() {
+        let exec = elf(&[(PT_LOAD, PF_RX, 0, 0x40_0000, 0x1000, 0x1000)], 0x1000);
+        let taken = segments(&exec, 0).unwrap();
+        let interp = elf(&[(PT_LOAD, PF_RX, 0, 0, 0x1000, 0x1000)], 0x1000);
+        let interp = ElfFile::new(&interp).unwrap();
+        assert!(interp.load_segments(0x1000, 0x40_0000, &taken).is_err());
+        let bias = interpreter_bias(&taken);
+        let segs = interp.load_segments(0x1000, bias, &taken).unwrap();
+        assert_eq!(segs[0].start, bias);
+    }
+
+    #[test]
+    fn executable_stack() {
+        let stack = |flags| {
+            let data = elf(&[(PT_GNU_STACK, flags, 0, 0, 0, 0)], 0);
+            ElfFile::new(&data).unwrap().has_executable_stack()
+        };
+        assert!(!stack(PF_RW));
+        assert!(stack(PF_RWX));
+        let data = elf(&[(PT_LOAD, PF_RX, 0, 0x40_0000, 0x100, 0x100)], 0x1000);
+        assert!(!ElfFile::new(&data).unwrap().has_executable_stack());
+    }
+
+    #[test]
+    fn writable_segment_not_executable() {
+        let data = elf(&[(PT_LOAD, PF_RWX, 0, 0x40_0000, 0x100, 0x100)], 0x1000);
+        let segs = segments(&data, 0).unwrap();
+        assert!(!segs[0].attr.readonly && !segs[0].attr.execute);
+    }
+}
@@ -1,26 +1,37 @@
-use super::{abi, add_to_process_table, structs::ElfExt, Process, PID_INIT};
+use super::{
+    abi, add_to_process_table,
+    structs::{interpreter_bias, read_elf_header, ElfExt, LoadSegment},
+    Pid, Process, RLimit, PID_INIT,
+};
 use crate::{
     arch::{
+        asid::Asid,
+        cpu,
         interrupt::{
-            consts::{is_irq, is_page_fault, is_syscall},
+            consts::{
+                exception_signal, exception_syndrome, is_fatal_serror, is_fiq, is_irq,
+                is_page_fault, is_serror, is_syscall,
+            },
             IRQ_MANAGER,
         },
-        memory::{get_page_fault_addr, set_page_table},
+        memory::{fault_access, get_page_fault_addr, set_page_table},
     },
+    consts::MAX_CPU_NUM,
     drivers::IrqManager,
-    fs::{FileHandle, OpenOptions, FOLLOW_MAX_DEPTH, ROOT_INODE},
+    fs::{self, FileHandle, OpenOptions},
     memory::{
+        frame_stats,
         handler::{ByFrame, Delay},
-        GlobalFrameAlloc, MemoryAttr, MemorySet, PAGE_SIZE,
+        Access, GlobalFrameAlloc, MemoryAttr, MemorySet, UserMemory, VirtAddr, PAGE_SIZE,
     },
     process::abi::ProcInitInfo,
-    signal::{handle_signal, Signal, SignalAction, SignalStack, Sigset},
-    sync::{
-        spin::{MutexNoIrq, RwLock},
-        EventBus,
+    signal::{
+        handle_signal, send_signal, Siginfo, Signal, SignalAction, SignalStack, Sigset, BUS_ADRERR,
+        BUS_MCEERR_AR, SEGV_MAPERR,
     },
-    syscall::handle_syscall,
-    task::{yield_now, SchedTaskRef, Task, executor},
+    sync::{spin::MutexNoIrq, EventBus, PerCpu, RwLockNoIrq},
+    syscall::{handle_syscall, SysError},
+    task::{yield_now, SchedPolicy, SchedTaskRef, Task, executor},
 };
 use aarch64::trap::UserContext;
 use alloc::{
@@ -32,9 +43,10 @@ use alloc::{
 };
 use core::{
     future::Future,
-    mem::MaybeUninit,
     pin::Pin,
-    task::{Context, Poll},
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
 };
 use num_traits::FromPrimitive;
 use queen_fs::INode;
@@ -42,7 +54,51 @@ use xmas_elf::{header, ElfFile};
 
 pub type Tid = usize;
 pub type ThreadRef = Arc<Thread>;
-pub static THREADS: RwLock<BTreeMap<Tid, ThreadRef>> = RwLock::new(BTreeMap::new());
+pub static THREADS: RwLockNoIrq<BTreeMap<Tid, ThreadRef>> = RwLockNoIrq::new(BTreeMap::new());
+
+/// Pid of the user thread running on each CPU, 0 if none.
+static CURRENT_PIDS: PerCpu<AtomicUsize> = {
+    const NONE: AtomicUsize = AtomicUsize::new(0);
+    PerCpu::new([NONE; MAX_CPU_NUM])
+};
+
+/// User thread being polled on each CPU.
+static CURRENT_THREADS: PerCpu<MutexNoIrq<Option<ThreadRef>>> = {
+    const NONE: MutexNoIrq<Option<ThreadRef>> = MutexNoIrq::new(None);
+    PerCpu::new([NONE; MAX_CPU_NUM])
+};
+
+/// The user thread being polled on this CPU, `None` in kernel tasks.
+pub fn current_thread() -> Option<ThreadRef> {
+    CURRENT_THREADS.this_cpu().lock().clone()
+}
+
+/// Pid of the user thread being polled on this CPU.
+///
+/// This does not lock the process, so it is fine to call while the process is locked.
+pub fn current_pid() -> Option<Pid> {
+    match CURRENT_PIDS.this_cpu().load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(pid),
+    }
+}
+
+/// Get thread by tid
+pub fn thread(tid: Tid) -> Option<ThreadRef> {
+    THREADS.read().get(&tid).cloned()
+}
+
+/// Wake the scheduler task of thread `tid`, so that it re-checks whatever it
+/// is blocked on. Return `false` if there is no such thread.
+pub fn wake_thread(tid: Tid) -> bool {
+    match thread(tid) {
+        Some(thread) => {
+            thread.wake();
+            true
+        }
+        None => false,
+    }
+}
 
 /// Mutable part of a thread struct
 #[derive(Default)]
@@ -56,6 +112,36 @@ pub struct ThreadInner {
     pub sig_mask: Sigset,
     /// signal alternate stack
     pub signal_alternate_stack: SignalStack,
+    /// Waker of the scheduler task, recorded each time it is polled
+    waker: Option<Waker>,
+    /// Set by `kill`, the thread must not return to user mode
+    killed: bool,
+}
+
+#[cfg(test)]
+impl ThreadInner {
+    /// A thread which never ran, for host tests.
+    pub(super) fn mock() -> Self {
+        ThreadInner {
+            context: Some(UserContext::default()),
+            task: None,
+            clear_child_tid: 0,
+            sig_mask: Sigset::default(),
+            signal_alternate_stack: SignalStack::default(),
+            waker: None,
+            killed: false,
+        }
+    }
+}
+
+/// An ELF with its interpreter, read and checked before the image it replaces is dropped.
+pub struct UserImage {
+    inode: Arc<dyn INode>,
+    segments: Vec<LoadSegment>,
+    interpreter: Option<(Arc<dyn INode>, Vec<LoadSegment>)>,
+    entry_addr: usize,
+    auxv: BTreeMap<u8, usize>,
+    executable_stack: bool,
 }
 
 pub struct Thread {
@@ -83,30 +169,28 @@ impl Thread {
         self_ref
     }
 
-    /// Construct virtual memory of a new user process from ELF at `inode`.
-    /// Return `(MemorySet, entry_point, ustack_top)`
-    pub fn new_user_vm(
-        inode: &Arc<dyn INode>,
-        args: Vec<String>,
-        envs: Vec<String>,
-        vm: &mut MemorySet,
-    ) -> Result<(usize, usize), &'static str> {
+    /// Read the ELF at `inode` and its interpreter, checking every segment to load.
+    pub fn load_user_image(inode: &Arc<dyn INode>) -> Result<UserImage, &'static str> {
+        let file_size = |inode: &Arc<dyn INode>| {
+            inode
+                .metadata()
+                .map(|metadata| metadata.size)
+                .map_err(|_| "failed to read from INode")
+        };
+
         // Read ELF header
-        // 0x3c0: magic number from ld-musl.so
-        let mut data = [0u8; 0x3c0];
-        inode
-            .read_at(0, &mut data)
-            .map_err(|_| "failed to read from INode")?;
+        let data = read_elf_header(inode)?;
 
         // Parse ELF
         let elf = ElfFile::new(&data)?;
 
-        // Check ELF type
-        match elf.header.pt2.type_().as_type() {
-            header::Type::Executable => {}
-            header::Type::SharedObject => {}
+        // Check ELF type, position independent executables (static-PIE too) are loaded
+        // away from the null page
+        let load_bias = match elf.header.pt2.type_().as_type() {
+            header::Type::Executable => 0,
+            header::Type::SharedObject => crate::consts::USER_PIE_OFFSET,
             _ => return Err("ELF is not executable or shared object"),
-        }
+        };
 
         // Check ELF arch
         match elf.header.pt2.machine().as_machine() {
@@ -118,56 +202,100 @@ impl Thread {
         let mut auxv = {
             let mut map = BTreeMap::new();
             if let Some(phdr_vaddr) = elf.get_phdr_vaddr() {
-                map.insert(abi::AT_PHDR, phdr_vaddr as usize);
+                map.insert(abi::AT_PHDR, phdr_vaddr as usize + load_bias);
             }
             map.insert(abi::AT_PHENT, elf.header.pt2.ph_entry_size() as usize);
             map.insert(abi::AT_PHNUM, elf.header.pt2.ph_count() as usize);
             map.insert(abi::AT_PAGESZ, PAGE_SIZE);
+            map.insert(abi::AT_HWCAP, cpu::hwcap());
+            map.insert(abi::AT_CLKTCK, abi::USER_HZ);
+            // new processes run as root
+            map.insert(abi::AT_UID, 0);
+            map.insert(abi::AT_EUID, 0);
+            map.insert(abi::AT_GID, 0);
+            map.insert(abi::AT_EGID, 0);
+            map.insert(abi::AT_SECURE, 0);
             map
         };
 
         // entry point
-        let mut entry_addr = elf.header.pt2.entry_point() as usize;
-        // Make page table
-        vm.clear();
-        let bias = elf.make_memory_set(vm, inode);
+        let mut entry_addr = elf.header.pt2.entry_point() as usize + load_bias;
+        let segments = elf.load_segments(file_size(inode)?, load_bias, &[])?;
 
         // Check interpreter (for dynamic link)
         // When interpreter is used, map both dynamic linker and executable
+        let mut interpreter = None;
         if let Ok(loader_path) = elf.get_interpreter() {
+            let bias = interpreter_bias(&segments);
             info!("Handling interpreter... offset={:x}", bias);
             // assuming absolute path
-            let interp_inode = ROOT_INODE
-                .lookup_follow(loader_path, FOLLOW_MAX_DEPTH)
-                .map_err(|_| "interpreter not found")?;
+            let interp_inode =
+                fs::lookup(loader_path, true).map_err(|_| "interpreter not found")?;
             // load loader by bias and set aux vector.
-            let mut interp_data: [u8; 0x3c0] = unsafe { MaybeUninit::zeroed().assume_init() };
-            interp_inode
-                .read_at(0, &mut interp_data)
-                .map_err(|_| "failed to read from INode")?;
+            let interp_data = read_elf_header(&interp_inode)?;
             let elf_interp = ElfFile::new(&interp_data)?;
-            elf_interp.append_as_interpreter(&interp_inode, vm, bias);
+            let interp_segments =
+                elf_interp.load_segments(file_size(&interp_inode)?, bias, &segments)?;
 
             // update auxiliary vector
-            auxv.insert(abi::AT_ENTRY, elf.header.pt2.entry_point() as usize);
+            auxv.insert(abi::AT_ENTRY, entry_addr);
             auxv.insert(abi::AT_BASE, bias);
 
             // use interpreter as actual entry point
-            debug!("entry point: {:x}", elf.header.pt2.entry_point() as usize);
+            debug!("entry point: {:x}", entry_addr);
             entry_addr = elf_interp.header.pt2.entry_point() as usize + bias;
+            interpreter = Some((interp_inode, interp_segments));
         }
 
+        Ok(UserImage {
+            inode: inode.clone(),
+            segments,
+            interpreter,
+            entry_addr,
+            auxv,
+            executable_stack: elf.has_executable_stack(),
+        })
+    }
+
+    /// Replace the virtual memory `vm` with the checked `image`.
+    /// Return `(entry_point, ustack_top, heap_start)`
+    pub fn new_user_vm(
+        image: UserImage,
+        args: Vec<String>,
+        envs: Vec<String>,
+        vm: &mut MemorySet,
+    ) -> (usize, usize, usize) {
+        // Make page table
+        debug!("creating MemorySet from ELF");
+        vm.clear();
+        for segment in image.segments {
+            segment.push(vm, &image.inode, "elf");
+        }
+        if let Some((interp_inode, interp_segments)) = image.interpreter {
+            for segment in interp_segments {
+                segment.push(vm, &interp_inode, "elf-interp");
+            }
+        }
+
+        // program break starts right after the loaded images
+        let heap_start = vm.end_addr();
+
         // User stack
         use crate::consts::{USER_STACK_OFFSET, USER_STACK_SIZE};
         let mut ustack_top = {
             let ustack_buttom = USER_STACK_OFFSET;
+            let ustack_attr = if image.executable_stack {
+                MemoryAttr::default().user().execute()
+            } else {
+                MemoryAttr::default().user()
+            };
             let ustack_top = USER_STACK_OFFSET + USER_STACK_SIZE;
 
             // user stack except top 4 pages
             vm.push(
                 ustack_buttom,
                 ustack_top - PAGE_SIZE * 4,
-                MemoryAttr::default().user().execute(),
+                ustack_attr,
                 Delay::new(GlobalFrameAlloc),
                 "user_stack_delay",
             );
@@ -176,32 +304,43 @@ impl Thread {
             vm.push(
                 ustack_top - PAGE_SIZE * 4,
                 ustack_top,
-                MemoryAttr::default().user().execute(), // feature
+                ustack_attr,
                 ByFrame::new(GlobalFrameAlloc),
                 "user_stack",
             );
             ustack_top
         };
 
+        // clocks read by user space without a syscall
+        crate::arch::time_page::map(vm);
+
         // Make init info
-        let init_info = ProcInitInfo { args, envs, auxv };
+        let init_info = ProcInitInfo {
+            args,
+            envs,
+            auxv: image.auxv,
+        };
         unsafe {
             vm.with(|| ustack_top = init_info.push_at(ustack_top));
         }
 
-        Ok((entry_addr, ustack_top))
+        (image.entry_addr, ustack_top, heap_start)
     }
 
-    /// Make a new user process from ELF `data`
+    /// Make a new user process from ELF `data`, `ENOEXEC` if it can not be loaded
     pub fn new_user(
         inode: &Arc<dyn INode>,
         exec_path: &str,
         args: Vec<String>,
         envs: Vec<String>,
-    ) -> ThreadRef {
+    ) -> Result<ThreadRef, SysError> {
+        let image = Self::load_user_image(inode).map_err(|err| {
+            warn!("failed to load {:?}: {}", exec_path, err);
+            SysError::ENOEXEC
+        })?;
         // get virtual memory info
         let mut vm = MemorySet::new();
-        let (entry_addr, ustack_top) = Self::new_user_vm(inode, args, envs, &mut vm).unwrap();
+        let (entry_addr, ustack_top, heap_start) = Self::new_user_vm(image, args, envs, &mut vm);
 
         let vm_token = vm.token();
         let vm = Arc::new(MutexNoIrq::new(vm));
@@ -267,6 +406,8 @@ impl Thread {
                 clear_child_tid: 0,
                 sig_mask: Sigset::default(),
                 signal_alternate_stack: SignalStack::default(),
+                waker: None,
+                killed: false,
             }), // allocated below
             vm: vm.clone(),
             tid: 0,
@@ -275,15 +416,27 @@ impl Thread {
                 files,
                 cwd: String::from("/"),
                 exec_path: String::from(exec_path),
+                futexes: BTreeMap::new(),
+                brk_start: heap_start,
+                brk: heap_start,
                 pid: 0, // allocated later
                 pgid: 0,
+                uid: 0,
+                euid: 0,
+                gid: 0,
+                egid: 0,
                 parent: (0, Weak::new()),
                 children: Vec::new(),
                 threads: Vec::new(),
                 exit_code: 0,
+                exited_cpu_time: Duration::default(),
+                children_cpu_time: Duration::default(),
+                exit_signal: None,
+                stop_signal: None,
                 pending_sigset: Sigset::empty(),
                 sig_queue: VecDeque::new(),
                 dispositions: [SignalAction::default(); Signal::RTMAX + 1],
+                rlimits: RLimit::defaults(),
                 event_bus: EventBus::new(),
             })),
         };
@@ -293,14 +446,18 @@ impl Thread {
         // set pid to tid
         add_to_process_table(res.process.clone(), res.tid);
 
-        res
+        Ok(res)
     }
 
     /// Fork a new process from current one
     /// Only current process is persisted
     pub fn fork(&self, tf: &UserContext) -> ThreadRef {
-        // clone virtual memory
-        let vm = self.vm.lock().clone();
+        // clone virtual memory, with every page in memory or in the swap
+        let vm = {
+            let mut vm = self.vm.lock();
+            vm.wait_swap_io();
+            vm.clone()
+        };
         let vm_token = vm.token();
         let vm = Arc::new(MutexNoIrq::new(vm));
 
@@ -315,15 +472,27 @@ impl Thread {
             files: process.files.clone(), // share open file descriptions
             cwd: process.cwd.clone(),
             exec_path: process.exec_path.clone(),
+            futexes: BTreeMap::new(),
+            brk_start: process.brk_start,
+            brk: process.brk,
             pid: 0, // assigned later
             pgid: process.pgid,
+            uid: process.uid,
+            euid: process.euid,
+            gid: process.gid,
+            egid: process.egid,
             parent: (process.pid, Arc::downgrade(&self.process)),
             children: Vec::new(),
             threads: Vec::new(),
             exit_code: 0,
+            exited_cpu_time: Duration::default(),
+            children_cpu_time: Duration::default(),
+            exit_signal: None,
+            stop_signal: None,
             pending_sigset: Sigset::empty(),
             sig_queue: VecDeque::new(),
             dispositions: process.dispositions.clone(),
+            rlimits: process.rlimits,
             event_bus: EventBus::new(),
         }));
 
@@ -342,6 +511,8 @@ impl Thread {
                 clear_child_tid: 0,
                 sig_mask,
                 signal_alternate_stack: sigaltstack,
+                waker: None,
+                killed: false,
             }),
             vm,
             process: new_process,
@@ -386,6 +557,8 @@ impl Thread {
                 clear_child_tid,
                 sig_mask,
                 signal_alternate_stack: signal_stack,
+                waker: None,
+                killed: false,
             }),
             vm: self.vm.clone(),
             process: self.process.clone(),
@@ -403,6 +576,73 @@ impl Thread {
         self.inner.lock().context = Some(ctx);
     }
 
+    /// Total CPU time this thread has been running.
+    pub fn exec_runtime(&self) -> Duration {
+        let ns = match &self.inner.lock().task {
+            Some((_, sched_task)) => sched_task.lock().sum_exec_runtime(),
+            None => 0,
+        };
+        Duration::from_nanos(ns as u64)
+    }
+
+    /// Nice value of this thread.
+    pub fn nice(&self) -> isize {
+        match &self.inner.lock().task {
+            Some((_, sched_task)) => sched_task.lock().nice(),
+            None => 0,
+        }
+    }
+
+    /// Set the nice value of this thread, clamped to the valid range.
+    pub fn set_nice(&self, nice: isize) {
+        if let Some((_, sched_task)) = &self.inner.lock().task {
+            sched_task.lock().set_nice(nice);
+        }
+    }
+
+    /// Scheduling policy of this thread.
+    pub fn sched_policy(&self) -> SchedPolicy {
+        match &self.inner.lock().task {
+            Some((_, sched_task)) => sched_task.lock().policy(),
+            None => SchedPolicy::Normal,
+        }
+    }
+
+    pub fn set_sched_policy(&self, policy: SchedPolicy) {
+        if let Some((_, sched_task)) = &self.inner.lock().task {
+            sched_task.lock().set_policy(policy);
+        }
+    }
+
+    /// Stop this thread: its task is canceled and the future is dropped the
+    /// next time it reaches the scheduler. If it is running on another CPU
+    /// it finishes the current step first, but never returns to user mode.
+    pub fn kill(&self) {
+        let task = {
+            let mut inner = self.inner.lock();
+            inner.killed = true;
+            inner.task.take()
+        };
+        // dropping the `Task` handle cancels it
+        drop(task);
+    }
+
+    /// Wake the scheduler task of this thread if it has been polled before.
+    pub fn wake(&self) {
+        // do not wake while holding the lock, that reschedules the task
+        let waker = self.inner.lock().waker.clone();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Move this thread behind its peers, call before yielding.
+    pub fn prepare_yield(&self) {
+        if let Some((_, sched_task)) = &self.inner.lock().task {
+            sched_task.lock().yield_task();
+        }
+    }
+
     /// this thread has signal to handle
     pub fn has_signal_to_handle(&self) -> bool {
         self.process
@@ -422,73 +662,156 @@ impl Thread {
             .is_some()
     }
 
-    pub fn spawn(self: &Arc<Self>) {
-        let vmtoken = self.vm.lock().token() as usize;
-        let thread = self.clone();
-        let future = async move {
-            loop {
-                let mut thread_context = thread.begin_running();
-                trace!("go to user: {:#x?}", thread_context);
-                thread_context.run();
-
-                let trap_num = thread_context.trap_num;
-                trace!(
-                    "back from user: {:#x?} trap_num {:#x}",
-                    thread_context,
-                    trap_num
-                );
-
-                let mut exit = false;
-                let mut do_yield = false;
+    /// Run the thread in user space until it exits or is killed: enter EL0, then
+    /// handle the trap that returned to the kernel and the pending signals.
+    async fn run(self: Arc<Self>) {
+        loop {
+            if self.inner.lock().killed {
+                info!("thread {} killed", self.tid);
+                break;
+            }
+            let mut thread_context = self.begin_running();
+            trace!("go to user: {:#x?}", thread_context);
+            thread_context.run();
+
+            let trap_num = thread_context.trap_num;
+            trace!(
+                "back from user: {:#x?} trap_num {:#x}",
+                thread_context,
+                trap_num
+            );
 
-                match trap_num {
-                    // must be first
-                    _ if is_page_fault(trap_num) => {
-                        // page fault
-                        let addr = get_page_fault_addr();
-                        trace!("page fault from user @ {:#x}", addr);
+            let mut exit = false;
+            let mut do_yield = false;
 
-                        if !thread.vm.lock().handle_page_fault(addr) {
-                            // TODO: SIGSEGV
-                            panic!("page fault handle failed");
-                        }
-                    }
-                    _ if is_syscall(trap_num) => {
-                        exit = handle_syscall(&thread, &mut thread_context).await
-                    }
-                    _ if is_irq(trap_num) => {
-                        trace!("handle irq {:#x}", trap_num);
-                        IRQ_MANAGER.get().unwrap().handle_pending_irqs();
-                        do_yield = true;
-                    }
-                    _ => {
-                        panic!(
-                            "unhandled trap in thread {} trap {:#x} {:x?}",
-                            thread.tid, trap_num, thread_context
-                        );
+            match trap_num {
+                // must be first
+                _ if is_page_fault(trap_num) => {
+                    self.handle_page_fault(get_page_fault_addr(), fault_access());
+                }
+                _ if is_syscall(trap_num) => {
+                    exit = handle_syscall(&self, &mut thread_context).await
+                }
+                _ if is_irq(trap_num) || is_fiq(trap_num) => {
+                    trace!("handle irq {:#x}", trap_num);
+                    IRQ_MANAGER.get().unwrap().handle_pending_irqs();
+                    do_yield = true;
+                }
+                _ if is_serror(trap_num) => {
+                    let esr = exception_syndrome();
+                    warn!(
+                        "SError in thread {}, ESR: {:#x}, FAR: {:#x}, ELR: {:#x}",
+                        self.tid,
+                        esr,
+                        get_page_fault_addr(),
+                        thread_context.elr
+                    );
+                    // the error is confined to the process, the kernel carries on
+                    if is_fatal_serror(esr) {
+                        self.send_fault_signal(Signal::SIGBUS, BUS_MCEERR_AR);
                     }
                 }
-
-                // check signals
-                if !exit {
-                    exit = handle_signal(&thread, &mut thread_context);
+                _ => {
+                    let esr = exception_syndrome();
+                    let (signal, code) = exception_signal(esr);
+                    info!(
+                        "thread {} trap {:#x}, ESR: {:#x}, ELR: {:#x}: {:?}",
+                        self.tid, trap_num, esr, thread_context.elr, signal
+                    );
+                    self.send_fault_signal(signal, code);
                 }
+            }
 
-                thread.end_running(thread_context);
-                if exit {
-                    info!("thread {} stopped", thread.tid);
-                    break;
-                } else if do_yield {
-                    yield_now().await;
-                }
+            // check signals
+            if !exit {
+                exit = handle_signal(&self, &mut thread_context);
             }
+            // the user memory checked by the syscall may be swapped out again
+            self.vm.lock().unpin(self.tid);
+
+            self.end_running(thread_context);
+            if exit {
+                info!("thread {} stopped", self.tid);
+                break;
+            } else if do_yield {
+                yield_now().await;
+            }
+        }
+    }
+
+    /// Options to spawn a child of this thread with: the child inherits its
+    /// nice value and starts from its vruntime, instead of the minimum of the
+    /// run queue which would let it run ahead of every other task.
+    pub fn child_spawn_options(&self) -> executor::SpawnExtraOptions {
+        match &self.inner.lock().task {
+            Some((_, sched_task)) => executor::SpawnExtraOptions::fork(sched_task.clone()),
+            None => executor::SpawnExtraOptions::none(),
+        }
+    }
+
+    /// Handle a page fault of this thread in user mode at `addr`, growing the
+    /// stack if needed. SIGSEGV if the address is not mapped.
+    pub fn handle_page_fault(&self, addr: VirtAddr, access: Access) {
+        use crate::consts::{USER_STACK_OFFSET, USER_STACK_SIZE};
+        trace!("page fault from user @ {:#x}, {:?}", addr, access);
+        let (handled, mapped) = {
+            let stack_limit = self.process.lock().max_stack_size();
+            let mut vm = self.vm.lock();
+            let handled = vm.fault_in(addr, access)
+                || vm.grow_down(
+                    USER_STACK_OFFSET + USER_STACK_SIZE,
+                    stack_limit,
+                    addr,
+                    access,
+                );
+            (handled, vm.iter().any(|area| area.contains(addr)))
         };
+        if !handled && mapped && frame_stats().1 == 0 {
+            // a page of the mapping could not be backed
+            info!("thread {} out of memory @ {:#x}", self.tid, addr);
+            self.send_fault_signal(Signal::SIGBUS, BUS_ADRERR);
+        } else if !handled {
+            info!("thread {} segfault @ {:#x}", self.tid, addr);
+            self.send_fault_signal(Signal::SIGSEGV, SEGV_MAPERR);
+        }
+    }
+
+    /// Send `signal` caused by the last trap to this thread.
+    fn send_fault_signal(&self, signal: Signal, code: i32) {
+        send_signal(
+            self.process.clone(),
+            self.tid as isize,
+            Siginfo {
+                signo: signal as i32,
+                errno: 0,
+                code,
+                field: Default::default(),
+            },
+        );
+    }
 
-        let (task, sched_task) = executor::local_executor().spawn(PageTableSwitchWrapper {
-            inner: MutexNoIrq::new(Box::pin(future)),
+    /// Spawn the task running the thread on the least loaded CPU, and keep it
+    /// to cancel it on `kill`.
+    pub fn spawn(self: &Arc<Self>, options: executor::SpawnExtraOptions) {
+        let (vmtoken, asid) = {
+            let mut vm = self.vm.lock();
+            (vm.token() as usize, vm.get_page_table_mut().asid())
+        };
+        // pid has been assigned and never changes
+        let pid = self.process.lock().pid;
+        let nice = match &options {
+            executor::SpawnExtraOptions::Fork { parent_sched_task } => {
+                parent_sched_task.lock().nice()
+            }
+            executor::SpawnExtraOptions::None => 0,
+        };
+        let (task, sched_task) = executor::least_loaded_executor().spawn(PageTableSwitchWrapper {
+            inner: MutexNoIrq::new(Box::pin(self.clone().run())),
             vmtoken,
-            thread: self.clone()
-        }, 0, executor::SpawnExtraOptions::None);
+            asid,
+            thread: self.clone(),
+            pid,
+        }, nice, options);
         self.inner.lock().task = Some((task, sched_task));
     }
 }
@@ -497,15 +820,30 @@ impl Thread {
 struct PageTableSwitchWrapper {
     inner: MutexNoIrq<Pin<Box<dyn Future<Output = ()> + Send>>>,
     vmtoken: usize,
+    asid: Arc<Asid>,
     thread: Arc<Thread>,
+    pid: Pid,
 }
 
 impl Future for PageTableSwitchWrapper {
     type Output = ();
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         // vmtoken won't change
-        set_page_table(self.vmtoken);
+        set_page_table(self.vmtoken, &self.asid);
+        {
+            let mut inner = self.thread.inner.lock();
+            if !inner.waker.as_ref().map_or(false, |w| w.will_wake(cx.waker())) {
+                inner.waker = Some(cx.waker().clone());
+            }
+        }
+        // the task stays on this CPU until the poll returns
+        let current_pid = CURRENT_PIDS.this_cpu();
+        let current_thread = CURRENT_THREADS.this_cpu();
+        current_pid.store(self.pid, Ordering::Relaxed);
+        *current_thread.lock() = Some(self.thread.clone());
         let res = self.inner.lock().as_mut().poll(cx);
+        *current_thread.lock() = None;
+        current_pid.store(0, Ordering::Relaxed);
         res
     }
 }
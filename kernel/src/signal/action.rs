@@ -16,6 +16,27 @@ pub const SI_QUEUE: i32 = -1;
 pub const SI_USER: i32 = 0;
 /// from user
 pub const SI_KERNEL: i32 = 128;
+
+/// `si_code` of SIGSEGV: address not mapped.
+pub const SEGV_MAPERR: i32 = 1;
+/// `si_code` of SIGSEGV: invalid permissions for the mapping.
+pub const SEGV_ACCERR: i32 = 2;
+/// `si_code` of SIGILL: illegal opcode.
+pub const ILL_ILLOPC: i32 = 1;
+/// `si_code` of SIGFPE: undiagnosed floating-point exception.
+pub const FPE_FLTUNK: i32 = 14;
+/// `si_code` of SIGBUS: invalid address alignment.
+pub const BUS_ADRALN: i32 = 1;
+/// `si_code` of SIGBUS: nonexistent physical address.
+pub const BUS_ADRERR: i32 = 2;
+/// `si_code` of SIGBUS: hardware memory error consumed, action required.
+pub const BUS_MCEERR_AR: i32 = 4;
+/// `si_code` of SIGTRAP: process breakpoint.
+pub const TRAP_BRKPT: i32 = 1;
+/// `si_code` of SIGTRAP: process trace trap.
+pub const TRAP_TRACE: i32 = 2;
+/// `si_code` of SIGTRAP: hardware breakpoint or watchpoint.
+pub const TRAP_HWBKPT: i32 = 4;
 /// from kernel
 
 // yet there's a bug because of mismatching bits: https://sourceware.org/bugzilla/show_bug.cgi?id=25657
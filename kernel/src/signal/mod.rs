@@ -1,6 +1,7 @@
 use crate::{
     arch::signal::{set_signal_handler, MachineContext, RET_CODE},
-    process::{Process, Thread},
+    memory::UserMemory,
+    process::{thread::wake_thread, Process, Thread},
     sync::{Event, MutexNoIrq},
 };
 use aarch64::trap::UserContext;
@@ -89,6 +90,30 @@ impl Signal {
     pub fn is_standard(self) -> bool {
         (self as usize) < Self::RTMIN
     }
+
+    /// What `SIG_DFL` does with the signal, see signal(7)
+    pub fn default_action(self) -> DefaultAction {
+        use Signal::*;
+        match self {
+            SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGXCPU
+            | SIGXFSZ | SIGSYS => DefaultAction::Core,
+            SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ign,
+            SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+            SIGCONT => DefaultAction::Cont,
+            _ => DefaultAction::Term,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum DefaultAction {
+    /// Terminate the process
+    Term,
+    /// Terminate the process and dump core
+    Core,
+    Ign,
+    Stop,
+    Cont,
 }
 
 // process and tid must be checked
@@ -100,11 +125,16 @@ pub fn send_signal(process: Arc<MutexNoIrq<Process>>, tid: isize, info: Siginfo)
     }
     process.sig_queue.push_back((info, tid));
     process.pending_sigset.add(signal);
-    process.event_bus.lock().set(Event::RECEIVE_SIGNAL);
     info!(
         "send signal {} to pid {} tid {}",
         info.signo, process.pid, tid
-    )
+    );
+    let event_bus = process.event_bus.clone();
+    drop(process);
+    // a signal to a specific thread only needs to wake that thread
+    if tid < 0 || !wake_thread(tid as usize) {
+        event_bus.lock().set(Event::RECEIVE_SIGNAL);
+    }
 }
 
 /// See musl struct __ucontext
@@ -150,8 +180,6 @@ pub fn handle_signal(thread: &Arc<Thread>, tf: &mut UserContext) -> bool {
                 }
             })
     {
-        use Signal::*;
-
         let signal: Signal = <Signal as FromPrimitive>::from_i32(info.signo).unwrap();
         info!(
             "process {} thread {} received signal: {:?}",
@@ -166,15 +194,30 @@ pub fn handle_signal(thread: &Arc<Thread>, tf: &mut UserContext) -> bool {
 
         // enter signal handler
         match action.handler {
-            // TODO: complete default actions
             x if x == SIG_DFL => {
-                match signal {
-                    SIGALRM | SIGHUP | SIGINT => {
-                        info!("default action: Term");
-                        // TODO: exit code ref please?
-                        process.exit(info.signo as usize + 128);
+                let default_action = signal.default_action();
+                match default_action {
+                    // no core is written
+                    DefaultAction::Term | DefaultAction::Core => {
+                        info!("default action: {:?}", default_action);
+                        let files = process.exit_by_signal(signal);
+                        drop(process);
+                        drop(files);
                         return true;
                     }
+                    // reported to the parent by `wait4` with `WUNTRACED`
+                    // TODO: hold the threads until SIGCONT
+                    DefaultAction::Stop => {
+                        info!("default action: {:?}", default_action);
+                        process.stop_signal = Some(signal);
+                        if let Some(parent) = process.parent.1.upgrade() {
+                            parent
+                                .lock()
+                                .event_bus
+                                .lock()
+                                .set(Event::CHILD_PROCESS_QUIT);
+                        }
+                    }
                     _ => (),
                 }
             }
@@ -295,3 +338,25 @@ impl Default for SignalStack {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_actions() {
+        use Signal::*;
+        for &signal in &[SIGILL, SIGSEGV, SIGBUS, SIGFPE, SIGABRT, SIGSYS] {
+            assert_eq!(signal.default_action(), DefaultAction::Core, "{:?}", signal);
+        }
+        for &signal in &[
+            SIGHUP, SIGINT, SIGKILL, SIGPIPE, SIGALRM, SIGTERM, SIGUSR1, SIGRT40,
+        ] {
+            assert_eq!(signal.default_action(), DefaultAction::Term, "{:?}", signal);
+        }
+        assert_eq!(SIGCHLD.default_action(), DefaultAction::Ign);
+        assert_eq!(SIGWINCH.default_action(), DefaultAction::Ign);
+        assert_eq!(SIGSTOP.default_action(), DefaultAction::Stop);
+        assert_eq!(SIGCONT.default_action(), DefaultAction::Cont);
+    }
+}
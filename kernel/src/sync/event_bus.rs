@@ -1,5 +1,9 @@
 use crate::sync::MutexNoIrq;
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use alloc::{
+    boxed::Box,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 use bitflags::bitflags;
 use core::{
     future::Future,
@@ -8,6 +12,17 @@ use core::{
 };
 
 bitflags! {
+    /// Events of an `EventBus`.
+    ///
+    /// The file events are reported by poll as:
+    ///
+    /// | Event    | poll       |
+    /// |----------|------------|
+    /// | READABLE | `POLLIN`   |
+    /// | WRITABLE | `POLLOUT`  |
+    /// | ERROR    | `POLLERR`  |
+    /// | HANGUP   | `POLLHUP`  |
+    /// | CLOSED   | `POLLNVAL` |
     #[derive(Default)]
     pub struct Event: u32 {
         /// File
@@ -15,6 +30,8 @@ bitflags! {
         const WRITABLE                      = 1 << 1;
         const ERROR                         = 1 << 2;
         const CLOSED                        = 1 << 3;
+        /// The peer closed its end, reads return EOF once the data is drained
+        const HANGUP                        = 1 << 4;
 
         /// Process
         const PROCESS_QUIT                  = 1 << 10;
@@ -27,12 +44,57 @@ bitflags! {
     }
 }
 
+pub const POLLIN: u16 = 0x001;
+pub const POLLOUT: u16 = 0x004;
+pub const POLLERR: u16 = 0x008;
+pub const POLLHUP: u16 = 0x010;
+pub const POLLNVAL: u16 = 0x020;
+
+impl Event {
+    /// The `revents` of poll for these file events.
+    pub fn poll_events(self) -> u16 {
+        let mut events = 0;
+        for &(event, poll) in &[
+            (Event::READABLE, POLLIN),
+            (Event::WRITABLE, POLLOUT),
+            (Event::ERROR, POLLERR),
+            (Event::HANGUP, POLLHUP),
+            (Event::CLOSED, POLLNVAL),
+        ] {
+            if self.contains(event) {
+                events |= poll;
+            }
+        }
+        events
+    }
+}
+
+/// Callback of a persistent subscription, returns whether to stay subscribed.
 pub type EventHandler = Box<dyn Fn(Event) -> bool + Send>;
+/// Callback of a one-shot subscription.
+pub type OnceEventHandler = Box<dyn FnOnce(Event) + Send>;
+
+/// Identifies a subscription to an `EventBus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionId(usize);
+
+enum Callback {
+    Persistent(EventHandler),
+    Once(OnceEventHandler),
+}
+
+struct Subscriber {
+    id: SubscriptionId,
+    /// Changes leaving none of these events set are not reported.
+    mask: Event,
+    callback: Callback,
+}
 
 #[derive(Default)]
 pub struct EventBus {
     event: Event,
-    callbacks: Vec<EventHandler>,
+    subscribers: Vec<Subscriber>,
+    next_id: usize,
 }
 
 impl EventBus {
@@ -54,47 +116,226 @@ impl EventBus {
         new.remove(reset);
         new.insert(set);
         self.event = new;
-        if new != orig {
-            self.callbacks.retain(|f| !f(new));
+        if new == orig {
+            return;
+        }
+        let mut i = 0;
+        while i < self.subscribers.len() {
+            if (new & self.subscribers[i].mask).is_empty() {
+                i += 1;
+                continue;
+            }
+            let keep = match &self.subscribers[i].callback {
+                Callback::Persistent(callback) => callback(new),
+                Callback::Once(_) => false,
+            };
+            if keep {
+                i += 1;
+            } else if let Callback::Once(callback) = self.subscribers.remove(i).callback {
+                callback(new);
+            }
         }
     }
 
-    pub fn subscribe(&mut self, callback: EventHandler) {
-        self.callbacks.push(callback);
+    /// Call `callback` on every change leaving some of the events of `mask` set,
+    /// until it returns false or is unsubscribed.
+    pub fn subscribe(&mut self, mask: Event, callback: EventHandler) -> SubscriptionId {
+        self.push(mask, Callback::Persistent(callback))
+    }
+
+    /// Call `callback` on the first change leaving some of the events of `mask`
+    /// set, unless unsubscribed before.
+    pub fn subscribe_once(&mut self, mask: Event, callback: OnceEventHandler) -> SubscriptionId {
+        self.push(mask, Callback::Once(callback))
+    }
+
+    fn push(&mut self, mask: Event, callback: Callback) -> SubscriptionId {
+        let id = SubscriptionId(self.next_id);
+        self.next_id += 1;
+        self.subscribers.push(Subscriber { id, mask, callback });
+        id
+    }
+
+    /// Remove the subscription `id`, return false if it was already removed.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        let len = self.subscribers.len();
+        self.subscribers.retain(|subscriber| subscriber.id != id);
+        self.subscribers.len() != len
     }
 
     pub fn get_callback_len(&self) -> usize {
-        self.callbacks.len()
+        self.subscribers.len()
+    }
+}
+
+/// A subscription to an `EventBus`, unsubscribed when dropped.
+///
+/// Dropping it locks the bus, use `unsubscribe` with the bus locked already.
+#[must_use = "the subscription is removed when dropped"]
+pub struct Subscription {
+    bus: Weak<MutexNoIrq<EventBus>>,
+    id: Option<SubscriptionId>,
+}
+
+impl Subscription {
+    pub fn new(bus: &Arc<MutexNoIrq<EventBus>>, id: SubscriptionId) -> Self {
+        Subscription {
+            bus: Arc::downgrade(bus),
+            id: Some(id),
+        }
+    }
+
+    /// Unsubscribe from `bus`, the locked bus of this subscription.
+    pub fn unsubscribe(mut self, bus: &mut EventBus) {
+        if let Some(id) = self.id.take() {
+            bus.unsubscribe(id);
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let (Some(id), Some(bus)) = (self.id, self.bus.upgrade()) {
+            bus.lock().unsubscribe(id);
+        }
     }
 }
 
 pub fn wait_for_event(bus: Arc<MutexNoIrq<EventBus>>, mask: Event) -> impl Future<Output = Event> {
-    EventBusFuture { bus, mask }
+    EventBusFuture {
+        bus,
+        mask,
+        subscription: None,
+    }
 }
 
 #[must_use = "future does nothing unless polled/`await`-ed"]
 struct EventBusFuture {
     bus: Arc<MutexNoIrq<EventBus>>,
     mask: Event,
+    subscription: Option<Subscription>,
 }
 
 impl Future for EventBusFuture {
     type Output = Event;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        let mut lock = self.bus.lock();
-        if !(lock.event & self.mask).is_empty() {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut lock = this.bus.lock();
+        // the subscription of the previous poll, if it did not fire
+        if let Some(subscription) = this.subscription.take() {
+            subscription.unsubscribe(&mut lock);
+        }
+        if !(lock.event & this.mask).is_empty() {
             return Poll::Ready(lock.event);
         }
         let waker = cx.waker().clone();
-        let mask = self.mask;
-        lock.subscribe(Box::new(move |s| {
-            if (s & mask).is_empty() {
-                return false;
-            }
-            waker.wake_by_ref();
-            true
-        }));
+        let id = lock.subscribe_once(this.mask, Box::new(move |_| waker.wake()));
+        drop(lock);
+        this.subscription = Some(Subscription::new(&this.bus, id));
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::mock::{poll_once, MockWaker};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counter() -> (Arc<AtomicUsize>, impl Fn() + Send) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let count2 = count.clone();
+        (count, move || {
+            count2.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+
+    #[test]
+    fn persistent_and_once() {
+        let mut bus = EventBus::default();
+        let (persistent, inc) = counter();
+        bus.subscribe(
+            Event::READABLE,
+            Box::new(move |_| {
+                inc();
+                true
+            }),
+        );
+        let (once, inc) = counter();
+        bus.subscribe_once(Event::READABLE, Box::new(move |_| inc()));
+        // a change leaving none of the events set
+        bus.set(Event::WRITABLE);
+        assert_eq!(bus.get_callback_len(), 2);
+
+        bus.set(Event::READABLE);
+        bus.clear(Event::WRITABLE);
+        assert_eq!(persistent.load(Ordering::Relaxed), 2);
+        assert_eq!(once.load(Ordering::Relaxed), 1);
+        assert_eq!(bus.get_callback_len(), 1);
+    }
+
+    #[test]
+    fn keep_subscribed() {
+        let mut bus = EventBus::default();
+        let (count, inc) = counter();
+        let calls = AtomicUsize::new(0);
+        bus.subscribe(
+            Event::READABLE,
+            Box::new(move |_| {
+                inc();
+                // stay for two calls
+                calls.fetch_add(1, Ordering::Relaxed) == 0
+            }),
+        );
+        for _ in 0..3 {
+            bus.set(Event::READABLE);
+            bus.clear(Event::READABLE);
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+        assert_eq!(bus.get_callback_len(), 0);
+    }
+
+    #[test]
+    fn unsubscribe() {
+        let bus = EventBus::new();
+        let (count, inc) = counter();
+        let id = bus
+            .lock()
+            .subscribe_once(Event::READABLE, Box::new(move |_| inc()));
+        assert!(bus.lock().unsubscribe(id));
+        assert!(!bus.lock().unsubscribe(id));
+
+        let (dropped, inc) = counter();
+        let id = bus
+            .lock()
+            .subscribe_once(Event::READABLE, Box::new(move |_| inc()));
+        drop(Subscription::new(&bus, id));
+        bus.lock().set(Event::READABLE);
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+        assert_eq!(bus.lock().get_callback_len(), 0);
+    }
+
+    #[test]
+    fn spurious_polls() {
+        let bus = EventBus::new();
+        let (mock, waker) = MockWaker::new();
+        let mut future = Box::pin(wait_for_event(bus.clone(), Event::READABLE));
+        for _ in 0..10 {
+            assert!(poll_once(&mut future, &waker).is_pending());
+        }
+        assert_eq!(bus.lock().get_callback_len(), 1);
+        bus.lock().set(Event::READABLE);
+        assert_eq!(mock.wakes(), 1);
+        assert_eq!(bus.lock().get_callback_len(), 0);
+        assert!(poll_once(&mut future, &waker).is_ready());
+
+        // dropped while waiting
+        bus.lock().clear(Event::READABLE);
+        let mut future = Box::pin(wait_for_event(bus.clone(), Event::READABLE));
+        assert!(poll_once(&mut future, &waker).is_pending());
+        drop(future);
+        assert_eq!(bus.lock().get_callback_len(), 0);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::sync::MutexNoIrq;
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/// Kernel side of a fast userspace mutex: the tasks waiting on one user address.
+#[derive(Default)]
+pub struct Futex {
+    waiters: MutexNoIrq<VecDeque<Arc<FutexWaiter>>>,
+}
+
+#[derive(Default)]
+struct FutexWaiter {
+    woken: AtomicBool,
+    waker: MutexNoIrq<Option<Waker>>,
+}
+
+impl Futex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a waiter if `cond` holds, return `None` otherwise.
+    ///
+    /// `cond` is checked with the wait queue locked, so a `wake` issued
+    /// after the user changed the value can not be missed.
+    pub fn wait(self: &Arc<Self>, cond: impl FnOnce() -> bool) -> Option<FutexWait> {
+        let mut waiters = self.waiters.lock();
+        if !cond() {
+            return None;
+        }
+        let waiter = Arc::new(FutexWaiter::default());
+        waiters.push_back(waiter.clone());
+        Some(FutexWait {
+            futex: self.clone(),
+            waiter,
+        })
+    }
+
+    /// Wake up to `count` waiters in FIFO order, return the number woken.
+    pub fn wake(&self, count: usize) -> usize {
+        let mut woken = 0;
+        while woken < count {
+            let waiter = match self.waiters.lock().pop_front() {
+                Some(waiter) => waiter,
+                None => break,
+            };
+            waiter.woken.store(true, Ordering::Release);
+            if let Some(waker) = waiter.waker.lock().take() {
+                waker.wake();
+            }
+            woken += 1;
+        }
+        woken
+    }
+}
+
+/// A queued waiter, leaves the queue when dropped before being woken.
+#[must_use = "future does nothing unless polled/`await`-ed"]
+pub struct FutexWait {
+    futex: Arc<Futex>,
+    waiter: Arc<FutexWaiter>,
+}
+
+impl FutexWait {
+    #[inline]
+    pub fn is_woken(&self) -> bool {
+        self.waiter.woken.load(Ordering::Acquire)
+    }
+
+    /// Register `waker` to be woken by `Futex::wake`.
+    /// Check `is_woken` afterwards, or a wake in between is lost.
+    pub fn register(&self, waker: &Waker) {
+        *self.waiter.waker.lock() = Some(waker.clone());
+    }
+}
+
+impl Future for FutexWait {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.register(cx.waker());
+        if self.is_woken() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for FutexWait {
+    fn drop(&mut self) {
+        if !self.is_woken() {
+            let waiter = &self.waiter;
+            self.futex
+                .waiters
+                .lock()
+                .retain(|other| !Arc::ptr_eq(other, waiter));
+        }
+    }
+}
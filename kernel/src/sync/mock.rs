@@ -0,0 +1,43 @@
+//! Wakers on the host, for testing futures without an executor.
+
+use alloc::sync::Arc;
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Waker},
+};
+use std::task::Wake;
+
+/// A waker counting how many times it was woken.
+#[derive(Default)]
+pub struct MockWaker {
+    wakes: AtomicUsize,
+}
+
+impl MockWaker {
+    pub fn new() -> (Arc<Self>, Waker) {
+        let mock = Arc::new(MockWaker::default());
+        (mock.clone(), Waker::from(mock))
+    }
+
+    pub fn wakes(&self) -> usize {
+        self.wakes.load(Ordering::Relaxed)
+    }
+}
+
+impl Wake for MockWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wakes.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Poll `future` once with `waker`.
+pub fn poll_once<F: core::future::Future + Unpin>(
+    future: &mut F,
+    waker: &Waker,
+) -> core::task::Poll<F::Output> {
+    core::pin::Pin::new(future).poll(&mut Context::from_waker(waker))
+}
@@ -1,5 +1,15 @@
 pub mod event_bus;
+pub mod futex;
+#[cfg(test)]
+pub mod mock;
+pub mod percpu;
+pub mod rwlock;
+pub mod seqlock;
 pub mod spin;
 
 pub use self::event_bus::*;
+pub use self::futex::*;
+pub use self::percpu::*;
+pub use self::rwlock::*;
+pub use self::seqlock::*;
 pub use self::spin::*;
@@ -0,0 +1,59 @@
+//! Per-CPU variables, one slot for each CPU.
+//!
+//! A task only moves to another CPU at an `.await`, so the slot of `this_cpu`
+//! stays the one of the running CPU until then. IRQ handlers run on the CPU
+//! they interrupted and see the same slot: a slot they use too must be an
+//! atomic, or a lock held with IRQs disabled like `MutexNoIrq`.
+
+use crate::consts::MAX_CPU_NUM;
+use core::slice;
+
+pub struct PerCpu<T> {
+    slots: [T; MAX_CPU_NUM],
+}
+
+impl<T> PerCpu<T> {
+    /// Start every CPU with its slot of `slots`.
+    #[inline]
+    pub const fn new(slots: [T; MAX_CPU_NUM]) -> Self {
+        PerCpu { slots }
+    }
+
+    /// The slot of the calling CPU.
+    #[inline]
+    pub fn this_cpu(&self) -> &T {
+        self.get(crate::cpu::id())
+    }
+
+    /// The slot of the CPU `cpu_id`.
+    #[inline]
+    pub fn get(&self, cpu_id: usize) -> &T {
+        &self.slots[cpu_id]
+    }
+
+    /// The slots of all the CPUs, in order of CPU id.
+    #[inline]
+    pub fn iter(&self) -> slice::Iter<T> {
+        self.slots.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn distinct_slots() {
+        const ZERO: AtomicUsize = AtomicUsize::new(0);
+        let counters: PerCpu<AtomicUsize> = PerCpu::new([ZERO; MAX_CPU_NUM]);
+        // host tests run as CPU 0
+        counters.this_cpu().fetch_add(1, Ordering::Relaxed);
+        for cpu in 1..MAX_CPU_NUM {
+            counters.get(cpu).fetch_add(cpu + 1, Ordering::Relaxed);
+        }
+        let values: Vec<_> = counters.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        assert_eq!(values, (1..=MAX_CPU_NUM).collect::<Vec<_>>());
+        assert!(!core::ptr::eq(counters.get(0), counters.get(1)));
+    }
+}
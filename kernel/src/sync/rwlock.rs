@@ -0,0 +1,243 @@
+//! Writer-preferring reader-writer spin lock, held with IRQs disabled.
+//!
+//! Once a writer waits, new readers wait too, so that a steady stream of readers
+//! cannot starve it. A reader must thus not take the lock again while holding it:
+//! a writer arriving in between would deadlock both. IRQs stay disabled while
+//! waiting for the lock, an IRQ handler cannot wait behind a writer it interrupted.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Set in `state` while a writer holds the lock.
+const WRITER: usize = 1;
+/// Added to `state` by each reader holding the lock.
+const READER: usize = 2;
+
+pub struct RwLockNoIrq<T> {
+    state: AtomicUsize,
+    /// Writers spinning for the lock.
+    waiting_writers: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLockNoIrq<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLockNoIrq<T> {}
+
+impl<T> RwLockNoIrq<T> {
+    #[inline]
+    pub const fn new(value: T) -> Self {
+        RwLockNoIrq {
+            state: AtomicUsize::new(0),
+            waiting_writers: AtomicUsize::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Lock for reading, waiting for the writers, even the ones which did not get
+    /// the lock yet.
+    pub fn read(&self) -> RwLockReadGuardNoIrq<T> {
+        let flags = unsafe { crate::arch::interrupt::disable_and_store() };
+        while !self.lock_read() {
+            spin_loop();
+        }
+        RwLockReadGuardNoIrq { lock: self, flags }
+    }
+
+    /// Lock for reading unless a writer holds or waits for the lock.
+    pub fn try_read(&self) -> Option<RwLockReadGuardNoIrq<T>> {
+        let flags = unsafe { crate::arch::interrupt::disable_and_store() };
+        if self.lock_read() {
+            return Some(RwLockReadGuardNoIrq { lock: self, flags });
+        }
+        unsafe { crate::arch::interrupt::restore(flags) };
+        None
+    }
+
+    /// Lock for writing, new readers wait from now on.
+    pub fn write(&self) -> RwLockWriteGuardNoIrq<T> {
+        let flags = unsafe { crate::arch::interrupt::disable_and_store() };
+        self.waiting_writers.fetch_add(1, Ordering::Relaxed);
+        while !self.lock_write() {
+            spin_loop();
+        }
+        self.waiting_writers.fetch_sub(1, Ordering::Relaxed);
+        RwLockWriteGuardNoIrq { lock: self, flags }
+    }
+
+    /// Lock for writing if nobody holds the lock.
+    pub fn try_write(&self) -> Option<RwLockWriteGuardNoIrq<T>> {
+        let flags = unsafe { crate::arch::interrupt::disable_and_store() };
+        if self.lock_write() {
+            return Some(RwLockWriteGuardNoIrq { lock: self, flags });
+        }
+        unsafe { crate::arch::interrupt::restore(flags) };
+        None
+    }
+
+    fn lock_read(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        state & WRITER == 0
+            && self.waiting_writers.load(Ordering::Relaxed) == 0
+            && self
+                .state
+                .compare_exchange_weak(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    fn lock_write(&self) -> bool {
+        self.state
+            .compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Returns a mutable reference to the underlying data, no locking needed since
+    /// `self` is borrowed mutably.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLockNoIrq<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => write!(f, "RwLockNoIrq {{ data: {:?} }}", &*guard),
+            None => write!(f, "RwLockNoIrq {{ <locked> }}"),
+        }
+    }
+}
+
+impl<T: Default> Default for RwLockNoIrq<T> {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+pub struct RwLockReadGuardNoIrq<'a, T: 'a> {
+    lock: &'a RwLockNoIrq<T>,
+    flags: usize,
+}
+
+impl<'a, T: 'a> Deref for RwLockReadGuardNoIrq<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: 'a> Drop for RwLockReadGuardNoIrq<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+        unsafe { crate::arch::interrupt::restore(self.flags) };
+    }
+}
+
+pub struct RwLockWriteGuardNoIrq<'a, T: 'a> {
+    lock: &'a RwLockNoIrq<T>,
+    flags: usize,
+}
+
+impl<'a, T: 'a> Deref for RwLockWriteGuardNoIrq<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: 'a> DerefMut for RwLockWriteGuardNoIrq<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: 'a> Drop for RwLockWriteGuardNoIrq<'a, T> {
+    fn drop(&mut self) {
+        // readers wait while `WRITER` is set, the state is `WRITER` alone
+        self.lock.state.store(0, Ordering::Release);
+        unsafe { crate::arch::interrupt::restore(self.flags) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::MutexNoIrq;
+    use alloc::collections::BTreeMap;
+    use std::{
+        sync::{atomic::AtomicBool, Arc},
+        thread,
+    };
+
+    #[test]
+    fn writer_not_starved() {
+        let lock = Arc::new(RwLockNoIrq::new((0usize, 0usize)));
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (lock, done) = (lock.clone(), done.clone());
+                thread::spawn(move || {
+                    let mut reads = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let pair = lock.read();
+                        assert_eq!(pair.0, pair.1);
+                        reads += 1;
+                    }
+                    reads
+                })
+            })
+            .collect();
+        for _ in 0..10_000 {
+            let mut pair = lock.write();
+            pair.0 += 1;
+            pair.1 += 1;
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            assert!(reader.join().unwrap() > 0);
+        }
+        assert_eq!(*lock.read(), (10_000, 10_000));
+    }
+
+    /// Entries locked while the table is written, like the processes and
+    /// `PROCESSES`: the others lock entries only once the table is released.
+    #[test]
+    fn table_lock_order() {
+        type Table = RwLockNoIrq<BTreeMap<usize, Arc<MutexNoIrq<usize>>>>;
+        let table: Arc<Table> = Arc::new(RwLockNoIrq::new(
+            (0..8).map(|i| (i, Arc::new(MutexNoIrq::new(i)))).collect(),
+        ));
+        let threads: Vec<_> = (0..4)
+            .map(|id| {
+                let table = table.clone();
+                thread::spawn(move || {
+                    for i in 0..2_000 {
+                        if id % 2 == 0 {
+                            let entry = table.read()[&(i % 8)].clone();
+                            let entry = entry.lock();
+                            let copy = Arc::new(MutexNoIrq::new(*entry));
+                            table.write().insert(8 + i % 8, copy);
+                        } else {
+                            let entries: Vec<_> = table.read().values().cloned().collect();
+                            let sum: usize = entries.iter().map(|entry| *entry.lock()).sum();
+                            assert!(sum >= 28);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(table.read().len(), 16);
+    }
+}
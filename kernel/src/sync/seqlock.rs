@@ -0,0 +1,164 @@
+//! Sequence lock, readers never block the writer nor each other.
+//!
+//! The sequence number is odd while a write is in progress. A reader copies the
+//! value out and retries if the sequence was odd or changed meanwhile, since the
+//! copy may then be torn. Writers exclude each other by taking the sequence from
+//! even to odd.
+//!
+//! The layout is `repr(C)`, the sequence first: a `SeqLock` can be shared with
+//! readers outside of the kernel, like the time page of user space.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    hint::spin_loop,
+    ptr,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+
+#[repr(C)]
+pub struct SeqLock<T> {
+    seq: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T> SeqLock<T> {
+    #[inline]
+    pub const fn new(value: T) -> Self {
+        SeqLock {
+            seq: AtomicUsize::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data, no write can be in
+    /// progress since `self` is borrowed mutably.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Copy> SeqLock<T> {
+    /// A copy of the value, not torn by a concurrent write.
+    ///
+    /// Spins while a write is in progress, so it must not interrupt a writer on
+    /// the same CPU: writers run with IRQs disabled.
+    pub fn read(&self) -> T {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq % 2 != 0 {
+                spin_loop();
+                continue;
+            }
+            let value = unsafe { ptr::read_volatile(self.data.get()) };
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return value;
+            }
+        }
+    }
+
+    /// Update the value with `f`, waiting for a concurrent writer first.
+    pub fn write(&self, f: impl FnOnce(&mut T)) {
+        let flags = unsafe { crate::arch::interrupt::disable_and_store() };
+        let seq = loop {
+            if let Some(seq) = self.try_begin() {
+                break seq;
+            }
+            spin_loop();
+        };
+        self.update(seq, f);
+        unsafe { crate::arch::interrupt::restore(flags) };
+    }
+
+    /// Update the value with `f` unless another write is in progress.
+    /// Return whether it was updated.
+    pub fn try_write(&self, f: impl FnOnce(&mut T)) -> bool {
+        let flags = unsafe { crate::arch::interrupt::disable_and_store() };
+        let seq = self.try_begin();
+        if let Some(seq) = seq {
+            self.update(seq, f);
+        }
+        unsafe { crate::arch::interrupt::restore(flags) };
+        seq.is_some()
+    }
+
+    /// Make the sequence odd, return its previous value.
+    fn try_begin(&self) -> Option<usize> {
+        let seq = self.seq.load(Ordering::Relaxed);
+        if seq % 2 != 0 {
+            return None;
+        }
+        self.seq
+            .compare_exchange(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        // readers see the odd sequence before any of the new data
+        fence(Ordering::Release);
+        Some(seq)
+    }
+
+    fn update(&self, seq: usize, f: impl FnOnce(&mut T)) {
+        let mut value = unsafe { ptr::read_volatile(self.data.get()) };
+        f(&mut value);
+        unsafe { ptr::write_volatile(self.data.get(), value) };
+        self.seq.store(seq + 2, Ordering::Release);
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for SeqLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SeqLock")
+            .field("data", &self.read())
+            .finish()
+    }
+}
+
+impl<T: Copy + Default> Default for SeqLock<T> {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn read_not_torn() {
+        let lock = Arc::new(SeqLock::new([0u64; 8]));
+        let writer = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                for i in 1..=100_000 {
+                    lock.write(|value| *value = [i; 8]);
+                }
+            })
+        };
+        let mut last = 0;
+        while last < 100_000 {
+            let value = lock.read();
+            assert!(value.iter().all(|&x| x == value[0]));
+            assert!(value[0] >= last);
+            last = value[0];
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn write_sequence() {
+        let lock = SeqLock::new(1);
+        assert!(lock.try_write(|value| *value += 1));
+        lock.write(|value| *value *= 10);
+        assert_eq!(lock.read(), 20);
+        assert_eq!(lock.seq.load(Ordering::Relaxed), 4);
+        // a write in progress
+        lock.seq.store(5, Ordering::Relaxed);
+        assert!(!lock.try_write(|value| *value = 0));
+        assert_eq!(*SeqLock::new(3).get_mut(), 3);
+    }
+}
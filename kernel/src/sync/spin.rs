@@ -3,12 +3,31 @@ use core::{
     mem::ManuallyDrop,
     ops::{Deref, DerefMut},
 };
+#[cfg(debug_assertions)]
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 pub use spin::{
     Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockUpgradableGuard, RwLockWriteGuard,
 };
 
-pub struct MutexNoIrq<T>(Mutex<T>);
+/// Spin lock held with IRQs disabled.
+///
+/// Debug builds record the CPU holding the lock: locking it again on that CPU
+/// panics instead of hanging, and spinning for longer than `SPIN_WARN` rounds
+/// prints a warning.
+pub struct MutexNoIrq<T> {
+    inner: Mutex<T>,
+    /// `cpu id + 1` of the holder, 0 if unlocked.
+    #[cfg(debug_assertions)]
+    owner: AtomicUsize,
+}
+
+/// Spin rounds after which a waiter warns of a possible deadlock.
+#[cfg(debug_assertions)]
+const SPIN_WARN: usize = 1 << 26;
 
 unsafe impl<T: Send> Sync for MutexNoIrq<T> {}
 unsafe impl<T: Send> Send for MutexNoIrq<T> {}
@@ -16,12 +35,16 @@ unsafe impl<T: Send> Send for MutexNoIrq<T> {}
 impl<T> MutexNoIrq<T> {
     #[inline]
     pub const fn new(value: T) -> Self {
-        Self(Mutex::new(value))
+        MutexNoIrq {
+            inner: Mutex::new(value),
+            #[cfg(debug_assertions)]
+            owner: AtomicUsize::new(0),
+        }
     }
 
     #[inline]
     pub fn into_inner(self) -> T {
-        self.0.into_inner()
+        self.inner.into_inner()
     }
 }
 
@@ -34,7 +57,7 @@ impl<T> MutexNoIrq<T> {
     /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
     #[inline]
     pub fn is_locked(&self) -> bool {
-        self.0.is_locked()
+        self.inner.is_locked()
     }
 
     /// Locks the [`Mutex`] and returns a guard that permits access to the inner data.
@@ -44,7 +67,44 @@ impl<T> MutexNoIrq<T> {
     #[inline]
     pub fn lock(&self) -> MutexGuardNoIrq<T> {
         let flags = unsafe { crate::arch::interrupt::disable_and_store() };
-        MutexGuardNoIrq::new(self.0.lock(), flags)
+        #[cfg(not(debug_assertions))]
+        let guard = self.inner.lock();
+        #[cfg(debug_assertions)]
+        let guard = self.lock_checked();
+        MutexGuardNoIrq::new(self, guard, flags)
+    }
+
+    /// Lock, panicking if this CPU holds the lock already and warning if the lock
+    /// takes too long.
+    #[cfg(debug_assertions)]
+    fn lock_checked(&self) -> MutexGuard<T> {
+        let cpu = crate::cpu::id() + 1;
+        if self.owner.load(Ordering::Relaxed) == cpu {
+            panic!(
+                "deadlock: CPU{} locks the MutexNoIrq at {:p} it holds",
+                cpu - 1,
+                self
+            );
+        }
+        let mut spins = 0;
+        let guard = loop {
+            if let Some(guard) = self.inner.try_lock() {
+                break guard;
+            }
+            spins += 1;
+            if spins == SPIN_WARN {
+                // the lock may be the one of the console
+                crate::logging::_print_unlocked(format_args!(
+                    "[WARN] CPU{} still waiting for the MutexNoIrq at {:p} held by CPU{}\n",
+                    cpu - 1,
+                    self,
+                    self.owner.load(Ordering::Relaxed).wrapping_sub(1) as isize
+                ));
+            }
+            spin_loop();
+        };
+        self.owner.store(cpu, Ordering::Relaxed);
+        guard
     }
 
     /// Force unlock this [`Mutex`].
@@ -56,16 +116,26 @@ impl<T> MutexNoIrq<T> {
     /// lock to FFI that doesn't know how to deal with RAII.
     #[inline]
     pub unsafe fn force_unlock(&self) {
-        self.0.force_unlock()
+        #[cfg(debug_assertions)]
+        self.owner.store(0, Ordering::Relaxed);
+        self.inner.force_unlock()
     }
 
     /// Try to lock this [`Mutex`], returning a lock guard if successful.
     #[inline]
     pub fn try_lock(&self) -> Option<MutexGuardNoIrq<T>> {
         let flags = unsafe { crate::arch::interrupt::disable_and_store() };
-        self.0
-            .try_lock()
-            .map(|guard| MutexGuardNoIrq::new(guard, flags))
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(debug_assertions)]
+                self.owner.store(crate::cpu::id() + 1, Ordering::Relaxed);
+                Some(MutexGuardNoIrq::new(self, guard, flags))
+            }
+            None => {
+                unsafe { crate::arch::interrupt::restore(flags) };
+                None
+            }
+        }
     }
 
     /// Returns a mutable reference to the underlying data.
@@ -75,13 +145,13 @@ impl<T> MutexNoIrq<T> {
     /// this is a 'zero-cost' operation.
     #[inline]
     pub fn get_mut(&mut self) -> &mut T {
-        self.0.get_mut()
+        self.inner.get_mut()
     }
 }
 
 impl<T: fmt::Debug> fmt::Debug for MutexNoIrq<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(&self.0, f)
+        fmt::Debug::fmt(&self.inner, f)
     }
 }
 
@@ -100,23 +170,42 @@ impl<T> From<T> for MutexNoIrq<T> {
 pub struct MutexGuardNoIrq<'a, T: 'a> {
     inner: ManuallyDrop<MutexGuard<'a, T>>,
     flags: usize,
+    lock: &'a MutexNoIrq<T>,
 }
 
 impl<'a, T: 'a> MutexGuardNoIrq<'a, T> {
-    fn new(inner: MutexGuard<'a, T>, flags: usize) -> Self {
+    fn new(lock: &'a MutexNoIrq<T>, inner: MutexGuard<'a, T>, flags: usize) -> Self {
         MutexGuardNoIrq {
             inner: ManuallyDrop::new(inner),
             flags,
+            lock,
         }
     }
+
+    /// Release the lock and restore IRQs while `f` runs, e.g. for I/O too slow
+    /// to do with IRQs disabled, then take the lock again.
+    ///
+    /// Other CPUs may change the data meanwhile.
+    pub fn unlocked<U>(this: &mut Self, f: impl FnOnce() -> U) -> U {
+        unsafe { this.release() };
+        let ret = f();
+        let guard = ManuallyDrop::new(this.lock.lock());
+        this.inner = unsafe { core::ptr::read(&guard.inner) };
+        this.flags = guard.flags;
+        ret
+    }
+
+    unsafe fn release(&mut self) {
+        #[cfg(debug_assertions)]
+        self.lock.owner.store(0, Ordering::Relaxed);
+        ManuallyDrop::drop(&mut self.inner);
+        crate::arch::interrupt::restore(self.flags);
+    }
 }
 
 impl<'a, T: 'a> Drop for MutexGuardNoIrq<'a, T> {
     fn drop(&mut self) {
-        unsafe {
-            ManuallyDrop::drop(&mut self.inner);
-            crate::arch::interrupt::restore(self.flags);
-        }
+        unsafe { self.release() };
     }
 }
 
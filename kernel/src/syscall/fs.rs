@@ -1,18 +1,35 @@
 use super::*;
 use crate::{
     drivers::read_epoch,
-    fs::{FileHandle, FileType, FsError, INode, SeekFrom, FOLLOW_MAX_DEPTH, ROOT_INODE},
-    process::Process,
-    utils::{from_cstr, write_cstr},
+    fs::{
+        self, FileHandle, FileType, FsError, INode, OpenFileSlot, SeekFrom, Termios, TtyINode,
+        WinSize, ROOT_INODE,
+    },
+    memory::PAGE_SIZE,
+    process::{Gid, Pgid, Process, Uid, PROCESSES},
+    utils::from_cstr,
 };
-use alloc::{string::String, vec::Vec};
+use alloc::vec::Vec;
 use queen_syscall::flags::{AtFlags, OpenFlags, AT_FDCWD};
 
 impl Syscall<'_> {
+    /// The file `fd` to read, out of the process: do not hold the process lock
+    /// while blocking, or reading procfs of itself.
+    ///
+    /// A background process waits here to read a tty, whichever read it uses.
+    async fn file_to_read(&mut self, fd: usize) -> Result<FileHandle, SysError> {
+        let file = self.process().get_file(fd)?.clone();
+        let inode = file.inode();
+        if let Some(tty) = inode.as_any_ref().downcast_ref::<TtyINode>() {
+            tty.wait_for_foreground(self.thread).await?;
+        }
+        Ok(file)
+    }
+
     pub async fn sys_read(&mut self, fd: usize, base: usize, len: usize) -> SysResult {
-        let mut process = self.process();
+        let mut file = self.file_to_read(fd).await?;
         let buf = unsafe { self.vm().check_write_array(base as _, len)? };
-        let len = process.get_file_mut(fd)?.read(buf).await?;
+        let len = file.read(buf).await?;
 
         Ok(len)
     }
@@ -26,9 +43,9 @@ impl Syscall<'_> {
     }
 
     pub async fn sys_pread(&mut self, fd: usize, base: usize, len: usize, pos: usize) -> SysResult {
-        let mut process = self.process();
+        let file = self.file_to_read(fd).await?;
         let buf = unsafe { self.vm().check_write_array(base as _, len)? };
-        let len = process.get_file_mut(fd)?.read_at(pos, buf).await?;
+        let len = file.read_at(pos, buf).await?;
 
         Ok(len)
     }
@@ -41,6 +58,79 @@ impl Syscall<'_> {
         Ok(len)
     }
 
+    pub async fn sys_readv(&mut self, fd: usize, iov: usize, iov_count: usize) -> SysResult {
+        let iovs = self.read_iovecs(iov as _, iov_count, true)?;
+        let mut file = self.file_to_read(fd).await?;
+        let mut total = 0;
+        for iov in iovs.iter() {
+            let buf = unsafe { self.vm().check_write_array(iov.base as *mut u8, iov.len)? };
+            match file.read(buf).await {
+                Ok(len) => {
+                    total += len;
+                    if len < iov.len {
+                        break;
+                    }
+                }
+                // report what has been transferred before the error
+                Err(_) if total > 0 => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(total)
+    }
+
+    pub fn sys_writev(&mut self, fd: usize, iov: *const IoVec, iov_count: usize) -> SysResult {
+        let iovs = self.read_iovecs(iov, iov_count, false)?;
+        let mut process = self.process();
+        let file = process.get_file_mut(fd)?;
+        let mut total = 0;
+        for iov in iovs.iter() {
+            let buf = unsafe { self.vm().check_read_array(iov.base as *const u8, iov.len)? };
+            match file.write(buf) {
+                Ok(len) => {
+                    total += len;
+                    if len < iov.len {
+                        break;
+                    }
+                }
+                Err(_) if total > 0 => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(total)
+    }
+
+    /// Copy the iovec array from user, checking every buffer is accessible
+    /// (writable if `write`) before any data is transferred.
+    fn read_iovecs(
+        &self,
+        iov: *const IoVec,
+        iov_count: usize,
+        write: bool,
+    ) -> Result<Vec<IoVec>, SysError> {
+        if iov_count > IOV_MAX {
+            return Err(SysError::EINVAL);
+        }
+        let mut vm = self.vm();
+        let iovs = unsafe { vm.check_read_array(iov, iov_count)? }.to_vec();
+        let mut total: usize = 0;
+        for iov in iovs.iter() {
+            // the sum must fit in the return value
+            total = total
+                .checked_add(iov.len)
+                .filter(|&total| total <= isize::MAX as usize)
+                .ok_or(SysError::EINVAL)?;
+            unsafe {
+                if write {
+                    vm.check_write_array(iov.base as *mut u8, iov.len)?;
+                } else {
+                    vm.check_read_array(iov.base as *const u8, iov.len)?;
+                }
+            }
+        }
+        Ok(iovs)
+    }
+
     #[inline]
     pub fn sys_open(&mut self, path: *const u8, flags: usize, mode: usize) -> SysResult {
         self.sys_open_at(AT_FDCWD, path, flags, mode)
@@ -55,7 +145,13 @@ impl Syscall<'_> {
     ) -> SysResult {
         let mut process = self.process();
         let path = unsafe { from_cstr(path) };
+        if path.is_empty() {
+            return Err(SysError::ENOENT);
+        }
+        let directory = flags & O_DIRECTORY != 0;
         let flags = OpenFlags::from_bits_truncate(flags);
+        // before O_CREAT or O_TRUNC change anything
+        let slot = OpenFileSlot::reserve().ok_or(SysError::ENFILE)?;
 
         let inode = if flags.contains(OpenFlags::CREATE) {
             let (dir_path, file_name) = split_path(path);
@@ -66,6 +162,7 @@ impl Syscall<'_> {
                     if flags.contains(OpenFlags::EXCLUSIVE) {
                         return Err(SysError::EEXIST);
                     }
+                    process.check_open_permission(&*file_inode, flags)?;
                     if flags.contains(OpenFlags::TRUNCATE) {
                         file_inode.resize(0).ok();
                     }
@@ -81,24 +178,70 @@ impl Syscall<'_> {
                 Err(e) => return Err(SysError::from(e)),
             }
         } else {
-            process.lookup_inode_at(dir_fd, &path, true)?
+            let inode = process.lookup_inode_at(dir_fd, &path, true)?;
+            process.check_open_permission(&*inode, flags)?;
+            inode
         };
+        if directory && inode.metadata()?.r#type != FileType::Dir {
+            return Err(SysError::ENOTDIR);
+        }
 
-        let file = FileHandle::new(
+        // keep the absolute path when known, umount and fchdir rely on it
+        let path = if dir_fd == AT_FDCWD || path.starts_with('/') {
+            fs::normalize_path(&process.cwd, path)
+        } else {
+            // absolute, or the lookup failed
+            fs::normalize_path(&process.get_file(dir_fd)?.path, path)
+        };
+        let file = FileHandle::new_in_slot(
+            slot,
             inode,
             flags.into(),
-            path.into(),
+            path,
             flags.contains(OpenFlags::CLOEXEC),
         );
-        let fd = process.add_file(file);
+        let fd = process.add_file(file)?;
 
         Ok(fd)
     }
 
+    /// `openat` with the arguments in an extensible `struct open_how`.
+    pub fn sys_openat2(
+        &mut self,
+        dir_fd: usize,
+        path: *const u8,
+        how: *const OpenHow,
+        size: usize,
+    ) -> SysResult {
+        let known = core::mem::size_of::<OpenHow>();
+        if size < known {
+            return Err(SysError::EINVAL);
+        }
+        if size > PAGE_SIZE {
+            return Err(SysError::E2BIG);
+        }
+        // fields of a newer `struct open_how` must be unset
+        if size > known {
+            let mut vm = self.vm();
+            let tail = unsafe { vm.check_read_array((how as *const u8).add(known), size - known)? };
+            if tail.iter().any(|&byte| byte != 0) {
+                return Err(SysError::E2BIG);
+            }
+        }
+        let how = unsafe { *self.vm().check_read_ptr(how)? };
+        let create = OpenFlags::from_bits_truncate(how.flags as _).contains(OpenFlags::CREATE);
+        // no RESOLVE_* flag is supported yet, and mode only makes sense when creating
+        if how.resolve != 0 || (how.mode != 0 && !create) {
+            return Err(SysError::EINVAL);
+        }
+        self.sys_open_at(dir_fd, path, how.flags as _, how.mode as _)
+    }
+
     #[inline]
     pub fn sys_close(&mut self, fd: usize) -> SysResult {
-        self.process().files.remove(&fd).ok_or(SysError::EBADF)?;
-
+        let file = self.process().files.remove(&fd).ok_or(SysError::EBADF)?;
+        // with the process unlocked, see `FileHandle`
+        drop(file);
         Ok(0)
     }
 
@@ -114,23 +257,30 @@ impl Syscall<'_> {
         mode: usize,
         flags: usize,
     ) -> SysResult {
-        // TODO: check permissions based on uid/gid
         let proc = self.process();
         let path = unsafe { from_cstr(path) };
         let flags = AtFlags::from_bits_truncate(flags);
+        if mode & !(R_OK | W_OK | X_OK) != 0 {
+            return Err(SysError::EINVAL);
+        }
 
-        let _inode =
-            proc.lookup_inode_at(dir_fd, &path, !flags.contains(AtFlags::SYMLINK_NOFOLLOW))?;
+        let inode = proc.lookup_inode_at_flags(dir_fd, &path, flags)?;
+        // like linux, checked against the real ids
+        check_permission(&*inode, proc.uid, proc.gid, mode)?;
 
         Ok(0)
     }
 
+    /// Copy the absolute, normalized path of the cwd to `buf`, null-terminated.
     pub fn sys_get_cwd(&mut self, buf: *mut u8, len: usize) -> SysResult {
         let process = self.process();
-        if process.cwd.len() + 1 > len {
+        let cwd = process.cwd.as_bytes();
+        if cwd.len() + 1 > len {
             return Err(SysError::ERANGE);
         }
-        unsafe { write_cstr(buf, process.cwd.as_str()) }
+        let slice = unsafe { self.vm().check_write_array(buf, cwd.len() + 1)? };
+        slice[..cwd.len()].copy_from_slice(cwd);
+        slice[cwd.len()] = 0;
 
         Ok(buf as usize)
     }
@@ -151,9 +301,9 @@ impl Syscall<'_> {
         let path = unsafe { from_cstr(path) };
         let slice = unsafe { self.vm().check_write_array(base, len)? };
 
+        // links leading to the last component are followed by the lookup
         let inode = proc.lookup_inode_at(dir_fd, path, false)?;
         if inode.metadata()?.r#type == FileType::SymLink {
-            // TODO: recursive link resolution and loop detection
             let len = inode.read_at(0, slice)?;
             Ok(len)
         } else {
@@ -181,6 +331,49 @@ impl Syscall<'_> {
         Ok(0)
     }
 
+    pub fn sys_ioctl(&mut self, fd: usize, request: usize, arg: usize) -> SysResult {
+        let file = self.process().get_file(fd)?.clone();
+        let request = request as u32;
+        self.check_ioctl_arg(request, arg)?;
+        match file.io_control(request, arg) {
+            Ok(ret) => Ok(ret),
+            // the inode is a terminal, but does not know the request
+            Err(FsError::IOCTLError) => Err(SysError::ENOTTY),
+            Err(FsError::NotSupported) => Err(SysError::EINVAL),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Check the user memory `arg` of `request` points to, before the inode reads
+    /// or writes it through the raw pointer.
+    fn check_ioctl_arg(&mut self, request: u32, arg: usize) -> Result<(), SysError> {
+        let mut vm = self.vm();
+        unsafe {
+            match request {
+                fs::TCGETS => {
+                    vm.check_write_ptr(arg as *mut Termios)?;
+                }
+                fs::TCSETS | fs::TCSETSW | fs::TCSETSF => {
+                    vm.check_read_ptr(arg as *const Termios)?;
+                }
+                fs::TIOCGPGRP => {
+                    vm.check_write_ptr(arg as *mut Pgid)?;
+                }
+                fs::TIOCSPGRP => {
+                    vm.check_read_ptr(arg as *const Pgid)?;
+                }
+                fs::TIOCGWINSZ => {
+                    vm.check_write_ptr(arg as *mut WinSize)?;
+                }
+                fs::FIONREAD => {
+                    vm.check_write_ptr(arg as *mut i32)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
     #[inline]
     pub fn sys_fdata_sync(&mut self, fd: usize) -> SysResult {
         self.process().get_file_mut(fd)?.sync_data()?;
@@ -206,11 +399,14 @@ impl Syscall<'_> {
 
     pub fn sys_dup3(&mut self, fd1: usize, fd2: usize, flags: usize) -> SysResult {
         let mut process = self.process();
-        // close fd2 first if it is opened
-        process.files.remove(&fd2);
+        if fd2 >= process.max_fd() {
+            return Err(SysError::EBADF);
+        }
         let file = process.get_file(fd1)?.dup(flags != 0);
-        process.files.insert(fd2, file);
-
+        // fd2 is closed if it is opened, once the process is unlocked
+        let closed = process.files.insert(fd2, file);
+        drop(process);
+        drop(closed);
         Ok(fd2)
     }
 
@@ -218,38 +414,32 @@ impl Syscall<'_> {
         let mut process = self.process();
         let path = unsafe { from_cstr(path) };
 
-        let inode = process.lookup_inode(&path)?;
-        let info = inode.metadata()?;
-        if info.r#type != FileType::Dir {
+        if path.is_empty() {
+            return Err(SysError::ENOENT);
+        }
+
+        // ".." of "/" stays at "/", and trailing slashes are dropped
+        let cwd = fs::normalize_path(&process.cwd, path);
+        let inode = fs::lookup(&cwd, true)?;
+        if inode.metadata()?.r#type != FileType::Dir {
             return Err(SysError::ENOTDIR);
         }
+        process.cwd = cwd;
+        Ok(0)
+    }
 
-        // FIXME: '..' and '.'
-        if path.len() > 0 {
-            let cwd = match path.as_bytes()[0] {
-                b'/' => String::from("/"),
-                _ => process.cwd.clone(),
-            };
-            let mut cwd_vec: Vec<_> = cwd.split("/").filter(|&x| x != "").collect();
-            let path_split = path.split("/").filter(|&x| x != "");
-            for seg in path_split {
-                if seg == ".." {
-                    cwd_vec.pop();
-                } else if seg == "." {
-                    // nothing to do here.
-                } else {
-                    cwd_vec.push(seg);
-                }
-            }
-            process.cwd = String::from("");
-            for seg in cwd_vec {
-                process.cwd.push_str("/");
-                process.cwd.push_str(seg);
-            }
-            if process.cwd == "" {
-                process.cwd = String::from("/");
-            }
+    /// Change the cwd to the directory opened as `fd`.
+    pub fn sys_fchdir(&mut self, fd: usize) -> SysResult {
+        let mut process = self.process();
+        let file = process.get_file(fd)?;
+        if file.metadata()?.r#type != FileType::Dir {
+            return Err(SysError::ENOTDIR);
+        }
+        // the cwd must be absolute, so does the path the file was opened with
+        if !file.path.starts_with('/') {
+            return Err(SysError::ENOENT);
         }
+        process.cwd = fs::normalize_path("/", &file.path);
         Ok(0)
     }
 
@@ -331,7 +521,7 @@ impl Syscall<'_> {
         let flags = AtFlags::from_bits_truncate(flags);
 
         let (new_dir_path, new_file_name) = split_path(&new_path);
-        let inode = proc.lookup_inode_at(old_dir_fd, &old_path, true)?;
+        let inode = proc.lookup_inode_at_flags(old_dir_fd, &old_path, flags)?;
         let new_dir_inode = proc.lookup_inode_at(new_dir_fd, new_dir_path, true)?;
         new_dir_inode.link(new_file_name, &inode)?;
         Ok(0)
@@ -389,12 +579,87 @@ impl Syscall<'_> {
         Ok(0)
     }
 
+    pub fn sys_mount(
+        &mut self,
+        source: *const u8,
+        target: *const u8,
+        fs_type: *const u8,
+        _flags: usize,
+        _data: usize,
+    ) -> SysResult {
+        let source = unsafe { from_cstr(source) };
+        let target = unsafe { from_cstr(target) };
+        let fs_type = unsafe { from_cstr(fs_type) };
+        info!("mount: {:?} on {:?} type {:?}", source, target, fs_type);
+        if self.process().euid != 0 {
+            return Err(SysError::EPERM);
+        }
+
+        let target = fs::normalize_path(&self.process().cwd, target);
+        let fs = fs::new_fs(fs_type).map_err(|_| SysError::ENODEV)?;
+        fs::mount(&target, fs)?;
+        Ok(0)
+    }
+
+    pub fn sys_umount2(&mut self, target: *const u8, _flags: usize) -> SysResult {
+        let target = unsafe { from_cstr(target) };
+        info!("umount: {:?}", target);
+        if self.process().euid != 0 {
+            return Err(SysError::EPERM);
+        }
+
+        let target = fs::normalize_path(&self.process().cwd, target);
+        // busy if some process still works or has files opened below the mount point
+        let is_busy = |mount_point: &str| {
+            // processes take the table lock with their own held, never lock them the other way
+            let procs = PROCESSES.read().values().cloned().collect::<Vec<_>>();
+            procs.iter().any(|proc| {
+                let proc = proc.lock();
+                fs::is_below(&proc.cwd, mount_point)
+                    || proc
+                        .files
+                        .values()
+                        .any(|file| fs::is_below(&file.path, mount_point))
+            })
+        };
+        fs::umount(&target, is_busy)?;
+        Ok(0)
+    }
+
     pub fn sys_sync(&mut self) -> SysResult {
         ROOT_INODE.fs().sync()?;
         Ok(0)
     }
 }
 
+/// Maximum number of iovecs accepted by `readv`/`writev`
+const IOV_MAX: usize = 1024;
+
+/// `struct open_how` of `openat2`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct OpenHow {
+    /// Flags of `openat`
+    flags: u64,
+    /// File mode of `openat`
+    mode: u64,
+    /// `RESOLVE_*` flags restricting the path resolution
+    resolve: u64,
+}
+
+/// Open flag to fail if the path is not a directory, missing from `OpenFlags`
+const O_DIRECTORY: usize = 0o40000;
+
+/// `struct iovec`
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct IoVec {
+    /// Starting address
+    base: usize,
+    /// Number of bytes
+    len: usize,
+}
+
 impl Process {
     #[inline]
     pub fn get_file_mut(&mut self, fd: usize) -> Result<&mut FileHandle, SysError> {
@@ -417,6 +682,8 @@ impl Process {
     /// - If `path` is absolute, then `dirfd` is ignored.
     ///
     /// - If `follow` is true, then dereference `path` if it is a symbolic link.
+    ///
+    /// - An empty `path` does not exist, see `lookup_inode_at_flags` for `AT_EMPTY_PATH`.
     pub fn lookup_inode_at(
         &self,
         dir_fd: usize,
@@ -428,24 +695,91 @@ impl Process {
             dir_fd as isize, self.cwd, path, follow
         );
 
-        let (fd_dir_path, fd_name) = split_path(&path);
-
-        let follow_max_depth = if follow { FOLLOW_MAX_DEPTH } else { 0 };
-        if dir_fd == AT_FDCWD {
-            Ok(ROOT_INODE
-                .lookup(&self.cwd)?
-                .lookup_follow(path, follow_max_depth)?)
+        if path.is_empty() {
+            return Err(SysError::ENOENT);
+        }
+        let base = if dir_fd == AT_FDCWD || path.starts_with('/') {
+            &self.cwd
         } else {
-            Ok(self
-                .get_file(dir_fd)?
-                .lookup_follow(path, follow_max_depth)?)
+            let dir_path = &self.get_file(dir_fd)?.path;
+            // directories are opened by their absolute path, files without
+            // one like a timerfd are no directory
+            if !dir_path.starts_with('/') {
+                return Err(SysError::ENOTDIR);
+            }
+            dir_path
+        };
+        let path = fs::normalize_path(base, path);
+        Ok(fs::lookup(&path, follow)?)
+    }
+
+    /// Like `lookup_inode_at`, with `AT_SYMLINK_NOFOLLOW` and `AT_EMPTY_PATH` from `flags`.
+    ///
+    /// With `AT_EMPTY_PATH`, an empty `path` refers to `dirfd` itself, or the
+    /// current working directory if it is `AT_FDCWD`.
+    pub fn lookup_inode_at_flags(
+        &self,
+        dir_fd: usize,
+        path: &str,
+        flags: AtFlags,
+    ) -> Result<Arc<dyn INode>, SysError> {
+        if path.is_empty() && flags.contains(AtFlags::EMPTY_PATH) {
+            return match dir_fd {
+                AT_FDCWD => Ok(fs::lookup(&self.cwd, true)?),
+                _ => Ok(self.get_file(dir_fd)?.inode()),
+            };
         }
+        self.lookup_inode_at(dir_fd, path, !flags.contains(AtFlags::SYMLINK_NOFOLLOW))
     }
 
     #[inline]
     pub fn lookup_inode(&self, path: &str) -> Result<Arc<dyn INode>, SysError> {
         self.lookup_inode_at(AT_FDCWD, path, true)
     }
+
+    /// Check the access requested by open `flags` against the effective ids.
+    fn check_open_permission(&self, inode: &dyn INode, flags: OpenFlags) -> Result<(), SysError> {
+        let mut mask = 0;
+        if flags.readable() {
+            mask |= R_OK;
+        }
+        if flags.writable() || flags.contains(OpenFlags::TRUNCATE) {
+            mask |= W_OK;
+        }
+        check_permission(inode, self.euid, self.egid, mask)
+    }
+}
+
+/// Test for read permission
+const R_OK: usize = 4;
+/// Test for write permission
+const W_OK: usize = 2;
+/// Test for execute permission
+const X_OK: usize = 1;
+
+/// Check `mask` (a combination of `R_OK`, `W_OK` and `X_OK`) against the
+/// permission bits of `inode` for the user `uid` in group `gid`.
+///
+/// Root bypasses the check.
+fn check_permission(inode: &dyn INode, uid: Uid, gid: Gid, mask: usize) -> Result<(), SysError> {
+    if uid == 0 || mask == 0 {
+        return Ok(());
+    }
+    let metadata = inode.metadata()?;
+    // pick the owner, group or other class, in that order
+    let shift = if metadata.uid as Uid == uid {
+        6
+    } else if metadata.gid as Gid == gid {
+        3
+    } else {
+        0
+    };
+    let granted = (metadata.mode as usize >> shift) & 0o7;
+    if granted & mask == mask {
+        Ok(())
+    } else {
+        Err(SysError::EACCES)
+    }
 }
 
 /// Split a `path` str to `(base_path, file_name)`
@@ -458,3 +792,243 @@ fn split_path(path: &str) -> (&str, &str) {
     }
     (dir_path, file_name)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fs::{NullINode, OpenOptions},
+        process::mock::MockProcess,
+    };
+    use alloc::string::String;
+    use core::any::Any;
+    use queen_fs::vfs::{Metadata, PollStatus, Result};
+
+    /// A file of `uid` and `gid` with the permission bits `mode`.
+    struct Owned {
+        uid: usize,
+        gid: usize,
+        mode: u16,
+    }
+
+    impl INode for Owned {
+        fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+            Ok(0)
+        }
+
+        fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn poll(&self) -> Result<PollStatus> {
+            NullINode.poll()
+        }
+
+        fn metadata(&self) -> Result<Metadata> {
+            let mut metadata = NullINode.metadata()?;
+            metadata.uid = self.uid as _;
+            metadata.gid = self.gid as _;
+            metadata.mode = self.mode as _;
+            Ok(metadata)
+        }
+
+        fn as_any_ref(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn permission_bits() {
+        let file = |mode| Owned {
+            uid: 1000,
+            gid: 100,
+            mode,
+        };
+        let denied = |inode: &Owned, uid, gid, mask| {
+            let result = check_permission(inode, uid, gid, mask);
+            matches!(result, Err(SysError::EACCES))
+        };
+        let inode = file(0o640);
+        assert!(check_permission(&inode, 0, 0, R_OK | W_OK | X_OK).is_ok());
+        assert!(check_permission(&inode, 1002, 200, 0).is_ok());
+        // the owner
+        assert!(check_permission(&inode, 1000, 200, R_OK | W_OK).is_ok());
+        assert!(denied(&inode, 1000, 200, X_OK));
+        // in the group
+        assert!(check_permission(&inode, 1001, 100, R_OK).is_ok());
+        assert!(denied(&inode, 1001, 100, R_OK | W_OK));
+        // the others
+        assert!(denied(&inode, 1002, 200, R_OK));
+
+        // only the class of the caller counts, not a more permissive one
+        let inode = file(0o077);
+        assert!(denied(&inode, 1000, 100, R_OK));
+        assert!(check_permission(&inode, 1001, 100, R_OK).is_ok());
+    }
+
+    #[test]
+    fn lookup_at_dir_fd() {
+        let tmp = fs::lookup("/tmp", true).unwrap();
+        let dir = tmp.create("at", FileType::Dir, 0o777).unwrap();
+        dir.create("file", FileType::File, 0o666).unwrap();
+        let link = dir.create("loop", FileType::SymLink, 0o777).unwrap();
+        link.write_at(0, b"loop").unwrap();
+
+        let mock = MockProcess::new(0);
+        let mut process = mock.process();
+        let options = OpenOptions {
+            read: true,
+            write: false,
+            append: false,
+        };
+        let dir = FileHandle::new(dir, options, String::from("/tmp/at"), false);
+        process.files.insert(3, dir);
+        let pipe = FileHandle::new(Arc::new(NullINode), options, String::from("pipe:"), false);
+        process.files.insert(4, pipe);
+
+        assert!(process.lookup_inode_at(3, "file", true).is_ok());
+        assert!(process.lookup_inode_at(3, "../at/./file", true).is_ok());
+        let result = process.lookup_inode_at(3, "loop", true);
+        assert!(matches!(result, Err(SysError::ELOOP)));
+        assert!(process.lookup_inode_at(3, "loop", false).is_ok());
+        let result = process.lookup_inode_at(4, "file", true);
+        assert!(matches!(result, Err(SysError::ENOTDIR)));
+    }
+
+    #[test]
+    fn cwd_after_relative_chdirs() {
+        let tmp = fs::lookup("/tmp", true).unwrap();
+        let dir = tmp.create("cwd", FileType::Dir, 0o777).unwrap();
+        let a = dir.create("a", FileType::Dir, 0o777).unwrap();
+        a.create("b", FileType::Dir, 0o777).unwrap();
+
+        let mock = MockProcess::new(0);
+        let buf = mock.map_user(1) as *mut u8;
+        let mut context = UserContext::default();
+        let mut syscall = Syscall {
+            thread: &mock.thread,
+            context: &mut context,
+            exit: false,
+        };
+        assert!(syscall.sys_chdir(b"/tmp/\0".as_ptr()).is_ok());
+        assert!(syscall.sys_chdir(b"cwd//a/\0".as_ptr()).is_ok());
+        assert!(syscall.sys_chdir(b"./b/..\0".as_ptr()).is_ok());
+        assert!(syscall.sys_chdir(b"../a/b/../../a/./b\0".as_ptr()).is_ok());
+        assert!(syscall.sys_chdir(b"c\0".as_ptr()).is_err());
+
+        let cwd = "/tmp/cwd/a/b";
+        let result = syscall.sys_get_cwd(buf, cwd.len());
+        assert!(matches!(result, Err(SysError::ERANGE)));
+        let result = syscall.sys_get_cwd(buf, cwd.len() + 1);
+        assert!(matches!(result, Ok(addr) if addr == buf as usize));
+        assert_eq!(unsafe { from_cstr(buf) }, cwd);
+    }
+
+    #[test]
+    fn open_directory() {
+        let tmp = fs::lookup("/tmp", true).unwrap();
+        let dir = tmp.create("odir", FileType::Dir, 0o777).unwrap();
+        dir.create("file", FileType::File, 0o666).unwrap();
+
+        let mock = MockProcess::new(0);
+        let mut context = UserContext::default();
+        let mut syscall = Syscall {
+            thread: &mock.thread,
+            context: &mut context,
+            exit: false,
+        };
+        let file = b"/tmp/odir/file\0".as_ptr();
+        let result = syscall.sys_open_at(AT_FDCWD, file, O_DIRECTORY, 0);
+        assert!(matches!(result, Err(SysError::ENOTDIR)));
+        let file_fd = syscall.sys_open_at(AT_FDCWD, file, 0, 0).unwrap();
+        let dir = b"/tmp/odir/\0".as_ptr();
+        let dir_fd = syscall.sys_open_at(AT_FDCWD, dir, O_DIRECTORY, 0).unwrap();
+
+        let result = syscall.sys_fchdir(file_fd);
+        assert!(matches!(result, Err(SysError::ENOTDIR)));
+        assert!(syscall.sys_fchdir(dir_fd).is_ok());
+        assert_eq!(mock.process().cwd, "/tmp/odir");
+    }
+
+    #[test]
+    fn lookup_at_flags() {
+        let tmp = fs::lookup("/tmp", true).unwrap();
+        let dir = tmp.create("atf", FileType::Dir, 0o777).unwrap();
+        let file = dir.create("file", FileType::File, 0o666).unwrap();
+        let dir_id = dir.metadata().unwrap().inode;
+        let file_id = file.metadata().unwrap().inode;
+
+        let mock = MockProcess::new(0);
+        let mut process = mock.process();
+        let options = OpenOptions {
+            read: true,
+            write: false,
+            append: false,
+        };
+        let dir = FileHandle::new(dir, options, String::from("/tmp/atf"), false);
+        process.files.insert(3, dir);
+        let empty = AtFlags::EMPTY_PATH;
+        let none = AtFlags::empty();
+        // the inode number found, or the error
+        let lookup = |process: &Process, fd, path, flags| {
+            let inode = process.lookup_inode_at_flags(fd, path, flags)?;
+            Ok::<_, SysError>(inode.metadata().unwrap().inode)
+        };
+
+        // an empty path is the dir fd itself, or the cwd
+        assert_eq!(lookup(&process, 3, "", empty).ok(), Some(dir_id));
+        let result = lookup(&process, 3, "", none);
+        assert!(matches!(result, Err(SysError::ENOENT)));
+        process.cwd = String::from("/tmp/atf");
+        assert_eq!(lookup(&process, AT_FDCWD, "", empty).ok(), Some(dir_id));
+        process.cwd = String::from("/");
+
+        // an absolute path ignores the dir fd, even a closed one
+        let result = lookup(&process, 3, "/tmp/atf/file", none);
+        assert_eq!(result.ok(), Some(file_id));
+        assert_eq!(lookup(&process, 9, "/tmp/atf", none).ok(), Some(dir_id));
+
+        // a relative path is resolved from the dir fd, or the cwd
+        assert_eq!(lookup(&process, 3, "file", none).ok(), Some(file_id));
+        let result = lookup(&process, AT_FDCWD, "file", none);
+        assert!(matches!(result, Err(SysError::ENOENT)));
+
+        // a closed dir fd
+        let result = lookup(&process, 9, "file", none);
+        assert!(matches!(result, Err(SysError::EBADF)));
+        let result = lookup(&process, 9, "", empty);
+        assert!(matches!(result, Err(SysError::EBADF)));
+    }
+
+    #[test]
+    fn open_past_limits() {
+        let mock = MockProcess::new(0);
+        mock.process().rlimits[crate::process::RLIMIT_NOFILE].cur = 2;
+        let mut context = UserContext::default();
+        let mut syscall = Syscall {
+            thread: &mock.thread,
+            context: &mut context,
+            exit: false,
+        };
+        let root = b"/\0".as_ptr();
+        assert!(syscall.sys_open(root, 0, 0).is_ok());
+        assert!(syscall.sys_open(root, 0, 0).is_ok());
+        let result = syscall.sys_open(root, 0, 0);
+        assert!(matches!(result, Err(SysError::EMFILE)));
+        mock.process().rlimits[crate::process::RLIMIT_NOFILE].cur = 1024;
+
+        // no open file left in the system, and nothing created
+        let slots: Vec<_> = core::iter::from_fn(OpenFileSlot::reserve).collect();
+        assert!(slots.len() <= fs::MAX_OPEN_FILES);
+        let result = syscall.sys_open(root, 0, 0);
+        assert!(matches!(result, Err(SysError::ENFILE)));
+        let path = b"/tmp/enfile\0".as_ptr();
+        let result = syscall.sys_open(path, OpenFlags::CREATE.bits(), 0o666);
+        assert!(matches!(result, Err(SysError::ENFILE)));
+        assert!(fs::lookup("/tmp/enfile", true).is_err());
+
+        drop(slots);
+        assert_eq!(syscall.sys_open(root, 0, 0).unwrap(), 2);
+        assert_eq!(mock.process().files.len(), 3);
+    }
+}
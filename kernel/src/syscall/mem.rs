@@ -0,0 +1,157 @@
+use super::*;
+use crate::{
+    consts::USER_TIME_PAGE_OFFSET,
+    memory::{handler::Delay, GlobalFrameAlloc, MemoryAttr, PAGE_SIZE},
+};
+use bitflags::bitflags;
+
+const MADV_NORMAL: usize = 0;
+const MADV_RANDOM: usize = 1;
+const MADV_SEQUENTIAL: usize = 2;
+const MADV_WILLNEED: usize = 3;
+const MADV_DONTNEED: usize = 4;
+
+bitflags! {
+    /// `prot` of `mmap` and `mprotect`
+    pub struct MmapProt: usize {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+    }
+}
+
+#[inline]
+fn page_up(addr: usize) -> Option<usize> {
+    Some(addr.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1))
+}
+
+/// End of the heap areas for a break at `addr`, `None` if the heap would be larger
+/// than `max_size` or reach the time page and the stack above it.
+fn heap_end(brk_start: usize, addr: usize, max_size: usize) -> Option<usize> {
+    let end = page_up(addr)?;
+    if addr - brk_start > max_size || end > USER_TIME_PAGE_OFFSET {
+        return None;
+    }
+    Some(end)
+}
+
+impl Syscall<'_> {
+    /// Move the program break to `addr`, return the new break.
+    ///
+    /// Like linux, a failed request returns the current break unchanged.
+    pub fn sys_brk(&mut self, addr: usize) -> SysResult {
+        let mut process = self.process();
+        if addr < process.brk_start {
+            // `brk(0)` queries the current break
+            return Ok(process.brk);
+        }
+
+        // the break was checked by `heap_end` when set
+        let old_end = page_up(process.brk).unwrap();
+        let new_end = match heap_end(process.brk_start, addr, process.max_data_size()) {
+            Some(end) => end,
+            None => return Ok(process.brk),
+        };
+        let mut vm = self.vm();
+        if new_end > old_end {
+            // refuse to grow into other mappings, e.g. those made by mmap
+            if !vm.test_free_area(old_end, new_end) {
+                return Ok(process.brk);
+            }
+            let attr = MemoryAttr::default().user();
+            vm.push(old_end, new_end, attr, Delay::new(GlobalFrameAlloc), "heap");
+        } else if new_end < old_end {
+            vm.pop_with_split(new_end, old_end);
+        }
+        process.brk = addr;
+        Ok(addr)
+    }
+
+    /// Change the access protection of the pages in `[addr, addr + len)`.
+    ///
+    /// Pages can't be made write-only, `PROT_WRITE` or `PROT_EXEC` imply `PROT_READ`.
+    pub fn sys_mprotect(&mut self, addr: usize, len: usize, prot: usize) -> SysResult {
+        let prot = MmapProt::from_bits(prot).ok_or(SysError::EINVAL)?;
+        if addr % PAGE_SIZE != 0 {
+            return Err(SysError::EINVAL);
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+        let end = addr
+            .checked_add(len)
+            .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+            .ok_or(SysError::ENOMEM)?
+            & !(PAGE_SIZE - 1);
+        let mut vm = self.vm();
+        if vm.is_fixed(addr, end) {
+            // the time page stays read-only
+            return Err(SysError::EACCES);
+        }
+        let protected = vm.protect(addr, end, |attr| {
+            // without any access the pages are kept from user mode
+            attr.user = !prot.is_empty();
+            attr.readonly = !prot.contains(MmapProt::WRITE);
+            attr.execute = prot.contains(MmapProt::EXEC);
+        });
+        if protected {
+            Ok(0)
+        } else {
+            Err(SysError::ENOMEM)
+        }
+    }
+
+    /// Give advice about the use of `[addr, addr + len)`.
+    ///
+    /// Only `MADV_DONTNEED` has an effect, on anonymous memory only: the pages are
+    /// freed and read as zeros on the next access.
+    pub fn sys_madvise(&mut self, addr: usize, len: usize, advice: usize) -> SysResult {
+        if addr % PAGE_SIZE != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let end = addr
+            .checked_add(len)
+            .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+            .ok_or(SysError::EINVAL)?
+            & !(PAGE_SIZE - 1);
+        if self.vm().is_fixed(addr, end) {
+            return Err(SysError::EINVAL);
+        }
+        match advice {
+            MADV_NORMAL | MADV_RANDOM | MADV_SEQUENTIAL | MADV_WILLNEED => Ok(0),
+            MADV_DONTNEED => {
+                let mut vm = self.vm();
+                if !vm.is_mapped(addr, end) {
+                    Err(SysError::ENOMEM)
+                } else if vm.discard(addr, end) {
+                    Ok(0)
+                } else {
+                    // file mappings
+                    Err(SysError::EINVAL)
+                }
+            }
+            _ => Err(SysError::EINVAL),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heap_limits() {
+        let start = 0x40_0000;
+        let end = |addr| heap_end(start, addr, usize::MAX);
+        assert_eq!(end(start), Some(start));
+        assert_eq!(end(start + 1), Some(start + PAGE_SIZE));
+        // no wrap around to 0
+        assert_eq!(end(usize::MAX - 1), None);
+        assert_eq!(end(USER_TIME_PAGE_OFFSET), Some(USER_TIME_PAGE_OFFSET));
+        assert_eq!(end(USER_TIME_PAGE_OFFSET + 1), None);
+        // RLIMIT_DATA
+        let limited = |addr| heap_end(start, addr, 0x1000);
+        assert_eq!(limited(start + 0x1000), Some(start + 0x1000));
+        assert_eq!(limited(start + 0x1001), None);
+    }
+}
@@ -0,0 +1,193 @@
+use super::*;
+use crate::{
+    arch::timer,
+    fs::ROOT_INODE,
+    klog,
+    memory::{frame_stats, PAGE_SIZE},
+    process::PROCESSES,
+    utils::fill_random,
+};
+use bitflags::bitflags;
+
+bitflags! {
+    pub struct GetRandomFlags: u32 {
+        const NONBLOCK = 0x0001;
+        const RANDOM = 0x0002;
+        const INSECURE = 0x0004;
+    }
+}
+
+/// Length of each field of `struct utsname`, including the terminating null
+const UTSNAME_LEN: usize = 65;
+
+/// `struct utsname`
+#[repr(C)]
+pub struct UtsName {
+    pub sysname: [u8; UTSNAME_LEN],
+    pub nodename: [u8; UTSNAME_LEN],
+    pub release: [u8; UTSNAME_LEN],
+    pub version: [u8; UTSNAME_LEN],
+    pub machine: [u8; UTSNAME_LEN],
+    pub domainname: [u8; UTSNAME_LEN],
+}
+
+/// Copy `value` into `field` null-terminated, truncating it if too long.
+fn fill_utsname_field(field: &mut [u8; UTSNAME_LEN], value: &str) {
+    let len = value.len().min(UTSNAME_LEN - 1);
+    field[..len].copy_from_slice(&value.as_bytes()[..len]);
+    field[len..].fill(0);
+}
+
+/// `struct sysinfo` as defined by the kernel, musl reserves more space after it.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SysInfo {
+    pub uptime: usize,
+    pub loads: [usize; 3],
+    pub totalram: usize,
+    pub freeram: usize,
+    pub sharedram: usize,
+    pub bufferram: usize,
+    pub totalswap: usize,
+    pub freeswap: usize,
+    pub procs: u16,
+    pub pad: u16,
+    pub totalhigh: usize,
+    pub freehigh: usize,
+    pub mem_unit: u32,
+}
+
+const LINUX_REBOOT_MAGIC1: u32 = 0xfee1_dead;
+const LINUX_REBOOT_MAGIC2: u32 = 672274793;
+const LINUX_REBOOT_MAGIC2A: u32 = 85072278;
+const LINUX_REBOOT_MAGIC2B: u32 = 369367448;
+const LINUX_REBOOT_MAGIC2C: u32 = 537993216;
+
+const LINUX_REBOOT_CMD_RESTART: u32 = 0x0123_4567;
+const LINUX_REBOOT_CMD_HALT: u32 = 0xcdef_0123;
+const LINUX_REBOOT_CMD_POWER_OFF: u32 = 0x4321_fedc;
+
+const SYSLOG_ACTION_CLOSE: i32 = 0;
+const SYSLOG_ACTION_OPEN: i32 = 1;
+const SYSLOG_ACTION_READ: i32 = 2;
+const SYSLOG_ACTION_READ_ALL: i32 = 3;
+const SYSLOG_ACTION_READ_CLEAR: i32 = 4;
+const SYSLOG_ACTION_CLEAR: i32 = 5;
+const SYSLOG_ACTION_CONSOLE_OFF: i32 = 6;
+const SYSLOG_ACTION_CONSOLE_ON: i32 = 7;
+const SYSLOG_ACTION_CONSOLE_LEVEL: i32 = 8;
+const SYSLOG_ACTION_SIZE_UNREAD: i32 = 9;
+const SYSLOG_ACTION_SIZE_BUFFER: i32 = 10;
+
+impl Syscall<'_> {
+    /// Read the kernel log buffer, as `klogctl`. Reading everything and the size of
+    /// the buffer are open to all, like Linux without `dmesg_restrict`, the other
+    /// actions are for root. `READ` does not wait for new records, and the console
+    /// actions are accepted and ignored.
+    pub fn sys_syslog(&mut self, action: i32, buf: *mut u8, len: i32) -> SysResult {
+        let public = matches!(action, SYSLOG_ACTION_READ_ALL | SYSLOG_ACTION_SIZE_BUFFER);
+        if !public && self.process().euid != 0 {
+            return Err(SysError::EPERM);
+        }
+        match action {
+            SYSLOG_ACTION_CLOSE
+            | SYSLOG_ACTION_OPEN
+            | SYSLOG_ACTION_CONSOLE_OFF
+            | SYSLOG_ACTION_CONSOLE_ON
+            | SYSLOG_ACTION_CONSOLE_LEVEL => Ok(0),
+            SYSLOG_ACTION_CLEAR => {
+                klog::clear();
+                Ok(0)
+            }
+            SYSLOG_ACTION_SIZE_UNREAD => Ok(klog::unread()),
+            SYSLOG_ACTION_SIZE_BUFFER => Ok(klog::LOG_BUF_LEN),
+            SYSLOG_ACTION_READ | SYSLOG_ACTION_READ_ALL | SYSLOG_ACTION_READ_CLEAR => {
+                if len < 0 {
+                    return Err(SysError::EINVAL);
+                }
+                if len == 0 {
+                    return Ok(0);
+                }
+                // check before `read` takes the bytes, they are lost on a fault
+                let len = (len as usize).min(klog::LOG_BUF_LEN);
+                let buf = unsafe { self.vm().check_write_array(buf, len)? };
+                // copy through the kernel, the log is locked with IRQs disabled
+                let mut bytes = vec![0; len];
+                let len = match action {
+                    SYSLOG_ACTION_READ => klog::read(&mut bytes),
+                    _ => klog::read_all(&mut bytes, action == SYSLOG_ACTION_READ_CLEAR),
+                };
+                buf[..len].copy_from_slice(&bytes[..len]);
+                Ok(len)
+            }
+            _ => Err(SysError::EINVAL),
+        }
+    }
+
+    /// Restart, halt or power off, for root only. The root filesystem is synced
+    /// first, unlike Linux.
+    pub fn sys_reboot(&mut self, magic1: u32, magic2: u32, cmd: u32, _arg: usize) -> SysResult {
+        if self.process().euid != 0 {
+            return Err(SysError::EPERM);
+        }
+        let magic2_valid = matches!(
+            magic2,
+            LINUX_REBOOT_MAGIC2
+                | LINUX_REBOOT_MAGIC2A
+                | LINUX_REBOOT_MAGIC2B
+                | LINUX_REBOOT_MAGIC2C
+        );
+        if magic1 != LINUX_REBOOT_MAGIC1 || !magic2_valid {
+            return Err(SysError::EINVAL);
+        }
+        let stop: fn() -> ! = match cmd {
+            LINUX_REBOOT_CMD_RESTART => cpu::reboot,
+            LINUX_REBOOT_CMD_HALT => cpu::halt_all,
+            LINUX_REBOOT_CMD_POWER_OFF => cpu::power_off,
+            _ => return Err(SysError::EINVAL),
+        };
+        if let Err(err) = ROOT_INODE.fs().sync() {
+            warn!("reboot: failed to sync the root filesystem: {:?}", err);
+        }
+        stop()
+    }
+
+    /// The generator is always ready, so this never blocks and `GRND_NONBLOCK` has no effect.
+    pub fn sys_getrandom(&mut self, buf: *mut u8, len: usize, flags: u32) -> SysResult {
+        let flags = GetRandomFlags::from_bits(flags).ok_or(SysError::EINVAL)?;
+        if flags.contains(GetRandomFlags::RANDOM | GetRandomFlags::INSECURE) {
+            return Err(SysError::EINVAL);
+        }
+        let buf = unsafe { self.vm().check_write_array(buf, len)? };
+        fill_random(buf);
+        Ok(len)
+    }
+
+    pub fn sys_uname(&mut self, buf: *mut UtsName) -> SysResult {
+        let buf = unsafe { self.vm().check_write_ptr(buf)? };
+        fill_utsname_field(&mut buf.sysname, "QueenOS");
+        fill_utsname_field(&mut buf.nodename, "queen");
+        fill_utsname_field(&mut buf.release, env!("CARGO_PKG_VERSION"));
+        fill_utsname_field(&mut buf.version, "#1 SMP");
+        fill_utsname_field(&mut buf.machine, "aarch64");
+        fill_utsname_field(&mut buf.domainname, "(none)");
+        Ok(0)
+    }
+
+    /// Load averages are not tracked and reported as zeros.
+    pub fn sys_sysinfo(&mut self, info: *mut SysInfo) -> SysResult {
+        let (total, free) = frame_stats();
+        let procs = PROCESSES.read().len();
+        let info = unsafe { self.vm().check_write_ptr(info)? };
+        *info = SysInfo {
+            uptime: timer::read().as_secs() as usize,
+            totalram: total,
+            freeram: free,
+            procs: procs as u16,
+            // memory is counted in pages
+            mem_unit: PAGE_SIZE as u32,
+            ..Default::default()
+        };
+        Ok(0)
+    }
+}
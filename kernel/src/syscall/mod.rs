@@ -1,19 +1,20 @@
 use crate::{
     arch::{cpu, syscall::*},
-    memory::{MemorySet, VmError},
+    memory::{MemorySet, UserMemory, VmError},
     process::{Process, Thread},
     sync::MutexGuardNoIrq,
 };
 use aarch64::trap::UserContext;
 use alloc::sync::Arc;
-use core::ptr::NonNull;
 
 pub use queen_syscall::Error as SysError;
 pub type SysResult = queen_syscall::Result<usize>;
 
-pub use self::{fs::*, process::*, time::*};
+pub use self::{fs::*, mem::*, misc::*, process::*, time::*};
 
 mod fs;
+mod mem;
+mod misc;
 mod process;
 mod time;
 
@@ -65,7 +66,10 @@ impl Syscall<'_> {
             // file
             SYS_READ => self.sys_read(args[0], args[1], args[2]).await,
             SYS_WRITE => self.sys_write(args[0], args[1] as _, args[2]),
+            SYS_READV => self.sys_readv(args[0], args[1], args[2]).await,
+            SYS_WRITEV => self.sys_writev(args[0], args[1] as _, args[2]),
             SYS_OPENAT => self.sys_open_at(args[0], args[1] as _, args[2], args[3]),
+            SYS_OPENAT2 => self.sys_openat2(args[0], args[1] as _, args[2] as _, args[3]),
             SYS_CLOSE => self.sys_close(args[0]),
             SYS_LSEEK => self.sys_lseek(args[0], args[1] as i64, args[2] as u8),
             SYS_PREAD64 => self.sys_pread(args[0], args[1], args[2], args[3]).await,
@@ -76,6 +80,7 @@ impl Syscall<'_> {
             SYS_FTRUNCATE => self.sys_ftruncate(args[0], args[1]),
             SYS_GETCWD => self.sys_get_cwd(args[0] as _, args[1]),
             SYS_CHDIR => self.sys_chdir(args[0] as _),
+            SYS_FCHDIR => self.sys_fchdir(args[0]),
             SYS_RENAMEAT => self.sys_rename_at(args[0], args[1] as _, args[2], args[3] as _),
             SYS_MKDIRAT => self.sys_mkdir_at(args[0], args[1] as _, args[2]),
             SYS_LINKAT => self.sys_link_at(args[0], args[1] as _, args[2], args[3] as _, args[4]),
@@ -83,26 +88,71 @@ impl Syscall<'_> {
             SYS_SYMLINKAT => self.sys_symlink_at(args[0] as _, args[1] as usize, args[2] as _),
             SYS_FACCESSAT => self.sys_faccess_at(args[0], args[1] as _, args[2], args[3]),
             SYS_DUP3 => self.sys_dup3(args[0], args[1], args[2]),
+            SYS_IOCTL => self.sys_ioctl(args[0], args[1], args[2]),
+            SYS_MOUNT => {
+                self.sys_mount(args[0] as _, args[1] as _, args[2] as _, args[3], args[4])
+            }
+            SYS_UMOUNT2 => self.sys_umount2(args[0] as _, args[1]),
+
+            // memory
+            SYS_BRK => self.sys_brk(args[0]),
+            SYS_MPROTECT => self.sys_mprotect(args[0], args[1], args[2]),
+            SYS_MADVISE => self.sys_madvise(args[0], args[1], args[2]),
 
             // schedule
             SYS_SCHED_YIELD => self.sys_yield().await,
+            SYS_SCHED_SETSCHEDULER => {
+                self.sys_sched_setscheduler(args[0], args[1], args[2] as _)
+            }
+            SYS_SCHED_GETSCHEDULER => self.sys_sched_getscheduler(args[0]),
+            SYS_SETPRIORITY => self.sys_set_priority(args[0], args[1], args[2] as _),
+            SYS_GETPRIORITY => self.sys_get_priority(args[0], args[1]),
 
             // process
             SYS_CLONE => self.sys_clone(args[0], args[1], args[2] as _, args[3] as _, args[4]),
+            SYS_EXECVE => self.sys_execve(args[0] as _, args[1] as _, args[2] as _),
             SYS_EXIT => self.sys_exit(args[0]),
             SYS_EXIT_GROUP => self.sys_exit_group(args[0]),
-            SYS_WAIT4 => self.sys_wait4(args[0] as _, args[1]).await, // TODO: wait4
+            SYS_WAIT4 => self.sys_wait4(args[0] as _, args[1], args[2]).await,
             SYS_SET_TID_ADDRESS => self.sys_set_tid_address(args[0] as _),
             SYS_NANOSLEEP => self.sys_nanosleep(args[0]).await,
+            SYS_FUTEX => {
+                self.sys_futex(args[0], args[1] as _, args[2] as _, args[3])
+                    .await
+            }
+            SYS_TKILL => self.sys_tkill(args[0], args[1]),
+            SYS_TGKILL => self.sys_tgkill(args[0], args[1], args[2]),
+            SYS_GETUID => self.sys_getuid(),
+            SYS_GETEUID => self.sys_geteuid(),
+            SYS_GETGID => self.sys_getgid(),
+            SYS_GETEGID => self.sys_getegid(),
+            SYS_SETUID => self.sys_setuid(args[0]),
+            SYS_SETGID => self.sys_setgid(args[0]),
+            SYS_GETRLIMIT => self.sys_getrlimit(args[0], args[1] as _),
+            SYS_SETRLIMIT => self.sys_setrlimit(args[0], args[1] as _),
+            SYS_PRLIMIT64 => self.sys_prlimit64(args[0], args[1], args[2] as _, args[3] as _),
 
             // time
-            SYS_CLOCK_GETTIME => {
-                self.sys_clock_get_time(args[0], NonNull::new(args[1] as _).unwrap())
+            SYS_CLOCK_GETTIME => self.sys_clock_get_time(args[0], args[1] as _),
+            SYS_GETTIMEOFDAY => self.sys_get_time_of_day(args[0] as _, args[1]),
+            SYS_SETTIMEOFDAY => self.sys_set_time_of_day(args[0] as _, args[1]),
+            SYS_GETRUSAGE => self.sys_getrusage(args[0] as _, args[1] as _),
+            SYS_TIMERFD_CREATE => self.sys_timerfd_create(args[0], args[1]),
+            SYS_TIMERFD_SETTIME => {
+                self.sys_timerfd_settime(args[0], args[1], args[2] as _, args[3] as _)
             }
+            SYS_TIMERFD_GETTIME => self.sys_timerfd_gettime(args[0], args[1] as _),
+
+            // misc
+            SYS_GETRANDOM => self.sys_getrandom(args[0] as _, args[1], args[2] as _),
+            SYS_REBOOT => self.sys_reboot(args[0] as _, args[1] as _, args[2] as _, args[3]),
+            SYS_SYSINFO => self.sys_sysinfo(args[0] as _),
+            SYS_SYSLOG => self.sys_syslog(args[0] as _, args[1] as _, args[2] as _),
+            SYS_UNAME => self.sys_uname(args[0] as _),
 
             _ => {
-                error!("unknown syscall id: {}, args: {:x?}", id, args);
-                todo!()
+                warn!("unknown syscall id: {}, args: {:x?}", id, args);
+                Err(SysError::ENOSYS)
             }
         };
 
@@ -118,3 +168,31 @@ impl From<VmError> for SysError {
         SysError::EFAULT
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        process::mock::MockProcess,
+        sync::mock::{poll_once, MockWaker},
+    };
+    use alloc::boxed::Box;
+    use core::task::Poll;
+
+    #[test]
+    fn unknown_syscall() {
+        let mock = MockProcess::new(1000);
+        let mut context = UserContext::default();
+        let mut syscall = Syscall {
+            thread: &mock.thread,
+            context: &mut context,
+            exit: false,
+        };
+        let (_, waker) = MockWaker::new();
+        let mut call = |id| poll_once(&mut Box::pin(syscall.syscall(id, [0; 6])), &waker);
+
+        // -ENOSYS, and the next syscall still runs
+        assert!(matches!(call(4242), Poll::Ready(-38)));
+        assert!(matches!(call(SYS_GETUID), Poll::Ready(1000)));
+    }
+}
@@ -1,25 +1,70 @@
 use super::*;
 use crate::{
     arch::timer,
-    process::{Pgid, Thread, PROCESSES},
-    sync::{wait_for_event, Event, EventBus, MutexNoIrq},
-    task::timer::TIMER,
+    memory::PAGE_SIZE,
+    process::{
+        process_group, processes_of_user,
+        thread::{thread, THREADS},
+        Gid, Pgid, Process, RLimit, Thread, Uid, PROCESSES, RLIM_NLIMITS,
+    },
+    signal::{send_signal, Siginfo, Signal, SignalAction, SignalStack, SIG_IGN, SI_TKILL},
+    sync::{wait_for_event, Event, EventBus, FutexWait, MutexNoIrq, Subscription},
+    task::{
+        timer::{TimerHandle, TIMER},
+        SchedPolicy,
+    },
     TimeSpec,
 };
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
 use core::{
     future::Future,
     pin::Pin,
+    sync::atomic::{AtomicU32, Ordering},
     task::{Context, Poll},
     time::Duration,
 };
+use bitflags::bitflags;
+use num_traits::FromPrimitive;
 use queen_syscall::flags::CloneFlags;
 
+bitflags! {
+    pub struct WaitOptions: usize {
+        /// Return immediately if no child has exited
+        const NOHANG = 1;
+        /// Also return if a child has stopped
+        const UNTRACED = 2;
+    }
+}
+
+/// Longest path with its null, `PATH_MAX` of Linux.
+const PATH_MAX: usize = 4096;
+/// Longest argument or environment string with its null, `MAX_ARG_STRLEN`.
+const MAX_ARG_STRLEN: usize = 32 * PAGE_SIZE;
+
+const FUTEX_WAIT: u32 = 0;
+const FUTEX_WAKE: u32 = 1;
+const FUTEX_PRIVATE_FLAG: u32 = 128;
+
+const PRIO_PROCESS: usize = 0;
+const PRIO_PGRP: usize = 1;
+const PRIO_USER: usize = 2;
+
+const SCHED_OTHER: usize = 0;
+const SCHED_BATCH: usize = 3;
+const SCHED_IDLE: usize = 5;
+
+/// `struct sched_param`
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct SchedParam {
+    sched_priority: i32,
+}
+
 impl Syscall<'_> {
     /// Fork the current process. Return the child's PID.
     pub fn sys_fork(&mut self) -> SysResult {
         let new_thread = self.thread.fork(self.context);
-        new_thread.spawn();
+        new_thread.spawn(self.thread.child_spawn_options());
         let pid = new_thread.process.lock().pid;
 
         Ok(pid)
@@ -64,14 +109,87 @@ impl Syscall<'_> {
         *parent_tid_ref = tid as u32;
         *child_tid_ref = tid as u32;
 
-        new_thread.spawn();
+        new_thread.spawn(self.thread.child_spawn_options());
 
         Ok(tid)
     }
 
+    /// Replace the current process image with the ELF at `path`.
+    /// Other threads of the process are stopped, and files marked with
+    /// `FD_CLOEXEC` are closed.
+    pub fn sys_execve(
+        &mut self,
+        path: *const u8,
+        argv: *const *const u8,
+        envp: *const *const u8,
+    ) -> SysResult {
+        let path = self.vm().check_read_cstr(path, PATH_MAX)?;
+        let args = self.read_cstr_array(argv)?;
+        let envs = self.read_cstr_array(envp)?;
+        info!("execve: path: {:?}, args: {:?}", path, args);
+
+        let inode = self.process().lookup_inode(&path)?;
+        // a bad ELF fails the call with the old image still in place
+        let image = Thread::load_user_image(&inode).map_err(|err| {
+            warn!("execve: failed to load {:?}: {}", path, err);
+            SysError::ENOEXEC
+        })?;
+        // the old image goes away below, its other threads must not run on
+        self.process().kill_other_threads(self.thread.tid);
+        // dropped with the process unlocked, see `FileHandle`
+        let closed = self.process().close_on_exec();
+        drop(closed);
+        let (entry_addr, ustack_top, heap_start) =
+            Thread::new_user_vm(image, args, envs, &mut self.vm());
+
+        let mut process = self.process();
+        process.exec_path = path;
+        process.brk_start = heap_start;
+        process.brk = heap_start;
+        process.futexes.clear();
+        // handlers point into the old image, ignored signals stay ignored
+        for action in process.dispositions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SignalAction::default();
+            }
+        }
+        drop(process);
+
+        {
+            let mut inner = self.thread.inner.lock();
+            inner.clear_child_tid = 0;
+            inner.signal_alternate_stack = SignalStack::default();
+        }
+
+        *self.context = UserContext::default();
+        self.context.set_ip(entry_addr);
+        self.context.set_sp(ustack_top);
+        // F | A | D | EL0
+        self.context.spsr = 0b1101_00_0000;
+        Ok(0)
+    }
+
+    /// Read a null-terminated array of C strings, like `argv`.
+    fn read_cstr_array(&self, mut ptr: *const *const u8) -> Result<Vec<String>, SysError> {
+        let mut strings = Vec::new();
+        if ptr.is_null() {
+            return Ok(strings);
+        }
+        loop {
+            let s = unsafe { *self.vm().check_read_ptr(ptr)? };
+            if s.is_null() {
+                break;
+            }
+            strings.push(self.vm().check_read_cstr(s, MAX_ARG_STRLEN)?);
+            ptr = unsafe { ptr.add(1) };
+        }
+        Ok(strings)
+    }
+
     /// Wait for the process exit.
     /// Return the PID. Store exit code to `wstatus` if it's not null.
-    pub async fn sys_wait4(&mut self, pid: isize, wstatus: usize) -> SysResult {
+    pub async fn sys_wait4(&mut self, pid: isize, wstatus: usize, options: usize) -> SysResult {
+        let options = WaitOptions::from_bits_truncate(options);
         #[derive(Debug)]
         enum WaitFor {
             AnyChild,
@@ -86,51 +204,50 @@ impl Syscall<'_> {
         };
 
         loop {
-            let mut process = self.process();
-
-            // check child state
-            let find = match target {
-                WaitFor::AnyChild | WaitFor::AnyChildInGroup => {
-                    let mut res = None;
-                    for (pid, child) in &process.children {
-                        if let Some(c) = child.upgrade() {
-                            let p = c.lock();
-                            if p.exited() {
-                                res = Some((p.pid, p.exit_code));
-                                break;
-                            }
-                        }
-                    }
-                    res
-                }
-                WaitFor::Pid(pid) => {
-                    let mut res = None;
-                    if let Some(c) = crate::process::process(pid) {
-                        let p = c.lock();
-                        if p.exited() {
-                            res = Some((p.pid, p.exit_code));
-                        }
-                    }
-                    res
-                }
+            // check child state, without the process locked: an exiting child locks
+            // its parent
+            let children = match target {
+                WaitFor::AnyChild | WaitFor::AnyChildInGroup => self
+                    .process()
+                    .children
+                    .iter()
+                    .filter_map(|(_, child)| child.upgrade())
+                    .collect::<Vec<_>>(),
+                WaitFor::Pid(pid) => crate::process::process(pid).into_iter().collect(),
             };
+            let find = children.iter().find_map(|child| {
+                let mut p = child.lock();
+                wait_status(&mut p, options).map(|(status, reap)| (p.pid, status, reap))
+            });
             // if found, return
-            if let Some((pid, exit_code)) = find {
+            if let Some((pid, status, reap)) = find {
                 // write before removing to handle EFAULT
-                let wstatus = wstatus as *mut i32;
-                if !wstatus.is_null() {
-                    unsafe { *wstatus = exit_code as i32 }
+                if wstatus != 0 {
+                    let wstatus = unsafe { self.vm().check_write_ptr(wstatus as *mut i32)? };
+                    *wstatus = status;
                 }
 
-                // remove from process table
-                PROCESSES.write().remove(&pid);
+                if reap {
+                    let cpu_time = crate::process::process(pid).map(|child| {
+                        let child = child.lock();
+                        child.cpu_time() + child.children_cpu_time
+                    });
 
-                // remove from children
-                process.children.retain(|(p, _)| *p != pid);
+                    // remove from process table, unless another thread reaped it first
+                    if PROCESSES.write().remove(&pid).is_none() {
+                        continue;
+                    }
+
+                    // remove from children
+                    let mut process = self.process();
+                    process.children_cpu_time += cpu_time.unwrap_or_default();
+                    process.children.retain(|(p, _)| *p != pid);
+                }
 
                 return Ok(pid);
             }
             // if not, check pid
+            let process = self.process();
             let invalid = {
                 let children = process
                     .children
@@ -151,6 +268,9 @@ impl Syscall<'_> {
             if invalid {
                 return Err(SysError::ECHILD);
             }
+            if options.contains(WaitOptions::NOHANG) {
+                return Ok(0);
+            }
 
             let event_bus = process.event_bus.clone();
             drop(process);
@@ -161,6 +281,7 @@ impl Syscall<'_> {
     }
 
     pub async fn sys_yield(&mut self) -> SysResult {
+        self.thread.prepare_yield();
         crate::task::yield_now().await;
 
         Ok(0)
@@ -176,12 +297,9 @@ impl Syscall<'_> {
             pid = self.process().pid;
         }
 
-        let process_table = PROCESSES.read();
-        let process = process_table.get(&pid);
-        if let Some(process) = process {
-            Ok(process.lock().pgid as usize)
-        } else {
-            Err(SysError::ESRCH)
+        match crate::process::process(pid) {
+            Some(process) => Ok(process.lock().pgid as usize),
+            None => Err(SysError::ESRCH),
         }
     }
 
@@ -190,13 +308,12 @@ impl Syscall<'_> {
             pid = self.process().pid;
         }
 
-        let process_table = PROCESSES.read();
-        let process = process_table.get(&pid);
-        if let Some(process) = process {
-            process.lock().pgid = pgid as Pgid;
-            Ok(0)
-        } else {
-            Err(SysError::ESRCH)
+        match crate::process::process(pid) {
+            Some(process) => {
+                process.lock().pgid = pgid as Pgid;
+                Ok(0)
+            }
+            None => Err(SysError::ESRCH),
         }
     }
 
@@ -215,33 +332,171 @@ impl Syscall<'_> {
         }
     }
 
+    /// Get the real user id
+    pub fn sys_getuid(&mut self) -> SysResult {
+        Ok(self.process().uid as usize)
+    }
+
+    /// Get the effective user id
+    pub fn sys_geteuid(&mut self) -> SysResult {
+        Ok(self.process().euid as usize)
+    }
+
+    /// Get the real group id
+    pub fn sys_getgid(&mut self) -> SysResult {
+        Ok(self.process().gid as usize)
+    }
+
+    /// Get the effective group id
+    pub fn sys_getegid(&mut self) -> SysResult {
+        Ok(self.process().egid as usize)
+    }
+
+    /// Set the user id.
+    ///
+    /// Root sets both real and effective id, others may only switch
+    /// the effective id back to the real one.
+    pub fn sys_setuid(&mut self, uid: usize) -> SysResult {
+        let uid = uid as Uid;
+        let mut process = self.process();
+        if process.euid == 0 {
+            process.uid = uid;
+            process.euid = uid;
+        } else if uid == process.uid {
+            process.euid = uid;
+        } else {
+            return Err(SysError::EPERM);
+        }
+        Ok(0)
+    }
+
+    /// Set the group id, with the same rules as `sys_setuid`.
+    pub fn sys_setgid(&mut self, gid: usize) -> SysResult {
+        let gid = gid as Gid;
+        let mut process = self.process();
+        if process.euid == 0 {
+            process.gid = gid;
+            process.egid = gid;
+        } else if gid == process.gid {
+            process.egid = gid;
+        } else {
+            return Err(SysError::EPERM);
+        }
+        Ok(0)
+    }
+
+    /// Get and/or set a resource limit of the process `pid`, 0 for the current one.
+    ///
+    /// Only root may raise a hard limit or change the limits of a process
+    /// owned by someone else.
+    pub fn sys_prlimit64(
+        &mut self,
+        pid: usize,
+        resource: usize,
+        new_limit: *const RLimit,
+        old_limit: *mut RLimit,
+    ) -> SysResult {
+        if resource >= RLIM_NLIMITS {
+            return Err(SysError::EINVAL);
+        }
+        let new_limit = if new_limit.is_null() {
+            None
+        } else {
+            Some(unsafe { *self.vm().check_read_ptr(new_limit)? })
+        };
+        if matches!(new_limit, Some(limit) if limit.cur > limit.max) {
+            return Err(SysError::EINVAL);
+        }
+
+        let (current_pid, euid) = {
+            let process = self.process();
+            (process.pid, process.euid)
+        };
+        let target = if pid == 0 || pid == current_pid {
+            self.thread.process.clone()
+        } else {
+            crate::process::process(pid).ok_or(SysError::ESRCH)?
+        };
+        let old = {
+            let mut target = target.lock();
+            if euid != 0 && target.pid != current_pid && target.uid != euid {
+                return Err(SysError::EPERM);
+            }
+            let old = target.rlimits[resource];
+            if let Some(limit) = new_limit {
+                if euid != 0 && limit.max > old.max {
+                    return Err(SysError::EPERM);
+                }
+                target.rlimits[resource] = limit;
+            }
+            old
+        };
+
+        if !old_limit.is_null() {
+            let old_limit = unsafe { self.vm().check_write_ptr(old_limit)? };
+            *old_limit = old;
+        }
+        Ok(0)
+    }
+
+    #[inline]
+    pub fn sys_getrlimit(&mut self, resource: usize, limit: *mut RLimit) -> SysResult {
+        self.sys_prlimit64(0, resource, core::ptr::null(), limit)
+    }
+
+    #[inline]
+    pub fn sys_setrlimit(&mut self, resource: usize, limit: *const RLimit) -> SysResult {
+        self.sys_prlimit64(0, resource, limit, core::ptr::null_mut())
+    }
+
     /// Exit the current thread
     pub fn sys_exit(&mut self, exit_code: usize) -> SysResult {
         let tid = self.thread.tid;
+        self.clear_child_tid();
 
         let mut process = self.process();
         process.threads.retain(|&id| id != tid);
+        process.exited_cpu_time += self.thread.exec_runtime();
 
         // for last thread, exit the process
-        if process.threads.len() == 0 {
-            process.exit(exit_code);
-        }
+        let files = if process.threads.is_empty() {
+            process.exit(exit_code)
+        } else {
+            BTreeMap::new()
+        };
 
         drop(process);
+        drop(files);
         self.exit = true;
         Ok(0)
     }
 
     /// Exit the current thread group (i.e. process)
     pub fn sys_exit_group(&mut self, exit_code: usize) -> SysResult {
-        self.process().exit(exit_code);
-        // TODO: quit other threads
+        self.clear_child_tid();
+        // also stops the other threads
+        let files = self.process().exit(exit_code);
+        drop(files);
         self.exit = true;
         Ok(0)
     }
 
+    /// Zero the `clear_child_tid` address and wake a futex waiter on it,
+    /// which is how `pthread_join` learns that the thread is gone.
+    fn clear_child_tid(&self) {
+        let addr = self.thread.inner.lock().clear_child_tid;
+        if addr == 0 {
+            return;
+        }
+        // the thread may have unmapped it, nothing to do then
+        if let Ok(tid) = unsafe { self.vm().check_write_ptr(addr as *mut u32) } {
+            *tid = 0;
+            self.process().wake_futex(addr, 1);
+        }
+    }
+
     pub async fn sys_nanosleep(&mut self, req: usize) -> SysResult {
-        let time = unsafe { *(req as *const TimeSpec) };
+        let time = unsafe { *self.vm().check_read_ptr(req as *const TimeSpec)? };
         if !time.is_zero() {
             self.sleep_for(time.into()).await?;
             if self.thread.has_signal_to_handle() {
@@ -251,17 +506,220 @@ impl Syscall<'_> {
         Ok(0)
     }
 
-    pub fn sys_set_priority(&mut self, priority: usize) -> SysResult {
-        // TODO
+    /// Set the nice value of the targets, only root may lower it.
+    ///
+    /// aarch64 has no `nice` syscall, libc implements it with this one.
+    pub fn sys_set_priority(&mut self, which: usize, who: usize, prio: isize) -> SysResult {
+        let threads = self.priority_targets(which, who)?;
+        if !threads.iter().all(|thread| self.may_schedule(thread)) {
+            return Err(SysError::EPERM);
+        }
+        let is_root = self.process().euid == 0;
+        if !is_root && threads.iter().any(|thread| prio < thread.nice()) {
+            return Err(SysError::EPERM);
+        }
+        for thread in threads.iter() {
+            thread.set_nice(prio);
+        }
+        Ok(0)
+    }
+
+    /// Return the highest priority among the targets as `20 - nice`,
+    /// so that the result is always positive.
+    pub fn sys_get_priority(&mut self, which: usize, who: usize) -> SysResult {
+        let threads = self.priority_targets(which, who)?;
+        let nice = threads.iter().map(|thread| thread.nice()).min().unwrap();
+        Ok((20 - nice) as usize)
+    }
+
+    /// Only `SCHED_OTHER` (with `SCHED_BATCH` as an alias) and `SCHED_IDLE` are supported.
+    pub fn sys_sched_setscheduler(
+        &mut self,
+        tid: usize,
+        policy: usize,
+        param: *const SchedParam,
+    ) -> SysResult {
+        let thread = self.sched_target(tid)?;
+        if !self.may_schedule(&thread) {
+            return Err(SysError::EPERM);
+        }
+        let param = unsafe { *self.vm().check_read_ptr(param)? };
+        let policy = match policy {
+            SCHED_OTHER | SCHED_BATCH => SchedPolicy::Normal,
+            SCHED_IDLE => SchedPolicy::Idle,
+            _ => return Err(SysError::EINVAL),
+        };
+        // static priority is only meaningful for real-time policies
+        if param.sched_priority != 0 {
+            return Err(SysError::EINVAL);
+        }
+        thread.set_sched_policy(policy);
+        Ok(0)
+    }
+
+    pub fn sys_sched_getscheduler(&mut self, tid: usize) -> SysResult {
+        match self.sched_target(tid)?.sched_policy() {
+            SchedPolicy::Normal => Ok(SCHED_OTHER),
+            SchedPolicy::Idle => Ok(SCHED_IDLE),
+        }
+    }
+
+    /// Whether the scheduling of `thread` may be changed: by root, or by
+    /// the real or effective user of its process.
+    fn may_schedule(&self, thread: &Thread) -> bool {
+        let euid = self.process().euid;
+        if euid == 0 {
+            return true;
+        }
+        let target = thread.process.lock();
+        euid == target.uid || euid == target.euid
+    }
+
+    /// The thread `tid`, or the current thread if `tid` is 0.
+    fn sched_target(&self, tid: usize) -> Result<Arc<Thread>, SysError> {
+        if tid == 0 {
+            return Ok(self.thread.clone());
+        }
+        thread(tid).ok_or(SysError::ESRCH)
+    }
+
+    pub fn sys_tkill(&mut self, tid: usize, sig: usize) -> SysResult {
+        let target = thread(tid).ok_or(SysError::ESRCH)?;
+        Self::signal_thread(&target, sig)
+    }
+
+    pub fn sys_tgkill(&mut self, tgid: usize, tid: usize, sig: usize) -> SysResult {
+        let target = thread(tid).ok_or(SysError::ESRCH)?;
+        if target.process.lock().pid != tgid {
+            return Err(SysError::ESRCH);
+        }
+        Self::signal_thread(&target, sig)
+    }
+
+    /// Queue `sig` for `target` and wake it, signal 0 only checks the target exists.
+    fn signal_thread(target: &Arc<Thread>, sig: usize) -> SysResult {
+        if sig == 0 {
+            return Ok(0);
+        }
+        let signal: Signal = FromPrimitive::from_usize(sig).ok_or(SysError::EINVAL)?;
+        send_signal(
+            target.process.clone(),
+            target.tid as isize,
+            Siginfo {
+                signo: signal as i32,
+                errno: 0,
+                code: SI_TKILL,
+                field: Default::default(),
+            },
+        );
         Ok(0)
     }
 
+    /// Threads selected by `which` and `who` of `getpriority`/`setpriority`.
+    fn priority_targets(&self, which: usize, who: usize) -> Result<Vec<Arc<Thread>>, SysError> {
+        let processes = match which {
+            PRIO_PROCESS => {
+                let tid = if who == 0 { self.thread.tid } else { who };
+                return thread(tid)
+                    .map(|thread| vec![thread])
+                    .ok_or(SysError::ESRCH);
+            }
+            PRIO_PGRP => {
+                let pgid = if who == 0 {
+                    self.process().pgid
+                } else {
+                    who as Pgid
+                };
+                process_group(pgid)
+            }
+            PRIO_USER => {
+                let uid = if who == 0 {
+                    self.process().uid
+                } else {
+                    who as Uid
+                };
+                processes_of_user(uid)
+            }
+            _ => return Err(SysError::EINVAL),
+        };
+        let tids: Vec<_> = processes
+            .iter()
+            .flat_map(|process| process.lock().threads.clone())
+            .collect();
+        let threads: Vec<_> = {
+            let thread_table = THREADS.read();
+            tids.iter()
+                .filter_map(|tid| thread_table.get(tid).cloned())
+                .collect()
+        };
+        if threads.is_empty() {
+            return Err(SysError::ESRCH);
+        }
+        Ok(threads)
+    }
+
     pub fn sys_set_tid_address(&mut self, tidptr: *mut u32) -> SysResult {
         self.thread.inner.lock().clear_child_tid = tidptr as usize;
 
         Ok(self.thread.tid)
     }
 
+    /// Only process-private futexes exist, as there is no shared memory,
+    /// so `FUTEX_PRIVATE_FLAG` makes no difference.
+    pub async fn sys_futex(
+        &mut self,
+        uaddr: usize,
+        op: u32,
+        val: u32,
+        timeout: usize,
+    ) -> SysResult {
+        if uaddr % core::mem::align_of::<AtomicU32>() != 0 {
+            return Err(SysError::EINVAL);
+        }
+        match op & !FUTEX_PRIVATE_FLAG {
+            FUTEX_WAIT => {
+                // relative to now
+                let deadline = if timeout == 0 {
+                    None
+                } else {
+                    let timeout = timeout as *const TimeSpec;
+                    let timeout: Duration = unsafe { *self.vm().check_read_ptr(timeout)? }.into();
+                    Some(timer::read() + timeout)
+                };
+                let futex = self.process().get_futex(uaddr);
+                let wait = {
+                    let mut vm = self.vm();
+                    unsafe { vm.check_read_ptr(uaddr as *const AtomicU32) }
+                        .map(|value| futex.wait(|| value.load(Ordering::SeqCst) == val))
+                };
+                drop(futex);
+                let result = match wait {
+                    Ok(Some(wait)) => {
+                        FutexFuture {
+                            wait,
+                            deadline,
+                            thread: self.thread.clone(),
+                            event_bus: self.thread.process.lock().event_bus.clone(),
+                            subscription: None,
+                            timer: None,
+                        }
+                        .await
+                    }
+                    Ok(None) => Err(SysError::EAGAIN),
+                    Err(err) => Err(err.into()),
+                };
+                // the wait is over, the futex may be unused now
+                self.process().put_futex(uaddr);
+                result
+            }
+            FUTEX_WAKE => Ok(self.process().wake_futex(uaddr, val as usize)),
+            _ => {
+                warn!("unsupported futex op: {:#x}", op);
+                Err(SysError::ENOSYS)
+            }
+        }
+    }
+
     // sleeping
     pub fn sleep_for(&mut self, duration: Duration) -> impl Future<Output = SysResult> {
         SleepFuture {
@@ -269,6 +727,8 @@ impl Syscall<'_> {
             duration,
             thread: self.thread.clone(),
             event_bus: self.thread.process.lock().event_bus.clone(),
+            subscription: None,
+            timer: None,
         }
     }
 }
@@ -279,12 +739,14 @@ pub struct SleepFuture {
     duration: Duration,
     thread: Arc<Thread>,
     event_bus: Arc<MutexNoIrq<EventBus>>,
+    subscription: Option<Subscription>,
+    timer: Option<TimerHandle>,
 }
 
 impl Future for SleepFuture {
     type Output = SysResult;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         // check
         if timer::read() >= self.deadline {
             return Poll::Ready(Ok(0));
@@ -294,17 +756,292 @@ impl Future for SleepFuture {
 
         // handle infinity
         if self.duration.as_nanos() < i64::max_value() as u128 {
-            TIMER.lock().add(self.deadline, cx.waker().clone());
+            let timer = TIMER.lock().add(self.deadline, cx.waker().clone());
+            self.timer = Some(timer);
         }
 
         let waker = cx.waker().clone();
-        self.event_bus.lock().subscribe(Box::new({
-            move |_| {
-                waker.wake_by_ref();
-                true
+        let id = self
+            .event_bus
+            .lock()
+            .subscribe_once(Event::all(), Box::new(move |_| waker.wake()));
+        // replaces the subscription of the previous poll
+        self.subscription = Some(Subscription::new(&self.event_bus, id));
+
+        Poll::Pending
+    }
+}
+
+#[must_use = "future does nothing unless polled/`await`-ed"]
+struct FutexFuture {
+    wait: FutexWait,
+    deadline: Option<Duration>,
+    thread: Arc<Thread>,
+    event_bus: Arc<MutexNoIrq<EventBus>>,
+    subscription: Option<Subscription>,
+    timer: Option<TimerHandle>,
+}
+
+impl Future for FutexFuture {
+    type Output = SysResult;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // register before checking, so that a wake in between is not lost
+        self.wait.register(cx.waker());
+        if self.wait.is_woken() {
+            return Poll::Ready(Ok(0));
+        } else if self.thread.has_signal_to_handle() {
+            return Poll::Ready(Err(SysError::EINTR));
+        }
+
+        if let Some(deadline) = self.deadline {
+            if timer::read() >= deadline {
+                return Poll::Ready(Err(SysError::ETIMEDOUT));
             }
-        }));
+            let timer = TIMER.lock().add(deadline, cx.waker().clone());
+            self.timer = Some(timer);
+        }
+
+        let waker = cx.waker().clone();
+        let id = self
+            .event_bus
+            .lock()
+            .subscribe_once(Event::all(), Box::new(move |_| waker.wake()));
+        // replaces the subscription of the previous poll
+        self.subscription = Some(Subscription::new(&self.event_bus, id));
 
         Poll::Pending
     }
 }
+
+/// The `wstatus` to report for `child`, and whether it should be reaped.
+/// `None` if there is nothing to report.
+fn wait_status(child: &mut Process, options: WaitOptions) -> Option<(i32, bool)> {
+    if child.exited() {
+        let status = match child.exit_signal {
+            // W_EXITCODE(0, sig), the core dump flag is never set as no core is written
+            Some(signal) => signal as i32 & 0x7f,
+            // W_EXITCODE(code, 0)
+            None => ((child.exit_code & 0xff) << 8) as i32,
+        };
+        return Some((status, true));
+    }
+    if options.contains(WaitOptions::UNTRACED) {
+        // W_STOPCODE(sig), a stop is only reported once
+        if let Some(signal) = child.stop_signal.take() {
+            return Some(((signal as i32) << 8 | 0x7f, false));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fs::{FileHandle, NullINode, OpenOptions},
+        process::mock::MockProcess,
+        sync::mock::{poll_once, MockWaker},
+    };
+    use alloc::sync::Weak;
+    use core::{any::Any, sync::atomic::AtomicUsize};
+    use queen_fs::vfs::{INode, Metadata, PollStatus, Result};
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    /// An inode which must be dropped with its process unlocked, like one written
+    /// back to its filesystem.
+    struct Probe(Weak<MutexNoIrq<Process>>);
+
+    impl Drop for Probe {
+        fn drop(&mut self) {
+            if let Some(process) = self.0.upgrade() {
+                assert!(process.try_lock().is_some(), "process locked");
+            }
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl INode for Probe {
+        fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+            Ok(0)
+        }
+
+        fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn poll(&self) -> Result<PollStatus> {
+            NullINode.poll()
+        }
+
+        fn metadata(&self) -> Result<Metadata> {
+            NullINode.metadata()
+        }
+
+        fn as_any_ref(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn close_and_exit_unlocked() {
+        let mock = MockProcess::new(0);
+        let options = OpenOptions {
+            read: true,
+            write: true,
+            append: false,
+        };
+        for fd in 0..1000 {
+            let probe = Arc::new(Probe(Arc::downgrade(&mock.thread.process)));
+            let file = FileHandle::new(probe, options, String::from("/probe"), false);
+            mock.process().files.insert(fd, file);
+        }
+        let mut context = UserContext::default();
+        let mut syscall = Syscall {
+            thread: &mock.thread,
+            context: &mut context,
+            exit: false,
+        };
+
+        let dropped = DROPPED.load(Ordering::Relaxed);
+        assert!(syscall.sys_close(3).is_ok());
+        assert!(matches!(syscall.sys_close(3), Err(SysError::EBADF)));
+        // over an open fd
+        assert!(matches!(syscall.sys_dup3(5, 7, 0), Ok(7)));
+        assert_eq!(DROPPED.load(Ordering::Relaxed), dropped + 2);
+
+        assert!(syscall.sys_exit_group(0).is_ok());
+        assert!(syscall.exit);
+        assert!(mock.process().files.is_empty());
+        assert_eq!(DROPPED.load(Ordering::Relaxed), dropped + 1000);
+    }
+
+    #[test]
+    fn priority_of_other_users() {
+        let user = MockProcess::new(1000);
+        let other = MockProcess::new(1001);
+        let root = MockProcess::new(0);
+        let tids = |mock: &MockProcess, which, who| {
+            let mut context = UserContext::default();
+            let syscall = Syscall {
+                thread: &mock.thread,
+                context: &mut context,
+                exit: false,
+            };
+            let threads = syscall.priority_targets(which, who);
+            threads.map(|threads| threads.iter().map(|thread| thread.tid).collect::<Vec<_>>())
+        };
+        assert!(matches!(tids(&user, PRIO_USER, 0), Ok(tids) if tids == [user.pid()]));
+        assert!(matches!(tids(&root, PRIO_USER, 1001), Ok(tids) if tids == [other.pid()]));
+        assert!(matches!(tids(&root, PRIO_USER, 1002), Err(SysError::ESRCH)));
+
+        let mut context = UserContext::default();
+        let mut syscall = Syscall {
+            thread: &user.thread,
+            context: &mut context,
+            exit: false,
+        };
+        let other_pid = other.pid();
+        let mut set = |which, who| syscall.sys_set_priority(which, who, 5);
+        assert!(set(PRIO_PROCESS, 0).is_ok());
+        assert!(matches!(set(PRIO_PROCESS, other_pid), Err(SysError::EPERM)));
+        assert!(matches!(set(PRIO_USER, 1001), Err(SysError::EPERM)));
+        // the effective user of the target counts too
+        other.process().euid = 1000;
+        assert!(set(PRIO_PROCESS, other_pid).is_ok());
+    }
+
+    #[test]
+    fn sched_policy_of_other_users() {
+        let user = MockProcess::new(1000);
+        let other = MockProcess::new(1001);
+        user.schedule();
+        let mut context = UserContext::default();
+        let mut syscall = Syscall {
+            thread: &user.thread,
+            context: &mut context,
+            exit: false,
+        };
+        let param = SchedParam { sched_priority: 0 };
+        let other_pid = other.pid();
+        let result = syscall.sys_sched_setscheduler(other_pid, SCHED_IDLE, &param);
+        assert!(matches!(result, Err(SysError::EPERM)));
+
+        // the policy round-trips through the scheduler task
+        assert!(matches!(syscall.sys_sched_getscheduler(0), Ok(SCHED_OTHER)));
+        user.thread.set_sched_policy(SchedPolicy::Idle);
+        assert!(matches!(syscall.sys_sched_getscheduler(0), Ok(SCHED_IDLE)));
+        user.thread.set_sched_policy(SchedPolicy::Normal);
+        assert!(matches!(syscall.sys_sched_getscheduler(0), Ok(SCHED_OTHER)));
+    }
+
+    #[test]
+    fn wait_for_stopped_child() {
+        let parent = MockProcess::new(0);
+        let child = MockProcess::new(0);
+        let child_pid = child.pid();
+        child.process().parent = (parent.pid(), Arc::downgrade(&parent.thread.process));
+        let weak_child = Arc::downgrade(&child.thread.process);
+        parent.process().children.push((child_pid, weak_child));
+
+        let info = Siginfo {
+            signo: Signal::SIGTSTP as i32,
+            errno: 0,
+            code: SI_TKILL,
+            field: Default::default(),
+        };
+        send_signal(child.thread.process.clone(), -1, info);
+        let mut context = UserContext::default();
+        assert!(!crate::signal::handle_signal(&child.thread, &mut context));
+        assert!(matches!(child.process().stop_signal, Some(Signal::SIGTSTP)));
+
+        let mut syscall = Syscall {
+            thread: &parent.thread,
+            context: &mut context,
+            exit: false,
+        };
+        let options = (WaitOptions::NOHANG | WaitOptions::UNTRACED).bits();
+        let (_, waker) = MockWaker::new();
+        let mut wait = |options| {
+            let mut wait4 = Box::pin(syscall.sys_wait4(-1, 0, options));
+            poll_once(&mut wait4, &waker)
+        };
+        let nohang = WaitOptions::NOHANG.bits();
+        assert!(matches!(wait(nohang), Poll::Ready(Ok(0))));
+        assert!(matches!(wait(options), Poll::Ready(Ok(pid)) if pid == child_pid));
+        // a stop is reported once
+        assert!(matches!(wait(options), Poll::Ready(Ok(0))));
+    }
+
+    #[test]
+    fn rlimit_nofile() {
+        let mock = MockProcess::new(1000);
+        let limit = mock.map_user(1) as *mut RLimit;
+        let mut context = UserContext::default();
+        let mut syscall = Syscall {
+            thread: &mock.thread,
+            context: &mut context,
+            exit: false,
+        };
+        let nofile = crate::process::RLIMIT_NOFILE;
+        let mut set = |cur, max| {
+            unsafe { *limit = RLimit { cur, max } };
+            syscall.sys_setrlimit(nofile, limit)
+        };
+        assert!(matches!(set(4, 2), Err(SysError::EINVAL)));
+        // only root raises the hard limit
+        assert!(matches!(set(3, 8192), Err(SysError::EPERM)));
+        assert!(set(3, 4096).is_ok());
+        assert!(syscall.sys_getrlimit(nofile, limit).is_ok());
+        assert_eq!(unsafe { *limit }, RLimit { cur: 3, max: 4096 });
+
+        for fd in 0..3 {
+            assert_eq!(syscall.sys_open(b"/\0".as_ptr(), 0, 0).unwrap(), fd);
+        }
+        let result = syscall.sys_open(b"/\0".as_ptr(), 0, 0);
+        assert!(matches!(result, Err(SysError::EMFILE)));
+        assert!(matches!(syscall.sys_dup3(0, 3, 0), Err(SysError::EBADF)));
+        assert_eq!(mock.process().files.len(), 3);
+    }
+}
@@ -1,13 +1,363 @@
 use super::*;
-use core::ptr::NonNull;
+use crate::{
+    arch::timer,
+    drivers::{read_epoch, RTC_DRIVER},
+    fs::{FileHandle, OpenFileSlot, OpenOptions, TimerFdINode},
+};
+use alloc::string::String;
+use bitflags::bitflags;
+use core::{
+    sync::atomic::{AtomicI64, Ordering},
+    time::Duration,
+};
 use queen_fs::TimeSpec;
 
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+const CLOCK_PROCESS_CPUTIME_ID: usize = 2;
+const CLOCK_THREAD_CPUTIME_ID: usize = 3;
+const CLOCK_MONOTONIC_RAW: usize = 4;
+const CLOCK_REALTIME_COARSE: usize = 5;
+const CLOCK_MONOTONIC_COARSE: usize = 6;
+const CLOCK_BOOTTIME: usize = 7;
+
+const RUSAGE_SELF: isize = 0;
+const RUSAGE_CHILDREN: isize = -1;
+const RUSAGE_THREAD: isize = 1;
+
+const USEC_PER_SEC: usize = 1_000_000;
+const NSEC_PER_SEC: i64 = 1_000_000_000;
+
+bitflags! {
+    pub struct TimerFdFlags: usize {
+        /// Ignored, reads always block as file handles have no `O_NONBLOCK`
+        const NONBLOCK = 0o4000;
+        const CLOEXEC = 0o2000000;
+    }
+}
+
+bitflags! {
+    pub struct TimerFdSetFlags: usize {
+        /// `it_value` is an absolute time on the clock of the timer
+        const ABSTIME = 1;
+    }
+}
+
+/// `REALTIME_OFFSET` until it is read from the RTC.
+const UNSET: i64 = i64::MIN;
+/// Wall-clock time when the monotonic clock was zero, in nanoseconds, read from
+/// the RTC then moved by `settimeofday`.
+static REALTIME_OFFSET: AtomicI64 = AtomicI64::new(UNSET);
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct TimeVal {
+    pub sec: isize,
+    pub usec: usize,
+}
+
+/// `struct rusage`, only the times are filled
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RUsage {
+    pub utime: TimeVal,
+    pub stime: TimeVal,
+    pub maxrss: isize,
+    pub ixrss: isize,
+    pub idrss: isize,
+    pub isrss: isize,
+    pub minflt: isize,
+    pub majflt: isize,
+    pub nswap: isize,
+    pub inblock: isize,
+    pub oublock: isize,
+    pub msgsnd: isize,
+    pub msgrcv: isize,
+    pub nsignals: isize,
+    pub nvcsw: isize,
+    pub nivcsw: isize,
+}
+
+/// `struct itimerspec`
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ITimerSpec {
+    pub interval: TimeSpec,
+    pub value: TimeSpec,
+}
+
+impl From<Duration> for TimeVal {
+    fn from(time: Duration) -> Self {
+        TimeVal {
+            sec: time.as_secs() as isize,
+            usec: time.subsec_micros() as usize,
+        }
+    }
+}
+
 impl Syscall<'_> {
-    pub fn sys_clock_get_time(&mut self, clock: usize, mut ts: NonNull<TimeSpec>) -> SysResult {
-        unsafe {
-            *ts.as_mut() = crate::drivers::read_epoch();
+    pub fn sys_clock_get_time(&mut self, clock: usize, ts: *mut TimeSpec) -> SysResult {
+        let time = match clock {
+            CLOCK_REALTIME | CLOCK_REALTIME_COARSE => realtime(),
+            CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => {
+                timer::read()
+            }
+            CLOCK_PROCESS_CPUTIME_ID => self.process().cpu_time(),
+            CLOCK_THREAD_CPUTIME_ID => self.thread.exec_runtime(),
+            _ => return Err(SysError::EINVAL),
+        };
+        let ts = unsafe { self.vm().check_write_ptr(ts)? };
+        *ts = to_timespec(time);
+
+        Ok(0)
+    }
+
+    pub fn sys_get_time_of_day(&mut self, tv: *mut TimeVal, _tz: usize) -> SysResult {
+        // timezone is obsolete, leave it untouched
+        if !tv.is_null() {
+            let tv = unsafe { self.vm().check_write_ptr(tv)? };
+            *tv = realtime().into();
+        }
+        Ok(0)
+    }
+
+    pub fn sys_set_time_of_day(&mut self, tv: *const TimeVal, _tz: usize) -> SysResult {
+        if self.process().euid != 0 {
+            return Err(SysError::EPERM);
+        }
+        if tv.is_null() {
+            return Ok(0);
+        }
+        let tv = unsafe { *self.vm().check_read_ptr(tv)? };
+        let time = settable_time(&tv).ok_or(SysError::EINVAL)?;
+        set_realtime(time);
+        Ok(0)
+    }
+
+    /// Scheduler runtime is reported as user time, system time is not tracked separately.
+    pub fn sys_getrusage(&mut self, who: isize, usage: *mut RUsage) -> SysResult {
+        let time = match who {
+            RUSAGE_SELF => self.process().cpu_time(),
+            RUSAGE_CHILDREN => self.process().children_cpu_time,
+            RUSAGE_THREAD => self.thread.exec_runtime(),
+            _ => return Err(SysError::EINVAL),
+        };
+        let usage = unsafe { self.vm().check_write_ptr(usage)? };
+        *usage = RUsage {
+            utime: time.into(),
+            ..Default::default()
+        };
+        Ok(0)
+    }
+
+    pub fn sys_timerfd_create(&mut self, clock: usize, flags: usize) -> SysResult {
+        match clock {
+            CLOCK_REALTIME | CLOCK_MONOTONIC | CLOCK_BOOTTIME => {}
+            _ => return Err(SysError::EINVAL),
         }
+        let flags = TimerFdFlags::from_bits(flags).ok_or(SysError::EINVAL)?;
+        let slot = OpenFileSlot::reserve().ok_or(SysError::ENFILE)?;
+        let file = FileHandle::new_in_slot(
+            slot,
+            TimerFdINode::new(clock == CLOCK_REALTIME),
+            OpenOptions {
+                read: true,
+                write: false,
+                append: false,
+            },
+            String::from("anon_inode:[timerfd]"),
+            flags.contains(TimerFdFlags::CLOEXEC),
+        );
+        let fd = self.process().add_file(file)?;
+        Ok(fd)
+    }
+
+    pub fn sys_timerfd_settime(
+        &mut self,
+        fd: usize,
+        flags: usize,
+        new: *const ITimerSpec,
+        old: *mut ITimerSpec,
+    ) -> SysResult {
+        let flags = TimerFdSetFlags::from_bits(flags).ok_or(SysError::EINVAL)?;
+        let new = unsafe { *self.vm().check_read_ptr(new)? };
+        if !is_valid_timespec(&new.value) || !is_valid_timespec(&new.interval) {
+            return Err(SysError::EINVAL);
+        }
+        let inode = self.process().get_file(fd)?.inode();
+        let timerfd = inode
+            .as_any_ref()
+            .downcast_ref::<TimerFdINode>()
+            .ok_or(SysError::EINVAL)?;
 
+        // the timer runs on the monotonic clock, an absolute realtime is converted
+        // once: a later change of the realtime clock does not move it
+        let value = Duration::from(new.value);
+        let deadline = if new.value.is_zero() {
+            None
+        } else if !flags.contains(TimerFdSetFlags::ABSTIME) {
+            Some(timer::read() + value)
+        } else if timerfd.is_realtime() {
+            Some(timer::read() + value.checked_sub(realtime()).unwrap_or_default())
+        } else {
+            Some(value)
+        };
+        let (value, interval) = timerfd.set(deadline, new.interval.into());
+
+        if !old.is_null() {
+            let old = unsafe { self.vm().check_write_ptr(old)? };
+            *old = ITimerSpec {
+                interval: to_timespec(interval),
+                value: to_timespec(value),
+            };
+        }
         Ok(0)
     }
+
+    pub fn sys_timerfd_gettime(&mut self, fd: usize, curr: *mut ITimerSpec) -> SysResult {
+        let inode = self.process().get_file(fd)?.inode();
+        let timerfd = inode
+            .as_any_ref()
+            .downcast_ref::<TimerFdINode>()
+            .ok_or(SysError::EINVAL)?;
+        let (value, interval) = timerfd.get();
+        let curr = unsafe { self.vm().check_write_ptr(curr)? };
+        *curr = ITimerSpec {
+            interval: to_timespec(interval),
+            value: to_timespec(value),
+        };
+        Ok(0)
+    }
+}
+
+/// Whether `ts` is a non-negative time with `nsec` below a second.
+fn is_valid_timespec(ts: &TimeSpec) -> bool {
+    ts.sec >= 0 && ts.nsec >= 0 && (ts.nsec as i64) < NSEC_PER_SEC
+}
+
+/// `tv` as a wall-clock time, if it is not before 1970 and fits in
+/// `REALTIME_OFFSET`.
+fn settable_time(tv: &TimeVal) -> Option<Duration> {
+    if tv.sec < 0 || tv.usec >= USEC_PER_SEC {
+        return None;
+    }
+    let time = Duration::new(tv.sec as u64, (tv.usec * 1000) as u32);
+    if time.as_nanos() > i64::MAX as u128 {
+        return None;
+    }
+    Some(time)
+}
+
+/// Wall-clock time.
+///
+/// It follows the monotonic counter from the time of the RTC read once, a
+/// reading of the RTC with its resolution of one second would go backwards
+/// against the sub-second part of the counter.
+pub fn realtime() -> Duration {
+    let now = timer::read().as_nanos() as i64;
+    Duration::from_nanos(now.saturating_add(realtime_offset()).max(0) as u64)
+}
+
+/// Set the wall-clock time.
+///
+/// The seconds are also loaded into the RTC when there is one.
+pub fn set_realtime(time: Duration) {
+    if let Some(rtc) = RTC_DRIVER.get() {
+        rtc.set_epoch(time.as_secs());
+    }
+    // clamped, far from wrapping to a time before 1970
+    let nanos = time.as_nanos().min(i64::MAX as u128) as i64;
+    let offset = nanos - timer::read().as_nanos() as i64;
+    REALTIME_OFFSET.store(offset, Ordering::Release);
+    crate::arch::time_page::update();
+}
+
+/// `REALTIME_OFFSET`, read from the RTC on the first call once there is one.
+fn realtime_offset() -> i64 {
+    let offset = REALTIME_OFFSET.load(Ordering::Acquire);
+    if offset != UNSET {
+        return offset;
+    }
+    let epoch: Duration = read_epoch().into();
+    let offset = epoch.as_nanos() as i64 - timer::read().as_nanos() as i64;
+    if RTC_DRIVER.get().is_none() {
+        return offset;
+    }
+    // the first reader sets it for everyone, unless the time was set meanwhile
+    match REALTIME_OFFSET.compare_exchange(UNSET, offset, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => offset,
+        Err(offset) => offset,
+    }
+}
+
+/// Convert a `Duration` to a normalized `TimeSpec` (`nsec < 1_000_000_000`).
+#[inline]
+pub fn to_timespec(time: Duration) -> TimeSpec {
+    TimeSpec::new(time.as_secs() as _, time.subsec_nanos() as _)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consts::KERNEL_OFFSET,
+        memory::PAGE_SIZE,
+        process::mock::MockProcess,
+        sync::mock::{poll_once, MockWaker},
+    };
+    use alloc::boxed::Box;
+    use core::task::Poll;
+
+    #[test]
+    fn settable_times() {
+        let tv = |sec, usec| TimeVal { sec, usec };
+        assert_eq!(settable_time(&tv(0, 0)), Some(Duration::default()));
+        assert_eq!(
+            settable_time(&tv(1, 999_999)),
+            Some(Duration::new(1, 999_999_000))
+        );
+        assert_eq!(settable_time(&tv(1, USEC_PER_SEC)), None);
+        assert_eq!(settable_time(&tv(-1, 0)), None);
+        // more nanoseconds than `REALTIME_OFFSET` holds
+        assert_eq!(settable_time(&tv(isize::MAX, 0)), None);
+        let max = (i64::MAX / NSEC_PER_SEC) as isize;
+        assert!(settable_time(&tv(max, 0)).is_some());
+    }
+
+    #[test]
+    fn set_time_of_day_needs_root() {
+        let mock = MockProcess::new(1000);
+        let mut context = UserContext::default();
+        let mut syscall = Syscall {
+            thread: &mock.thread,
+            context: &mut context,
+            exit: false,
+        };
+        let tv = TimeVal::default();
+        let result = syscall.sys_set_time_of_day(&tv, 0);
+        assert!(matches!(result, Err(SysError::EPERM)));
+    }
+
+    #[test]
+    fn kernel_pointers() {
+        let mock = MockProcess::new(0);
+        let ts = mock.map_user(1) as *mut TimeSpec;
+        let mut context = UserContext::default();
+        let mut syscall = Syscall {
+            thread: &mock.thread,
+            context: &mut context,
+            exit: false,
+        };
+        let kernel = (KERNEL_OFFSET + PAGE_SIZE) as *mut TimeSpec;
+        for &ptr in &[kernel, core::ptr::null_mut()] {
+            let result = syscall.sys_clock_get_time(CLOCK_MONOTONIC, ptr);
+            assert!(matches!(result, Err(SysError::EFAULT)));
+        }
+        assert!(syscall.sys_clock_get_time(CLOCK_MONOTONIC, ts).is_ok());
+
+        let (_, waker) = MockWaker::new();
+        let mut sleep = Box::pin(syscall.sys_nanosleep(kernel as usize));
+        let result = poll_once(&mut sleep, &waker);
+        assert!(matches!(result, Poll::Ready(Err(SysError::EFAULT))));
+    }
 }
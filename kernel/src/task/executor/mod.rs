@@ -1,9 +1,10 @@
 use crate::{
     arch,
+    drivers::irq::IpiReason,
     sync::spin::{Mutex, MutexGuard, MutexNoIrq, RwLock},
 };
 use ahash::RandomState;
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 use async_task::Runnable;
 use core::{
     cmp,
@@ -11,6 +12,7 @@ use core::{
     mem,
     num::NonZeroU32,
     ops::{self, Not},
+    sync::atomic::{AtomicBool, Ordering},
 };
 use priority_queue::PriorityQueue;
 use smallvec::SmallVec;
@@ -42,17 +44,34 @@ const SCHED_LATENCY: usize = 6_000_000;
 const SCHED_WAKEUP_GRANULARITY: usize = 1_000_000;
 
 /// Minimal preemption granularity for CPU-bound tasks, units: nanoseconds.
-pub const SCHED_MIN_GRANULARITY: usize = 750_000;
+const SCHED_MIN_GRANULARITY: usize = 750_000;
+
+/// Longest interval between two timer ticks, units: nanoseconds.
+///
+/// The tick is due when the slice of the current task runs out, so a task
+/// running alone is only interrupted this often, or when a timer expires.
+pub const SCHED_TICK_MAX_INTERVAL: usize = 10_000_000;
 
 /// This value is kept at sysctl_sched_latency/sysctl_sched_min_granularity.
 const SCHED_NR_LATENCY: usize = 8;
 
+/// Minimal interval between two load balancing attempts, units: nanoseconds.
+const SCHED_BALANCE_INTERVAL: usize = 4_000_000;
+
+/// The busiest run queue must be this much heavier (in percent) than the
+/// local one before tasks are pulled, which keeps tasks from bouncing.
+const SCHED_IMBALANCE_PCT: usize = 125;
+
 /// After fork, child runs first. If set to false (default) then parent will (try to) run first.
 const SCHED_CHILD_RUNS_FIRST: bool = false;
 
 /// Default task weight.
 const NICE_0_WEIGHT: usize = nice_to_weight(0);
 
+/// Weight of `SCHED_IDLE` tasks, far below nice 19 but never zero, so that
+/// they still make some progress next to busy normal tasks.
+const WEIGHT_IDLEPRIO: usize = 3;
+
 static SCHED_FEAT: Lazy<SchedFeatures> = Lazy::new(SchedFeatures::new);
 
 /// Higher nice value means lower priority.
@@ -72,7 +91,7 @@ static GLOBAL_STATE: GlobalState = GlobalState::new();
 pub fn init(cpu_count: usize) {
     GLOBAL_STATE
         .executors
-        .call_once(|| (0..cpu_count).map(|_| Executor::new()).collect());
+        .call_once(|| (0..cpu_count).map(Executor::new).collect());
 }
 
 #[inline]
@@ -127,6 +146,79 @@ impl GlobalState {
     }
 }
 
+/// The executor of the CPU with the lightest load, to spawn a task on.
+///
+/// Only the CPUs running their executor count, and run queues locked at the
+/// moment are skipped. The local executor wins a tie.
+pub fn least_loaded_executor() -> &'static Executor {
+    let local_cpu_id = crate::cpu::id();
+    let executors = unsafe { global_state().executors.get_unchecked() };
+    executors
+        .iter()
+        .enumerate()
+        .filter(|(_, executor)| executor.running.load(Ordering::Acquire))
+        .filter_map(|(cpu_id, executor)| {
+            let load = executor.run_queue.try_lock()?.load.weight;
+            Some((cpu_id, load))
+        })
+        .min_by_key(|&(cpu_id, load)| (load, cpu_id != local_cpu_id))
+        .map_or_else(local_executor, |(cpu_id, _)| &executors[cpu_id])
+}
+
+/// Snapshot of a run queue, see [`sched_debug`].
+pub struct RunQueueStats {
+    pub cpu: usize,
+    pub nr_running: usize,
+    pub load: usize,
+    pub min_vruntime: usize,
+    /// `(tid, vruntime)` of the running task, the vruntime is unknown if it is locked
+    pub current: Option<(usize, Option<usize>)>,
+    /// `(tid, vruntime)` of the ready tasks
+    pub ready_tasks: Vec<(usize, usize)>,
+}
+
+/// Snapshot all run queues for debugging.
+///
+/// Only `try_lock` is used, run queues locked at the moment are skipped.
+pub fn sched_debug() -> Vec<RunQueueStats> {
+    let executors = match global_state().executors.get() {
+        Some(executors) => executors,
+        None => return Vec::new(),
+    };
+    executors
+        .iter()
+        .enumerate()
+        .filter_map(|(cpu, executor)| {
+            let rq = executor.run_queue.try_lock()?;
+            let current = rq.current_task.as_ref().map(|(tid, task)| {
+                (*tid, task.try_lock().map(|task| task.vruntime.0))
+            });
+            let mut ready_tasks: Vec<_> = rq
+                .ready_tasks
+                .iter()
+                .map(|(tid, task)| (*tid, task.vruntime.0))
+                .collect();
+            ready_tasks.sort_by_key(|&(_, vruntime)| VRuntime(vruntime));
+            Some(RunQueueStats {
+                cpu,
+                nr_running: rq.nr_running,
+                load: rq.load.weight,
+                min_vruntime: rq.min_vruntime.0,
+                current,
+                ready_tasks,
+            })
+        })
+        .collect()
+}
+
+/// Scheduling policies handled by CFS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicy {
+    Normal,
+    /// Very low priority background work, `nice` is ignored.
+    Idle,
+}
+
 pub enum SpawnExtraOptions {
     None,
     Fork { parent_sched_task: SchedTaskRef },
@@ -152,17 +244,28 @@ impl Default for SpawnExtraOptions {
 
 pub struct Executor {
     run_queue: RunQueueRef,
+    /// Set once its CPU runs it, tasks spawned on it before may never run.
+    running: AtomicBool,
 }
 
 impl Executor {
     #[inline]
-    fn new() -> Self {
+    fn new(cpu_id: usize) -> Self {
         let executor = Executor {
-            run_queue: Arc::new(MutexNoIrq::new(RunQueue::new())),
+            run_queue: Arc::new(MutexNoIrq::new(RunQueue::new(cpu_id))),
+            running: AtomicBool::new(false),
         };
 
-        let (idle_task, _) = executor.spawn(idle_task(), MAX_NICE, SpawnExtraOptions::none());
+        let (idle_task, idle_sched_task) =
+            executor.spawn(idle_task(), MAX_NICE, SpawnExtraOptions::none());
         idle_task.detach();
+        // the idle task must never be migrated
+        let idle_tid = {
+            let mut idle_sched_task = idle_sched_task.lock();
+            idle_sched_task.set_policy(SchedPolicy::Idle);
+            idle_sched_task.tid
+        };
+        executor.run_queue.lock().idle_tid = Some(idle_tid);
 
         executor
     }
@@ -179,7 +282,20 @@ impl Executor {
 
         let vruntime = match &extra_options {
             SpawnExtraOptions::None => run_queue.min_vruntime,
-            SpawnExtraOptions::Fork { parent_sched_task } => parent_sched_task.lock().vruntime,
+            SpawnExtraOptions::Fork { parent_sched_task } => {
+                let parent_task = parent_sched_task.lock();
+                if Arc::ptr_eq(&parent_task.run_queue, &self.run_queue) {
+                    parent_task.vruntime
+                } else {
+                    // `try_lock`, another CPU may spawn on our run queue from its own
+                    match parent_task.run_queue.try_lock() {
+                        Some(parent_run_queue) => parent_task
+                            .vruntime
+                            .renormalize(parent_run_queue.min_vruntime, run_queue.min_vruntime),
+                        None => run_queue.min_vruntime,
+                    }
+                }
+            }
         };
 
         let mut sched_task = SchedTask::new(tid, nice, self.run_queue.clone(), vruntime);
@@ -231,13 +347,40 @@ impl Executor {
         sched_task.lock().on_rq = true;
         trace!("Task[{}] spawned", tid);
 
+        // like a wakeup, an idle remote CPU is halted
+        let cpu_id = run_queue.cpu_id;
+        if self.running.load(Ordering::Acquire) && run_queue.is_idle() && cpu_id != crate::cpu::id()
+        {
+            drop(run_queue);
+            drop(active_tasks);
+            arch::interrupt::send_ipi(cpu_id, IpiReason::Reschedule);
+        }
+
         (task, sched_task)
     }
 
+    /// Whether tasks other than the running one are waiting for this CPU.
+    #[inline]
+    pub fn has_ready_tasks(&self) -> bool {
+        !self.run_queue.lock().ready_tasks.is_empty()
+    }
+
+    /// Time until the current task may have to be preempted, units: nanoseconds.
+    pub fn next_tick(&self) -> usize {
+        self.run_queue.lock().next_tick()
+    }
+
     pub fn run(&self) {
+        self.running.store(true, Ordering::Release);
         let run_queue = self.run_queue.clone();
         loop {
-            let (tid, task, runnable) = run_queue.lock().pop_task_to_run();
+            let (tid, task, runnable, next_tick) = {
+                let mut run_queue = run_queue.lock();
+                let (tid, task, runnable) = run_queue.pop_task_to_run();
+                (tid, task, runnable, run_queue.next_tick())
+            };
+            // the tick may be far ahead if the previous task ran alone
+            arch::timer::tick_within(next_tick);
             trace!("Task[{}] run", tid);
             let is_yielded = runnable.run();
             let mut run_queue = run_queue.lock();
@@ -251,22 +394,30 @@ impl Executor {
 }
 
 struct RunQueue {
+    /// The CPU running this queue.
+    cpu_id: usize,
     ready_tasks: PriorityQueue<Tid, ReadyTask, RandomState>,
     current_task: Option<(Tid, SchedTaskRef)>,
     load: LoadWeight,
     min_vruntime: VRuntime,
     nr_running: usize,
+    idle_tid: Option<Tid>,
+    /// Time of the next load balancing, units: nanoseconds.
+    next_balance: usize,
 }
 
 impl RunQueue {
     #[inline]
-    fn new() -> Self {
+    fn new(cpu_id: usize) -> Self {
         RunQueue {
+            cpu_id,
             ready_tasks: PriorityQueue::with_default_hasher(),
             current_task: None,
             load: LoadWeight::new(0),
             min_vruntime: VRuntime(0),
             nr_running: 0,
+            idle_tid: None,
+            next_balance: 0,
         }
     }
 
@@ -345,7 +496,10 @@ impl RunQueue {
             let current_task = current_task.lock();
             let ideal_runtime = self.sched_slice(&current_task);
             let delta_exec = current_task.sum_exec_runtime - current_task.prev_sum_exec_runtime;
-            let preempt_current = if !current_task.on_rq || delta_exec > ideal_runtime {
+            let preempt_current = if !current_task.on_rq
+                || current_task.yielded
+                || delta_exec > ideal_runtime
+            {
                 true
                 // TODO: clear buddies
             } else if delta_exec < SCHED_MIN_GRANULARITY {
@@ -375,18 +529,64 @@ impl RunQueue {
 
         let runnable = self.ready_tasks.remove(&next_tid).unwrap().1.runnable;
         let task = global_state().task(next_tid).unwrap();
-        task.lock().exec_start = arch::timer::read_ns() as usize;
+        let switched = self
+            .current_task
+            .as_ref()
+            .map(|(current_tid, _)| *current_tid != next_tid)
+            .unwrap_or(true);
+        {
+            let mut task = task.lock();
+            task.exec_start = arch::timer::read_ns() as usize;
+            task.yielded = false;
+            // a new slice starts
+            if switched {
+                task.prev_sum_exec_runtime = task.sum_exec_runtime;
+            }
+        }
         self.current_task = Some((next_tid, task.clone()));
 
         (next_tid, task, runnable)
     }
 
+    /// Whether no task but the idle task waits, then nothing preempts the
+    /// current task.
+    fn runs_alone(&self) -> bool {
+        self.ready_tasks
+            .iter()
+            .all(|(tid, _)| Some(*tid) == self.idle_tid)
+    }
+
+    /// Time until the slice of the current task runs out, units: nanoseconds.
+    fn next_tick(&self) -> usize {
+        if self.runs_alone() {
+            return SCHED_TICK_MAX_INTERVAL;
+        }
+        // the task may be locked by the code a timer interrupt interrupted
+        let current_task = match self.current_task.as_ref().and_then(|(_, task)| task.try_lock()) {
+            Some(current_task) => current_task,
+            None => return SCHED_MIN_GRANULARITY,
+        };
+        let now = arch::timer::read_ns() as usize;
+        let delta_exec = current_task.sum_exec_runtime - current_task.prev_sum_exec_runtime
+            + now.saturating_sub(current_task.exec_start);
+        self.sched_slice(&current_task)
+            .saturating_sub(delta_exec)
+            .max(SCHED_MIN_GRANULARITY)
+            .min(SCHED_TICK_MAX_INTERVAL)
+    }
+
     fn task_tick(&mut self, mut task: MutexGuard<SchedTask>) {
         task.tick();
         self.ready_tasks
             .change_priority_by(&task.tid, |t| t.vruntime = task.vruntime);
         drop(task);
         self.update_min_vruntime();
+
+        let now = arch::timer::read_ns() as usize;
+        if now >= self.next_balance {
+            self.next_balance = now + SCHED_BALANCE_INTERVAL;
+            self.load_balance();
+        }
     }
 
     fn update_min_vruntime(&mut self) {
@@ -408,6 +608,15 @@ impl RunQueue {
         self.min_vruntime = self.min_vruntime.max(vruntime);
     }
 
+    /// Whether the idle task is running, or nothing has run yet.
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.current_task
+            .as_ref()
+            .map(|(current_tid, _)| Some(*current_tid) == self.idle_tid)
+            .unwrap_or(true)
+    }
+
     #[inline]
     fn is_current_task(&self, tid: Tid) -> bool {
         self.current_task
@@ -416,6 +625,82 @@ impl RunQueue {
             .unwrap_or(false)
     }
 
+    /// Pull tasks from the busiest run queue if it is significantly heavier
+    /// than this one. Imitates linux `load_balance`, without sched domains.
+    fn load_balance(&mut self) {
+        let cpu_id = crate::cpu::id();
+        let self_ref = global_state().executor(cpu_id).run_queue.clone();
+        let other_run_queues = global_state().other_run_queues(cpu_id);
+        let mut busiest = match other_run_queues
+            .iter()
+            .filter_map(|rq| rq.try_lock())
+            .max_by_key(|rq| rq.load.weight)
+        {
+            Some(rq) => rq,
+            None => return,
+        };
+
+        // moving a single task must not just reverse the imbalance
+        if busiest.load.weight * 100 <= self.load.weight * SCHED_IMBALANCE_PCT
+            || busiest.nr_running < self.nr_running + 2
+        {
+            return;
+        }
+
+        // take half of the difference so that both end up around the average
+        let imbalance = (busiest.load.weight - self.load.weight) / 2;
+        self.pull_tasks(&mut busiest, imbalance, &self_ref);
+    }
+
+    /// Move ready tasks from `src` to this run queue, up to `max_load` weight in total.
+    fn pull_tasks(&mut self, src: &mut RunQueue, max_load: usize, self_ref: &RunQueueRef) {
+        let mut moved = 0;
+        let mut tasks_to_push_back = SmallVec::<[(Tid, ReadyTask); 32]>::new();
+        while moved < max_load {
+            let (tid, mut ready_task) = match src.ready_tasks.pop() {
+                Some(task) => task,
+                None => break,
+            };
+            if src.is_current_task(tid) || src.idle_tid == Some(tid) {
+                tasks_to_push_back.push((tid, ready_task));
+                continue;
+            }
+            // the usual lock order is task then run queue, so never wait here
+            let task = global_state().task(tid).unwrap();
+            let mut task = match task.try_lock() {
+                Some(task) if moved + task.load.weight <= max_load => task,
+                _ => {
+                    tasks_to_push_back.push((tid, ready_task));
+                    continue;
+                }
+            };
+
+            task.vruntime = task
+                .vruntime
+                .renormalize(src.min_vruntime, self.min_vruntime);
+            // the queue is ordered by this key, keep it in sync
+            ready_task.vruntime = task.vruntime;
+            task.run_queue = self_ref.clone();
+
+            src.nr_running -= 1;
+            src.load -= task.load;
+            self.nr_running += 1;
+            self.load += task.load;
+            moved += task.load.weight;
+            trace!("Task[{}] migrated", tid);
+            drop(task);
+
+            self.ready_tasks.push(tid, ready_task);
+        }
+        for (tid, task) in tasks_to_push_back {
+            src.ready_tasks.push(tid, task);
+        }
+        if moved != 0 {
+            src.update_min_vruntime();
+            self.update_min_vruntime();
+        }
+    }
+
     fn try_steal_tasks(&mut self) {
         let other_run_queues = global_state().other_run_queues(crate::cpu::id());
         let self_ref = global_state().executor(crate::cpu::id()).run_queue.clone();
@@ -423,37 +708,54 @@ impl RunQueue {
             .iter()
             .find_map(|rq| rq.try_lock().filter(|rq| rq.nr_running > 2))
         {
-            let mut count = rq.ready_tasks.len() / 2;
-            let mut tasks_to_push_back = SmallVec::<[(Tid, ReadyTask); 32]>::new();
-            while count != 0 {
-                count -= 1;
-                let (tid, ready_task) = rq.ready_tasks.pop().unwrap();
-                if rq.is_current_task(tid) {
-                    tasks_to_push_back.push((tid, ready_task));
-                    continue;
-                }
-                let task = global_state().task(tid).unwrap();
-                if let Some(mut task) = task.try_lock() {
-                    task.vruntime -= rq.min_vruntime;
-                    task.vruntime += self.min_vruntime;
-                    self.ready_tasks.push(tid, ready_task);
-                    task.run_queue = self_ref.clone();
-                } else {
-                    tasks_to_push_back.push((tid, ready_task));
-                };
-            }
-            for (tid, task) in tasks_to_push_back {
-                rq.ready_tasks.push(tid, task);
-            }
+            self.steal_tasks(&mut rq, &self_ref);
         };
     }
+
+    /// Move half of the ready tasks of `src` to this run queue.
+    fn steal_tasks(&mut self, src: &mut RunQueue, self_ref: &RunQueueRef) {
+        let mut count = src.ready_tasks.len() / 2;
+        let mut tasks_to_push_back = SmallVec::<[(Tid, ReadyTask); 32]>::new();
+        while count != 0 {
+            count -= 1;
+            let (tid, mut ready_task) = src.ready_tasks.pop().unwrap();
+            if src.is_current_task(tid) || src.idle_tid == Some(tid) {
+                tasks_to_push_back.push((tid, ready_task));
+                continue;
+            }
+            let task = global_state().task(tid).unwrap();
+            if let Some(mut task) = task.try_lock() {
+                task.vruntime = task
+                    .vruntime
+                    .renormalize(src.min_vruntime, self.min_vruntime);
+                // the queue is ordered by this key, keep it in sync
+                ready_task.vruntime = task.vruntime;
+                task.run_queue = self_ref.clone();
+                src.nr_running -= 1;
+                src.load -= task.load;
+                self.nr_running += 1;
+                self.load += task.load;
+                self.ready_tasks.push(tid, ready_task);
+            } else {
+                tasks_to_push_back.push((tid, ready_task));
+            };
+        }
+        for (tid, task) in tasks_to_push_back {
+            src.ready_tasks.push(tid, task);
+        }
+        src.update_min_vruntime();
+        self.update_min_vruntime();
+    }
 }
 
 pub struct SchedTask {
     tid: Tid,
     load: LoadWeight,
-    pub nice: isize,
+    nice: isize,
+    policy: SchedPolicy,
     on_rq: bool,
+    /// Give up the CPU at the next pick, set by `yield_task`
+    yielded: bool,
     run_queue: RunQueueRef,
 
     exec_start: usize,
@@ -468,7 +770,9 @@ impl SchedTask {
             tid,
             load: LoadWeight::new(nice_to_weight(nice)),
             nice,
+            policy: SchedPolicy::Normal,
             on_rq: false,
+            yielded: false,
             run_queue,
             exec_start: 0,
             sum_exec_runtime: 0,
@@ -477,6 +781,61 @@ impl SchedTask {
         }
     }
 
+    #[inline]
+    pub fn nice(&self) -> isize {
+        self.nice
+    }
+
+    /// Total time this task has been running, in nanoseconds.
+    #[inline]
+    pub fn sum_exec_runtime(&self) -> usize {
+        self.sum_exec_runtime
+    }
+
+    /// Change the nice value, clamped to `[MIN_NICE, MAX_NICE]`.
+    pub fn set_nice(&mut self, nice: isize) {
+        self.nice = nice.max(MIN_NICE).min(MAX_NICE);
+        self.reweight();
+    }
+
+    #[inline]
+    pub fn policy(&self) -> SchedPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: SchedPolicy) {
+        self.policy = policy;
+        self.reweight();
+    }
+
+    /// Recompute the weight from `nice` and `policy`.
+    ///
+    /// The run queue load is adjusted under its lock when the task is
+    /// accounted there, whether it is running or waiting to run.
+    fn reweight(&mut self) {
+        let load = LoadWeight::new(match self.policy {
+            SchedPolicy::Normal => nice_to_weight(self.nice),
+            SchedPolicy::Idle => WEIGHT_IDLEPRIO,
+        });
+        if self.on_rq {
+            let run_queue = self.run_queue.clone();
+            let mut run_queue = run_queue.lock();
+            run_queue.load -= self.load;
+            run_queue.load += load;
+        }
+        self.load = load;
+    }
+
+    /// Place the task behind its peers before yielding, so that an
+    /// equal-priority task really gets the CPU. Imitates linux `yield_task_fair`.
+    pub fn yield_task(&mut self) {
+        let run_queue = self.run_queue.clone();
+        let run_queue = run_queue.lock();
+        let vruntime = run_queue.min_vruntime + run_queue.sched_vslice(self);
+        self.vruntime = self.vruntime.max(vruntime);
+        self.yielded = true;
+    }
+
     /// `delta /= w`
     #[inline]
     fn delta_fair(&self, delta_exec: usize) -> usize {
@@ -501,14 +860,27 @@ impl SchedTask {
 
             let run_queue = task.run_queue.clone();
             let mut run_queue = run_queue.lock();
+            let ran_alone = run_queue.runs_alone();
 
             // sleeps up to a single latency don't count.
+            // this wraps right after boot, which the signed comparison of `VRuntime` handles.
             let vruntime = run_queue.min_vruntime - thresh;
             // ensure we never gain time by being placed backwards.
             task.vruntime = task.vruntime.max(vruntime);
 
-            let ready_task = ReadyTask::new(vruntime, runnable);
+            let ready_task = ReadyTask::new(task.vruntime, runnable);
             run_queue.insert_task(task.tid, ready_task, task.load);
+
+            // an idle remote CPU is halted, and one running a task alone ticks
+            // rarely: either would only notice the task at its next tick
+            let cpu_id = run_queue.cpu_id;
+            if cpu_id == crate::cpu::id() {
+                drop(run_queue);
+                arch::timer::tick_within(SCHED_MIN_GRANULARITY);
+            } else if run_queue.is_idle() || ran_alone {
+                drop(run_queue);
+                arch::interrupt::send_ipi(cpu_id, IpiReason::Reschedule);
+            }
         }
     }
 
@@ -557,6 +929,8 @@ impl PartialEq for ReadyTask {
 impl Eq for ReadyTask {}
 
 /// Virtual runtime dealing with overflow.
+///
+/// Arithmetic wraps around and values are compared by their signed distance.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 #[repr(C)]
 struct VRuntime(usize);
@@ -565,6 +939,13 @@ impl VRuntime {
     fn delta(self, other: Self) -> isize {
         self.0 as isize - other.0 as isize
     }
+
+    /// Move from a run queue with `from` as `min_vruntime` to one with `to`,
+    /// keeping the relative position.
+    #[inline]
+    fn renormalize(self, from: Self, to: Self) -> Self {
+        VRuntime(self.0.wrapping_sub(from.0).wrapping_add(to.0))
+    }
 }
 
 impl PartialOrd for VRuntime {
@@ -595,7 +976,7 @@ impl ops::Add<usize> for VRuntime {
     type Output = Self;
 
     fn add(self, rhs: usize) -> Self::Output {
-        VRuntime(self.0 + rhs)
+        VRuntime(self.0.wrapping_add(rhs))
     }
 }
 
@@ -603,19 +984,19 @@ impl ops::Add for VRuntime {
     type Output = Self;
 
     fn add(self, rhs: Self) -> Self::Output {
-        VRuntime(self.0 + rhs.0)
+        VRuntime(self.0.wrapping_add(rhs.0))
     }
 }
 
 impl ops::AddAssign for VRuntime {
     fn add_assign(&mut self, rhs: Self) {
-        self.0 += rhs.0;
+        self.0 = self.0.wrapping_add(rhs.0);
     }
 }
 
 impl ops::AddAssign<usize> for VRuntime {
     fn add_assign(&mut self, rhs: usize) {
-        self.0 += rhs;
+        self.0 = self.0.wrapping_add(rhs);
     }
 }
 
@@ -623,7 +1004,7 @@ impl ops::Sub<usize> for VRuntime {
     type Output = Self;
 
     fn sub(self, rhs: usize) -> Self::Output {
-        VRuntime(self.0 - rhs)
+        VRuntime(self.0.wrapping_sub(rhs))
     }
 }
 
@@ -631,19 +1012,19 @@ impl ops::Sub for VRuntime {
     type Output = Self;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        VRuntime(self.0 - rhs.0)
+        VRuntime(self.0.wrapping_sub(rhs.0))
     }
 }
 
 impl ops::SubAssign for VRuntime {
     fn sub_assign(&mut self, rhs: Self) {
-        self.0 -= rhs.0;
+        self.0 = self.0.wrapping_sub(rhs.0);
     }
 }
 
 impl ops::SubAssign<usize> for VRuntime {
     fn sub_assign(&mut self, rhs: usize) {
-        self.0 -= rhs;
+        self.0 = self.0.wrapping_sub(rhs);
     }
 }
 
@@ -798,11 +1179,17 @@ const fn sched_period(nr_running: usize) -> usize {
     }
 }
 
+/// Sleep in WFI while the run queue is empty, the timer tick or a reschedule IPI
+/// wakes it up.
 #[inline]
 async fn idle_task() {
     loop {
         super::yield_now().await;
-        crate::cpu::halt();
+        let flags = unsafe { arch::interrupt::disable_and_store() };
+        if !local_executor().has_ready_tasks() {
+            arch::interrupt::wait_for_interrupt();
+        }
+        unsafe { arch::interrupt::restore(flags) };
     }
 }
 
@@ -814,3 +1201,203 @@ impl<F: Fn()> Drop for CallOnDrop<F> {
         (self.0)();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forked_child_after_parent() {
+        let executor = Executor::new(0);
+        let (_parent, parent_sched_task) = executor.spawn(async {}, 0, SpawnExtraOptions::none());
+        // the parent already ran for a while
+        let parent_vruntime = VRuntime(100 * SCHED_LATENCY);
+        parent_sched_task.lock().vruntime = parent_vruntime;
+
+        let fork = SpawnExtraOptions::fork(parent_sched_task.clone());
+        let (_child, child_sched_task) = executor.spawn(async {}, 0, fork);
+        let (_other, other_sched_task) = executor.spawn(async {}, 0, SpawnExtraOptions::none());
+
+        let child_vruntime = child_sched_task.lock().vruntime;
+        assert_ne!(child_vruntime, VRuntime(0));
+        assert!(child_vruntime >= parent_vruntime);
+        assert!(other_sched_task.lock().vruntime < child_vruntime);
+        // the parent keeps the CPU, no swap with the child
+        assert_eq!(parent_sched_task.lock().vruntime, parent_vruntime);
+    }
+
+    #[test]
+    fn reweight_ready_and_running() {
+        let executor = Executor::new(0);
+        let load = || executor.run_queue.lock().load.weight;
+        let (_ready, ready_task) = executor.spawn(async {}, 0, SpawnExtraOptions::none());
+        let (_running, running_task) = executor.spawn(async {}, 0, SpawnExtraOptions::none());
+        assert_eq!(load(), WEIGHT_IDLEPRIO + 2 * NICE_0_WEIGHT);
+
+        // waiting in the queue
+        ready_task.lock().set_nice(5);
+        assert_eq!(load(), WEIGHT_IDLEPRIO + NICE_0_WEIGHT + nice_to_weight(5));
+
+        // out of the queue while it runs, but still accounted
+        let tid = running_task.lock().tid;
+        let runnable = {
+            let mut rq = executor.run_queue.lock();
+            let (_, ready) = rq.ready_tasks.remove(&tid).unwrap();
+            rq.current_task = Some((tid, running_task.clone()));
+            ready.runnable
+        };
+        running_task.lock().set_nice(-5);
+        let weight = nice_to_weight(5) + nice_to_weight(-5);
+        assert_eq!(load(), WEIGHT_IDLEPRIO + weight);
+        running_task.lock().set_policy(SchedPolicy::Idle);
+        assert_eq!(load(), 2 * WEIGHT_IDLEPRIO + nice_to_weight(5));
+
+        // removing them takes away their new weights, nothing more
+        for task in [&running_task, &ready_task].iter() {
+            let task = task.lock();
+            executor.run_queue.lock().remove_task(task);
+        }
+        assert_eq!(load(), WEIGHT_IDLEPRIO);
+        drop(runnable);
+    }
+
+    #[test]
+    fn idle_policy_weight() {
+        let executor = Executor::new(0);
+        let load = || executor.run_queue.lock().load.weight;
+        let (_task, sched_task) = executor.spawn(async {}, -5, SpawnExtraOptions::none());
+        assert_eq!(load(), WEIGHT_IDLEPRIO + nice_to_weight(-5));
+
+        sched_task.lock().set_policy(SchedPolicy::Idle);
+        assert_eq!(sched_task.lock().load.weight, WEIGHT_IDLEPRIO);
+        assert_eq!(load(), 2 * WEIGHT_IDLEPRIO);
+        // nice is kept for later, but ignored
+        sched_task.lock().set_nice(-10);
+        assert_eq!(sched_task.lock().nice(), -10);
+        assert_eq!(load(), 2 * WEIGHT_IDLEPRIO);
+
+        sched_task.lock().set_policy(SchedPolicy::Normal);
+        assert_eq!(sched_task.lock().policy(), SchedPolicy::Normal);
+        assert_eq!(load(), WEIGHT_IDLEPRIO + nice_to_weight(-10));
+    }
+
+    #[test]
+    fn steal_onto_later_queue() {
+        let src = Executor::new(0);
+        let dst = Executor::new(1);
+        let tasks: Vec<_> = (0..4)
+            .map(|_| src.spawn(async {}, 0, SpawnExtraOptions::none()))
+            .collect();
+        // the destination has run for much longer
+        let min_vruntime = VRuntime(1000 * SCHED_LATENCY);
+        dst.run_queue.lock().min_vruntime = min_vruntime;
+        let (_local, local_task) = dst.spawn(async {}, 0, SpawnExtraOptions::none());
+        let local_vruntime = local_task.lock().vruntime;
+
+        let dst_ref = dst.run_queue.clone();
+        let (src_load, dst_load) = {
+            let mut src_rq = src.run_queue.lock();
+            let mut dst_rq = dst.run_queue.lock();
+            let loads = src_rq.load.weight + dst_rq.load.weight;
+            let nr_running = src_rq.nr_running + dst_rq.nr_running;
+            dst_rq.steal_tasks(&mut src_rq, &dst_ref);
+            assert_eq!(src_rq.nr_running + dst_rq.nr_running, nr_running);
+            assert_eq!(src_rq.load.weight + dst_rq.load.weight, loads);
+            assert!(dst_rq.min_vruntime >= min_vruntime);
+            (src_rq.load.weight, dst_rq.load.weight)
+        };
+        assert!(src_load < WEIGHT_IDLEPRIO + 4 * NICE_0_WEIGHT);
+        assert!(dst_load > WEIGHT_IDLEPRIO + NICE_0_WEIGHT);
+
+        let mut stolen = 0;
+        for (_, sched_task) in tasks.iter() {
+            let task = sched_task.lock();
+            if !Arc::ptr_eq(&task.run_queue, &dst_ref) {
+                continue;
+            }
+            stolen += 1;
+            // queued by its new vruntime, next to the local task rather than
+            // far before it
+            let dst_rq = dst.run_queue.lock();
+            let ready_task = dst_rq.ready_tasks.get_priority(&task.tid).unwrap();
+            assert_eq!(ready_task.vruntime, task.vruntime);
+            assert!(task.vruntime >= min_vruntime);
+            assert!(task.vruntime.delta(local_vruntime).abs() <= SCHED_LATENCY as isize);
+        }
+        assert!(stolen > 0);
+    }
+
+    #[test]
+    fn sleeper_placement_near_zero() {
+        // right after boot, a single latency reaches below zero
+        let min_vruntime = VRuntime(1000);
+        let thresh = 100 * SCHED_LATENCY;
+        let floor = min_vruntime - thresh;
+        assert!(floor < VRuntime(0) && floor < min_vruntime);
+        assert_eq!(floor.delta(min_vruntime), -(thresh as isize));
+        assert_eq!(floor + thresh, min_vruntime);
+
+        // a task which never slept keeps its vruntime, a new one starts at the floor
+        assert_eq!(VRuntime(500).max(floor), VRuntime(500));
+        assert_eq!(VRuntime::default().max(floor), VRuntime(0));
+        let mut vruntime = min_vruntime;
+        vruntime -= thresh;
+        assert_eq!(vruntime, floor);
+        vruntime -= VRuntime(usize::MAX);
+        assert_eq!(vruntime, floor + 1);
+    }
+
+    #[test]
+    fn yield_alternates() {
+        use crate::arch::timer::MOCK_COUNT;
+        // `task_tick` balances the load between the global executors
+        init(1);
+        let executor = Executor::new(0);
+        let (_a, a) = executor.spawn(async {}, 0, SpawnExtraOptions::none());
+        let (_b, b) = executor.spawn(async {}, 0, SpawnExtraOptions::none());
+        let (a, b) = (a.lock().tid, b.lock().tid);
+
+        let mut ran = Vec::new();
+        for _ in 0..20 {
+            let mut rq = executor.run_queue.lock();
+            let (tid, task, runnable) = rq.pop_task_to_run();
+            drop(rq);
+            // runs for a millisecond and yields, woken again at once
+            MOCK_COUNT.fetch_add(62_500, Ordering::Relaxed);
+            task.lock().yield_task();
+            let mut rq = executor.run_queue.lock();
+            let (vruntime, load) = {
+                let task = task.lock();
+                (task.vruntime, task.load)
+            };
+            rq.insert_task(tid, ReadyTask::new(vruntime, runnable), load);
+            rq.task_tick(task.lock());
+            ran.push(tid);
+        }
+        assert!(ran.windows(2).all(|pair| pair[0] != pair[1]));
+        assert!(ran.iter().all(|&tid| tid == a || tid == b));
+    }
+
+    #[test]
+    fn pull_updates_min_vruntime() {
+        let src = Executor::new(0);
+        let dst = Executor::new(1);
+        let _tasks: Vec<_> = (0..4)
+            .map(|_| src.spawn(async {}, 0, SpawnExtraOptions::none()))
+            .collect();
+
+        let dst_ref = dst.run_queue.clone();
+        let mut src_rq = src.run_queue.lock();
+        let mut dst_rq = dst.run_queue.lock();
+        assert_eq!(src_rq.min_vruntime, VRuntime(0));
+        dst_rq.pull_tasks(&mut src_rq, 2 * NICE_0_WEIGHT, &dst_ref);
+        assert_eq!(dst_rq.load.weight, WEIGHT_IDLEPRIO + 2 * NICE_0_WEIGHT);
+
+        // both follow the tasks now queued first
+        for rq in [&src_rq, &dst_rq].iter() {
+            let (_, next) = rq.peek();
+            assert!(rq.min_vruntime > VRuntime(0));
+            assert_eq!(rq.min_vruntime, next.vruntime);
+        }
+    }
+}
@@ -4,7 +4,7 @@ pub mod executor;
 mod future;
 pub mod timer;
 
-pub use executor::{Executor, local_executor, Task, SchedTaskRef};
+pub use executor::{Executor, local_executor, SchedPolicy, Task, SchedTaskRef};
 pub use future::*;
 pub use timer::delay_for;
 
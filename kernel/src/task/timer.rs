@@ -1,5 +1,5 @@
 use crate::{arch, sync::spin::MutexNoIrq};
-use alloc::collections::BTreeMap;
+use alloc::{collections::BTreeMap, vec::Vec};
 use core::{
     future::Future,
     pin::Pin,
@@ -9,25 +9,46 @@ use core::{
 
 pub static TIMER: MutexNoIrq<Timer> = MutexNoIrq::new(Timer::new());
 
-/// A naive timer.
+/// Wakers ordered by deadline.
 #[derive(Default)]
 pub struct Timer {
-    events: BTreeMap<Duration, Waker>,
+    /// Wakers sharing a deadline are woken in the order they were added, each
+    /// with the id of its `TimerHandle`.
+    events: BTreeMap<Duration, Vec<(u64, Waker)>>,
+    next_id: u64,
 }
 
 impl Timer {
     pub const fn new() -> Self {
         Timer {
             events: BTreeMap::new(),
+            next_id: 0,
         }
     }
 
-    /// Add a timer.
-    pub fn add(&mut self, mut deadline: Duration, waker: Waker) {
-        while self.events.contains_key(&deadline) {
-            deadline += Duration::from_nanos(1);
+    /// Add a timer, canceled when the returned handle is dropped.
+    pub fn add(&mut self, deadline: Duration, waker: Waker) -> TimerHandle {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.events.entry(deadline).or_default().push((id, waker));
+        // the tick of this CPU may be due later
+        arch::timer::tick_by(deadline);
+        TimerHandle { deadline, id }
+    }
+
+    /// Deadline of the earliest timer.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.events.keys().next().copied()
+    }
+
+    /// Remove a timer, if it did not expire yet.
+    fn cancel(&mut self, deadline: Duration, id: u64) {
+        if let Some(wakers) = self.events.get_mut(&deadline) {
+            wakers.retain(|&(i, _)| i != id);
+            if wakers.is_empty() {
+                self.events.remove(&deadline);
+            }
         }
-        self.events.insert(deadline, waker);
     }
 
     /// Expire timers.
@@ -38,35 +59,98 @@ impl Timer {
             if *entry.key() > now {
                 return;
             }
-            let (_, waker) = entry.remove_entry();
-            waker.wake();
+            for (_, waker) in entry.remove() {
+                waker.wake();
+            }
         }
     }
 }
 
+/// A pending timer, removed from `TIMER` on drop.
+///
+/// Dropping it locks `TIMER`, so it must not be dropped with `TIMER` locked.
+#[must_use = "the timer is canceled when the handle is dropped"]
+#[derive(Debug)]
+pub struct TimerHandle {
+    deadline: Duration,
+    id: u64,
+}
+
+impl Drop for TimerHandle {
+    fn drop(&mut self) {
+        TIMER.lock().cancel(self.deadline, self.id);
+    }
+}
+
 /// Creates a timer that expires after the given duration of time.
 pub async fn delay_for(duration: Duration) {
     DelayFuture {
         deadline: arch::timer::read() + duration,
+        timer: None,
     }
     .await;
 }
 
 pub struct DelayFuture {
     deadline: Duration,
+    timer: Option<TimerHandle>,
 }
 
 impl Future for DelayFuture {
     type Output = ();
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let deadline = self.deadline;
         // fast path
         if arch::timer::read() >= deadline {
             return Poll::Ready(());
         }
         let waker = cx.waker().clone();
-        TIMER.lock().add(deadline, waker);
+        let timer = TIMER.lock().add(deadline, waker);
+        // replaces the timer of the previous poll, with `TIMER` unlocked
+        self.timer = Some(timer);
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::mock::MockWaker;
+
+    #[test]
+    fn same_deadline() {
+        let deadline = Duration::from_secs(5);
+        let (mock, waker) = MockWaker::new();
+        let handles: Vec<_> = (0..100)
+            .map(|_| TIMER.lock().add(deadline, waker.clone()))
+            .collect();
+        assert_eq!(TIMER.lock().next_deadline(), Some(deadline));
+        TIMER.lock().expire(deadline - Duration::from_nanos(1));
+        assert_eq!(mock.wakes(), 0);
+        TIMER.lock().expire(deadline);
+        assert_eq!(mock.wakes(), 100);
+        assert_eq!(TIMER.lock().next_deadline(), None);
+        drop(handles);
+    }
+
+    #[test]
+    fn expire_in_order() {
+        let secs = Duration::from_secs;
+        let (mock, waker) = MockWaker::new();
+        let (canceled, canceled_waker) = MockWaker::new();
+        let _late = TIMER.lock().add(secs(3), waker.clone());
+        let _early = TIMER.lock().add(secs(1), waker.clone());
+        let handle = TIMER.lock().add(secs(2), canceled_waker);
+        let _next = TIMER.lock().add(secs(2), waker);
+        drop(handle);
+        assert_eq!(TIMER.lock().next_deadline(), Some(secs(1)));
+
+        TIMER.lock().expire(secs(2));
+        assert_eq!(mock.wakes(), 2);
+        assert_eq!(canceled.wakes(), 0);
+        assert_eq!(TIMER.lock().next_deadline(), Some(secs(3)));
+        TIMER.lock().expire(secs(10));
+        assert_eq!(mock.wakes(), 3);
+    }
+}
@@ -1,5 +1,9 @@
 use core::{slice, str};
 
+mod random;
+
+pub use random::{add_entropy, fill_random};
+
 /// Convert C string to Rust string.
 #[inline]
 pub unsafe fn from_cstr<'a>(s: *const u8) -> &'a str {
@@ -0,0 +1,116 @@
+//! Kernel random number generator.
+//!
+//! ChaCha20 keyed from the counter timer and the RTC, rekeyed after every
+//! request so earlier output can not be recovered from the state.
+// Ref: [https://datatracker.ietf.org/doc/html/rfc8439]
+
+use crate::{arch::timer, drivers::read_epoch, sync::MutexNoIrq};
+use core::time::Duration;
+use spin::Lazy;
+
+static RNG: Lazy<MutexNoIrq<ChaCha20Rng>> = Lazy::new(|| {
+    let epoch: Duration = read_epoch().into();
+    let mut seed = [0u32; 8];
+    let count = timer::read_count();
+    seed[0] = count as u32;
+    seed[1] = (count >> 32) as u32;
+    seed[2] = epoch.as_secs() as u32;
+    seed[3] = (epoch.as_secs() >> 32) as u32;
+    seed[4] = epoch.subsec_nanos();
+    MutexNoIrq::new(ChaCha20Rng::new(seed))
+});
+
+/// Bytes generated per lock of `RNG`, which keeps IRQs off while held.
+const CHUNK_SIZE: usize = 256;
+
+/// Fill `buf` with random bytes.
+pub fn fill_random(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(CHUNK_SIZE) {
+        RNG.lock().fill(chunk);
+    }
+}
+
+/// Mix `data` into the generator state.
+pub fn add_entropy(data: &[u8]) {
+    RNG.lock().mix(data);
+}
+
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+struct ChaCha20Rng {
+    key: [u32; 8],
+    counter: u64,
+}
+
+impl ChaCha20Rng {
+    fn new(key: [u32; 8]) -> Self {
+        let mut rng = ChaCha20Rng { key, counter: 0 };
+        // mix in the time of the first use
+        rng.mix(&timer::read_count().to_ne_bytes());
+        rng
+    }
+
+    fn block(&mut self) -> [u32; 16] {
+        let mut input = [0u32; 16];
+        input[..4].copy_from_slice(&CONSTANTS);
+        input[4..12].copy_from_slice(&self.key);
+        input[12] = self.counter as u32;
+        input[13] = (self.counter >> 32) as u32;
+        self.counter = self.counter.wrapping_add(1);
+
+        let mut x = input;
+        for _ in 0..10 {
+            // column rounds
+            quarter_round(&mut x, 0, 4, 8, 12);
+            quarter_round(&mut x, 1, 5, 9, 13);
+            quarter_round(&mut x, 2, 6, 10, 14);
+            quarter_round(&mut x, 3, 7, 11, 15);
+            // diagonal rounds
+            quarter_round(&mut x, 0, 5, 10, 15);
+            quarter_round(&mut x, 1, 6, 11, 12);
+            quarter_round(&mut x, 2, 7, 8, 13);
+            quarter_round(&mut x, 3, 4, 9, 14);
+        }
+        for (x, input) in x.iter_mut().zip(input.iter()) {
+            *x = x.wrapping_add(*input);
+        }
+        x
+    }
+
+    /// Replace the key with fresh output.
+    fn rekey(&mut self) {
+        let block = self.block();
+        self.key.copy_from_slice(&block[..8]);
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(64) {
+            let block = self.block();
+            for (bytes, word) in chunk.chunks_mut(4).zip(block.iter()) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+        }
+        self.rekey();
+    }
+
+    fn mix(&mut self, data: &[u8]) {
+        for (i, bytes) in data.chunks(4).enumerate() {
+            let mut word = [0u8; 4];
+            word[..bytes.len()].copy_from_slice(bytes);
+            self.key[i % 8] ^= u32::from_le_bytes(word);
+        }
+        self.rekey();
+    }
+}
+
+#[inline]
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
@@ -0,0 +1,64 @@
+#!/usr/bin/env python3
+"""Build the kernel symbol table embedded in the `.ksymtab` section.
+
+Reads `nm --defined-only --numeric-sort --print-size --demangle` output from
+stdin and writes the table, padded to the section size given as argument, to
+stdout. Layout, little-endian:
+
+    u32 magic "KSYM", u32 count
+    count * (u64 address, u64 size, u32 name offset, u32 name length)
+    names, offsets are relative to the start of the names
+"""
+
+import struct
+import sys
+
+MAGIC = 0x4D59534B
+HEADER = struct.Struct("<II")
+ENTRY = struct.Struct("<QQII")
+MAX_NAME_LEN = 128
+# code symbols, local or global, and weak ones
+CODE_KINDS = {"t", "T", "w", "W"}
+
+
+def parse(lines):
+    symbols = []
+    for line in lines:
+        line = line.rstrip("\n")
+        fields = line.split(" ", 3)
+        if len(fields) < 3:
+            continue
+        if len(fields[1]) == 1:
+            # no size
+            address, kind, name = line.split(" ", 2)
+            size = "0"
+        else:
+            address, size, kind, name = fields
+        if kind not in CODE_KINDS:
+            continue
+        symbols.append((int(address, 16), int(size, 16), name.encode()[:MAX_NAME_LEN]))
+    return symbols
+
+
+def build(symbols, capacity):
+    entries, names = [], bytearray()
+    used = HEADER.size
+    for address, size, name in symbols:
+        used += ENTRY.size + len(name)
+        if used > capacity:
+            print("ksymtab: table full, dropped %d symbols" % (len(symbols) - len(entries)),
+                  file=sys.stderr)
+            break
+        entries.append(ENTRY.pack(address, size, len(names), len(name)))
+        names += name
+    table = HEADER.pack(MAGIC, len(entries)) + b"".join(entries) + names
+    return table + bytes(capacity - len(table))
+
+
+def main():
+    capacity = int(sys.argv[1], 0)
+    sys.stdout.buffer.write(build(parse(sys.stdin), capacity))
+
+
+if __name__ == "__main__":
+    main()